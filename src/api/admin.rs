@@ -0,0 +1,250 @@
+use crate::api::auth::admin_only;
+use crate::api::ApiState;
+use crate::services::archive;
+use crate::services::auth::Caller;
+use crate::services::epub_parser::PARSER_VERSION;
+use crate::services::reparse::{self, ReparseError, ReparseReport};
+use crate::services::store;
+use actix_multipart::Multipart;
+use actix_web::http::header::ContentDisposition;
+use actix_web::{get, post, web, HttpResponse, Responder};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::{Seek, SeekFrom, Write};
+use tokio_util::io::ReaderStream;
+
+#[derive(Debug, Deserialize)]
+pub struct ReparseAllParams {
+    /// Only re-parse documents parsed by an older parser version
+    #[serde(default)]
+    pub outdated_only: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ReparseFailure {
+    document_id: i64,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ReparseAllResponse {
+    parser_version: i64,
+    reparsed: Vec<ReparseReport>,
+    failed: Vec<ReparseFailure>,
+}
+
+#[post("/admin/documents/{id}/reparse")]
+async fn reparse_document(
+    path: web::Path<i64>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let id = path.into_inner();
+
+    let result = store::run(&data.store, move |store| {
+        Ok(reparse::reparse_document(store, id))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        Ok(Err(e)) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", id))
+        }
+        Ok(Err(e @ (ReparseError::NoOriginal(_) | ReparseError::Parse(..)))) => {
+            HttpResponse::UnprocessableEntity().body(e.to_string())
+        }
+        Ok(Err(e)) => {
+            HttpResponse::InternalServerError().body(format!("Error re-parsing document: {}", e))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error re-parsing document: {}", e))
+        }
+    }
+}
+
+#[post("/admin/documents/reparse")]
+async fn reparse_all(
+    params: web::Query<ReparseAllParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let outdated_only = params.outdated_only;
+
+    let result = store::run(&data.store, move |store| {
+        Ok(reparse::reparse_all(store, outdated_only))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(results)) => {
+            let mut response = ReparseAllResponse {
+                parser_version: PARSER_VERSION,
+                reparsed: Vec::new(),
+                failed: Vec::new(),
+            };
+            for (document_id, result) in results {
+                match result {
+                    Ok(report) => response.reparsed.push(report),
+                    Err(e) => response.failed.push(ReparseFailure {
+                        document_id,
+                        error: e.to_string(),
+                    }),
+                }
+            }
+
+            HttpResponse::Ok().json(response)
+        }
+        Ok(Err(e)) => {
+            HttpResponse::InternalServerError().body(format!("Error re-parsing documents: {}", e))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error re-parsing documents: {}", e))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// Also bundle the audio cache
+    #[serde(default)]
+    pub audio: bool,
+}
+
+/// Download the whole library as a zip archive, see `services::archive`
+#[get("/admin/export")]
+async fn export_library(
+    params: web::Query<ExportParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let audio = match data.tts_service.audio_cache() {
+        Some(cache) if params.audio => Some(cache.clone()),
+        None if params.audio => {
+            return HttpResponse::BadRequest().body("The audio cache is disabled on this server")
+        }
+        _ => None,
+    };
+    let result = store::run(&data.store, move |store| {
+        Ok(archive::export_to_tempfile(store, audio.as_deref()))
+    })
+    .await;
+
+    let file = match result {
+        Ok(Ok(file)) => file,
+        Ok(Err(e)) if e.is_unsupported() => {
+            return HttpResponse::NotImplemented().body(format!("Error exporting library: {}", e))
+        }
+        Ok(Err(e)) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error exporting library: {}", e))
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error exporting library: {}", e))
+        }
+    };
+
+    let length = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error exporting library: {}", e))
+        }
+    };
+
+    // The temporary file has no name, it goes away once the response is sent
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .append_header(ContentDisposition::attachment("library.zip"))
+        .no_chunking(length)
+        .streaming(ReaderStream::new(tokio::fs::File::from_std(file)))
+}
+
+/// Add the documents of an exported archive to the library, under new ids
+#[post("/admin/import")]
+async fn import_library(
+    mut payload: Multipart,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let mut file = match tempfile::tempfile() {
+        Ok(file) => file,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error storing archive: {}", e))
+        }
+    };
+
+    let mut received = false;
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(field) => field,
+            Err(e) => {
+                return HttpResponse::BadRequest().body(format!("Error processing form: {}", e))
+            }
+        };
+        if field.content_disposition().get_filename().is_none() {
+            continue;
+        }
+
+        // Archives can be large, so they are spooled to disk instead of kept in memory
+        while let Some(chunk) = field.next().await {
+            let written = match chunk {
+                Ok(bytes) => file.write_all(&bytes),
+                Err(e) => {
+                    return HttpResponse::BadRequest().body(format!("Error reading file: {}", e))
+                }
+            };
+            if let Err(e) = written {
+                return HttpResponse::InternalServerError()
+                    .body(format!("Error storing archive: {}", e));
+            }
+        }
+        received = true;
+        break;
+    }
+
+    if !received {
+        return HttpResponse::BadRequest().body("No archive found in the upload");
+    }
+    if let Err(e) = file.seek(SeekFrom::Start(0)) {
+        return HttpResponse::InternalServerError().body(format!("Error storing archive: {}", e));
+    }
+
+    let importer = caller.user_id;
+    let audio = data.tts_service.audio_cache().cloned();
+    let result = store::run(&data.store, move |store| {
+        Ok(store.import_library(file, importer, audio.as_ref()))
+    })
+    .await;
+
+    match result {
+        Ok(Ok(imported)) => HttpResponse::Ok().json(json!({ "imported": imported })),
+        Ok(Err(e)) if e.is_unsupported() => {
+            HttpResponse::NotImplemented().body(format!("Error importing library: {}", e))
+        }
+        Ok(Err(e)) if e.is_invalid_archive() => {
+            HttpResponse::BadRequest().body(format!("Error importing library: {}", e))
+        }
+        Ok(Err(e)) => {
+            HttpResponse::InternalServerError().body(format!("Error importing library: {}", e))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error importing library: {}", e))
+        }
+    }
+}
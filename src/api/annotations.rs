@@ -0,0 +1,382 @@
+use crate::api::documents::attachment;
+use crate::api::ApiState;
+use crate::services::annotations::{
+    self, Annotation, AnnotationKind, AnnotationUpdate, NewAnnotation,
+};
+use crate::services::auth::Caller;
+use crate::services::store::{self, StoreError};
+use actix_web::{delete, get, patch, post, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+/// Longest accepted note, in characters
+const MAX_NOTE_LENGTH: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateAnnotation {
+    pub kind: AnnotationKind,
+    pub chapter_index: usize,
+    pub start_offset: usize,
+    /// Defaults to `start_offset`, which is what bookmarks use
+    pub end_offset: Option<usize>,
+    pub note: Option<String>,
+    pub color: Option<String>,
+    /// The text the client saw at the offsets; refused if the stored text differs
+    pub quote: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    pub kind: Option<AnnotationKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// `markdown` (the default) or `json`
+    pub format: Option<ExportFormat>,
+}
+
+#[derive(Debug, Serialize)]
+struct AnnotationsResponse {
+    document_id: i64,
+    annotations: Vec<Annotation>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedDocument {
+    id: i64,
+    title: String,
+    author: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AnnotationsExport {
+    document: ExportedDocument,
+    annotations: Vec<Annotation>,
+}
+
+/// Trim a note; an empty note is no note
+fn validate_note(note: Option<String>) -> Result<Option<String>, String> {
+    let note = note
+        .map(|note| note.trim().to_string())
+        .filter(|note| !note.is_empty());
+    if note
+        .as_ref()
+        .is_some_and(|note| note.chars().count() > MAX_NOTE_LENGTH)
+    {
+        return Err(format!(
+            "note must be at most {} characters",
+            MAX_NOTE_LENGTH
+        ));
+    }
+    Ok(note)
+}
+
+/// Colors are a CSS hex color such as `#ffd54f` or a lowercase name such as `yellow`
+fn validate_color(color: Option<String>) -> Result<Option<String>, String> {
+    let Some(color) = color.map(|color| color.trim().to_string()) else {
+        return Ok(None);
+    };
+    if color.is_empty() {
+        return Ok(None);
+    }
+
+    let valid = match color.strip_prefix('#') {
+        Some(hex) => [3, 6].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => color.len() <= 20 && color.chars().all(|c| c.is_ascii_lowercase()),
+    };
+    if !valid {
+        return Err(format!("{} is not a valid color", color));
+    }
+    Ok(Some(color))
+}
+
+/// Check a new annotation against its kind, apart from its position in the text
+fn validate_create(
+    request: &CreateAnnotation,
+) -> Result<(usize, Option<String>, Option<String>), String> {
+    let end = request.end_offset.unwrap_or(request.start_offset);
+    match request.kind {
+        AnnotationKind::Bookmark if end != request.start_offset => {
+            return Err("A bookmark is a position; end_offset must equal start_offset".to_string())
+        }
+        AnnotationKind::Highlight | AnnotationKind::Note if end <= request.start_offset => {
+            return Err("end_offset must be after start_offset".to_string())
+        }
+        _ => {}
+    }
+
+    let note = validate_note(request.note.clone())?;
+    if request.kind == AnnotationKind::Note && note.is_none() {
+        return Err("A note needs note text".to_string());
+    }
+    let color = validate_color(request.color.clone())?;
+
+    Ok((end, note, color))
+}
+
+fn error_response(e: StoreError, document_id: i64, action: &str) -> HttpResponse {
+    match e {
+        StoreError::NotFound => HttpResponse::NotFound().body(format!(
+            "Document or annotation not found in document {}",
+            document_id
+        )),
+        StoreError::Invalid(message) => HttpResponse::BadRequest().body(message),
+        e => HttpResponse::InternalServerError().body(format!("Error {}: {}", action, e)),
+    }
+}
+
+#[post("/document/{id}/annotations")]
+async fn create_annotation(
+    path: web::Path<i64>,
+    request: web::Json<CreateAnnotation>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let document_id = path.into_inner();
+    let request = request.into_inner();
+    let (end, note, color) = match validate_create(&request) {
+        Ok(fields) => fields,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let result = store::run(&data.store, move |store| {
+        let chapters = store.get_chapters(document_id)?;
+        let chapter = chapters.get(request.chapter_index).ok_or_else(|| {
+            StoreError::Invalid(format!("Chapter not found: {}", request.chapter_index))
+        })?;
+
+        let anchor = annotations::anchor(
+            &chapter.text,
+            request.chapter_index,
+            request.start_offset,
+            end,
+        )
+        .map_err(StoreError::Invalid)?;
+        if request
+            .quote
+            .as_ref()
+            .is_some_and(|quote| *quote != anchor.quote)
+        {
+            return Err(StoreError::Invalid(
+                "quote does not match the text at the given offsets".to_string(),
+            ));
+        }
+
+        store.create_annotation(
+            document_id,
+            &NewAnnotation {
+                user_id: caller.user_id,
+                kind: request.kind,
+                anchor,
+                note,
+                color,
+            },
+        )
+    })
+    .await;
+
+    match result {
+        Ok(annotation) => HttpResponse::Created().json(annotation),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", document_id))
+        }
+        Err(e) => error_response(e, document_id, "creating annotation"),
+    }
+}
+
+#[get("/document/{id}/annotations")]
+async fn list_annotations(
+    path: web::Path<i64>,
+    params: web::Query<ListParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let document_id = path.into_inner();
+    let kind = params.kind;
+
+    match store::run(&data.store, move |store| {
+        store.list_annotations(document_id, caller.user_id)
+    })
+    .await
+    {
+        Ok(mut annotations) => {
+            if let Some(kind) = kind {
+                annotations.retain(|annotation| annotation.kind == kind);
+            }
+            HttpResponse::Ok().json(AnnotationsResponse {
+                document_id,
+                annotations,
+            })
+        }
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", document_id))
+        }
+        Err(e) => error_response(e, document_id, "listing annotations"),
+    }
+}
+
+/// Download all of the caller's annotations of a book as Markdown or JSON
+#[get("/document/{id}/annotations/export")]
+async fn export_annotations(
+    path: web::Path<i64>,
+    params: web::Query<ExportParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let document_id = path.into_inner();
+    let format = params.format.unwrap_or(ExportFormat::Markdown);
+
+    let result = store::run(&data.store, move |store| {
+        let document = store.get_document(document_id)?;
+        let chapters = store.get_chapters(document_id)?;
+        let annotations = store.list_annotations(document_id, caller.user_id)?;
+        Ok((document, chapters, annotations))
+    })
+    .await;
+
+    let (document, chapters, annotations) = match result {
+        Ok(loaded) => loaded,
+        Err(StoreError::NotFound) => {
+            return HttpResponse::NotFound().body(format!("Document not found: {}", document_id))
+        }
+        Err(e) => return error_response(e, document_id, "exporting annotations"),
+    };
+    let metadata = document.metadata;
+
+    match format {
+        ExportFormat::Markdown => {
+            let chapter_titles: Vec<String> =
+                chapters.into_iter().map(|chapter| chapter.title).collect();
+            let markdown = annotations::to_markdown(
+                &metadata.title,
+                &metadata.author,
+                &chapter_titles,
+                &annotations,
+            );
+
+            HttpResponse::Ok()
+                .content_type("text/markdown; charset=utf-8")
+                .append_header(attachment(&format!("{} - annotations.md", metadata.title)))
+                .body(markdown)
+        }
+        ExportFormat::Json => HttpResponse::Ok()
+            .append_header(attachment(&format!(
+                "{} - annotations.json",
+                metadata.title
+            )))
+            .json(AnnotationsExport {
+                document: ExportedDocument {
+                    id: document.id,
+                    title: metadata.title,
+                    author: metadata.author,
+                },
+                annotations,
+            }),
+    }
+}
+
+#[patch("/document/{id}/annotations/{annotation_id}")]
+async fn update_annotation(
+    path: web::Path<(i64, i64)>,
+    update: web::Json<AnnotationUpdate>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let (document_id, id) = path.into_inner();
+    let update = update.into_inner();
+
+    let validated = update.note.map(validate_note).transpose().and_then(|note| {
+        let color = update.color.map(validate_color).transpose()?;
+        Ok(AnnotationUpdate { note, color })
+    });
+    let update = match validated {
+        Ok(update) if update.note.is_none() && update.color.is_none() => {
+            return HttpResponse::BadRequest().body("No fields to update")
+        }
+        Ok(update) => update,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let result = store::run(&data.store, move |store| {
+        store.update_annotation(document_id, caller.user_id, id, &update)
+    })
+    .await;
+
+    match result {
+        Ok(annotation) => HttpResponse::Ok().json(annotation),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Annotation not found: {}", id))
+        }
+        Err(e) => error_response(e, document_id, "updating annotation"),
+    }
+}
+
+#[delete("/document/{id}/annotations/{annotation_id}")]
+async fn delete_annotation(
+    path: web::Path<(i64, i64)>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let (document_id, id) = path.into_inner();
+
+    let result = store::run(&data.store, move |store| {
+        store.delete_annotation(document_id, caller.user_id, id)
+    })
+    .await;
+
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Annotation not found: {}", id))
+        }
+        Err(e) => error_response(e, document_id, "deleting annotation"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_create_checks_kind() {
+        let request = |body: &str| serde_json::from_str::<CreateAnnotation>(body).unwrap();
+
+        let (end, note, color) = validate_create(&request(
+            r##"{"kind": "note", "chapter_index": 0, "start_offset": 3, "end_offset": 9,
+                 "note": " Who is he? ", "color": "#FFD54F"}"##,
+        ))
+        .unwrap();
+        assert_eq!(end, 9);
+        assert_eq!(note.as_deref(), Some("Who is he?"));
+        assert_eq!(color.as_deref(), Some("#FFD54F"));
+
+        let (end, _, _) = validate_create(&request(
+            r#"{"kind": "bookmark", "chapter_index": 2, "start_offset": 40}"#,
+        ))
+        .unwrap();
+        assert_eq!(end, 40);
+
+        let invalid = [
+            r#"{"kind": "highlight", "chapter_index": 0, "start_offset": 5}"#,
+            r#"{"kind": "bookmark", "chapter_index": 0, "start_offset": 5, "end_offset": 8}"#,
+            r#"{"kind": "note", "chapter_index": 0, "start_offset": 5, "end_offset": 8}"#,
+            r#"{"kind": "highlight", "chapter_index": 0, "start_offset": 5, "end_offset": 8,
+                "color": "url(evil)"}"#,
+        ];
+        for body in invalid {
+            assert!(
+                validate_create(&request(body)).is_err(),
+                "{} should be rejected",
+                body
+            );
+        }
+    }
+}
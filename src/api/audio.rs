@@ -0,0 +1,285 @@
+//! Chapter audio and audiobooks served from disk, in the format the client asks for
+//!
+//! The format is chosen by `?format=`, or else by the `Accept` header, and is WAV when
+//! neither names one.
+//! Cached audio is tagged with its cache key, which changes with the text, voice and prosody,
+//! so clients revalidating with `If-None-Match` or `If-Modified-Since` get `304 Not Modified`.
+//! Its length is known, so a single byte range can be requested for seeking and resuming
+//! downloads; requests for several ranges get the whole file.
+//! Finished audiobook exports are sent the same way.
+
+use crate::services::audio_cache::CachedAudio;
+use crate::services::audio_format::AudioFormat;
+use actix_web::body::SizedStream;
+use actix_web::http::header::{
+    Accept, ContentDisposition, ContentRange, ContentRangeSpec, DispositionType, ETag, EntityTag,
+    HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range,
+    ACCEPT_RANGES, VARY,
+};
+use actix_web::http::Method;
+use actix_web::web::Bytes;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde::Deserialize;
+use std::io::SeekFrom;
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FormatRequest {
+    /// One of [`AudioFormat::ALL`] by name, taking precedence over `Accept`
+    pub format: Option<String>,
+}
+
+/// The format a request asks for, in `?format=` or else in its `Accept` header
+pub fn requested_format(req: &HttpRequest, query: &FormatRequest) -> Result<AudioFormat, String> {
+    if let Some(name) = &query.format {
+        return AudioFormat::parse(name).ok_or_else(|| {
+            let names: Vec<_> = AudioFormat::ALL.iter().map(AudioFormat::name).collect();
+            format!("Unknown format {}, use one of {}", name, names.join(", "))
+        });
+    }
+
+    // Wildcards and formats we cannot make leave the choice to us
+    let format = req.get_header::<Accept>().and_then(|accept| {
+        accept
+            .ranked()
+            .iter()
+            .find_map(|mime| AudioFormat::from_media_type(mime.essence_str()))
+    });
+    Ok(format.unwrap_or(AudioFormat::Wav))
+}
+
+/// Headers of every chapter's audio in `format`, cached or not
+pub fn format_headers(response: &mut HttpResponseBuilder, format: AudioFormat) {
+    response
+        .content_type(format.content_type())
+        .insert_header((VARY, "Accept"))
+        .append_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![],
+        });
+}
+
+/// The part of a file of known length a request asks for
+#[derive(Debug, PartialEq)]
+enum Requested {
+    Whole,
+    /// First and last byte
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// Whether the client's copy of the audio is still current
+fn not_modified(req: &HttpRequest, etag: &EntityTag, last_modified: HttpDate) -> bool {
+    // A tag is the better validator, dates only count without one
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        };
+    }
+    match req.get_header::<IfModifiedSince>() {
+        Some(IfModifiedSince(since)) => SystemTime::from(last_modified) <= SystemTime::from(since),
+        None => false,
+    }
+}
+
+/// The range of `len` bytes a request asks for; ranges the client made for another version
+/// of the audio, as told by `If-Range`, are ignored
+fn requested(req: &HttpRequest, len: u64, etag: &EntityTag, last_modified: HttpDate) -> Requested {
+    let Some(Range::Bytes(ranges)) = req.get_header::<Range>() else {
+        return Requested::Whole;
+    };
+    let current = match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(IfRange::Date(date)) => SystemTime::from(date) == SystemTime::from(last_modified),
+        None => true,
+    };
+    if !current {
+        return Requested::Whole;
+    }
+
+    match ranges.as_slice() {
+        [range] => match range.to_satisfiable_range(len) {
+            Some((first, last)) => Requested::Part(first, last),
+            None => Requested::Unsatisfiable,
+        },
+        _ => Requested::Whole,
+    }
+}
+
+fn file_headers(
+    mut response: HttpResponseBuilder,
+    content_type: &str,
+    disposition: ContentDisposition,
+    etag: EntityTag,
+    last_modified: HttpDate,
+) -> HttpResponseBuilder {
+    response
+        .content_type(content_type)
+        .append_header(disposition)
+        .insert_header(ETag(etag))
+        .insert_header(LastModified(last_modified))
+        .insert_header((ACCEPT_RANGES, "bytes"));
+    response
+}
+
+/// The response for a chapter found in the audio cache, or the part of it asked for
+pub async fn cached_response(
+    req: &HttpRequest,
+    cached: CachedAudio,
+    format: AudioFormat,
+) -> actix_web::Result<HttpResponse> {
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![],
+    };
+    let mut response = file_response(req, cached, format.content_type(), disposition).await?;
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("Accept"));
+    Ok(response)
+}
+
+/// The response for a complete audio file, or the part of it asked for
+pub async fn file_response(
+    req: &HttpRequest,
+    cached: CachedAudio,
+    content_type: &str,
+    disposition: ContentDisposition,
+) -> actix_web::Result<HttpResponse> {
+    let etag = EntityTag::new_strong(cached.key.clone());
+    let last_modified = HttpDate::from(cached.modified);
+
+    if not_modified(req, &etag, last_modified) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(LastModified(last_modified))
+            .finish());
+    }
+
+    let (mut response, first, len) = match requested(req, cached.len, &etag, last_modified) {
+        Requested::Whole => (HttpResponse::Ok(), 0, cached.len),
+        Requested::Part(first, last) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((first, last)),
+                instance_length: Some(cached.len),
+            }));
+            (response, first, last - first + 1)
+        }
+        Requested::Unsatisfiable => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(cached.len),
+                }))
+                .finish());
+        }
+    };
+    response = file_headers(response, content_type, disposition, etag, last_modified);
+
+    if req.method() == Method::HEAD {
+        // The length is what HEAD is for, the body is dropped anyway
+        let nothing = futures_util::stream::empty::<std::io::Result<Bytes>>();
+        return Ok(response.body(SizedStream::new(len, nothing)));
+    }
+
+    let mut file = tokio::fs::File::open(&cached.path).await?;
+    file.seek(SeekFrom::Start(first)).await?;
+    Ok(response.body(SizedStream::new(len, ReaderStream::new(file.take(len)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_requested_formats() {
+        let requested = |query: Option<&str>, accept: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(accept) = accept {
+                req = req.insert_header(("Accept", accept));
+            }
+            let query = FormatRequest {
+                format: query.map(str::to_string),
+            };
+            requested_format(&req.to_http_request(), &query)
+        };
+
+        assert_eq!(requested(None, None), Ok(AudioFormat::Wav));
+        assert_eq!(requested(Some("mp3"), None), Ok(AudioFormat::Mp3));
+        assert_eq!(
+            requested(Some("FLAC"), Some("audio/ogg")),
+            Ok(AudioFormat::Flac)
+        );
+        assert!(requested(Some("aac"), None).unwrap_err().contains("opus"));
+        assert_eq!(requested(None, Some("audio/ogg")), Ok(AudioFormat::Opus));
+        assert_eq!(
+            requested(None, Some("audio/mpeg;q=0.5, audio/flac")),
+            Ok(AudioFormat::Flac)
+        );
+        assert_eq!(
+            requested(None, Some("audio/aac, audio/mpeg;q=0.1")),
+            Ok(AudioFormat::Mp3)
+        );
+        assert_eq!(requested(None, Some("*/*")), Ok(AudioFormat::Wav));
+        assert_eq!(requested(None, Some("audio/*")), Ok(AudioFormat::Wav));
+    }
+
+    #[test]
+    fn test_requested_ranges() {
+        let etag = EntityTag::new_strong("abc".to_string());
+        let modified = HttpDate::from(SystemTime::UNIX_EPOCH);
+        let requested = |headers: &[(&str, &str)]| {
+            let mut req = TestRequest::default();
+            for &header in headers {
+                req = req.insert_header(header);
+            }
+            requested(&req.to_http_request(), 1000, &etag, modified)
+        };
+
+        assert_eq!(requested(&[]), Requested::Whole);
+        assert_eq!(
+            requested(&[("Range", "bytes=0-99")]),
+            Requested::Part(0, 99)
+        );
+        assert_eq!(
+            requested(&[("Range", "bytes=900-")]),
+            Requested::Part(900, 999)
+        );
+        assert_eq!(
+            requested(&[("Range", "bytes=-100")]),
+            Requested::Part(900, 999)
+        );
+        assert_eq!(
+            requested(&[("Range", "bytes=500-5000")]),
+            Requested::Part(500, 999)
+        );
+        assert_eq!(
+            requested(&[("Range", "bytes=1000-")]),
+            Requested::Unsatisfiable
+        );
+        assert_eq!(requested(&[("Range", "bytes=0-1,5-6")]), Requested::Whole);
+        assert_eq!(requested(&[("Range", "pages=1-2")]), Requested::Whole);
+
+        // Resuming a download of the same audio
+        assert_eq!(
+            requested(&[("Range", "bytes=10-"), ("If-Range", "\"abc\"")]),
+            Requested::Part(10, 999)
+        );
+        assert_eq!(
+            requested(&[("Range", "bytes=10-"), ("If-Range", "\"old\"")]),
+            Requested::Whole
+        );
+        assert_eq!(
+            requested(&[
+                ("Range", "bytes=10-"),
+                ("If-Range", "Thu, 01 Jan 1970 00:00:00 GMT")
+            ]),
+            Requested::Part(10, 999)
+        );
+    }
+}
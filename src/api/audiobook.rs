@@ -0,0 +1,219 @@
+//! Whole-book audio exports, see `services::audiobook`
+//!
+//! Asking for an export queues it and answers with `202 Accepted` and where to follow its
+//! progress; once it is done, the book is downloaded like cached chapter audio.
+
+use crate::api::{audio, get_language_from_header, usage, ApiState};
+use crate::services::audio_cache::CachedAudio;
+use crate::services::audiobook::{
+    self, AudiobookError, AudiobookFormat, BookInfo, Cover, Export, Job, JobState, JobStatus, Work,
+};
+use crate::services::auth::Caller;
+use crate::services::epub_parser;
+use crate::services::store::{self, StoreError};
+use crate::services::voices::VoiceRequest;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType, LOCATION};
+use actix_web::{get, post, route, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportRequest {
+    /// `m4b` or `mp3`, M4B by default
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct JobResponse {
+    #[serde(flatten)]
+    status: JobStatus,
+    /// Where the finished book is
+    download_url: Option<String>,
+}
+
+fn job_response(job: &Job) -> JobResponse {
+    let status = job.status();
+    let download_url =
+        (status.state == JobState::Done).then(|| format!("/audiobook/{}/download", status.id));
+    JobResponse {
+        status,
+        download_url,
+    }
+}
+
+/// An export the caller may see: their own, or anyone's for admins
+fn visible_job(data: &ApiState, caller: &Caller, id: &str) -> Option<Arc<Job>> {
+    data.audiobooks
+        .get(id)
+        .filter(|job| caller.is_admin() || job.owner() == caller.user_id)
+}
+
+#[post("/document/{id}/audiobook")]
+async fn create_audiobook(
+    path: web::Path<i64>,
+    params: web::Query<VoiceRequest>,
+    export: web::Query<ExportRequest>,
+    req: HttpRequest,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+
+    let format = match export.format.as_deref() {
+        None => AudiobookFormat::M4b,
+        Some(name) => match AudiobookFormat::parse(name) {
+            Some(format) => format,
+            None => {
+                return HttpResponse::BadRequest()
+                    .body(format!("Unknown format {}, use m4b or mp3", name))
+            }
+        },
+    };
+
+    let result = store::run(&data.store, move |store| {
+        let document = store.get_document(id)?;
+        let chapters = store.get_chapters(id)?;
+        let toc = store.get_toc(id)?;
+        let original = match store.get_original(id) {
+            Ok(original) => Some(original),
+            Err(StoreError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        Ok((document, chapters, toc, original))
+    })
+    .await;
+    let (document, chapters, toc, original) = match result {
+        Ok(found) => found,
+        Err(e) if e.is_not_found() => {
+            return HttpResponse::NotFound().body(format!("Document not found: {}", id))
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Error retrieving document: {}", e))
+        }
+    };
+
+    let language = document
+        .metadata
+        .language
+        .clone()
+        .filter(|language| !language.trim().is_empty())
+        .unwrap_or_else(|| get_language_from_header(&req));
+    let settings = match data.tts_service.voices().select(&params, Some(&language)) {
+        Ok(settings) => settings,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let chapters = audiobook::book_chapters(&chapters, &toc);
+    if chapters.is_empty() {
+        return HttpResponse::UnprocessableEntity()
+            .body(format!("Document {} has no text to read", id));
+    }
+    if let Err(response) = usage::check_audio(&data, &caller).await {
+        return response;
+    }
+
+    let cover = original
+        .and_then(|original| epub_parser::parse_cover(&original.data))
+        .map(|(data, media_type)| Cover { data, media_type });
+    let export = Export {
+        document_id: id,
+        format,
+        info: BookInfo {
+            title: document.metadata.title,
+            author: document.metadata.author,
+            cover,
+        },
+        chapters,
+    };
+
+    let tts_service = data.tts_service.with_settings(&settings).for_document(id);
+    let key = format!("{} {} {}", id, tts_service.settings(), format.name());
+    let store = data.store.clone();
+    let record = usage::audio_recorder(&data, &caller);
+    let work: Work = Box::new(move |job, export, file| {
+        // Each chapter counts as it is synthesized, and none is started over the quota
+        audiobook::build(
+            export,
+            store.as_ref(),
+            &tts_service,
+            file,
+            || job.chapter_done(),
+            |seconds| Ok(record(seconds)?),
+        )
+    });
+
+    // The quota is only checked here and between chapters, so users with an audio quota
+    // export one book at a time
+    let one_at_a_time =
+        usage::limited(&caller).is_some() && data.quotas.max_audio_seconds.is_some();
+    let submitted = data
+        .audiobooks
+        .submit(caller.user_id, key, export, work, one_at_a_time);
+
+    match submitted {
+        Ok(job) => {
+            let response = job_response(&job);
+            HttpResponse::Accepted()
+                .insert_header((LOCATION, format!("/audiobook/{}", response.status.id)))
+                .json(response)
+        }
+        Err(e @ AudiobookError::InProgress(_)) => {
+            HttpResponse::TooManyRequests().body(e.to_string())
+        }
+        Err(e) => {
+            error!("Failed to queue audiobook: {}", e);
+            HttpResponse::InternalServerError().body(format!("Error queueing audiobook: {}", e))
+        }
+    }
+}
+
+#[get("/audiobook/{id}")]
+async fn get_audiobook(
+    path: web::Path<String>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+
+    match visible_job(&data, &caller, &id) {
+        Some(job) => HttpResponse::Ok().json(job_response(&job)),
+        None => HttpResponse::NotFound().body(format!("Audiobook not found: {}", id)),
+    }
+}
+
+#[route("/audiobook/{id}/download", method = "GET", method = "HEAD")]
+async fn download_audiobook(
+    path: web::Path<String>,
+    req: HttpRequest,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> actix_web::Result<HttpResponse> {
+    let id = path.into_inner();
+
+    let Some(job) = visible_job(&data, &caller, &id) else {
+        return Ok(HttpResponse::NotFound().body(format!("Audiobook not found: {}", id)));
+    };
+    let status = job.status();
+    if status.state != JobState::Done {
+        return Ok(HttpResponse::Conflict().body(format!(
+            "Audiobook {} is not ready, it is {}",
+            id,
+            status.state.as_str()
+        )));
+    }
+
+    let metadata = tokio::fs::metadata(job.path()).await?;
+    let book = CachedAudio {
+        path: job.path().to_path_buf(),
+        len: metadata.len(),
+        modified: metadata.modified()?,
+        key: status.id,
+    };
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(job.filename.clone())],
+    };
+    audio::file_response(&req, book, status.format.content_type(), disposition).await
+}
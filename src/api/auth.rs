@@ -0,0 +1,497 @@
+//! Bearer token authentication, token management and user administration
+//!
+//! Every request except logging in and the KOReader protocol, which has its own accounts,
+//! needs an `Authorization: Bearer` token with the scope for what it does: `read` for
+//! reading and for one's own annotations and progress, `upload` for changing the library
+//! and `admin` for the admin endpoints. Requests for a document the caller may not see are
+//! answered as if it did not exist.
+
+use crate::api::ApiState;
+use crate::services::auth::{self, ApiToken, Caller, Scope, User};
+use crate::services::store::{self, StoreError};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{delete, get, post, put, web, FromRequest, HttpMessage, HttpRequest};
+use actix_web::{HttpResponse, Responder};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Ready};
+
+/// Name of tokens created without one
+const DEFAULT_TOKEN_NAME: &str = "API token";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Login {
+    pub username: String,
+    pub password: String,
+    /// What the token is for, to tell tokens apart when revoking them
+    pub name: Option<String>,
+    /// `read` when missing
+    pub scopes: Option<Vec<Scope>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+/// A new token, the only time the token itself is shown
+#[derive(Debug, Serialize)]
+struct NewTokenResponse {
+    token: String,
+    #[serde(flatten)]
+    details: ApiToken,
+}
+
+#[derive(Debug, Serialize)]
+struct TokensResponse {
+    tokens: Vec<ApiToken>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsersResponse {
+    users: Vec<User>,
+}
+
+#[derive(Debug, Serialize)]
+struct CallerResponse {
+    /// `null` while authentication is disabled
+    user_id: Option<i64>,
+    username: String,
+    scopes: Vec<Scope>,
+}
+
+/// The caller established by [`authenticate`]
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Caller>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Authentication required")),
+        )
+    }
+}
+
+fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((WWW_AUTHENTICATE, "Bearer"))
+        .body(message.to_string())
+}
+
+/// The document a path is about and the rest of the path, for `/document/{id}/...`
+///
+/// `path` is the path the router matches; the id is decoded further, as the handlers'
+/// `web::Path` does, so `/document/%2B1` is about document 1 here too.
+fn document_path(path: &str) -> Option<(i64, &str)> {
+    let rest = path.strip_prefix("/document/")?;
+    let (id, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let id = percent_decode_str(id).decode_utf8().ok()?;
+    Some((id.parse().ok()?, rest))
+}
+
+/// Annotations, progress and audiobook exports belong to the caller, so reading a
+/// document is enough to make them
+fn is_personal(rest: &str) -> bool {
+    rest == "/progress"
+        || rest == "/annotations"
+        || rest.starts_with("/annotations/")
+        || rest == "/audiobook"
+}
+
+/// The scope a request needs, `None` for requests anyone may make
+fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    if path.starts_with("/koreader/") || (method == Method::POST && path == "/auth/tokens") {
+        return None;
+    }
+
+    let reading = method == Method::GET || method == Method::HEAD;
+    if path.starts_with("/admin/") {
+        Some(Scope::Admin)
+    } else if reading
+        || path.starts_with("/auth/")
+        || document_path(path).is_some_and(|(_, rest)| is_personal(rest))
+    {
+        Some(Scope::Read)
+    } else {
+        Some(Scope::Upload)
+    }
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    Some(token.trim()).filter(|token| scheme.eq_ignore_ascii_case("bearer") && !token.is_empty())
+}
+
+/// Who is making the request, or the response refusing it
+async fn check_request(
+    req: &ServiceRequest,
+    data: &ApiState,
+) -> Result<Option<Caller>, HttpResponse> {
+    // Requests are routed on their percent-decoded path, so `/%61dmin/users` reaches the
+    // admin handlers; checking `req.path()` would let it through with any scope
+    let path = req.match_info().as_str();
+    let Some(scope) = required_scope(req.method(), path) else {
+        return Ok(None);
+    };
+    let Some(token) = bearer_token(req) else {
+        return Err(unauthorized("Missing bearer token"));
+    };
+
+    let token_hash = auth::hash_token(token);
+    let caller = match store::run(&data.store, move |store| {
+        store.authenticate_token(&token_hash)
+    })
+    .await
+    {
+        Ok((user, token)) => Caller::for_token(&user, &token),
+        Err(StoreError::NotFound) => return Err(unauthorized("Invalid or revoked token")),
+        Err(e) => {
+            return Err(
+                HttpResponse::InternalServerError().body(format!("Error checking token: {}", e))
+            )
+        }
+    };
+    if !caller.has(scope) {
+        return Err(HttpResponse::Forbidden().body(format!(
+            "This token does not have the {} scope",
+            scope.as_str()
+        )));
+    }
+
+    if let Some((document_id, rest)) = document_path(path) {
+        let reading = req.method() == Method::GET || req.method() == Method::HEAD;
+        match store::run(&data.store, move |store| store.document_access(document_id)).await {
+            Ok(access) if !caller.can_read(&access) => {
+                return Err(
+                    HttpResponse::NotFound().body(format!("Document not found: {}", document_id))
+                )
+            }
+            Ok(access) if !reading && !is_personal(rest) && !caller.can_write(&access) => {
+                return Err(HttpResponse::Forbidden().body(format!(
+                    "Only the owner of document {} can change it",
+                    document_id
+                )))
+            }
+            // Unknown documents are left to the handlers
+            Ok(_) | Err(StoreError::NotFound) => {}
+            Err(e) => {
+                return Err(HttpResponse::InternalServerError()
+                    .body(format!("Error checking document access: {}", e)))
+            }
+        }
+    }
+
+    Ok(Some(caller))
+}
+
+/// Middleware that authenticates requests and checks their scope and document access
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let data = req
+        .app_data::<web::Data<ApiState>>()
+        .cloned()
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("API state is missing"))?;
+
+    if !data.auth_enabled {
+        req.extensions_mut().insert(Caller::local());
+    } else {
+        match check_request(&req, &data).await {
+            Ok(Some(caller)) => {
+                req.extensions_mut().insert(caller);
+            }
+            Ok(None) => {}
+            Err(response) => return Ok(req.into_response(response)),
+        }
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
+
+/// The caller's user id; accounts are not used while authentication is disabled
+pub(super) fn account(caller: &Caller) -> Result<i64, HttpResponse> {
+    caller.user_id.ok_or_else(|| {
+        HttpResponse::BadRequest().body("Accounts are not used while authentication is disabled")
+    })
+}
+
+/// Refuse callers who are not admins; admin endpoints check this themselves as well as
+/// through the admin scope [`authenticate`] asks for
+pub(super) fn admin_only(caller: &Caller) -> Result<(), HttpResponse> {
+    if caller.is_admin() {
+        Ok(())
+    } else {
+        Err(HttpResponse::Forbidden().body("Only admins can do this"))
+    }
+}
+
+/// Log in with a user name and password and get a new token
+#[post("/auth/tokens")]
+async fn create_token(request: web::Json<Login>, data: web::Data<ApiState>) -> impl Responder {
+    let Login {
+        username,
+        password,
+        name,
+        scopes,
+    } = request.into_inner();
+    let name = match name.map(|name| name.trim().to_string()) {
+        Some(name) if name.is_empty() => {
+            return HttpResponse::BadRequest().body("name must not be empty")
+        }
+        Some(name) => name,
+        None => DEFAULT_TOKEN_NAME.to_string(),
+    };
+    let mut scopes = scopes.unwrap_or_else(|| vec![Scope::Read]);
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return HttpResponse::BadRequest().body("scopes must not be empty");
+    }
+
+    let result = store::run(&data.store, move |store| {
+        let user = match store.get_user_by_name(&username) {
+            Ok((user, hash)) if auth::verify_password(&password, &hash) => user,
+            Ok(_) | Err(StoreError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if scopes.contains(&Scope::Admin) && !user.is_admin {
+            return Err(StoreError::Invalid(
+                "Only admins can create tokens with the admin scope".to_string(),
+            ));
+        }
+
+        let (token, token_hash) = auth::generate_token();
+        let details = store.create_token(user.id, &name, &scopes, &token_hash)?;
+        Ok(Some(NewTokenResponse { token, details }))
+    })
+    .await;
+
+    match result {
+        Ok(Some(token)) => HttpResponse::Created().json(token),
+        Ok(None) => unauthorized("Invalid username or password"),
+        Err(StoreError::Invalid(message)) => HttpResponse::Forbidden().body(message),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error creating token: {}", e)),
+    }
+}
+
+#[get("/auth/tokens")]
+async fn list_tokens(caller: Caller, data: web::Data<ApiState>) -> impl Responder {
+    let user_id = match account(&caller) {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+
+    match store::run(&data.store, move |store| store.list_tokens(user_id)).await {
+        Ok(tokens) => HttpResponse::Ok().json(TokensResponse { tokens }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error listing tokens: {}", e)),
+    }
+}
+
+/// Revoke one of the caller's tokens
+#[delete("/auth/tokens/{id}")]
+async fn delete_token(
+    path: web::Path<i64>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let user_id = match account(&caller) {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+
+    match store::run(&data.store, move |store| store.delete_token(user_id, id)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Token not found: {}", id))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error revoking token: {}", e)),
+    }
+}
+
+#[get("/auth/me")]
+async fn current_user(caller: Caller) -> impl Responder {
+    HttpResponse::Ok().json(CallerResponse {
+        user_id: caller.user_id,
+        username: caller.username,
+        scopes: caller.scopes,
+    })
+}
+
+#[put("/auth/password")]
+async fn change_password(
+    request: web::Json<PasswordChange>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = account(&caller) {
+        return response;
+    }
+    let PasswordChange {
+        current_password,
+        new_password,
+    } = request.into_inner();
+    if let Err(message) = auth::check_password(&new_password) {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let result = store::run(&data.store, move |store| {
+        let (user, hash) = store.get_user_by_name(&caller.username)?;
+        if !auth::verify_password(&current_password, &hash) {
+            return Ok(false);
+        }
+        let hash = auth::hash_password(&new_password).map_err(StoreError::Invalid)?;
+        store.set_password(user.id, &hash)?;
+        Ok(true)
+    })
+    .await;
+
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::Forbidden().body("Current password is incorrect"),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error changing password: {}", e))
+        }
+    }
+}
+
+#[post("/admin/users")]
+async fn create_user(
+    request: web::Json<NewUser>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let NewUser {
+        username,
+        password,
+        is_admin,
+    } = request.into_inner();
+    let username = username.trim().to_string();
+    if let Err(message) =
+        auth::check_username(&username).and_then(|()| auth::check_password(&password))
+    {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let result = store::run(&data.store, move |store| {
+        let hash = auth::hash_password(&password).map_err(StoreError::Invalid)?;
+        store.create_user(&username, &hash, is_admin)
+    })
+    .await;
+
+    match result {
+        Ok(user) => HttpResponse::Created().json(user),
+        Err(StoreError::Conflict(message)) => HttpResponse::Conflict().body(message),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error creating user: {}", e)),
+    }
+}
+
+#[get("/admin/users")]
+async fn list_users(caller: Caller, data: web::Data<ApiState>) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    match store::run(&data.store, |store| store.list_users()).await {
+        Ok(users) => HttpResponse::Ok().json(UsersResponse { users }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error listing users: {}", e)),
+    }
+}
+
+/// Delete a user with their tokens and annotations; their documents must be given to
+/// someone else or deleted first
+#[delete("/admin/users/{id}")]
+async fn delete_user(
+    path: web::Path<i64>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let id = path.into_inner();
+
+    match store::run(&data.store, move |store| store.delete_user(id)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("User not found: {}", id))
+        }
+        Err(StoreError::Conflict(message)) => HttpResponse::Conflict().body(message),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error deleting user: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_scope() {
+        let get = Method::GET;
+        let put = Method::PUT;
+        let post = Method::POST;
+
+        assert_eq!(required_scope(&post, "/auth/tokens"), None);
+        assert_eq!(required_scope(&put, "/koreader/syncs/progress"), None);
+        assert_eq!(required_scope(&get, "/documents"), Some(Scope::Read));
+        assert_eq!(required_scope(&get, "/admin/export"), Some(Scope::Admin));
+        assert_eq!(required_scope(&post, "/upload"), Some(Scope::Upload));
+        assert_eq!(
+            required_scope(&put, "/document/3/tags"),
+            Some(Scope::Upload)
+        );
+        assert_eq!(
+            required_scope(&put, "/document/3/progress"),
+            Some(Scope::Read)
+        );
+        assert_eq!(
+            required_scope(&Method::DELETE, "/document/3/annotations/7"),
+            Some(Scope::Read)
+        );
+        assert_eq!(
+            required_scope(&post, "/document/3/audiobook"),
+            Some(Scope::Read)
+        );
+        assert_eq!(
+            required_scope(&Method::DELETE, "/auth/tokens/2"),
+            Some(Scope::Read)
+        );
+    }
+
+    #[test]
+    fn test_document_path() {
+        assert_eq!(document_path("/document/12"), Some((12, "")));
+        assert_eq!(
+            document_path("/document/12/chapter/0/audio"),
+            Some((12, "/chapter/0/audio"))
+        );
+        assert_eq!(document_path("/document/%2B12/tags"), Some((12, "/tags")));
+        assert_eq!(document_path("/documents"), None);
+        assert_eq!(document_path("/document/abc/tags"), None);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::api::documents::{optional_field, required_field};
+use crate::api::ApiState;
+use crate::services::auth::Caller;
+use crate::services::store::{
+    self, Collection, CollectionUpdate, DocumentStore, DocumentSummary, StoreError, StoreResult,
+};
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewCollection {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CollectionDocuments {
+    /// Every document of the collection, in order
+    pub document_ids: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddDocument {
+    pub document_id: i64,
+    /// Position in the collection starting at 0; the end when missing
+    pub position: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionsResponse {
+    collections: Vec<Collection>,
+}
+
+/// A collection with its documents in order
+#[derive(Debug, Serialize)]
+struct CollectionResponse {
+    #[serde(flatten)]
+    collection: Collection,
+    documents: Vec<DocumentSummary>,
+}
+
+/// Map a store error for collection `id` to a response
+fn error_response(e: StoreError, id: i64, action: &str) -> HttpResponse {
+    match e {
+        StoreError::NotFound => {
+            HttpResponse::NotFound().body(format!("Collection not found: {}", id))
+        }
+        StoreError::Conflict(message) => HttpResponse::Conflict().body(message),
+        StoreError::Invalid(message) => HttpResponse::BadRequest().body(message),
+        e => HttpResponse::InternalServerError().body(format!("Error {}: {}", action, e)),
+    }
+}
+
+/// Whether the caller may see a document
+fn visible(store: &dyn DocumentStore, caller: &Caller, document_id: i64) -> StoreResult<bool> {
+    match store.document_access(document_id) {
+        Ok(access) => Ok(caller.can_read(&access)),
+        Err(StoreError::NotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Load a collection with the documents the caller may see, after it was changed
+fn load_collection(
+    store: &dyn DocumentStore,
+    caller: &Caller,
+    id: i64,
+) -> StoreResult<CollectionResponse> {
+    let mut documents = Vec::new();
+    for document in store.collection_documents(id)? {
+        if visible(store, caller, document.id)? {
+            documents.push(document);
+        }
+    }
+
+    Ok(CollectionResponse {
+        collection: store.get_collection(id, caller.visible_to())?,
+        documents,
+    })
+}
+
+/// Refuse changes to collections the caller does not own
+async fn check_owner(data: &ApiState, caller: &Caller, id: i64) -> Result<(), HttpResponse> {
+    match store::run(&data.store, move |store| store.get_collection(id, None)).await {
+        Ok(collection) if caller.owns(collection.owner_id) => Ok(()),
+        Ok(_) => Err(HttpResponse::Forbidden()
+            .body(format!("Only the owner of collection {} can change it", id))),
+        Err(e) => Err(error_response(e, id, "updating collection")),
+    }
+}
+
+/// Refuse documents the caller may not see as if they did not exist
+fn check_visible(store: &dyn DocumentStore, caller: &Caller, document_id: i64) -> StoreResult<()> {
+    if store.document_access(document_id).is_ok() && !visible(store, caller, document_id)? {
+        return Err(StoreError::Invalid(format!(
+            "Document not found: {}",
+            document_id
+        )));
+    }
+    Ok(())
+}
+
+#[get("/collections")]
+async fn list_collections(caller: Caller, data: web::Data<ApiState>) -> impl Responder {
+    let visible_to = caller.visible_to();
+
+    match store::run(&data.store, move |store| store.list_collections(visible_to)).await {
+        Ok(collections) => HttpResponse::Ok().json(CollectionsResponse { collections }),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error listing collections: {}", e))
+        }
+    }
+}
+
+#[post("/collections")]
+async fn create_collection(
+    request: web::Json<NewCollection>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let fields = required_field("name", request.name).and_then(|name| {
+        optional_field("description", request.description).map(|description| (name, description))
+    });
+    let (name, description) = match fields {
+        Ok(fields) => fields,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let result = store::run(&data.store, move |store| {
+        store.create_collection(&name, description.as_deref(), caller.user_id)
+    })
+    .await;
+
+    match result {
+        Ok(collection) => HttpResponse::Created().json(collection),
+        Err(e) => error_response(e, 0, "creating collection"),
+    }
+}
+
+#[get("/collections/{id}")]
+async fn get_collection(
+    path: web::Path<i64>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+
+    match store::run(&data.store, move |store| {
+        load_collection(store, &caller, id)
+    })
+    .await
+    {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(e) => error_response(e, id, "loading collection"),
+    }
+}
+
+#[patch("/collections/{id}")]
+async fn update_collection(
+    path: web::Path<i64>,
+    update: web::Json<CollectionUpdate>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let update = update.into_inner();
+    let validated = update
+        .name
+        .map(|name| required_field("name", name))
+        .transpose()
+        .and_then(|name| {
+            let description = update
+                .description
+                .map(|description| optional_field("description", description))
+                .transpose()?;
+            Ok(CollectionUpdate { name, description })
+        });
+    let update = match validated {
+        Ok(update) if update.name.is_none() && update.description.is_none() => {
+            return HttpResponse::BadRequest().body("No fields to update")
+        }
+        Ok(update) => update,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    if let Err(response) = check_owner(&data, &caller, id).await {
+        return response;
+    }
+
+    let visible_to = caller.visible_to();
+    let result = store::run(&data.store, move |store| {
+        store.update_collection(id, &update)?;
+        store.get_collection(id, visible_to)
+    })
+    .await;
+
+    match result {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(e) => error_response(e, id, "updating collection"),
+    }
+}
+
+/// Delete a collection; its documents stay in the library
+#[delete("/collections/{id}")]
+async fn delete_collection(
+    path: web::Path<i64>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    if let Err(response) = check_owner(&data, &caller, id).await {
+        return response;
+    }
+
+    match store::run(&data.store, move |store| store.delete_collection(id)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => error_response(e, id, "deleting collection"),
+    }
+}
+
+/// Replace the documents of a collection, in the given order
+///
+/// Documents the caller cannot see stay in the collection, after the given ones.
+#[put("/collections/{id}/documents")]
+async fn set_collection_documents(
+    path: web::Path<i64>,
+    request: web::Json<CollectionDocuments>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let mut document_ids = request.into_inner().document_ids;
+    if let Err(response) = check_owner(&data, &caller, id).await {
+        return response;
+    }
+
+    let result = store::run(&data.store, move |store| {
+        for &document_id in &document_ids {
+            check_visible(store, &caller, document_id)?;
+        }
+        for document in store.collection_documents(id)? {
+            if !visible(store, &caller, document.id)? && !document_ids.contains(&document.id) {
+                document_ids.push(document.id);
+            }
+        }
+        store.set_collection_documents(id, &document_ids)?;
+        load_collection(store, &caller, id)
+    })
+    .await;
+
+    match result {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(e) => error_response(e, id, "updating collection"),
+    }
+}
+
+/// Add a document to a collection, or move it within the collection
+#[post("/collections/{id}/documents")]
+async fn add_to_collection(
+    path: web::Path<i64>,
+    request: web::Json<AddDocument>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let AddDocument {
+        document_id,
+        position,
+    } = request.into_inner();
+    if let Err(response) = check_owner(&data, &caller, id).await {
+        return response;
+    }
+
+    let result = store::run(&data.store, move |store| {
+        check_visible(store, &caller, document_id)?;
+        store.add_to_collection(id, document_id, position)?;
+        load_collection(store, &caller, id)
+    })
+    .await;
+
+    match result {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(e) => error_response(e, id, "updating collection"),
+    }
+}
+
+#[delete("/collections/{id}/documents/{document_id}")]
+async fn remove_from_collection(
+    path: web::Path<(i64, i64)>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let (id, document_id) = path.into_inner();
+    if let Err(response) = check_owner(&data, &caller, id).await {
+        return response;
+    }
+
+    let result = store::run(&data.store, move |store| {
+        store.remove_from_collection(id, document_id)
+    })
+    .await;
+
+    match result {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(StoreError::NotFound) => HttpResponse::NotFound().body(format!(
+            "Document {} is not in collection {}",
+            document_id, id
+        )),
+        Err(e) => error_response(e, id, "updating collection"),
+    }
+}
@@ -0,0 +1,499 @@
+use crate::api::ApiState;
+use crate::services::auth::Caller;
+use crate::services::store::{
+    self, DocumentAccess, DocumentFilter, DocumentSort, DocumentSummary, DocumentUpdate,
+    ListCursor, SortOrder, StoreError, TagCount,
+};
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use actix_web::{delete, get, patch, put, web, HttpResponse, Responder};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io;
+use tracing::warn;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+/// Longest accepted metadata value, in characters
+const MAX_FIELD_LENGTH: usize = 1000;
+
+/// Longest accepted tag, in characters
+const MAX_TAG_LENGTH: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct ListParams {
+    /// `title`, `author` or `uploaded` (the default)
+    pub sort: Option<DocumentSort>,
+    /// `asc` or `desc`; newest first when sorting by upload date, A to Z otherwise
+    pub order: Option<SortOrder>,
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page, with the same sort and filters
+    pub cursor: Option<String>,
+    pub language: Option<String>,
+    pub author: Option<String>,
+    pub tag: Option<String>,
+    pub format: Option<String>,
+    /// Only documents in the collection with this id
+    pub collection: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListResponse {
+    documents: Vec<DocumentSummary>,
+    next_cursor: Option<String>,
+}
+
+/// Cursors are opaque to clients: URL-safe base64 of the JSON position
+fn encode_cursor(cursor: &ListCursor) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Option<ListCursor> {
+    let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// Treat `?author=` the same as a missing filter
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.trim().is_empty())
+}
+
+#[get("/documents")]
+async fn list_documents(
+    params: web::Query<ListParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let params = params.into_inner();
+
+    let sort = params.sort.unwrap_or(DocumentSort::Uploaded);
+    let order = params.order.unwrap_or(match sort {
+        DocumentSort::Uploaded => SortOrder::Desc,
+        _ => SortOrder::Asc,
+    });
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let after = match params.cursor.as_deref().filter(|c| !c.is_empty()) {
+        Some(cursor) => match decode_cursor(cursor) {
+            Some(cursor) => Some(cursor),
+            None => return HttpResponse::BadRequest().body("Invalid cursor"),
+        },
+        None => None,
+    };
+
+    let filter = DocumentFilter {
+        language: non_empty(params.language),
+        author: non_empty(params.author),
+        tag: non_empty(params.tag),
+        format: non_empty(params.format),
+        collection: params.collection,
+        visible_to: caller.visible_to(),
+    };
+
+    let result = store::run(&data.store, move |store| {
+        store.list_documents(&filter, sort, order, after.as_ref(), limit)
+    })
+    .await;
+
+    match result {
+        Ok((documents, next)) => HttpResponse::Ok().json(ListResponse {
+            documents,
+            next_cursor: next.as_ref().map(encode_cursor),
+        }),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error listing documents: {}", e))
+        }
+    }
+}
+
+/// Trim a required field and check that it is present and not too long
+pub(super) fn required_field(name: &str, value: String) -> Result<String, String> {
+    let value = value.trim().to_string();
+    if value.is_empty() {
+        return Err(format!("{} must not be empty", name));
+    }
+    if value.chars().count() > MAX_FIELD_LENGTH {
+        return Err(format!(
+            "{} must be at most {} characters",
+            name, MAX_FIELD_LENGTH
+        ));
+    }
+    Ok(value)
+}
+
+/// Like `required_field`, but an empty value clears the field
+pub(super) fn optional_field(name: &str, value: Option<String>) -> Result<Option<String>, String> {
+    match value.filter(|value| !value.trim().is_empty()) {
+        Some(value) => required_field(name, value).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// A BCP 47 language tag such as `en`, `ru-RU` or `zh-Hant-TW`
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary_ok = subtags.next().is_some_and(|primary| {
+        (2..=8).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic())
+    });
+    primary_ok
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// Check and normalize the requested changes
+fn validate_update(update: DocumentUpdate) -> Result<DocumentUpdate, String> {
+    let language = match update.language {
+        Some(language) => {
+            let language = optional_field("language", language)?;
+            if let Some(tag) = &language {
+                if !is_language_tag(tag) {
+                    return Err(format!("{} is not a valid language tag", tag));
+                }
+            }
+            Some(language)
+        }
+        None => None,
+    };
+
+    if let Some(Some(index)) = update.series_index {
+        if !index.is_finite() || index < 0.0 {
+            return Err("series_index must be a non-negative number".to_string());
+        }
+    }
+
+    let validated = DocumentUpdate {
+        title: update
+            .title
+            .map(|title| required_field("title", title))
+            .transpose()?,
+        author: update
+            .author
+            .map(|author| required_field("author", author))
+            .transpose()?,
+        publication_date: update
+            .publication_date
+            .map(|date| optional_field("publication_date", date))
+            .transpose()?,
+        language,
+        description: update
+            .description
+            .map(|description| optional_field("description", description))
+            .transpose()?,
+        series: update
+            .series
+            .map(|series| optional_field("series", series))
+            .transpose()?,
+        series_index: update.series_index,
+    };
+
+    let empty = validated.title.is_none()
+        && validated.author.is_none()
+        && validated.publication_date.is_none()
+        && validated.language.is_none()
+        && validated.description.is_none()
+        && validated.series.is_none()
+        && validated.series_index.is_none();
+    if empty {
+        return Err("No fields to update".to_string());
+    }
+
+    Ok(validated)
+}
+
+#[patch("/document/{id}")]
+async fn update_document(
+    path: web::Path<i64>,
+    update: web::Json<DocumentUpdate>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let update = match validate_update(update.into_inner()) {
+        Ok(update) => update,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let result = store::run(&data.store, move |store| store.update_document(id, &update)).await;
+
+    match result {
+        Ok(document) => {
+            // Same shape as the upload response
+            let mut response =
+                serde_json::to_value(&document.metadata).unwrap_or_else(|_| json!({}));
+            if let Value::Object(ref mut obj) = response {
+                obj.insert("document_id".to_string(), json!(document.id));
+            }
+
+            HttpResponse::Ok().json(response)
+        }
+        Err(e) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", e))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error updating document: {}", e))
+        }
+    }
+}
+
+#[delete("/document/{id}")]
+async fn delete_document(path: web::Path<i64>, data: web::Data<ApiState>) -> impl Responder {
+    let id = path.into_inner();
+
+    match store::run(&data.store, move |store| store.delete_document(id)).await {
+        Ok(()) => {
+            delete_cached_audio(&data, id).await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", e))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error deleting document: {}", e))
+        }
+    }
+}
+
+/// Delete the audio cached for a deleted document; the document is gone either way
+async fn delete_cached_audio(data: &ApiState, id: i64) {
+    let Some(cache) = data.tts_service.audio_cache().cloned() else {
+        return;
+    };
+    let result = web::block(move || cache.remove_document(id))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|removed| removed);
+    if let Err(e) = result {
+        warn!("Failed to delete cached audio of document {}: {}", id, e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsRequest {
+    pub tags: Vec<String>,
+}
+
+/// Trim tags and check their length; duplicates are dropped by the store
+fn validate_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    tags.into_iter()
+        .map(|tag| {
+            let tag = tag.trim().to_string();
+            if tag.is_empty() {
+                return Err("Tags must not be empty".to_string());
+            }
+            if tag.chars().count() > MAX_TAG_LENGTH {
+                return Err(format!(
+                    "Tags must be at most {} characters",
+                    MAX_TAG_LENGTH
+                ));
+            }
+            Ok(tag)
+        })
+        .collect()
+}
+
+/// Replace the tags of a document, including those read from the EPUB
+#[put("/document/{id}/tags")]
+async fn set_tags(
+    path: web::Path<i64>,
+    request: web::Json<TagsRequest>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let tags = match validate_tags(request.into_inner().tags) {
+        Ok(tags) => tags,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    match store::run(&data.store, move |store| store.set_tags(id, &tags)).await {
+        Ok(tags) => HttpResponse::Ok().json(json!({ "document_id": id, "tags": tags })),
+        Err(e) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", id))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error updating tags: {}", e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SharingRequest {
+    /// Whether every user may read the document
+    pub shared: Option<bool>,
+    /// Give the document to another user, or to nobody with `null`; admins only
+    #[serde(default, deserialize_with = "crate::services::store::nullable")]
+    pub owner_id: Option<Option<i64>>,
+}
+
+#[derive(Debug, Serialize)]
+struct SharingResponse {
+    document_id: i64,
+    #[serde(flatten)]
+    access: DocumentAccess,
+}
+
+/// Share a document with every user, or hand it to another user
+///
+/// The authentication middleware already limits this to the owner and admins.
+#[put("/document/{id}/sharing")]
+async fn set_sharing(
+    path: web::Path<i64>,
+    request: web::Json<SharingRequest>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let SharingRequest { shared, owner_id } = request.into_inner();
+    if owner_id.is_some() && !caller.is_admin() {
+        return HttpResponse::Forbidden().body("Only admins can change the owner of a document");
+    }
+    if shared.is_none() && owner_id.is_none() {
+        return HttpResponse::BadRequest().body("No fields to update");
+    }
+
+    let result = store::run(&data.store, move |store| {
+        let mut access = store.document_access(id)?;
+        if let Some(shared) = shared {
+            access.shared = shared;
+        }
+        if let Some(owner_id) = owner_id {
+            if let Some(owner_id) = owner_id {
+                if !store.list_users()?.iter().any(|user| user.id == owner_id) {
+                    return Err(StoreError::Invalid(format!("User not found: {}", owner_id)));
+                }
+            }
+            access.owner_id = owner_id;
+        }
+        store.set_document_access(id, &access)?;
+        Ok(access)
+    })
+    .await;
+
+    match result {
+        Ok(access) => HttpResponse::Ok().json(SharingResponse {
+            document_id: id,
+            access,
+        }),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", id))
+        }
+        Err(StoreError::Invalid(message)) => HttpResponse::BadRequest().body(message),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error updating sharing: {}", e))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TagsResponse {
+    tags: Vec<TagCount>,
+}
+
+/// Every tag of the documents the caller may see with the number of them that have it
+#[get("/tags")]
+async fn list_tags(caller: Caller, data: web::Data<ApiState>) -> impl Responder {
+    let visible_to = caller.visible_to();
+
+    match store::run(&data.store, move |store| store.list_tags(visible_to)).await {
+        Ok(tags) => HttpResponse::Ok().json(TagsResponse { tags }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error listing tags: {}", e)),
+    }
+}
+
+/// Attachment header for a file name, with an ASCII fallback for non-ASCII names
+pub(super) fn attachment(filename: &str) -> ContentDisposition {
+    let mut parameters = vec![DispositionParam::Filename(
+        filename
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() && c != '"' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    )];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Download the file a document was uploaded as
+#[get("/document/{id}/original")]
+async fn get_original(path: web::Path<i64>, data: web::Data<ApiState>) -> impl Responder {
+    let id = path.into_inner();
+
+    match store::run(&data.store, move |store| store.get_original(id)).await {
+        Ok(original) => HttpResponse::Ok()
+            .content_type(original.media_type.as_str())
+            .append_header(attachment(&original.filename))
+            .body(original.data),
+        Err(e) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("No original file stored for document {}", id))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error retrieving original: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_update_normalizes_fields() {
+        let update: DocumentUpdate = serde_json::from_str(
+            r#"{"title": "  Moby-Dick ", "series": "", "language": "en-GB", "series_index": null}"#,
+        )
+        .unwrap();
+
+        let update = validate_update(update).unwrap();
+        assert_eq!(update.title.as_deref(), Some("Moby-Dick"));
+        assert_eq!(update.series, Some(None));
+        assert_eq!(update.language, Some(Some("en-GB".to_string())));
+        assert_eq!(update.series_index, Some(None));
+        assert_eq!(update.author, None);
+    }
+
+    #[test]
+    fn test_validate_update_rejects_invalid_values() {
+        let invalid = [
+            r#"{}"#,
+            r#"{"title": " "}"#,
+            r#"{"language": "english please"}"#,
+            r#"{"series_index": -1}"#,
+        ];
+        for body in invalid {
+            let update: DocumentUpdate = serde_json::from_str(body).unwrap();
+            assert!(
+                validate_update(update).is_err(),
+                "{} should be rejected",
+                body
+            );
+        }
+
+        assert!(serde_json::from_str::<DocumentUpdate>(r#"{"chapters": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_validate_tags() {
+        let tags = validate_tags(vec![" Course 101 ".to_string(), "sea".to_string()]).unwrap();
+        assert_eq!(tags, vec!["Course 101", "sea"]);
+
+        assert!(validate_tags(vec!["  ".to_string()]).is_err());
+        assert!(validate_tags(vec!["x".repeat(MAX_TAG_LENGTH + 1)]).is_err());
+    }
+}
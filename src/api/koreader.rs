@@ -0,0 +1,481 @@
+//! The KOReader progress sync protocol, so e-readers can use this server as their sync server
+//!
+//! KOReader expects the paths, status codes and JSON error bodies of its own sync server,
+//! so these handlers follow that instead of the plain text errors used elsewhere. Books
+//! are identified by KOReader's digest of the file; progress for books that are also in
+//! the library is copied to the reader's progress, and progress saved by the web reader
+//! is offered to KOReader when it is newer.
+//!
+//! With authentication enabled, only accounts a user linked with one of their tokens share
+//! progress with the library, and KOReader cannot register accounts itself.
+
+use crate::api::auth::account;
+use crate::api::documents::required_field;
+use crate::api::ApiState;
+use crate::services::auth::Caller;
+use crate::services::koreader::{self, SyncProgress, SyncUser};
+use crate::services::store::{self, DocumentStore, Progress, StoreError, StoreResult};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Device id reported for progress that was saved by the web reader or audio player
+const SERVER_DEVICE_ID: &str = "epub-server";
+
+#[derive(Debug, Deserialize)]
+pub struct Registration {
+    pub username: String,
+    /// KOReader sends the MD5 of the password
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Link {
+    pub username: String,
+    /// The password as typed into KOReader
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProgressUpdate {
+    pub document: Option<String>,
+    pub progress: Option<String>,
+    pub percentage: Option<f64>,
+    pub device: Option<String>,
+    pub device_id: Option<String>,
+}
+
+fn error(status: StatusCode, code: u32, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "code": code, "message": message }))
+}
+
+fn unauthorized() -> HttpResponse {
+    error(StatusCode::UNAUTHORIZED, 2001, "Unauthorized")
+}
+
+fn invalid_request(message: &str) -> HttpResponse {
+    error(StatusCode::FORBIDDEN, 2003, message)
+}
+
+fn server_error(e: StoreError) -> HttpResponse {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        2000,
+        &format!("Unknown server error: {}", e),
+    )
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+}
+
+/// The account named by the `x-auth-user` and `x-auth-key` headers
+async fn authorize(req: &HttpRequest, data: &ApiState) -> Result<SyncUser, HttpResponse> {
+    let (Some(username), Some(key)) = (header(req, "x-auth-user"), header(req, "x-auth-key"))
+    else {
+        return Err(unauthorized());
+    };
+    let (username, key) = (username.to_string(), key.to_string());
+
+    match store::run(&data.store, move |store| store.get_sync_user(&username)).await {
+        Ok(user) if user.verify(&key) => Ok(user),
+        Ok(_) | Err(StoreError::NotFound) => Err(unauthorized()),
+        Err(e) => Err(server_error(e)),
+    }
+}
+
+/// The library reader whose progress an account shares, `Some(None)` being the local reader;
+/// `None` for accounts that are not linked to a user
+///
+/// With authentication disabled the whole library is the local reader's, and so is every
+/// account.
+fn library_reader(data: &ApiState, account: &SyncUser) -> Option<Option<i64>> {
+    if data.auth_enabled {
+        account.user_id.map(Some)
+    } else {
+        Some(None)
+    }
+}
+
+/// Copy KOReader progress to the progress of `user_id` in the matching stored document
+///
+/// Only documents the user may read are matched, as with a token of theirs with the read
+/// scope. Only XPointers tell the chapter, so progress in page based formats is not copied.
+fn copy_to_library(
+    store: &dyn DocumentStore,
+    user_id: Option<i64>,
+    progress: &SyncProgress,
+) -> StoreResult<()> {
+    let document_id = match store.find_by_digest(&progress.document, user_id) {
+        Ok(document_id) => document_id,
+        Err(StoreError::NotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let Some(chapter_index) = koreader::chapter_from_xpointer(&progress.progress) else {
+        return Ok(());
+    };
+    if chapter_index >= store.content_counts(document_id)?.chapters {
+        return Ok(());
+    }
+
+    store.save_progress(&Progress {
+        user_id,
+        document_id,
+        chapter_index,
+        char_offset: 0,
+        audio_position: None,
+        percentage: progress.percentage.clamp(0.0, 1.0),
+        device: Some(progress.device.clone()),
+        updated_at: progress.timestamp * 1000,
+    })?;
+    Ok(())
+}
+
+/// What KOReader last reported for a book, or the progress of the account's library reader
+/// in the matching stored document if that is newer
+fn latest_progress(
+    store: &dyn DocumentStore,
+    username: &str,
+    reader: Option<Option<i64>>,
+    document: &str,
+) -> StoreResult<Option<SyncProgress>> {
+    let synced = match store.get_sync_progress(username, document) {
+        Ok(progress) => Some(progress),
+        Err(StoreError::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+    let library = match reader {
+        Some(user_id) => match store.find_by_digest(document, user_id) {
+            Ok(document_id) => match store.get_progress(user_id, document_id) {
+                Ok(progress) => Some(progress),
+                Err(StoreError::NotFound) => None,
+                Err(e) => return Err(e),
+            },
+            Err(StoreError::NotFound) => None,
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+
+    Ok(match (synced, library) {
+        (synced, Some(library))
+            if synced
+                .as_ref()
+                .is_none_or(|synced| library.updated_at > synced.timestamp * 1000) =>
+        {
+            Some(SyncProgress {
+                username: username.to_string(),
+                document: document.to_string(),
+                progress: koreader::chapter_xpointer(library.chapter_index),
+                percentage: library.percentage,
+                device: library.device.unwrap_or_else(|| "Web".to_string()),
+                device_id: SERVER_DEVICE_ID.to_string(),
+                timestamp: library.updated_at / 1000,
+            })
+        }
+        (synced, _) => synced,
+    })
+}
+
+#[post("/koreader/users/create")]
+async fn create_user(
+    registration: web::Json<Registration>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if data.auth_enabled {
+        return invalid_request("Registration is closed, link an account to a user instead");
+    }
+    let Registration { username, password } = registration.into_inner();
+    let username = match required_field("username", username) {
+        Ok(username) if !password.is_empty() => username,
+        _ => return invalid_request("Invalid request"),
+    };
+
+    let user = SyncUser::new(username.clone(), &password, None);
+    match store::run(&data.store, move |store| store.create_sync_user(&user)).await {
+        Ok(()) => HttpResponse::Created().json(json!({ "username": username })),
+        Err(StoreError::Conflict(_)) => error(
+            StatusCode::PAYMENT_REQUIRED,
+            2002,
+            "Username is already registered.",
+        ),
+        Err(e) => server_error(e),
+    }
+}
+
+#[get("/koreader/users/auth")]
+async fn auth_user(req: HttpRequest, data: web::Data<ApiState>) -> impl Responder {
+    match authorize(&req, &data).await {
+        Ok(_) => HttpResponse::Ok().json(json!({ "authorized": "OK" })),
+        Err(response) => response,
+    }
+}
+
+#[put("/koreader/syncs/progress")]
+async fn update_progress(
+    req: HttpRequest,
+    update: web::Json<ProgressUpdate>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let account = match authorize(&req, &data).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    let reader = library_reader(&data, &account);
+
+    let update = update.into_inner();
+    let Some(document) = update.document.filter(|document| !document.is_empty()) else {
+        return error(
+            StatusCode::FORBIDDEN,
+            2004,
+            "Field 'document' not provided.",
+        );
+    };
+    let (Some(progress), Some(percentage), Some(device)) =
+        (update.progress, update.percentage, update.device)
+    else {
+        return invalid_request("Invalid request");
+    };
+    if !percentage.is_finite() {
+        return invalid_request("Invalid request");
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0);
+    let progress = SyncProgress {
+        username: account.username,
+        document: document.clone(),
+        progress,
+        percentage,
+        device,
+        device_id: update.device_id.unwrap_or_default(),
+        timestamp,
+    };
+
+    let result = store::run(&data.store, move |store| {
+        store.save_sync_progress(&progress)?;
+        let Some(user_id) = reader else {
+            return Ok(());
+        };
+        if let Err(e) = copy_to_library(store, user_id, &progress) {
+            // The sync itself succeeded, the library copy can catch up on the next one
+            warn!("Failed to copy KOReader progress to the library: {}", e);
+        }
+        Ok(())
+    })
+    .await;
+
+    match result {
+        Ok(()) => HttpResponse::Ok().json(json!({ "document": document, "timestamp": timestamp })),
+        Err(e) => server_error(e),
+    }
+}
+
+#[get("/koreader/syncs/progress/{document}")]
+async fn get_progress(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let account = match authorize(&req, &data).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+    let reader = library_reader(&data, &account);
+    let document = path.into_inner();
+
+    let result = store::run(&data.store, move |store| {
+        latest_progress(store, &account.username, reader, &document)
+    })
+    .await;
+
+    match result {
+        Ok(Some(progress)) => HttpResponse::Ok().json(progress),
+        // KOReader takes an empty object as no progress
+        Ok(None) => HttpResponse::Ok().json(json!({})),
+        Err(e) => server_error(e),
+    }
+}
+
+/// Link a KOReader account to the caller, registering it if the name is free
+///
+/// Accounts registered before are only linked with their password and only when no other
+/// user has linked them, so nobody takes over another reader's progress.
+#[put("/auth/koreader")]
+async fn link_user(
+    request: web::Json<Link>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let user_id = match account(&caller) {
+        Ok(user_id) => user_id,
+        Err(response) => return response,
+    };
+    let Link { username, password } = request.into_inner();
+    let username = match required_field("username", username) {
+        Ok(username) => username,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+    if password.is_empty() {
+        return HttpResponse::BadRequest().body("password must not be empty");
+    }
+    let key = koreader::password_key(&password);
+
+    let result = store::run(&data.store, move |store| {
+        match store.get_sync_user(&username) {
+            Ok(account) if !account.verify(&key) => Ok(false),
+            Ok(account) if account.user_id.is_some_and(|other| other != user_id) => {
+                Err(StoreError::Conflict(format!(
+                    "KOReader account {} is linked to another user",
+                    username
+                )))
+            }
+            Ok(_) => store.link_sync_user(&username, user_id).map(|()| true),
+            Err(StoreError::NotFound) => store
+                .create_sync_user(&SyncUser::new(username, &key, Some(user_id)))
+                .map(|()| true),
+            Err(e) => Err(e),
+        }
+    })
+    .await;
+
+    match result {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::Forbidden().body("Wrong password for this KOReader account"),
+        Err(StoreError::Conflict(message)) => HttpResponse::Conflict().body(message),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error linking KOReader account: {}", e)),
+    }
+}
+
+#[get("/koreader/healthcheck")]
+async fn healthcheck() -> impl Responder {
+    HttpResponse::Ok().json(json!({ "state": "OK" }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::metadata::{Chapter, EpubMetadata};
+    use crate::services::epub_parser::EpubContent;
+    use crate::services::memory_store::MemoryStore;
+    use crate::services::store::{DocumentAccess, OriginalFile};
+
+    fn sample_content() -> EpubContent {
+        let chapter = |index: usize| Chapter {
+            title: format!("Chapter {}", index + 1),
+            path: format!("c{}", index),
+            content: "Call me Ishmael.".to_string(),
+            html: "<p>Call me Ishmael.</p>".to_string(),
+        };
+        EpubContent {
+            metadata: EpubMetadata::new(
+                "Moby-Dick".to_string(),
+                "Herman Melville".to_string(),
+                None,
+                None,
+                None,
+            ),
+            chapters: (0..3).map(chapter).collect(),
+            resources: Vec::new(),
+            toc: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_progress_is_shared_with_the_library() {
+        let store = MemoryStore::new();
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: b"PK not really a zip".to_vec(),
+        };
+        let id = store
+            .save_document(&sample_content(), Some(&original))
+            .unwrap();
+        let digest = original.partial_md5();
+
+        let synced = SyncProgress {
+            username: "ann".to_string(),
+            document: digest.clone(),
+            progress: "/body/DocFragment[2]/body/p[3]/text().15".to_string(),
+            percentage: 0.4,
+            device: "Kobo".to_string(),
+            device_id: "kobo-1".to_string(),
+            timestamp: 1000,
+        };
+        store.save_sync_progress(&synced).unwrap();
+        copy_to_library(&store, None, &synced).unwrap();
+        let progress = store.get_progress(None, id).unwrap();
+        assert_eq!(progress.chapter_index, 1);
+        assert_eq!(progress.updated_at, 1_000_000);
+        assert_eq!(
+            latest_progress(&store, "ann", Some(None), &digest).unwrap(),
+            Some(synced.clone())
+        );
+
+        // Reading on in the browser is offered to KOReader
+        store
+            .save_progress(&Progress {
+                chapter_index: 2,
+                updated_at: 2_000_000,
+                device: None,
+                ..progress
+            })
+            .unwrap();
+        let latest = latest_progress(&store, "ann", Some(None), &digest)
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.progress, "/body/DocFragment[3]");
+        assert_eq!(latest.device_id, SERVER_DEVICE_ID);
+        assert_eq!(latest.timestamp, 2000);
+
+        // Accounts without a library reader only get what KOReader reported
+        assert_eq!(
+            latest_progress(&store, "ann", None, &digest).unwrap(),
+            Some(synced.clone())
+        );
+
+        // Books found by file name digest, and books not in the library
+        let by_name = koreader::filename_md5("moby-dick.epub");
+        assert!(latest_progress(&store, "ann", Some(None), &by_name)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            latest_progress(&store, "ann", Some(None), "0123").unwrap(),
+            None
+        );
+
+        // Private documents of others are neither updated nor offered
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let bob = store.create_user("bob", "hash", false).unwrap();
+        let private = DocumentAccess {
+            owner_id: Some(ann.id),
+            shared: false,
+        };
+        store.set_document_access(id, &private).unwrap();
+        copy_to_library(&store, Some(bob.id), &synced).unwrap();
+        assert!(store
+            .get_progress(Some(bob.id), id)
+            .unwrap_err()
+            .is_not_found());
+        copy_to_library(&store, Some(ann.id), &synced).unwrap();
+        assert_eq!(
+            latest_progress(&store, "ann", Some(Some(bob.id)), &digest).unwrap(),
+            Some(synced.clone())
+        );
+        assert_eq!(
+            store.get_progress(Some(ann.id), id).unwrap().chapter_index,
+            1
+        );
+    }
+}
@@ -1,22 +1,36 @@
-use crate::services::db;
+use crate::services::audiobook::AudiobookJobs;
+use crate::services::auth::Caller;
 use crate::services::epub_parser;
+use crate::services::quota::Quotas;
+use crate::services::store::{self, DocumentAccess, DocumentStore, OriginalFile};
 use crate::services::tts::TtsError;
 use crate::services::tts::TtsService;
+use crate::services::voices::VoiceRequest;
 use actix_multipart::Multipart;
-use actix_web::http::header::{ContentDisposition, DispositionType, ACCEPT_LANGUAGE};
-use actix_web::web::Bytes;
-use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header::{ACCEPT_LANGUAGE, ACCEPT_RANGES};
+use actix_web::http::Method;
+use actix_web::{get, post, route, web, HttpRequest, HttpResponse, Responder};
 use futures_util::StreamExt;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use serde_json::{json, Value};
 use std::fmt;
 use std::sync::Arc;
-use tracing::{error, info};
-
-#[derive(Debug, Deserialize)]
-pub struct HtmlRequest {
-    pub html_content: String,
-}
+use tracing::{debug, error};
+
+mod admin;
+mod annotations;
+mod audio;
+mod audiobook;
+mod auth;
+mod collections;
+mod documents;
+mod koreader;
+mod progress;
+mod search;
+mod usage;
+mod voices;
+
+const EPUB_MEDIA_TYPE: &str = "application/epub+zip";
 
 #[derive(Debug, Serialize)]
 pub struct ApiError {
@@ -37,8 +51,16 @@ impl From<TtsError> for ApiError {
     }
 }
 
+pub use auth::authenticate;
+
 pub struct ApiState {
     pub tts_service: Arc<TtsService>,
+    /// Whole-book exports, queued and finished
+    pub audiobooks: Arc<AudiobookJobs>,
+    pub store: Arc<dyn DocumentStore>,
+    /// Whether requests need API tokens; without, every caller may do everything
+    pub auth_enabled: bool,
+    pub quotas: Quotas,
 }
 
 /// Parse the Accept-Language header and return the preferred language
@@ -62,7 +84,11 @@ fn get_language_from_header(req: &HttpRequest) -> String {
 }
 
 #[post("/upload")]
-async fn upload_epub(mut payload: Multipart) -> impl Responder {
+async fn upload_epub(
+    mut payload: Multipart,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
     while let Some(field) = payload.next().await {
         let field = match field {
             Ok(field) => field,
@@ -78,13 +104,20 @@ async fn upload_epub(mut payload: Multipart) -> impl Responder {
                 return HttpResponse::BadRequest().body("Only EPUB files are supported");
             }
 
+            // Some clients send the full client-side path
+            let filename = filename
+                .rsplit(['/', '\\'])
+                .next()
+                .unwrap_or(filename)
+                .to_string();
+
             // Read file contents
-            let mut data = Vec::new();
+            let mut file_data = Vec::new();
             let mut field_stream = field;
 
             while let Some(chunk) = field_stream.next().await {
                 match chunk {
-                    Ok(bytes) => data.extend_from_slice(&bytes),
+                    Ok(bytes) => file_data.extend_from_slice(&bytes),
                     Err(e) => {
                         return HttpResponse::BadRequest()
                             .body(format!("Error reading file: {}", e))
@@ -92,25 +125,44 @@ async fn upload_epub(mut payload: Multipart) -> impl Responder {
                 }
             }
 
-            // Parse the EPUB file
-            match epub_parser::parse_epub(&data) {
-                Ok(epub_content) => {
-                    // Convert HTML content to a JSON object
-                    let html_json = json!({
-                        "chapters": epub_content.chapters.iter().map(|chapter| {
-                            json!({
-                                "title": chapter.title,
-                                "content": chapter.content
-                            })
-                        }).collect::<Vec<_>>()
-                    });
-
-                    // Save to database
-                    match db::save_document(&epub_content.metadata, &html_json) {
-                        Ok(document_id) => {
+            if let Err(response) = usage::check_upload(&data, &caller, file_data.len() as u64).await
+            {
+                return response;
+            }
+
+            // Parse the EPUB file off the async workers, it can take a while for large books
+            let parsed = web::block(move || {
+                epub_parser::parse_epub(&file_data).map(|content| (content, file_data))
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+
+            match parsed {
+                Ok((epub_content, file_data)) => {
+                    // Save to database, keeping the uploaded file for re-download
+                    let original = OriginalFile {
+                        filename,
+                        media_type: EPUB_MEDIA_TYPE.to_string(),
+                        data: file_data,
+                    };
+                    let saved = store::run(&data.store, move |store| {
+                        let document_id = store.save_document(&epub_content, Some(&original))?;
+                        if caller.user_id.is_some() {
+                            let access = DocumentAccess {
+                                owner_id: caller.user_id,
+                                shared: false,
+                            };
+                            store.set_document_access(document_id, &access)?;
+                        }
+                        Ok((document_id, epub_content.metadata))
+                    })
+                    .await;
+
+                    match saved {
+                        Ok((document_id, metadata)) => {
                             // Create response with metadata and document ID
-                            let mut metadata_value = serde_json::to_value(&epub_content.metadata)
-                                .unwrap_or_else(|_| json!({}));
+                            let mut metadata_value =
+                                serde_json::to_value(&metadata).unwrap_or_else(|_| json!({}));
 
                             if let Value::Object(ref mut obj) = metadata_value {
                                 obj.insert("document_id".to_string(), json!(document_id));
@@ -136,39 +188,57 @@ async fn upload_epub(mut payload: Multipart) -> impl Responder {
 }
 
 #[get("/document/{id}")]
-async fn get_document(path: web::Path<i64>) -> impl Responder {
+async fn get_document(path: web::Path<i64>, data: web::Data<ApiState>) -> impl Responder {
     let id = path.into_inner();
 
-    match db::get_document(id) {
-        Ok(doc) => {
-            // Parse the JSON strings into Value objects
-            let metadata: Value = serde_json::from_str(&doc.metadata).unwrap_or_else(|_| json!({}));
-
-            let chapters_html: Value =
-                serde_json::from_str(&doc.chapters_html).unwrap_or_else(|_| json!({}));
-
-            // Add document_id and chapters_html to response
-            let mut response = metadata;
+    let result = store::run(&data.store, move |store| {
+        let doc = store.get_document(id)?;
+        let chapters = store.get_chapters(id)?;
+        let toc = store.get_toc(id)?;
+        Ok((doc, chapters, toc))
+    })
+    .await;
+
+    match result {
+        Ok((doc, chapters, toc)) => {
+            let chapters_html = json!({
+                "chapters": chapters.iter().map(|chapter| {
+                    json!({
+                        "title": chapter.title,
+                        "content": chapter.text
+                    })
+                }).collect::<Vec<_>>()
+            });
+
+            // Add document_id, chapters_html and toc to response
+            let mut response = serde_json::to_value(&doc.metadata).unwrap_or_else(|_| json!({}));
             if let Value::Object(ref mut obj) = response {
                 obj.insert("document_id".to_string(), json!(doc.id));
                 obj.insert("chapters_html".to_string(), chapters_html);
+                obj.insert("toc".to_string(), json!(toc));
             }
 
             HttpResponse::Ok().json(response)
         }
-        Err(e) => HttpResponse::NotFound().body(format!("Document not found: {}", e)),
+        Err(e) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", e))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error retrieving document: {}", e))
+        }
     }
 }
 
 #[get("/document/{id}/chapter/{index}")]
-async fn get_chapter_by_index(path_params: web::Path<(i64, usize)>) -> impl Responder {
+async fn get_chapter_by_index(
+    path_params: web::Path<(i64, usize)>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
     let (id, index) = path_params.into_inner();
 
-    println!("Trying to access chapter with index: {}", index); // Debug log
-
-    match db::get_chapter_html_by_index(id, index) {
-        Ok(html) => HttpResponse::Ok().content_type("text/html").body(html),
-        Err(rusqlite::Error::QueryReturnedNoRows) => HttpResponse::NotFound().body(format!(
+    match store::run(&data.store, move |store| store.get_chapter_text(id, index)).await {
+        Ok(text) => HttpResponse::Ok().content_type("text/html").body(text),
+        Err(e) if e.is_not_found() => HttpResponse::NotFound().body(format!(
             "Chapter not found with index {} in document {}",
             index, id
         )),
@@ -178,81 +248,99 @@ async fn get_chapter_by_index(path_params: web::Path<(i64, usize)>) -> impl Resp
     }
 }
 
-#[get("/document/{id}/chapter/{index}/audio")]
+#[route(
+    "/document/{id}/chapter/{index}/audio",
+    method = "GET",
+    method = "HEAD"
+)]
 async fn get_audio(
     path_params: web::Path<(i64, usize)>,
+    params: web::Query<VoiceRequest>,
+    format: web::Query<audio::FormatRequest>,
     req: HttpRequest,
+    caller: Caller,
     data: web::Data<ApiState>,
-) -> impl Responder {
+) -> actix_web::Result<HttpResponse> {
     let (id, index) = path_params.into_inner();
 
-    println!("Received request to audio");
-
-    // Get language from header
-    let language = get_language_from_header(&req);
-    println!("Using language: {}", language);
+    let format = match audio::requested_format(&req, &format) {
+        Ok(format) => format,
+        Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+    };
 
-    // Get TTS service with the appropriate language
-    let tts_service = match data.tts_service.with_language(&language) {
-        Ok(service) => service,
-        Err(e) => {
-            error!(
-                "Failed to create TTS service for language {}: {}",
-                language, e
+    let chapter = store::run(&data.store, move |store| {
+        let html = store.get_chapter_html(id, index)?;
+        let document = store.get_document(id)?;
+        Ok((html, document.metadata.language))
+    })
+    .await;
+
+    match chapter {
+        Ok((html, book_language)) => {
+            // The book's language picks the voice, the listener's language if it has none
+            let language = book_language
+                .filter(|language| !language.trim().is_empty())
+                .unwrap_or_else(|| get_language_from_header(&req));
+            debug!(
+                "Reading chapter {} of document {} in {}",
+                index, id, language
             );
-            return Err(actix_web::error::ErrorInternalServerError(ApiError::from(
-                e,
-            )));
-        }
-    };
 
-    match db::get_chapter_html_by_index(id, index) {
-        Ok(html) => {
-            let audio_stream = tts_service.html_to_audio(&html).map_err(|e| {
+            let settings = match data.tts_service.voices().select(&params, Some(&language)) {
+                Ok(settings) => settings,
+                Err(message) => return Ok(HttpResponse::BadRequest().body(message)),
+            };
+
+            // Get TTS service with the chosen voice
+            let tts_service = data.tts_service.with_settings(&settings).for_document(id);
+            let text = tts_service.extract_text_from_html(&html).map_err(|e| {
                 error!("Failed to convert HTML to audio: {}", e);
                 actix_web::error::ErrorInternalServerError(ApiError::from(e))
             })?;
 
-            // Create WAV header
-            let wav_header = tts_service.create_wav_header(audio_stream.total_len);
-
-            // Map the stream to actix-compatible chunks
-            let stream = futures::stream::unfold(
-                (audio_stream, Some(wav_header), true),
-                |(mut stream, wav_header, is_first)| async move {
-                    if is_first && wav_header.is_some() {
-                        println!("sending wav header");
-                        // First chunk is the WAV header
-                        return Some((Ok(Bytes::from(wav_header.unwrap())), (stream, None, false)));
-                    }
+            // Chapters heard before are served without synthesizing, and free of quota
+            if let Some(cached) = tts_service.cached_audio(&text, format) {
+                return audio::cached_response(&req, cached, format).await;
+            }
+            if let Err(response) = usage::check_audio(&data, &caller).await {
+                return Ok(response);
+            }
 
-                    match stream.next().await {
-                        Some(Ok(bytes)) => {
-                            println!("Streaming audio chunk: {} bytes", bytes.len());
-                            Some((Ok(bytes), (stream, None, false)))
-                        }
-                        Some(Err(e)) => {
-                            error!("Error streaming audio: {}", e);
-                            Some((
-                                Err(actix_web::error::ErrorInternalServerError(e)),
-                                (stream, None, false),
-                            ))
-                        }
-                        None => None,
-                    }
-                },
-            );
+            // Audio that is still to be synthesized has no length and cannot be seeked
+            let mut response = HttpResponse::Ok();
+            audio::format_headers(&mut response, format);
+            response.insert_header((ACCEPT_RANGES, "none"));
+            if req.method() == Method::HEAD {
+                let nothing = futures_util::stream::empty::<actix_web::Result<web::Bytes>>();
+                return Ok(response.streaming(nothing));
+            }
 
-            // Return streaming response
-            Ok(HttpResponse::Ok()
-                .content_type("audio/wav")
-                .append_header(ContentDisposition {
-                    disposition: DispositionType::Attachment,
-                    parameters: vec![],
+            // Loading the model and phonemizing block, so keep them off the async workers
+            // Every sentence counts as it is spoken, so concurrent streams see each other's
+            // audio and stop once the quota is used up
+            let record = usage::audio_recorder(&data, &caller);
+            let on_spoken = move |seconds| record(seconds).is_ok();
+            let audio_stream =
+                web::block(move || tts_service.text_to_audio(&text, format, on_spoken))
+                    .await
+                    .map_err(actix_web::error::ErrorInternalServerError)?
+                    .map_err(|e| {
+                        error!("Failed to convert HTML to audio: {}", e);
+                        actix_web::error::ErrorInternalServerError(ApiError::from(e))
+                    })?;
+
+            // Map the stream to actix-compatible chunks, sentence by sentence as they are spoken
+            let stream = audio_stream.map(|chunk| {
+                chunk.map_err(|e| {
+                    error!("Error streaming audio: {}", e);
+                    actix_web::error::ErrorInternalServerError(ApiError::from(e))
                 })
-                .streaming(stream))
+            });
+
+            // Return streaming response
+            Ok(response.streaming(stream))
         }
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(HttpResponse::NotFound().body(format!(
+        Err(e) if e.is_not_found() => Ok(HttpResponse::NotFound().body(format!(
             "Chapter not found with index {} in document {}",
             index, id
         ))),
@@ -267,5 +355,610 @@ pub fn configure_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(upload_epub)
         .service(get_document)
         .service(get_audio)
-        .service(get_chapter_by_index);
+        .service(audiobook::create_audiobook)
+        .service(audiobook::get_audiobook)
+        .service(audiobook::download_audiobook)
+        .service(voices::list_voices)
+        .service(get_chapter_by_index)
+        .service(documents::list_documents)
+        .service(documents::update_document)
+        .service(documents::delete_document)
+        .service(documents::get_original)
+        .service(documents::set_tags)
+        .service(documents::set_sharing)
+        .service(documents::list_tags)
+        .service(annotations::export_annotations)
+        .service(annotations::create_annotation)
+        .service(annotations::list_annotations)
+        .service(annotations::update_annotation)
+        .service(annotations::delete_annotation)
+        .service(progress::list_progress)
+        .service(progress::get_progress)
+        .service(progress::save_progress)
+        .service(koreader::create_user)
+        .service(koreader::auth_user)
+        .service(koreader::update_progress)
+        .service(koreader::get_progress)
+        .service(koreader::healthcheck)
+        .service(koreader::link_user)
+        .service(auth::create_token)
+        .service(auth::list_tokens)
+        .service(auth::delete_token)
+        .service(auth::current_user)
+        .service(auth::change_password)
+        .service(auth::create_user)
+        .service(auth::list_users)
+        .service(auth::delete_user)
+        .service(usage::get_usage)
+        .service(usage::get_user_usage)
+        .service(collections::list_collections)
+        .service(collections::create_collection)
+        .service(collections::get_collection)
+        .service(collections::update_collection)
+        .service(collections::delete_collection)
+        .service(collections::set_collection_documents)
+        .service(collections::add_to_collection)
+        .service(collections::remove_from_collection)
+        .service(admin::export_library)
+        .service(admin::import_library)
+        .service(admin::reparse_all)
+        .service(admin::reparse_document)
+        .service(search::search_library)
+        .service(search::search_document);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::metadata::{Chapter, EpubMetadata};
+    use crate::services::audio_cache::AudioCache;
+    use crate::services::audiobook::{AudiobookFormat, BookChapter, BookInfo, Export, Work};
+    use crate::services::auth::{self, Scope, User};
+    use crate::services::epub_parser::EpubContent;
+    use crate::services::koreader::{self, SyncUser};
+    use crate::services::memory_store::MemoryStore;
+    use crate::services::tts::TtsConfig;
+    use crate::services::voices::{Voice, VoiceRegistry};
+    use actix_web::dev::Service;
+    use actix_web::http::header::AUTHORIZATION;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpMessage};
+    use std::path::Path;
+
+    /// Authentication on, an in-memory store and an audio cache in `dir`
+    fn state(dir: &Path) -> web::Data<ApiState> {
+        state_with_quotas(dir, Quotas::default())
+    }
+
+    /// With a voice whose model is never loaded, so requests get as far as synthesis
+    fn state_with_quotas(dir: &Path, quotas: Quotas) -> web::Data<ApiState> {
+        let cache = AudioCache::open(dir.join("audio"), 1 << 20).unwrap();
+        let voice = Voice::from_config(
+            "en_US-test-low",
+            r#"{"audio": {"sample_rate": 16000}}"#,
+            &dir.join("voices"),
+        )
+        .unwrap();
+        let voices = VoiceRegistry::new(vec![voice], None);
+        let tts_service = TtsService::new(TtsConfig::default(), Arc::new(voices), 1)
+            .unwrap()
+            .with_audio_cache(Arc::new(cache));
+
+        web::Data::new(ApiState {
+            tts_service: Arc::new(tts_service),
+            audiobooks: Arc::new(AudiobookJobs::open(dir.join("audiobooks")).unwrap()),
+            store: Arc::new(MemoryStore::new()),
+            auth_enabled: true,
+            quotas,
+        })
+    }
+
+    /// The whole API as the server runs it
+    macro_rules! app {
+        ($state:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data($state.clone())
+                    .wrap(from_fn(authenticate))
+                    .configure(configure_routes),
+            )
+            .await
+        };
+    }
+
+    /// A user with a token with `scopes`
+    fn login(state: &ApiState, username: &str, is_admin: bool, scopes: &[Scope]) -> (User, String) {
+        let user = state.store.create_user(username, "hash", is_admin).unwrap();
+        let (token, token_hash) = auth::generate_token();
+        state
+            .store
+            .create_token(user.id, "test", scopes, &token_hash)
+            .unwrap();
+        (user, token)
+    }
+
+    /// A document owned by `owner`
+    fn document(state: &ApiState, owner: &User, shared: bool) -> i64 {
+        let content = epub_parser::parse_epub(&std::fs::read("moby-dick.epub").unwrap()).unwrap();
+        let id = state.store.save_document(&content, None).unwrap();
+        let access = DocumentAccess {
+            owner_id: Some(owner.id),
+            shared,
+        };
+        state.store.set_document_access(id, &access).unwrap();
+        id
+    }
+
+    fn request(method: Method, uri: &str, token: &str) -> TestRequest {
+        TestRequest::default()
+            .method(method)
+            .uri(uri)
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+    }
+
+    #[actix_web::test]
+    async fn test_requests_need_a_token_with_the_scope() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (_, reader) = login(&state, "reader", false, &[Scope::Read]);
+        let (_, admin) = login(&state, "admin", true, &[Scope::Read, Scope::Admin]);
+        let app = app!(state);
+
+        let cases = [
+            (
+                TestRequest::get().uri("/documents"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                request(Method::GET, "/documents", "nonsense"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (request(Method::GET, "/documents", &reader), StatusCode::OK),
+            (
+                request(Method::GET, "/admin/users", &reader),
+                StatusCode::FORBIDDEN,
+            ),
+            (request(Method::GET, "/admin/users", &admin), StatusCode::OK),
+            (
+                request(Method::DELETE, "/document/1", &reader),
+                StatusCode::FORBIDDEN,
+            ),
+            // KOReader has its own accounts
+            (
+                TestRequest::get().uri("/koreader/healthcheck"),
+                StatusCode::OK,
+            ),
+        ];
+        for (request, status) in cases {
+            let request = request.to_request();
+            let uri = request.uri().to_string();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_private_documents_are_only_visible_to_their_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (alice, alices_token) = login(&state, "alice", false, &[Scope::Read, Scope::Upload]);
+        let (_, bobs_token) = login(&state, "bob", false, &[Scope::Read, Scope::Upload]);
+        let private = document(&state, &alice, false);
+        let shared = document(&state, &alice, true);
+        let app = app!(state);
+
+        let status = |method: Method, uri: String, token: &str| {
+            let request = request(method, &uri, token)
+                .set_json(json!({ "tags": ["sea"] }))
+                .to_request();
+            let app = &app;
+            async move { test::call_service(app, request).await.status() }
+        };
+
+        let uri = format!("/document/{}", private);
+        assert_eq!(
+            status(Method::GET, uri.clone(), &alices_token).await,
+            StatusCode::OK
+        );
+        // Answered as if it did not exist
+        assert_eq!(
+            status(Method::GET, uri, &bobs_token).await,
+            StatusCode::NOT_FOUND
+        );
+        let uri = format!("/document/{}/original", private);
+        assert_eq!(
+            status(Method::GET, uri, &bobs_token).await,
+            StatusCode::NOT_FOUND
+        );
+
+        // Shared documents can be read but only changed by their owner
+        let uri = format!("/document/{}", shared);
+        assert_eq!(status(Method::GET, uri, &bobs_token).await, StatusCode::OK);
+        let uri = format!("/document/{}/tags", shared);
+        assert_eq!(
+            status(Method::PUT, uri.clone(), &bobs_token).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(Method::PUT, uri, &alices_token).await,
+            StatusCode::OK
+        );
+
+        let request = request(Method::GET, "/documents", &bobs_token).to_request();
+        let listed: Value = test::call_and_read_body_json(&app, request).await;
+        let ids: Vec<i64> = listed["documents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|document| document["id"].as_i64().unwrap())
+            .collect();
+        assert_eq!(ids, vec![shared]);
+    }
+
+    #[actix_web::test]
+    async fn test_deleting_a_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (alice, alices_token) = login(&state, "alice", false, &[Scope::Read, Scope::Upload]);
+        let (_, bobs_token) = login(&state, "bob", false, &[Scope::Read, Scope::Upload]);
+        let id = document(&state, &alice, true);
+        let cache = state.tts_service.audio_cache().unwrap().clone();
+        cache.import(id, "a1", "wav", &b"RIFF"[..]).unwrap();
+        let app = app!(state);
+
+        let uri = format!("/document/{}", id);
+        let delete = |token: &str| request(Method::DELETE, &uri, token).to_request();
+        let response = test::call_service(&app, delete(&bobs_token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = test::call_service(&app, delete(&alices_token)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state
+            .store
+            .get_document(id)
+            .is_err_and(|e| e.is_not_found()));
+        assert!(cache.get(id, "a1").is_none());
+
+        let response = test::call_service(&app, delete(&alices_token)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let get = request(Method::GET, &uri, &alices_token).to_request();
+        let response = test::call_service(&app, get).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_collections_are_changed_by_their_owner_and_count_visible_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (alice, alices_token) = login(&state, "alice", false, &[Scope::Read, Scope::Upload]);
+        let (_, bobs_token) = login(&state, "bob", false, &[Scope::Read, Scope::Upload]);
+        let private = document(&state, &alice, false);
+        state
+            .store
+            .set_tags(private, &["secret".to_string()])
+            .unwrap();
+        let app = app!(state);
+
+        let create = request(Method::POST, "/collections", &alices_token)
+            .set_json(json!({ "name": "Reading list" }))
+            .to_request();
+        let collection: Value = test::call_and_read_body_json(&app, create).await;
+        let uri = format!("/collections/{}", collection["id"]);
+        let add = |token: &str| {
+            request(Method::POST, &format!("{}/documents", uri), token)
+                .set_json(json!({ "document_id": private }))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, add(&bobs_token)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = test::call_service(&app, add(&alices_token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let rename = request(Method::PATCH, &uri, &bobs_token)
+            .set_json(json!({ "name": "Mine now" }))
+            .to_request();
+        let response = test::call_service(&app, rename).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let delete = request(Method::DELETE, &uri, &bobs_token).to_request();
+        let response = test::call_service(&app, delete).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Counts leave out documents the caller may not see
+        let count = |token: &str| {
+            let request = request(Method::GET, "/collections", token).to_request();
+            let app = &app;
+            async move {
+                let listed: Value = test::call_and_read_body_json(app, request).await;
+                listed["collections"][0]["document_count"].as_u64().unwrap()
+            }
+        };
+        assert_eq!(count(&alices_token).await, 1);
+        assert_eq!(count(&bobs_token).await, 0);
+        let tags = request(Method::GET, "/tags", &bobs_token).to_request();
+        let tags: Value = test::call_and_read_body_json(&app, tags).await;
+        assert_eq!(tags["tags"], json!([]));
+
+        let delete = request(Method::DELETE, &uri, &alices_token).to_request();
+        let response = test::call_service(&app, delete).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn test_encoded_paths_are_checked_as_routed() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (alice, _) = login(&state, "alice", false, &[Scope::Read]);
+        let (_, bobs_token) = login(&state, "bob", false, &[Scope::Read, Scope::Upload]);
+        let private = document(&state, &alice, false);
+        assert_eq!(private, 1);
+        let app = app!(state);
+
+        let cases = [
+            (Method::GET, "/document/%31"),
+            (Method::GET, "/document/%31/original"),
+            (Method::GET, "/document/%2B1/original"),
+            (Method::GET, "/document/%2b1/chapter/0"),
+            (Method::PUT, "/document/%31/tags"),
+        ];
+        for (method, uri) in cases {
+            let request = request(method, uri, &bobs_token)
+                .set_json(json!({ "tags": [] }))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+
+        let create_user = request(Method::POST, "/%61dmin/users", &bobs_token)
+            .set_json(json!({ "username": "mallory", "password": "correct horse" }))
+            .to_request();
+        let response = test::call_service(&app, create_user).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(state.store.get_user_by_name("mallory").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_admin_handlers_refuse_other_callers_themselves() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (bob, _) = login(&state, "bob", false, &[Scope::Read, Scope::Upload]);
+        let caller = Caller {
+            user_id: Some(bob.id),
+            username: bob.username,
+            scopes: vec![Scope::Read, Scope::Upload],
+        };
+        // Without the middleware, as if a request got past its scope check
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .wrap_fn(move |req, srv| {
+                    req.extensions_mut().insert(caller.clone());
+                    srv.call(req)
+                })
+                .configure(configure_routes),
+        )
+        .await;
+
+        let cases = [
+            TestRequest::post()
+                .uri("/admin/users")
+                .set_json(json!({ "username": "mallory", "password": "correct horse" })),
+            TestRequest::get().uri("/admin/users"),
+            TestRequest::delete().uri(&format!("/admin/users/{}", bob.id)),
+            TestRequest::get().uri(&format!("/admin/users/{}/usage", bob.id)),
+            TestRequest::get().uri("/admin/export"),
+            TestRequest::post().uri("/admin/import"),
+            TestRequest::post().uri("/admin/documents/reparse"),
+            TestRequest::post().uri("/admin/documents/1/reparse"),
+        ];
+        for request in cases {
+            let request = request.to_request();
+            let uri = request.uri().to_string();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+        assert_eq!(state.store.list_users().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_koreader_accounts_share_progress_once_linked() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (alice, alices_token) = login(&state, "alice", false, &[Scope::Read]);
+        let (_, bobs_token) = login(&state, "bob", false, &[Scope::Read]);
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: std::fs::read("moby-dick.epub").unwrap(),
+        };
+        let content = epub_parser::parse_epub(&original.data).unwrap();
+        let id = state
+            .store
+            .save_document(&content, Some(&original))
+            .unwrap();
+        let access = DocumentAccess {
+            owner_id: Some(alice.id),
+            shared: false,
+        };
+        state.store.set_document_access(id, &access).unwrap();
+        let legacy = SyncUser::new("legacy".to_string(), &koreader::password_key("old"), None);
+        state.store.create_sync_user(&legacy).unwrap();
+        let app = app!(state);
+
+        let link = |token: &str, username: &str, password: &str| {
+            let request = request(Method::PUT, "/auth/koreader", token)
+                .set_json(json!({ "username": username, "password": password }))
+                .to_request();
+            let app = &app;
+            async move { test::call_service(app, request).await.status() }
+        };
+        let sync = |username: &str, password: &str| {
+            let request = TestRequest::put()
+                .uri("/koreader/syncs/progress")
+                .insert_header(("x-auth-user", username))
+                .insert_header(("x-auth-key", koreader::password_key(password)))
+                .set_json(json!({
+                    "document": original.partial_md5(),
+                    "progress": "/body/DocFragment[2]/body/p[1]",
+                    "percentage": 0.2,
+                    "device": "Kobo",
+                }))
+                .to_request();
+            let app = &app;
+            async move { test::call_service(app, request).await.status() }
+        };
+
+        // KOReader cannot register accounts of its own
+        let register = TestRequest::post()
+            .uri("/koreader/users/create")
+            .set_json(json!({ "username": "kobo", "password": koreader::password_key("x") }))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, register).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        assert_eq!(
+            link(&alices_token, "kobo", "secret").await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            link(&bobs_token, "kobo", "guess").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            link(&bobs_token, "kobo", "secret").await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(sync("kobo", "secret").await, StatusCode::OK);
+
+        // Names are matched ignoring case, as accounts are
+        let progress = request(
+            Method::GET,
+            &format!("/document/{}/progress?user=ALICE", id),
+            &alices_token,
+        )
+        .to_request();
+        let progress: Value = test::call_and_read_body_json(&app, progress).await;
+        assert_eq!(progress["chapter_index"], 1);
+
+        // Accounts registered before are linked with their password, and never reach
+        // documents their user cannot read
+        assert_eq!(
+            link(&bobs_token, "legacy", "wrong").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            link(&bobs_token, "legacy", "old").await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(sync("legacy", "old").await, StatusCode::OK);
+        let listed = request(Method::GET, "/progress", &bobs_token).to_request();
+        let listed: Value = test::call_and_read_body_json(&app, listed).await;
+        assert_eq!(listed["user"], "bob");
+        assert_eq!(listed["progress"], json!([]));
+    }
+
+    #[actix_web::test]
+    async fn test_find_in_book_escapes_the_chapter_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state(dir.path());
+        let (_, token) = login(&state, "reader", false, &[Scope::Read]);
+        // Text that looks like markup, as a book about HTML would have
+        let content = EpubContent {
+            metadata: EpubMetadata::new(
+                "Tags".to_string(),
+                "Anonymous".to_string(),
+                None,
+                None,
+                None,
+            ),
+            chapters: vec![Chapter {
+                title: "Bold".to_string(),
+                path: "c0".to_string(),
+                content: "Write <b>bold</b> or <img src=x onerror=alert(1)>".to_string(),
+                html: "<p>Write &lt;b&gt;bold&lt;/b&gt;</p>".to_string(),
+            }],
+            resources: Vec::new(),
+            toc: Vec::new(),
+        };
+        let id = state.store.save_document(&content, None).unwrap();
+        let app = app!(state);
+
+        let search = request(
+            Method::GET,
+            &format!("/document/{}/search?q=bold", id),
+            &token,
+        )
+        .to_request();
+        let found: Value = test::call_and_read_body_json(&app, search).await;
+        assert_eq!(
+            found["matches"][0]["text"],
+            "Write &lt;b&gt;<mark>bold</mark>&lt;/b&gt; or &lt;img src=x onerror=alert(1)&gt;"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_audiobooks_under_an_audio_quota_are_exported_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let quotas = Quotas {
+            max_audio_seconds: Some(60.0),
+            ..Quotas::default()
+        };
+        let state = state_with_quotas(dir.path(), quotas);
+        let (alice, alices_token) = login(&state, "alice", false, &[Scope::Read]);
+        let (_, admins_token) = login(&state, "admin", true, &[Scope::Read, Scope::Admin]);
+        let id = document(&state, &alice, false);
+        let app = app!(state);
+
+        // Exports run one after the other, so alice's wait behind this one
+        let (release, wait) = std::sync::mpsc::channel::<()>();
+        let busy: Work = Box::new(move |_, _, _| {
+            let _ = wait.recv();
+            Ok(())
+        });
+        let export = Export {
+            document_id: id,
+            format: AudiobookFormat::M4b,
+            info: BookInfo {
+                title: "Moby-Dick".to_string(),
+                author: "Herman Melville".to_string(),
+                cover: None,
+            },
+            chapters: vec![BookChapter {
+                title: "Loomings".to_string(),
+                chapters: vec![0],
+            }],
+        };
+        state
+            .audiobooks
+            .submit(None, "busy".to_string(), export, busy, false)
+            .unwrap();
+
+        let uri = |format: &str| format!("/document/{}/audiobook?format={}", id, format);
+        let first = test::call_service(
+            &app,
+            request(Method::POST, &uri("m4b"), &alices_token).to_request(),
+        )
+        .await;
+        assert_eq!(first.status(), StatusCode::ACCEPTED);
+        let first: Value = test::read_body_json(first).await;
+        assert_eq!(first["state"], "queued");
+
+        let second = request(Method::POST, &uri("mp3"), &alices_token).to_request();
+        let second = test::call_service(&app, second).await;
+        assert_eq!(second.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Asking for the same book again finds it, and admins have no quota
+        let again = request(Method::POST, &uri("m4b"), &alices_token).to_request();
+        let again: Value = test::read_body_json(test::call_service(&app, again).await).await;
+        assert_eq!(again["id"], first["id"]);
+        let admins = request(Method::POST, &uri("mp3"), &admins_token).to_request();
+        let admins = test::call_service(&app, admins).await;
+        assert_eq!(admins.status(), StatusCode::ACCEPTED);
+
+        release.send(()).unwrap();
+    }
 }
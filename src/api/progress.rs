@@ -0,0 +1,243 @@
+use crate::api::documents::{optional_field, required_field};
+use crate::api::ApiState;
+use crate::services::auth::Caller;
+use crate::services::store::{self, Progress, StoreError};
+use actix_web::{get, put, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize)]
+pub struct UserParams {
+    /// Whose progress to read or save; the caller when missing, and only admins may name
+    /// another user
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProgressRequest {
+    pub chapter_index: usize,
+    #[serde(default)]
+    pub char_offset: usize,
+    pub audio_position: Option<f64>,
+    pub percentage: f64,
+    pub device: Option<String>,
+    /// When the reader was at this position, in milliseconds since the Unix epoch; now
+    /// when missing
+    pub updated_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ProgressListResponse {
+    user: String,
+    progress: Vec<Progress>,
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// The id and name of the reader a request is about; an error response if the caller may
+/// not use it. Names are matched ignoring case, like logging in.
+async fn reader(
+    params: UserParams,
+    caller: &Caller,
+    data: &ApiState,
+) -> Result<(Option<i64>, String), HttpResponse> {
+    let Some(user) = params.user else {
+        return Ok((caller.user_id, caller.username.clone()));
+    };
+    let user =
+        required_field("user", user).map_err(|message| HttpResponse::BadRequest().body(message))?;
+    if user.eq_ignore_ascii_case(&caller.username) {
+        return Ok((caller.user_id, caller.username.clone()));
+    }
+    if !caller.is_admin() {
+        return Err(
+            HttpResponse::Forbidden().body("Only admins can use the progress of other users")
+        );
+    }
+
+    let name = user.clone();
+    match store::run(&data.store, move |store| store.get_user_by_name(&name)).await {
+        Ok((user, _)) => Ok((Some(user.id), user.username)),
+        Err(StoreError::NotFound) => {
+            Err(HttpResponse::NotFound().body(format!("User not found: {}", user)))
+        }
+        Err(e) => {
+            Err(HttpResponse::InternalServerError().body(format!("Error loading user: {}", e)))
+        }
+    }
+}
+
+/// Check reported progress and turn it into what is stored
+///
+/// Timestamps from the future are taken as now, so a device with a wrong clock cannot keep
+/// its position from ever being replaced.
+fn validate_progress(
+    user_id: Option<i64>,
+    document_id: i64,
+    request: ProgressRequest,
+    now: i64,
+) -> Result<Progress, String> {
+    if !(0.0..=1.0).contains(&request.percentage) {
+        return Err("percentage must be between 0 and 1".to_string());
+    }
+    if request
+        .audio_position
+        .is_some_and(|position| !position.is_finite() || position < 0.0)
+    {
+        return Err("audio_position must be a number of seconds, at least 0".to_string());
+    }
+    let device = optional_field("device", request.device)?;
+
+    Ok(Progress {
+        user_id,
+        document_id,
+        chapter_index: request.chapter_index,
+        char_offset: request.char_offset,
+        audio_position: request.audio_position,
+        percentage: request.percentage,
+        device,
+        updated_at: request
+            .updated_at
+            .map_or(now, |updated_at| updated_at.min(now)),
+    })
+}
+
+/// Progress of one reader in every document, to offer books to resume
+#[get("/progress")]
+async fn list_progress(
+    params: web::Query<UserParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let (user_id, user) = match reader(params.into_inner(), &caller, &data).await {
+        Ok(reader) => reader,
+        Err(response) => return response,
+    };
+
+    let result = store::run(&data.store, move |store| store.list_progress(user_id)).await;
+
+    match result {
+        Ok(progress) => HttpResponse::Ok().json(ProgressListResponse { user, progress }),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error loading progress: {}", e))
+        }
+    }
+}
+
+#[get("/document/{id}/progress")]
+async fn get_progress(
+    path: web::Path<i64>,
+    params: web::Query<UserParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let document_id = path.into_inner();
+    let (user_id, _) = match reader(params.into_inner(), &caller, &data).await {
+        Ok(reader) => reader,
+        Err(response) => return response,
+    };
+
+    let result = store::run(&data.store, move |store| {
+        store.get_progress(user_id, document_id)
+    })
+    .await;
+
+    match result {
+        Ok(progress) => HttpResponse::Ok().json(progress),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("No progress saved for document {}", document_id))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error loading progress: {}", e))
+        }
+    }
+}
+
+/// Save the reader's position; an update older than the saved one is refused with the
+/// saved progress, so devices can jump to it
+#[put("/document/{id}/progress")]
+async fn save_progress(
+    path: web::Path<i64>,
+    params: web::Query<UserParams>,
+    request: web::Json<ProgressRequest>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let document_id = path.into_inner();
+    let (user_id, _) = match reader(params.into_inner(), &caller, &data).await {
+        Ok(reader) => reader,
+        Err(response) => return response,
+    };
+    let progress = match validate_progress(user_id, document_id, request.into_inner(), now_millis())
+    {
+        Ok(progress) => progress,
+        Err(message) => return HttpResponse::BadRequest().body(message),
+    };
+
+    let result = store::run(&data.store, move |store| {
+        // Counts are zero rather than not found for unknown documents
+        store.get_document(document_id)?;
+        let chapters = store.content_counts(document_id)?.chapters;
+        if progress.chapter_index >= chapters {
+            return Err(StoreError::Invalid(format!(
+                "Chapter not found: {}",
+                progress.chapter_index
+            )));
+        }
+
+        let saved = store.save_progress(&progress)?;
+        Ok((saved, store.get_progress(progress.user_id, document_id)?))
+    })
+    .await;
+
+    match result {
+        Ok((true, progress)) => HttpResponse::Ok().json(progress),
+        Ok((false, progress)) => HttpResponse::Conflict().json(progress),
+        Err(StoreError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", document_id))
+        }
+        Err(StoreError::Invalid(message)) => HttpResponse::BadRequest().body(message),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error saving progress: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(percentage: f64, updated_at: Option<i64>) -> ProgressRequest {
+        ProgressRequest {
+            chapter_index: 2,
+            char_offset: 140,
+            audio_position: Some(12.5),
+            percentage,
+            device: Some(" Kobo ".to_string()),
+            updated_at,
+        }
+    }
+
+    #[test]
+    fn test_validate_progress() {
+        let progress = validate_progress(Some(1), 1, request(0.4, Some(500)), 1000).unwrap();
+        assert_eq!(progress.updated_at, 500);
+        assert_eq!(progress.device.as_deref(), Some("Kobo"));
+
+        // Clocks ahead of the server count as now
+        let progress = validate_progress(Some(1), 1, request(0.4, Some(5000)), 1000).unwrap();
+        assert_eq!(progress.updated_at, 1000);
+        let progress = validate_progress(Some(1), 1, request(1.0, None), 1000).unwrap();
+        assert_eq!(progress.updated_at, 1000);
+
+        assert!(validate_progress(Some(1), 1, request(1.5, None), 1000).is_err());
+        assert!(validate_progress(Some(1), 1, request(f64::NAN, None), 1000).is_err());
+        let mut negative = request(0.4, None);
+        negative.audio_position = Some(-1.0);
+        assert!(validate_progress(Some(1), 1, negative, 1000).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::api::ApiState;
+use crate::services::auth::Caller;
+use crate::services::search::{self, Analyzer, Query, Snippet};
+use crate::services::store;
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+/// Snippets returned per chapter; `match_count` still counts every match
+const MAX_SNIPPETS: usize = 3;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    /// Library search: rank books in this language (e.g. `ru`, `en-US`) ahead of the others.
+    /// Find in book: stem words for this language.
+    pub lang: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    document_id: i64,
+    title: String,
+    author: String,
+    language: Option<String>,
+    chapter_index: usize,
+    chapter_title: String,
+    score: f64,
+    match_count: usize,
+    snippets: Vec<Snippet>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    query: String,
+    total: usize,
+    limit: usize,
+    offset: usize,
+    results: Vec<SearchResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct DocumentMatch {
+    chapter_index: usize,
+    chapter_title: String,
+    #[serde(flatten)]
+    snippet: Snippet,
+}
+
+#[derive(Debug, Serialize)]
+struct DocumentSearchResponse {
+    query: String,
+    document_id: i64,
+    total: usize,
+    limit: usize,
+    offset: usize,
+    matches: Vec<DocumentMatch>,
+}
+
+#[get("/search")]
+async fn search_library(
+    params: web::Query<SearchParams>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let params = params.into_inner();
+    let query = Query::parse(&params.q);
+    if query.is_empty() {
+        return HttpResponse::BadRequest().body("Search query must contain at least one word");
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = params.offset.unwrap_or(0);
+    let language = params.lang.clone();
+    let visible_to = caller.visible_to();
+
+    // Snippets are computed next to the query, they need the whole chapter text
+    let result = store::run(&data.store, move |store| {
+        let (total, hits) =
+            store.search_chapters(&query, language.as_deref(), visible_to, limit, offset)?;
+
+        let results = hits
+            .into_iter()
+            .map(|hit| {
+                let analyzer = Analyzer::for_language(hit.language.as_deref());
+                let matches = query.find_matches(&hit.text, &analyzer);
+
+                SearchResult {
+                    snippets: matches
+                        .iter()
+                        .take(MAX_SNIPPETS)
+                        .map(|m| search::snippet(&hit.text, m))
+                        .collect(),
+                    match_count: matches.len(),
+                    document_id: hit.document_id,
+                    title: hit.document_title,
+                    author: hit.author,
+                    language: hit.language,
+                    chapter_index: hit.chapter_index,
+                    chapter_title: hit.chapter_title,
+                    score: hit.score,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok((total, results))
+    })
+    .await;
+
+    match result {
+        Ok((total, results)) => HttpResponse::Ok().json(SearchResponse {
+            query: params.q,
+            total,
+            limit,
+            offset,
+            results,
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error searching: {}", e)),
+    }
+}
+
+/// Find in book: every occurrence of the query in a document, in reading order
+///
+/// Words are matched whole and case-insensitively, without stemming unless `lang` asks for
+/// it, so the reader can step through exactly what was typed. `offset` and `limit` page
+/// through the matches.
+#[get("/document/{id}/search")]
+async fn search_document(
+    path: web::Path<i64>,
+    params: web::Query<SearchParams>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let params = params.into_inner();
+    let query = Query::parse(&params.q);
+    if query.is_empty() {
+        return HttpResponse::BadRequest().body("Search query must contain at least one word");
+    }
+
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = params.offset.unwrap_or(0);
+    // Without a language, or for one without a stemmer, words are matched as typed
+    let analyzer = Analyzer::for_language(params.lang.as_deref());
+
+    let result = store::run(&data.store, move |store| {
+        // Fails with not found for unknown documents rather than returning no matches
+        let chapters = store.get_chapters(id)?;
+
+        let mut total = 0;
+        let mut matches = Vec::new();
+        for (chapter_index, chapter) in chapters.iter().enumerate() {
+            for m in query.find_matches(&chapter.text, &analyzer) {
+                if total >= offset && matches.len() < limit {
+                    matches.push(DocumentMatch {
+                        chapter_index,
+                        chapter_title: chapter.title.clone(),
+                        snippet: search::snippet(&chapter.text, &m),
+                    });
+                }
+                total += 1;
+            }
+        }
+
+        Ok((total, matches))
+    })
+    .await;
+
+    match result {
+        Ok((total, matches)) => HttpResponse::Ok().json(DocumentSearchResponse {
+            query: params.q,
+            document_id: id,
+            total,
+            limit,
+            offset,
+            matches,
+        }),
+        Err(e) if e.is_not_found() => {
+            HttpResponse::NotFound().body(format!("Document not found: {}", e))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error searching: {}", e)),
+    }
+}
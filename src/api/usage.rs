@@ -0,0 +1,152 @@
+//! Per-user quotas on stored documents and synthesized audio, and the usage endpoints
+//!
+//! Uploads over the storage quota are refused with `507 Insufficient Storage`, audio over
+//! the monthly quota with `429 Too Many Requests` and a `Retry-After` of the time until
+//! the next month. Admins and callers without an account are not limited.
+
+use crate::api::auth::admin_only;
+use crate::api::ApiState;
+use crate::services::auth::Caller;
+use crate::services::quota::{self, QuotaError, Usage};
+use crate::services::store::{self, DocumentStore, StoreResult};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{get, web, HttpResponse, Responder};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// The account a caller's usage is counted for, `None` for callers without limits
+pub(super) fn limited(caller: &Caller) -> Option<i64> {
+    caller.user_id.filter(|_| !caller.is_admin())
+}
+
+fn usage(store: &dyn DocumentStore, quotas: &quota::Quotas, user_id: i64) -> StoreResult<Usage> {
+    let period = quota::audio_period(now());
+    let storage = store.storage_usage(user_id)?;
+    let audio_seconds = store.audio_usage(user_id, &period)?;
+    Ok(quotas.usage(&storage, audio_seconds, period))
+}
+
+/// Refuse an upload of `size` bytes that would take the caller over their storage quota
+pub async fn check_upload(data: &ApiState, caller: &Caller, size: u64) -> Result<(), HttpResponse> {
+    let Some(user_id) = limited(caller) else {
+        return Ok(());
+    };
+
+    match store::run(&data.store, move |store| store.storage_usage(user_id)).await {
+        Ok(usage) => data
+            .quotas
+            .check_upload(&usage, size)
+            .map_err(|e| HttpResponse::InsufficientStorage().body(e.to_string())),
+        Err(e) => Err(HttpResponse::InternalServerError()
+            .body(format!("Error checking storage quota: {}", e))),
+    }
+}
+
+/// Refuse synthesis to a caller who used up this month's audio quota
+pub async fn check_audio(data: &ApiState, caller: &Caller) -> Result<(), HttpResponse> {
+    let Some(user_id) = limited(caller) else {
+        return Ok(());
+    };
+    let now = now();
+    let period = quota::audio_period(now);
+
+    let used = store::run(&data.store, move |store| {
+        store.audio_usage(user_id, &period)
+    })
+    .await;
+    match used {
+        Ok(used) => data.quotas.check_audio(used).map_err(|e| {
+            HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, quota::seconds_until_next_period(now)))
+                .body(e.to_string())
+        }),
+        Err(e) => {
+            Err(HttpResponse::InternalServerError()
+                .body(format!("Error checking audio quota: {}", e)))
+        }
+    }
+}
+
+/// What counts the seconds of audio synthesized for the caller towards their quota, called
+/// on the synthesis thread as audio is synthesized; fails once the quota is used up, so
+/// synthesis can stop there
+pub fn audio_recorder(
+    data: &ApiState,
+    caller: &Caller,
+) -> impl Fn(f64) -> Result<(), QuotaError> + Send + 'static {
+    let store = data.store.clone();
+    let quotas = data.quotas;
+    let user_id = caller.user_id;
+    let limited = limited(caller).is_some();
+
+    move |seconds| {
+        let Some(user_id) = user_id else {
+            return Ok(());
+        };
+        let period = quota::audio_period(now());
+        if let Err(e) = store.add_audio_usage(user_id, &period, seconds) {
+            error!("Failed to record audio usage: {}", e);
+            return Ok(());
+        }
+        if !limited {
+            return Ok(());
+        }
+
+        match store.audio_usage(user_id, &period) {
+            Ok(used) => quotas.check_audio(used),
+            Err(e) => {
+                error!("Failed to read audio usage: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The caller's usage and limits
+#[get("/usage")]
+async fn get_usage(caller: Caller, data: web::Data<ApiState>) -> impl Responder {
+    let Some(user_id) = caller.user_id else {
+        return HttpResponse::BadRequest()
+            .body("Usage is not counted while authentication is disabled");
+    };
+    let quotas = data.quotas;
+
+    match store::run(&data.store, move |store| usage(store, &quotas, user_id)).await {
+        Ok(usage) => HttpResponse::Ok().json(usage),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading usage: {}", e)),
+    }
+}
+
+#[get("/admin/users/{id}/usage")]
+async fn get_user_usage(
+    path: web::Path<i64>,
+    caller: Caller,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    if let Err(response) = admin_only(&caller) {
+        return response;
+    }
+    let id = path.into_inner();
+    let quotas = data.quotas;
+
+    let result = store::run(&data.store, move |store| {
+        if !store.list_users()?.iter().any(|user| user.id == id) {
+            return Ok(None);
+        }
+        usage(store, &quotas, id).map(Some)
+    })
+    .await;
+
+    match result {
+        Ok(Some(usage)) => HttpResponse::Ok().json(usage),
+        Ok(None) => HttpResponse::NotFound().body(format!("User not found: {}", id)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error reading usage: {}", e)),
+    }
+}
@@ -0,0 +1,21 @@
+use crate::api::ApiState;
+use crate::services::voices::Voice;
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct VoicesResponse<'a> {
+    voices: &'a [Voice],
+    /// The voice for languages without one of their own
+    default_voice: Option<&'a str>,
+}
+
+/// The installed voices
+#[get("/voices")]
+async fn list_voices(data: web::Data<ApiState>) -> impl Responder {
+    let registry = data.tts_service.voices();
+    HttpResponse::Ok().json(VoicesResponse {
+        voices: registry.voices(),
+        default_voice: registry.default_voice().map(|voice| voice.name.as_str()),
+    })
+}
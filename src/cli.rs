@@ -0,0 +1,197 @@
+use crate::services::auth::{self, Scope};
+use crate::services::epub_parser::PARSER_VERSION;
+use crate::services::reparse::{self, ReparseReport};
+use crate::services::store::{DocumentStore, StoreError};
+use std::fs::File;
+use std::io::{self, BufRead};
+
+const USAGE: &str = "Usage:
+  rust-web-server                     Start the API server
+  rust-web-server reparse <id>...     Re-parse documents from their original files
+  rust-web-server reparse --all       Re-parse every document
+  rust-web-server reparse --outdated  Re-parse documents parsed by an older parser
+  rust-web-server export <file>       Write the library to a zip archive
+  rust-web-server import <file>       Add the documents of an exported archive
+  rust-web-server create-admin <name> Add an admin and print an API token for them; the
+                                      password is read from EPUB_ADMIN_PASSWORD or stdin";
+
+/// Run a maintenance command given on the command line instead of starting the server
+pub fn run(command: &str, args: &[String], store: &dyn DocumentStore) -> io::Result<()> {
+    match command {
+        "reparse" => reparse(args, store),
+        "export" => export(args, store),
+        "import" => import(args, store),
+        "create-admin" => create_admin(args, store),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(usage_error(&format!("Unknown command: {}", command))),
+    }
+}
+
+fn usage_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}\n\n{}", message, USAGE),
+    )
+}
+
+fn reparse(args: &[String], store: &dyn DocumentStore) -> io::Result<()> {
+    let results = match args {
+        [flag] if flag == "--all" || flag == "--outdated" => {
+            reparse::reparse_all(store, flag == "--outdated").map_err(io::Error::other)?
+        }
+        [] => {
+            return Err(usage_error(
+                "reparse needs document ids, --all or --outdated",
+            ))
+        }
+        ids => {
+            let ids = ids
+                .iter()
+                .map(|id| {
+                    id.parse::<i64>()
+                        .map_err(|_| usage_error(&format!("Invalid document id: {}", id)))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            ids.into_iter()
+                .map(|id| (id, reparse::reparse_document(store, id)))
+                .collect()
+        }
+    };
+
+    println!("Parser version {}", PARSER_VERSION);
+    let mut failures = 0;
+    for (id, result) in &results {
+        match result {
+            Ok(report) => print_report(report),
+            Err(e) => {
+                failures += 1;
+                if e.is_not_found() {
+                    println!("document {}: not found", id)
+                } else {
+                    println!("document {}: failed: {}", id, e)
+                }
+            }
+        }
+    }
+    println!(
+        "{} re-parsed, {} failed",
+        results.len() - failures,
+        failures
+    );
+
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "{} documents could not be re-parsed",
+            failures
+        )));
+    }
+    Ok(())
+}
+
+fn print_report(report: &ReparseReport) {
+    println!(
+        "document {}: parser {} -> {}, chapters {} -> {} ({:+}), resources {} -> {}, toc entries {} -> {}",
+        report.document_id,
+        report.previous_parser_version,
+        report.parser_version,
+        report.before.chapters,
+        report.after.chapters,
+        report.chapter_diff,
+        report.before.resources,
+        report.after.resources,
+        report.before.toc_entries,
+        report.after.toc_entries,
+    );
+}
+
+fn export(args: &[String], store: &dyn DocumentStore) -> io::Result<()> {
+    let [path] = args else {
+        return Err(usage_error("export needs the archive file to write"));
+    };
+
+    let manifest = store
+        .export_library(&mut File::create(path)?, None)
+        .map_err(io::Error::other)?;
+
+    println!(
+        "Exported {} documents and {} original files to {}",
+        manifest.documents.len(),
+        manifest.originals.len(),
+        path
+    );
+    Ok(())
+}
+
+fn import(args: &[String], store: &dyn DocumentStore) -> io::Result<()> {
+    let [path] = args else {
+        return Err(usage_error("import needs the archive file to read"));
+    };
+
+    let imported = store
+        .import_library(File::open(path)?, None, None)
+        .map_err(io::Error::other)?;
+
+    for document in &imported {
+        println!(
+            "document {} -> {}: {}",
+            document.previous_id, document.document_id, document.title
+        );
+    }
+    println!("Imported {} documents", imported.len());
+    Ok(())
+}
+
+/// Add an admin account, for the first login on a new server
+///
+/// The first user also gets every document and annotation stored before accounts existed,
+/// and the reading progress saved without a user.
+fn create_admin(args: &[String], store: &dyn DocumentStore) -> io::Result<()> {
+    let [username] = args else {
+        return Err(usage_error("create-admin needs the user name"));
+    };
+    auth::check_username(username).map_err(|message| usage_error(&message))?;
+
+    let password = match std::env::var("EPUB_ADMIN_PASSWORD") {
+        Ok(password) => password,
+        Err(_) => {
+            eprintln!("Password for {}:", username);
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    auth::check_password(&password).map_err(|message| usage_error(&message))?;
+
+    let first = store.list_users().map_err(io::Error::other)?.is_empty();
+    let hash = auth::hash_password(&password).map_err(io::Error::other)?;
+    let user = store
+        .create_user(username, &hash, true)
+        .map_err(|e| match e {
+            StoreError::Conflict(message) => io::Error::new(io::ErrorKind::AlreadyExists, message),
+            e => io::Error::other(e),
+        })?;
+    println!("Created admin {} (id {})", user.username, user.id);
+
+    if first {
+        let claimed = store.claim_unowned(&user).map_err(io::Error::other)?;
+        println!(
+            "{} existing documents now belong to {}",
+            claimed, user.username
+        );
+    }
+
+    let (token, token_hash) = auth::generate_token();
+    store
+        .create_token(
+            user.id,
+            "create-admin",
+            &[Scope::Read, Scope::Upload, Scope::Admin],
+            &token_hash,
+        )
+        .map_err(io::Error::other)?;
+    println!("API token, shown only once:\n{}", token);
+    Ok(())
+}
@@ -1,54 +1,191 @@
 use crate::api::ApiState;
-use crate::services::tts::{TtsConfig, TtsService};
+use crate::services::audio_cache::{self, AudioCache};
+use crate::services::audiobook::{self, AudiobookJobs};
+use crate::services::db::SqliteStore;
+use crate::services::memory_store::MemoryStore;
+use crate::services::quota::Quotas;
+use crate::services::store::DocumentStore;
+use crate::services::tts::{self, TtsConfig, TtsService};
+use crate::services::voices::{self, VoiceRegistry};
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
+use std::path::Path;
 use std::sync::Arc;
 use tracing::{error, info};
 
 mod api;
+mod cli;
 mod models;
 mod services;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    // Initialize the database
-    match services::db::init_db() {
-        Ok(_) => println!("Database initialized successfully"),
-        Err(e) => eprintln!("Failed to initialize database: {}", e),
+    // Initialize the document store, `EPUB_DATABASE=:memory:` keeps everything in memory
+    let database =
+        std::env::var("EPUB_DATABASE").unwrap_or_else(|_| services::db::DB_PATH.to_string());
+    let store: Arc<dyn DocumentStore> = if database == ":memory:" {
+        println!("Using an in-memory document store");
+        Arc::new(MemoryStore::new())
+    } else {
+        match SqliteStore::open(&database) {
+            Ok(store) => {
+                println!("Database initialized successfully");
+                Arc::new(store)
+            }
+            Err(e) => {
+                error!("Failed to initialize database: {}", e);
+                return Err(std::io::Error::other(format!(
+                    "Failed to initialize database: {}",
+                    e
+                )));
+            }
+        }
+    };
+
+    // Maintenance commands run against the document store and exit
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some((command, args)) = args.split_first() {
+        if let Err(e) = cli::run(command, args, store.as_ref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
     }
 
-    // Configure TTS service with default language (English)
-    // The actual language used will be determined from the Accept-Language header in the request
-    let config = TtsConfig::default();
+    // Find the installed voices, `EPUB_VOICES_DIR` overrides where
+    let voices_dir =
+        std::env::var("EPUB_VOICES_DIR").unwrap_or_else(|_| voices::VOICES_DIR.to_string());
+    let default_voice = std::env::var("EPUB_DEFAULT_VOICE").ok();
+    let voices = match VoiceRegistry::scan(Path::new(&voices_dir), default_voice.as_deref()) {
+        Ok(voices) => voices,
+        Err(e) => {
+            error!("Failed to read voices from {}: {}", voices_dir, e);
+            VoiceRegistry::default()
+        }
+    };
+    if voices.voices().is_empty() {
+        println!("No voices found in {}, audio is unavailable", voices_dir);
+    } else {
+        println!("Found {} voices in {}", voices.voices().len(), voices_dir);
+    }
+
+    // Configure TTS service with the default voice
+    // The actual voice used will be determined from the Accept-Language header in the request
+    let config = voices
+        .default_voice()
+        .map(TtsConfig::from_voice)
+        .unwrap_or_default();
     info!("Default language: {}", config.language);
 
+    // Keep up to `EPUB_CACHED_VOICES` models loaded between requests
+    let cached_models = match std::env::var("EPUB_CACHED_VOICES") {
+        Ok(value) => value.trim().parse().map_err(|_| {
+            std::io::Error::other(format!(
+                "EPUB_CACHED_VOICES must be a number, got {}",
+                value
+            ))
+        })?,
+        Err(_) => tts::CACHED_MODELS,
+    };
+
     // Create TTS service
-    let tts_service = match TtsService::new(config) {
+    let tts_service = match TtsService::new(config, Arc::new(voices), cached_models) {
         Ok(service) => service,
         Err(e) => {
             error!("Failed to initialize TTS service: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to initialize TTS service: {}", e),
-            ));
+            return Err(std::io::Error::other(format!(
+                "Failed to initialize TTS service: {}",
+                e
+            )));
         }
     };
 
+    // Keep synthesized chapters in `EPUB_AUDIO_CACHE_DIR`, up to `EPUB_AUDIO_CACHE_BYTES`;
+    // `EPUB_AUDIO_CACHE_BYTES=0` turns the cache off
+    let cache_bytes = match std::env::var("EPUB_AUDIO_CACHE_BYTES") {
+        Ok(value) => value.trim().parse().map_err(|_| {
+            std::io::Error::other(format!(
+                "EPUB_AUDIO_CACHE_BYTES must be a number, got {}",
+                value
+            ))
+        })?,
+        Err(_) => audio_cache::AUDIO_CACHE_BYTES,
+    };
+    let tts_service = if cache_bytes == 0 {
+        println!("Audio cache is disabled");
+        tts_service
+    } else {
+        let cache_dir = std::env::var("EPUB_AUDIO_CACHE_DIR")
+            .unwrap_or_else(|_| audio_cache::AUDIO_CACHE_DIR.to_string());
+        let cache = AudioCache::open(&cache_dir, cache_bytes).map_err(|e| {
+            std::io::Error::other(format!("Failed to open audio cache {}: {}", cache_dir, e))
+        })?;
+        println!("Caching audio in {}", cache.dir().display());
+        tts_service.with_audio_cache(Arc::new(cache))
+    };
+
+    // Load the models named in `EPUB_PRELOAD_VOICES` now instead of on their first request
+    if let Ok(names) = std::env::var("EPUB_PRELOAD_VOICES") {
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let Some(voice) = tts_service.voices().get(name) else {
+                error!("Cannot preload unknown voice {}", name);
+                continue;
+            };
+            match tts_service.preload(voice) {
+                Ok(()) => println!("Preloaded voice {}", name),
+                Err(e) => error!("Failed to preload voice {}: {}", name, e),
+            }
+        }
+    }
+
+    // Build whole-book exports in `EPUB_AUDIOBOOK_DIR`
+    let audiobook_dir = std::env::var("EPUB_AUDIOBOOK_DIR")
+        .unwrap_or_else(|_| audiobook::AUDIOBOOK_DIR.to_string());
+    let audiobooks = AudiobookJobs::open(&audiobook_dir).map_err(|e| {
+        std::io::Error::other(format!(
+            "Failed to open audiobook directory {}: {}",
+            audiobook_dir, e
+        ))
+    })?;
+    println!("Building audiobooks in {}", audiobooks.dir().display());
+
     println!("Starting server at http://127.0.0.1:8081");
-    start_server(tts_service).await
+    start_server(tts_service, audiobooks, store).await
 }
 
 /// Start the API server
-async fn start_server(tts_service: TtsService) -> std::io::Result<()> {
+async fn start_server(
+    tts_service: TtsService,
+    audiobooks: AudiobookJobs,
+    store: Arc<dyn DocumentStore>,
+) -> std::io::Result<()> {
     let bind_addr = "127.0.0.1:8081";
     info!("Starting server on {}", bind_addr);
 
+    // `EPUB_AUTH=off` opens every endpoint to everyone, for single-user servers
+    let auth_enabled = std::env::var("EPUB_AUTH").map_or(true, |value| value != "off");
+    if !auth_enabled {
+        println!("Authentication is disabled");
+    }
+
+    let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
+
     let state = web::Data::new(ApiState {
         tts_service: Arc::new(tts_service),
+        audiobooks: Arc::new(audiobooks),
+        store,
+        auth_enabled,
+        quotas,
     });
 
     HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
+            .wrap(from_fn(api::authenticate))
             .configure(api::configure_routes)
     })
     .bind(bind_addr)?
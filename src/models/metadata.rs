@@ -1,19 +1,52 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chapter {
     pub title: String,
     pub path: String,
+    /// Plain text extracted from the chapter body
     pub content: String,
+    /// Original XHTML markup of the chapter
+    pub html: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A non-chapter file packaged in the EPUB (images, stylesheets, fonts, ...)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resource {
+    pub path: String,
+    pub media_type: String,
+    #[serde(skip)]
+    pub data: Vec<u8>,
+}
+
+/// A flattened table of contents entry, in reading order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TocEntry {
+    pub title: String,
+    /// Target of the entry inside the EPUB, including any `#fragment`
+    pub href: String,
+    /// Nesting depth, starting at 0 for top-level entries
+    pub level: usize,
+    /// Index of the chapter the entry points into, if it could be resolved
+    pub chapter_index: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EpubMetadata {
     pub title: String,
     pub author: String,
     pub publication_date: Option<String>,
     pub language: Option<String>,
     pub description: Option<String>,
+    /// Name of the series the book belongs to
+    #[serde(default)]
+    pub series: Option<String>,
+    /// Position of the book in its series; fractional for in-between installments
+    #[serde(default)]
+    pub series_index: Option<f64>,
+    /// Subjects the book is filed under (`dc:subject`), used as tags
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 impl EpubMetadata {
@@ -30,6 +63,9 @@ impl EpubMetadata {
             publication_date,
             language,
             description,
+            series: None,
+            series_index: None,
+            tags: Vec::new(),
         }
     }
 }
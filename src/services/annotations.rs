@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+/// Characters of text kept on each side of an annotation to find it again
+const CONTEXT_LENGTH: usize = 32;
+
+/// How well an occurrence matches an anchor: context score, same chapter, closeness to the
+/// old offset
+type Rank = (usize, bool, Reverse<usize>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnotationKind {
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+impl AnnotationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnotationKind::Highlight => "highlight",
+            AnnotationKind::Note => "note",
+            AnnotationKind::Bookmark => "bookmark",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "highlight" => Some(AnnotationKind::Highlight),
+            "note" => Some(AnnotationKind::Note),
+            "bookmark" => Some(AnnotationKind::Bookmark),
+            _ => None,
+        }
+    }
+}
+
+/// Where an annotation is in a chapter's plain text
+///
+/// Offsets are in characters. The quoted text and some context on each side are kept so
+/// the annotation can be found again when re-parsing changes the text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextAnchor {
+    pub chapter_index: usize,
+    pub start_offset: usize,
+    pub end_offset: usize,
+    /// The annotated text; empty for bookmarks
+    pub quote: String,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+/// A highlight, note or bookmark in a document
+#[derive(Debug, Clone, Serialize)]
+pub struct Annotation {
+    pub id: i64,
+    pub document_id: i64,
+    /// The user who made it, `None` for annotations made without authentication
+    #[serde(skip)]
+    pub user_id: Option<i64>,
+    pub kind: AnnotationKind,
+    #[serde(flatten)]
+    pub anchor: TextAnchor,
+    pub note: Option<String>,
+    pub color: Option<String>,
+    /// The quoted text could not be found after the document was re-parsed; the offsets
+    /// are where it used to be
+    pub detached: bool,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// A new annotation, already anchored to the text
+#[derive(Debug)]
+pub struct NewAnnotation {
+    pub user_id: Option<i64>,
+    pub kind: AnnotationKind,
+    pub anchor: TextAnchor,
+    pub note: Option<String>,
+    pub color: Option<String>,
+}
+
+/// Changes to an annotation; fields that are `None` are left as they are, `Some(None)`
+/// clears them
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnnotationUpdate {
+    #[serde(default, deserialize_with = "crate::services::store::nullable")]
+    pub note: Option<Option<String>>,
+    #[serde(default, deserialize_with = "crate::services::store::nullable")]
+    pub color: Option<Option<String>>,
+}
+
+/// Anchor the characters `start..end` of a chapter's text
+pub fn anchor(
+    text: &str,
+    chapter_index: usize,
+    start: usize,
+    end: usize,
+) -> Result<TextAnchor, String> {
+    let chars: Vec<char> = text.chars().collect();
+    if start > end {
+        return Err("start_offset must not be after end_offset".to_string());
+    }
+    if end > chars.len() {
+        return Err(format!(
+            "end_offset is past the end of the chapter ({} characters)",
+            chars.len()
+        ));
+    }
+
+    Ok(TextAnchor {
+        chapter_index,
+        start_offset: start,
+        end_offset: end,
+        quote: chars[start..end].iter().collect(),
+        prefix: chars[start.saturating_sub(CONTEXT_LENGTH)..start]
+            .iter()
+            .collect(),
+        suffix: chars[end..(end + CONTEXT_LENGTH).min(chars.len())]
+            .iter()
+            .collect(),
+    })
+}
+
+/// Find an anchor in a new version of the chapter texts
+///
+/// Looks for the quoted text, or for a bookmark its context, in every chapter. When it
+/// occurs more than once, the occurrence with the most matching context wins, preferring
+/// the old chapter and the occurrence closest to the old offset. Returns `None` if the
+/// text is gone.
+pub fn locate(chapters: &[&str], anchor: &TextAnchor) -> Option<TextAnchor> {
+    let (needle, lead) = if anchor.quote.is_empty() {
+        (
+            format!("{}{}", anchor.prefix, anchor.suffix),
+            anchor.prefix.chars().count(),
+        )
+    } else {
+        (anchor.quote.clone(), 0)
+    };
+    let needle_length = needle.chars().count();
+    let quote_length = anchor.quote.chars().count();
+
+    let mut best: Option<(Rank, TextAnchor)> = None;
+    for (chapter_index, text) in chapters.iter().enumerate() {
+        if needle.is_empty() {
+            // A bookmark in an empty chapter stays at its start
+            if chapter_index == anchor.chapter_index {
+                return Some(TextAnchor {
+                    start_offset: 0,
+                    end_offset: 0,
+                    ..anchor.clone()
+                });
+            }
+            continue;
+        }
+
+        for (byte_start, _) in text.match_indices(needle.as_str()) {
+            let char_start = text[..byte_start].chars().count();
+            let start = char_start + lead;
+            let end = start + quote_length;
+
+            let before = &text[..byte_start];
+            let after_byte = byte_start + needle.len();
+            let after = &text[after_byte..];
+            let score = if anchor.quote.is_empty() {
+                needle_length
+            } else {
+                common_suffix(before, &anchor.prefix) + common_prefix(after, &anchor.suffix)
+            };
+            let rank = (
+                score,
+                chapter_index == anchor.chapter_index,
+                Reverse(start.abs_diff(anchor.start_offset)),
+            );
+
+            if best.as_ref().is_none_or(|(best_rank, _)| rank > *best_rank) {
+                let (prefix, suffix) = context(text, char_start + lead, end);
+                best = Some((
+                    rank,
+                    TextAnchor {
+                        chapter_index,
+                        start_offset: start,
+                        end_offset: end,
+                        quote: anchor.quote.clone(),
+                        prefix,
+                        suffix,
+                    },
+                ));
+            }
+        }
+    }
+
+    best.map(|(_, anchor)| anchor)
+}
+
+/// Text around `start..end`, as stored in an anchor
+fn context(text: &str, start: usize, end: usize) -> (String, String) {
+    let prefix = text
+        .chars()
+        .skip(start.saturating_sub(CONTEXT_LENGTH))
+        .take(start.min(CONTEXT_LENGTH))
+        .collect();
+    let suffix = text.chars().skip(end).take(CONTEXT_LENGTH).collect();
+    (prefix, suffix)
+}
+
+/// Number of characters at the end of `text` that match the end of `context`
+fn common_suffix(text: &str, context: &str) -> usize {
+    text.chars()
+        .rev()
+        .zip(context.chars().rev())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Number of characters at the start of `text` that match the start of `context`
+fn common_prefix(text: &str, context: &str) -> usize {
+    text.chars()
+        .zip(context.chars())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Annotations of a book as Markdown, grouped by chapter in reading order
+///
+/// `chapter_titles` are the titles of the document's chapters by index.
+pub fn to_markdown(
+    title: &str,
+    author: &str,
+    chapter_titles: &[String],
+    annotations: &[Annotation],
+) -> String {
+    let mut markdown = format!("# {}\n\n*{}*\n", title, author);
+
+    let mut current_chapter = None;
+    for annotation in annotations {
+        let chapter_index = annotation.anchor.chapter_index;
+        if current_chapter != Some(chapter_index) {
+            let chapter_title = chapter_titles
+                .get(chapter_index)
+                .filter(|title| !title.trim().is_empty())
+                .cloned()
+                .unwrap_or_else(|| format!("Chapter {}", chapter_index + 1));
+            let _ = write!(markdown, "\n## {}\n", chapter_title);
+            current_chapter = Some(chapter_index);
+        }
+
+        markdown.push('\n');
+        match annotation.kind {
+            AnnotationKind::Bookmark => {
+                let _ = writeln!(
+                    markdown,
+                    "- Bookmark: …{}",
+                    collapse_whitespace(&annotation.anchor.suffix)
+                );
+            }
+            AnnotationKind::Highlight | AnnotationKind::Note => {
+                if !annotation.anchor.quote.is_empty() {
+                    let _ = writeln!(
+                        markdown,
+                        "> {}",
+                        collapse_whitespace(&annotation.anchor.quote)
+                    );
+                }
+            }
+        }
+        if let Some(note) = &annotation.note {
+            let _ = writeln!(markdown, "\n{}", note.trim());
+        }
+        if annotation.detached {
+            let _ = writeln!(
+                markdown,
+                "\n*This passage could not be found after the book was re-parsed.*"
+            );
+        }
+    }
+
+    markdown
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAPTER: &str = "Call me Ishmael. Some years ago, never mind how long precisely, \
+        having little or no money in my purse, I thought I would sail about a little.";
+
+    #[test]
+    fn test_anchor_keeps_quote_and_context() {
+        let anchor = anchor(CHAPTER, 0, 8, 15).unwrap();
+        assert_eq!(anchor.quote, "Ishmael");
+        assert_eq!(anchor.prefix, "Call me ");
+        assert!(anchor.suffix.starts_with(". Some years"));
+
+        assert!(super::anchor(CHAPTER, 0, 10, 5).is_err());
+        assert!(super::anchor(CHAPTER, 0, 0, 10_000).is_err());
+    }
+
+    #[test]
+    fn test_locate_follows_moved_text() {
+        let old = anchor(CHAPTER, 1, 39, 63).unwrap();
+        assert_eq!(old.quote, "mind how long precisely,");
+
+        // Re-parsing split off a title page, moving the text to the next chapter
+        let moved = format!("CHAPTER 1. Loomings. {}", CHAPTER);
+        let located = locate(&["Title page", &moved], &old).unwrap();
+        assert_eq!(located.chapter_index, 1);
+        assert_eq!(located.start_offset, 39 + 21);
+        assert_eq!(located.quote, old.quote);
+
+        assert!(locate(&["Something else entirely"], &old).is_none());
+    }
+
+    #[test]
+    fn test_locate_prefers_occurrence_with_matching_context() {
+        let text = "a little way. I would sail about a little and see the watery part";
+        let old = anchor(text, 0, 35, 41).unwrap();
+        assert_eq!(old.quote, "little");
+
+        let located = locate(&[text], &old).unwrap();
+        assert_eq!(located.start_offset, 35);
+
+        // Bookmarks are found by their context
+        let bookmark = anchor(text, 0, 14, 14).unwrap();
+        let edited = format!("Preface. {}", text);
+        assert_eq!(locate(&[&edited], &bookmark).unwrap().start_offset, 23);
+    }
+
+    #[test]
+    fn test_markdown_groups_by_chapter() {
+        let annotation = |id, kind, chapter_index, start, end, note: Option<&str>| Annotation {
+            id,
+            document_id: 1,
+            user_id: None,
+            kind,
+            anchor: anchor(CHAPTER, chapter_index, start, end).unwrap(),
+            note: note.map(str::to_string),
+            color: None,
+            detached: false,
+            created_at: 0,
+            updated_at: 0,
+        };
+        let annotations = vec![
+            annotation(1, AnnotationKind::Highlight, 0, 0, 16, None),
+            annotation(2, AnnotationKind::Note, 0, 8, 15, Some("The narrator")),
+            annotation(3, AnnotationKind::Bookmark, 2, 17, 17, None),
+        ];
+        let titles = vec!["Loomings".to_string(), "".to_string(), " ".to_string()];
+
+        let markdown = to_markdown("Moby-Dick", "Herman Melville", &titles, &annotations);
+        assert_eq!(
+            markdown,
+            "# Moby-Dick\n\n*Herman Melville*\n\n## Loomings\n\n> Call me Ishmael.\n\n\
+             > Ishmael\n\nThe narrator\n\n## Chapter 3\n\n\
+             - Bookmark: …Some years ago, never mind how l\n"
+        );
+    }
+}
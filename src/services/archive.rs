@@ -0,0 +1,897 @@
+use crate::models::metadata::EpubMetadata;
+use crate::services::audio_cache::AudioCache;
+use crate::services::db;
+use crate::services::epub_parser::PARSER_VERSION;
+use crate::services::koreader;
+use crate::services::migrations::{self, MIGRATIONS};
+use crate::services::store::{DocumentStore, StoreError};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::warn;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Version of the archive layout; bump it when the layout changes incompatibly
+pub const ARCHIVE_FORMAT: u32 = 1;
+
+const MANIFEST_PATH: &str = "manifest.json";
+const DATABASE_PATH: &str = "library.db";
+const ORIGINALS_DIR: &str = "originals";
+const AUDIO_DIR: &str = "audio";
+
+/// Tables with a `document_id` that belong to the library as a whole, not to one document
+const LIBRARY_TABLES: &[&str] = &["collection_documents"];
+
+/// Columns holding user ids, mapped to the accounts of the importing server by username
+const USER_COLUMNS: &[(&str, &str)] = &[
+    ("documents", "owner_id"),
+    ("annotations", "user_id"),
+    ("reading_progress", "user_id"),
+];
+
+/// Wait between backup attempts while another connection holds a lock
+const BACKUP_RETRY: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+
+    #[error("Invalid archive: {0}")]
+    Invalid(String),
+
+    #[error("{0}")]
+    Store(#[from] StoreError),
+}
+
+impl ArchiveError {
+    /// Whether the store cannot export or import at all
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self, ArchiveError::Store(StoreError::Unsupported(_)))
+    }
+
+    /// Whether the archive itself is at fault rather than the server
+    pub fn is_invalid_archive(&self) -> bool {
+        matches!(
+            self,
+            ArchiveError::Zip(_) | ArchiveError::Manifest(_) | ArchiveError::Invalid(_)
+        )
+    }
+}
+
+/// Describes the contents of an archive; also meant for people and other tools
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    /// Seconds since the Unix epoch
+    pub exported_at: u64,
+    /// Schema version of the database copy
+    pub schema_version: i64,
+    pub parser_version: i64,
+    pub documents: Vec<ManifestDocument>,
+    pub originals: Vec<ManifestOriginal>,
+    /// Accounts that own documents or annotations; the accounts themselves are not exported
+    #[serde(default)]
+    pub users: Vec<ManifestUser>,
+    /// Cached audio, when the export asked for it
+    #[serde(default)]
+    pub audio: Vec<ManifestAudio>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestDocument {
+    pub id: i64,
+    #[serde(flatten)]
+    pub metadata: EpubMetadata,
+    pub format: String,
+    pub parser_version: i64,
+    pub original_sha256: Option<String>,
+    pub original_filename: Option<String>,
+    /// Username of the owner, none for documents everyone can read
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub shared: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestUser {
+    pub id: i64,
+    pub username: String,
+}
+
+/// A chapter from the audio cache, see `services::audio_cache`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestAudio {
+    pub document_id: i64,
+    /// The cache key, which stays valid for the same chapter text under another id
+    pub key: String,
+    /// Of the file, which tells its format
+    pub extension: String,
+    pub size: u64,
+    /// Location of the file inside the archive
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestOriginal {
+    pub sha256: String,
+    pub media_type: String,
+    pub size: u64,
+    /// Location of the file inside the archive
+    pub path: String,
+}
+
+/// A document recreated by an import
+#[derive(Debug, Serialize)]
+pub struct ImportedDocument {
+    /// Id of the document in the exported library
+    pub previous_id: i64,
+    pub document_id: i64,
+    pub title: String,
+}
+
+/// Write the whole library as a zip archive
+///
+/// The archive holds `manifest.json` with the metadata of every document, each original
+/// file under `originals/`, and `library.db`, a copy of the database made with the SQLite
+/// online backup API so it is consistent even while the server keeps writing. The original
+/// files are left out of the database copy, they are already in the archive. With an
+/// `audio` cache, the audio cached for each document goes under `audio/` as well.
+pub fn export_library<W: Write + Seek>(
+    conn: &Connection,
+    out: W,
+    audio: Option<&AudioCache>,
+) -> Result<Manifest, ArchiveError> {
+    let snapshot_file = tempfile::NamedTempFile::new()?;
+    let mut snapshot = Connection::open(snapshot_file.path())?;
+    backup(conn, &mut snapshot)?;
+
+    let mut zip = ZipWriter::new(out);
+    // EPUBs are zip files already
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let mut originals = Vec::new();
+    {
+        let mut stmt =
+            snapshot.prepare("SELECT sha256, media_type, data FROM originals ORDER BY sha256")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let sha256: String = row.get(0)?;
+            let media_type: String = row.get(1)?;
+            let data: Vec<u8> = row.get(2)?;
+
+            let path = format!(
+                "{}/{}.{}",
+                ORIGINALS_DIR,
+                sha256,
+                extension_for(&media_type)
+            );
+            zip.start_file(path.as_str(), stored)?;
+            zip.write_all(&data)?;
+
+            originals.push(ManifestOriginal {
+                sha256,
+                media_type,
+                size: data.len() as u64,
+                path,
+            });
+        }
+    }
+
+    let mut documents = Vec::new();
+    let mut audio_files = Vec::new();
+    for id in db::document_ids(&snapshot, None)? {
+        for cached in audio
+            .map(|cache| cache.document_audio(id))
+            .unwrap_or_default()
+        {
+            let extension = cached
+                .path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or_default()
+                .to_string();
+            let path = format!("{}/{}/{}.{}", AUDIO_DIR, id, cached.key, extension);
+            // Evicted since it was listed
+            let Ok(mut file) = File::open(&cached.path) else {
+                continue;
+            };
+            zip.start_file(path.as_str(), deflated)?;
+            let size = io::copy(&mut file, &mut zip)?;
+
+            audio_files.push(ManifestAudio {
+                document_id: id,
+                key: cached.key,
+                extension,
+                size,
+                path,
+            });
+        }
+
+        let document = db::get_document(&snapshot, id)?;
+        let (format, parser_version, original_sha256, original_filename, owner, shared) = snapshot
+            .query_row(
+                "SELECT d.format, d.parser_version, d.original_sha256, d.original_filename,
+                        u.username, d.shared
+                 FROM documents d LEFT JOIN users u ON u.id = d.owner_id
+                 WHERE d.id = ?1",
+                params![id],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                },
+            )?;
+        documents.push(ManifestDocument {
+            id,
+            metadata: document.metadata,
+            format,
+            parser_version,
+            original_sha256,
+            original_filename,
+            owner,
+            shared,
+        });
+    }
+
+    let users = {
+        let mut stmt = snapshot.prepare("SELECT id, username FROM users ORDER BY id")?;
+        let users = stmt
+            .query_map([], |row| {
+                Ok(ManifestUser {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        users
+    };
+
+    let schema_version: i64 = snapshot.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    // Documents keep the hashes of their originals, the files are found through the manifest.
+    // Accounts, KOReader's included, belong to the server, not the library; owners keep
+    // their ids in the copy and the manifest maps them to usernames.
+    snapshot.pragma_update(None, "foreign_keys", "OFF")?;
+    snapshot.execute_batch(
+        "DELETE FROM originals;
+        DELETE FROM koreader_progress;
+        DELETE FROM koreader_users;
+        DELETE FROM api_tokens;
+        DELETE FROM audio_usage;
+        DELETE FROM users;
+        VACUUM;",
+    )?;
+    drop(snapshot);
+
+    zip.start_file(DATABASE_PATH, deflated)?;
+    io::copy(&mut File::open(snapshot_file.path())?, &mut zip)?;
+
+    let manifest = Manifest {
+        format: ARCHIVE_FORMAT,
+        exported_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default(),
+        schema_version,
+        parser_version: PARSER_VERSION,
+        documents,
+        originals,
+        users,
+        audio: audio_files,
+    };
+    zip.start_file(MANIFEST_PATH, deflated)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest)?;
+
+    zip.finish()?;
+    Ok(manifest)
+}
+
+/// Export the library into an anonymous temporary file, rewound for reading
+pub fn export_to_tempfile(
+    store: &dyn DocumentStore,
+    audio: Option<&AudioCache>,
+) -> Result<File, ArchiveError> {
+    let mut file = tempfile::tempfile()?;
+    store.export_library(&mut file, audio)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Copy the whole database in one backup step, so the copy is a single consistent snapshot
+fn backup(from: &Connection, to: &mut Connection) -> Result<(), ArchiveError> {
+    let backup = Backup::new(from, to)?;
+    loop {
+        match backup.step(-1)? {
+            StepResult::Done => return Ok(()),
+            // Only Busy and Locked are expected, a step of -1 pages never stops halfway
+            _ => thread::sleep(BACKUP_RETRY),
+        }
+    }
+}
+
+fn extension_for(media_type: &str) -> &'static str {
+    match media_type {
+        "application/epub+zip" => "epub",
+        _ => "bin",
+    }
+}
+
+/// Add every document of an archive made by [`export_library`] to the library
+///
+/// Documents get new ids, and everything stored for them is copied over. Archives from
+/// older versions are migrated to the current schema first; archives from newer versions
+/// are refused. Collections are merged with those of the same name.
+///
+/// Documents and annotations go to the account with the owner's username. Those whose
+/// owner has no account here go to `importer`, or to the first admin without one, so
+/// private documents never become public; with neither, the import is refused.
+///
+/// Audio in the archive is added to the `audio` cache; chapters that cannot be added are
+/// logged and skipped, the documents are imported by then.
+pub fn import_library<R: Read + Seek>(
+    conn: &mut Connection,
+    archive: R,
+    importer: Option<i64>,
+    audio: Option<&Arc<AudioCache>>,
+) -> Result<Vec<ImportedDocument>, ArchiveError> {
+    let mut zip = ZipArchive::new(archive)?;
+
+    let manifest_file = zip.by_name(MANIFEST_PATH).map_err(|_| {
+        ArchiveError::Invalid(format!("{} not found, not a library export", MANIFEST_PATH))
+    })?;
+    let manifest: Manifest = serde_json::from_reader(manifest_file)?;
+    if manifest.format != ARCHIVE_FORMAT {
+        return Err(ArchiveError::Invalid(format!(
+            "unsupported archive format {}",
+            manifest.format
+        )));
+    }
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or_default();
+    if manifest.schema_version > latest {
+        return Err(ArchiveError::Invalid(format!(
+            "archive schema version {} is newer than this server's {}",
+            manifest.schema_version, latest
+        )));
+    }
+
+    let source_file = tempfile::NamedTempFile::new()?;
+    io::copy(&mut zip.by_name(DATABASE_PATH)?, &mut source_file.as_file())?;
+    {
+        let mut source = Connection::open(source_file.path())?;
+        // Exports keep no accounts; put the manifest's back so migrations that match names
+        // to user ids, as reading progress once was kept, still find them
+        for user in &manifest.users {
+            source.execute(
+                "INSERT OR IGNORE INTO users (id, username, password_hash) VALUES (?1, ?2, '')",
+                params![user.id, user.username],
+            )?;
+        }
+        migrations::run(&mut source)?;
+    }
+
+    let source_path = source_file.path().to_string_lossy().to_string();
+    conn.execute("ATTACH DATABASE ?1 AS source", params![source_path])?;
+    let result = copy_library(conn, &mut zip, &manifest, importer);
+    conn.execute_batch("DETACH DATABASE source")?;
+    let imported = result?;
+
+    if let Some(cache) = audio {
+        let new_ids: HashMap<i64, i64> = imported
+            .iter()
+            .map(|document| (document.previous_id, document.document_id))
+            .collect();
+        for file in &manifest.audio {
+            let Some(&document_id) = new_ids.get(&file.document_id) else {
+                continue;
+            };
+            let added = zip
+                .by_name(&file.path)
+                .map_err(ArchiveError::from)
+                .and_then(|data| {
+                    Ok(cache.import(document_id, &file.key, &file.extension, data)?)
+                });
+            if let Err(e) = added {
+                warn!("Failed to import cached audio {}: {}", file.path, e);
+            }
+        }
+    }
+
+    Ok(imported)
+}
+
+fn copy_library<R: Read + Seek>(
+    conn: &mut Connection,
+    zip: &mut ZipArchive<R>,
+    manifest: &Manifest,
+    importer: Option<i64>,
+) -> Result<Vec<ImportedDocument>, ArchiveError> {
+    let tx = conn.transaction()?;
+    map_users(&tx, manifest, importer)?;
+
+    for original in &manifest.originals {
+        let mut data = Vec::new();
+        zip.by_name(&original.path)?.read_to_end(&mut data)?;
+        if format!("{:x}", Sha256::digest(&data)) != original.sha256 {
+            return Err(ArchiveError::Invalid(format!(
+                "{} does not match its checksum",
+                original.path
+            )));
+        }
+
+        tx.execute(
+            "INSERT OR IGNORE INTO originals (sha256, media_type, size, data, partial_md5)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                original.sha256,
+                original.media_type,
+                data.len() as i64,
+                data,
+                koreader::partial_md5(&data)
+            ],
+        )?;
+    }
+
+    let document_columns = columns(&tx, "documents")?;
+    let child_tables = document_tables(&tx)?;
+
+    let previous_ids = {
+        let mut stmt = tx.prepare("SELECT id FROM source.documents ORDER BY id")?;
+        let ids = stmt
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        ids
+    };
+
+    let mut imported = Vec::new();
+    let mut new_ids = HashMap::new();
+    for previous_id in previous_ids {
+        let columns = document_columns.join(", ");
+        let values = select_list("documents", &document_columns);
+        tx.execute(
+            &format!(
+                "INSERT INTO main.documents ({columns})
+                 SELECT {values} FROM source.documents WHERE id = ?1"
+            ),
+            params![previous_id],
+        )?;
+        let document_id = tx.last_insert_rowid();
+
+        for (table, table_columns) in &child_tables {
+            let columns = table_columns.join(", ");
+            let values = select_list(table, table_columns);
+            // Users without an account here all fall to one, who keeps one of their positions
+            let insert = if table == "reading_progress" {
+                "INSERT OR IGNORE"
+            } else {
+                "INSERT"
+            };
+            tx.execute(
+                &format!(
+                    "{insert} INTO main.{table} (document_id, {columns})
+                     SELECT ?2, {values} FROM source.{table} WHERE document_id = ?1"
+                ),
+                params![previous_id, document_id],
+            )?;
+        }
+
+        let document = db::get_document(&tx, document_id)?;
+        db::index_chapters(&tx, document_id, document.metadata.language.as_deref())?;
+
+        new_ids.insert(previous_id, document_id);
+        imported.push(ImportedDocument {
+            previous_id,
+            document_id,
+            title: document.metadata.title,
+        });
+    }
+
+    copy_collections(&tx, &new_ids)?;
+
+    tx.execute_batch("DROP TABLE temp.imported_users")?;
+    tx.commit()?;
+    Ok(imported)
+}
+
+/// Fill `temp.imported_users` with the account here of every user id in the archive
+///
+/// Users without an account here fall to the importer, or to the first admin. With neither,
+/// the import is refused: an ownerless document would be public.
+fn map_users(
+    conn: &Connection,
+    manifest: &Manifest,
+    importer: Option<i64>,
+) -> Result<(), ArchiveError> {
+    let fallback = match importer {
+        Some(id) => Some(id),
+        None => conn
+            .query_row(
+                "SELECT id FROM main.users WHERE is_admin = 1 ORDER BY id LIMIT 1",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?,
+    };
+
+    conn.execute_batch(
+        "CREATE TEMP TABLE imported_users (previous_id INTEGER PRIMARY KEY, user_id INTEGER)",
+    )?;
+    for user in &manifest.users {
+        let user_id = conn
+            .query_row(
+                "SELECT id FROM main.users WHERE username = ?1",
+                params![user.username],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+            .or(fallback)
+            .ok_or_else(|| {
+                ArchiveError::Invalid(format!(
+                    "no account here for {} and no admin to take over their documents",
+                    user.username
+                ))
+            })?;
+        conn.execute(
+            "INSERT INTO temp.imported_users (previous_id, user_id) VALUES (?1, ?2)",
+            params![user.id, user_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Columns to select from a source table, with user ids mapped through `imported_users`
+fn select_list(table: &str, columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|column| {
+            if USER_COLUMNS.contains(&(table, column.as_str())) {
+                format!(
+                    "(SELECT user_id FROM temp.imported_users
+                      WHERE previous_id = source.{table}.{column})"
+                )
+            } else {
+                column.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Add the archive's collections, appending imported documents to existing collections
+/// of the same name
+fn copy_collections(conn: &Connection, new_ids: &HashMap<i64, i64>) -> rusqlite::Result<()> {
+    let collections = {
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.name, c.description, u.user_id
+             FROM source.collections c
+             LEFT JOIN temp.imported_users u ON u.previous_id = c.owner_id
+             ORDER BY c.id",
+        )?;
+        let collections = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        collections
+    };
+
+    for (previous_id, name, description, owner_id) in collections {
+        let existing = conn
+            .query_row(
+                "SELECT id FROM main.collections WHERE name = ?1",
+                params![name],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+        let collection_id = match existing {
+            Some(id) => id,
+            None => {
+                conn.execute(
+                    "INSERT INTO main.collections (name, description, owner_id)
+                     VALUES (?1, ?2, ?3)",
+                    params![name, description, owner_id],
+                )?;
+                conn.last_insert_rowid()
+            }
+        };
+
+        let mut position: i64 = conn.query_row(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM main.collection_documents
+             WHERE collection_id = ?1",
+            params![collection_id],
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(
+            "SELECT document_id FROM source.collection_documents
+             WHERE collection_id = ?1 ORDER BY position, document_id",
+        )?;
+        let members = stmt
+            .query_map(params![previous_id], |row| row.get::<_, i64>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for previous_document_id in members {
+            let Some(document_id) = new_ids.get(&previous_document_id) else {
+                continue;
+            };
+            conn.execute(
+                "INSERT OR IGNORE INTO main.collection_documents
+                    (collection_id, document_id, position)
+                 VALUES (?1, ?2, ?3)",
+                params![collection_id, document_id, position],
+            )?;
+            position += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// Columns of a table, without its `id` and `document_id`
+fn columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM pragma_table_info(?1)
+         WHERE name NOT IN ('id', 'document_id') ORDER BY cid",
+    )?;
+    let columns = stmt
+        .query_map(params![table], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(columns)
+}
+
+/// Tables holding rows that belong to a document, with the columns to copy
+fn document_tables(conn: &Connection) -> rusqlite::Result<Vec<(String, Vec<String>)>> {
+    let tables = {
+        let mut stmt = conn.prepare(
+            "SELECT m.name FROM main.sqlite_master m
+             WHERE m.type = 'table' AND m.sql NOT LIKE 'CREATE VIRTUAL TABLE%'
+               AND EXISTS (SELECT 1 FROM pragma_table_info(m.name) WHERE name = 'document_id')
+             ORDER BY m.name",
+        )?;
+        let tables = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        tables
+    };
+
+    tables
+        .into_iter()
+        .filter(|table| !LIBRARY_TABLES.contains(&table.as_str()))
+        .map(|table| {
+            let columns = columns(conn, &table)?;
+            Ok((table, columns))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::annotations::{self, AnnotationKind, NewAnnotation};
+    use crate::services::auth::User;
+    use crate::services::epub_parser;
+    use crate::services::search::Query;
+    use crate::services::store::{DocumentAccess, OriginalFile, Progress};
+    use std::fs;
+    use std::io::Cursor;
+
+    fn library() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::configure_connection(&mut conn).unwrap();
+        migrations::run(&mut conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_export_and_import_remaps_ids() {
+        let source = library();
+        let data = fs::read("moby-dick.epub").unwrap();
+        let content = epub_parser::parse_epub(&data).unwrap();
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: data.clone(),
+        };
+        let exported_id = db::save_document(&source, &content, Some(&original)).unwrap();
+        let course = db::create_collection(&source, "Whaling 101", None, None).unwrap();
+        db::add_to_collection(&source, course.id, exported_id, None).unwrap();
+
+        let mut archive = Cursor::new(Vec::new());
+        let manifest = export_library(&source, &mut archive, None).unwrap();
+        assert_eq!(manifest.documents.len(), 1);
+        assert_eq!(manifest.originals[0].size, data.len() as u64);
+
+        // The target library already has a document with the exported id
+        let mut target = library();
+        let existing_id = db::save_document(&target, &content, None).unwrap();
+        let existing = db::create_collection(&target, "whaling 101", None, None).unwrap();
+        db::add_to_collection(&target, existing.id, existing_id, None).unwrap();
+
+        archive.set_position(0);
+        let imported = import_library(&mut target, archive, None, None).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].previous_id, exported_id);
+        let id = imported[0].document_id;
+        assert_ne!(id, exported_id);
+
+        assert_eq!(db::get_original(&target, id).unwrap().data, data);
+        assert_eq!(
+            db::content_counts(&target, id).unwrap(),
+            db::content_counts(&source, exported_id).unwrap()
+        );
+        // Both copies in the target are searchable
+        let query = Query::parse("Queequeg");
+        let (in_source, _) = db::search_chapters(&source, &query, None, None, 10, 0).unwrap();
+        let (in_target, _) = db::search_chapters(&target, &query, None, None, 10, 0).unwrap();
+        assert!(in_source > 0);
+        assert_eq!(in_target, 2 * in_source);
+
+        // The collection is merged into the one of the same name
+        assert_eq!(db::list_collections(&target, None).unwrap().len(), 1);
+        let members: Vec<i64> = db::collection_documents(&target, existing.id)
+            .unwrap()
+            .iter()
+            .map(|document| document.id)
+            .collect();
+        assert_eq!(members, vec![existing_id, id]);
+    }
+
+    #[test]
+    fn test_import_keeps_owners() {
+        let source = library();
+        let content = epub_parser::parse_epub(&fs::read("moby-dick.epub").unwrap()).unwrap();
+        let admin = db::create_user(&source, "admin", "hash", true).unwrap();
+        let alice = db::create_user(&source, "alice", "hash", false).unwrap();
+        let bob = db::create_user(&source, "bob", "hash", false).unwrap();
+        let owned_by = |user: &User, shared| {
+            let id = db::save_document(&source, &content, None).unwrap();
+            let access = DocumentAccess {
+                owner_id: Some(user.id),
+                shared,
+            };
+            db::set_document_access(&source, id, &access).unwrap();
+            id
+        };
+        let alices = owned_by(&alice, false);
+        let bobs = owned_by(&bob, true);
+        let chapters = db::get_chapters(&source, alices).unwrap();
+        let note = NewAnnotation {
+            user_id: Some(alice.id),
+            kind: AnnotationKind::Highlight,
+            anchor: annotations::anchor(&chapters[1].text, 1, 0, 4).unwrap(),
+            note: None,
+            color: None,
+        };
+        db::create_annotation(&source, alices, &note).unwrap();
+        for (user_id, chapter_index) in
+            [(Some(alice.id), 1), (Some(bob.id), 2), (Some(admin.id), 3)]
+        {
+            let progress = Progress {
+                user_id,
+                document_id: alices,
+                chapter_index,
+                char_offset: 0,
+                audio_position: None,
+                percentage: 0.1,
+                device: None,
+                updated_at: 1000,
+            };
+            db::save_progress(&source, &progress).unwrap();
+        }
+
+        let mut archive = Cursor::new(Vec::new());
+        let manifest = export_library(&source, &mut archive, None).unwrap();
+        assert_eq!(manifest.documents[0].owner.as_deref(), Some("alice"));
+
+        // Alice has bob's id and a differently cased username here, bob has no account
+        let mut target = library();
+        let importer = db::create_user(&target, "admin", "hash", true).unwrap();
+        db::create_user(&target, "carol", "hash", false).unwrap();
+        let target_alice = db::create_user(&target, "Alice", "hash", false).unwrap();
+        assert_eq!(target_alice.id, bob.id);
+
+        archive.set_position(0);
+        let imported =
+            import_library(&mut target, archive.clone(), Some(importer.id), None).unwrap();
+        let new_id = |previous_id| {
+            imported
+                .iter()
+                .find(|document| document.previous_id == previous_id)
+                .unwrap()
+                .document_id
+        };
+
+        let access = db::document_access(&target, new_id(alices)).unwrap();
+        assert_eq!(access.owner_id, Some(target_alice.id));
+        assert!(!access.shared);
+        let notes = db::list_annotations(&target, new_id(alices)).unwrap();
+        assert_eq!(notes[0].user_id, Some(target_alice.id));
+        let progress = db::get_progress(&target, Some(target_alice.id), new_id(alices)).unwrap();
+        assert_eq!(progress.chapter_index, 1);
+        // Bob's and the admin's progress both fall to the importer, who keeps one
+        assert_eq!(
+            db::list_progress(&target, Some(importer.id)).unwrap().len(),
+            1
+        );
+
+        let access = db::document_access(&target, new_id(bobs)).unwrap();
+        assert_eq!(access.owner_id, Some(importer.id));
+        assert!(access.shared);
+
+        // Without an importer or an admin, alice's private book would be left ownerless
+        let mut target = library();
+        db::create_user(&target, "alice", "hash", false).unwrap();
+        archive.set_position(0);
+        let error = import_library(&mut target, archive, None, None).unwrap_err();
+        assert!(error.is_invalid_archive());
+        assert!(db::document_ids(&target, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export_and_import_bundle_audio() {
+        let source = library();
+        let content = epub_parser::parse_epub(&fs::read("moby-dick.epub").unwrap()).unwrap();
+        let exported_id = db::save_document(&source, &content, None).unwrap();
+        let source_dir = tempfile::tempdir().unwrap();
+        let source_cache = Arc::new(AudioCache::open(source_dir.path(), 1 << 20).unwrap());
+        source_cache
+            .import(exported_id, "a1", "mp3", &b"ID3 chapter one"[..])
+            .unwrap();
+
+        // Audio is only bundled when asked for
+        let mut archive = Cursor::new(Vec::new());
+        let manifest = export_library(&source, &mut archive, None).unwrap();
+        assert!(manifest.audio.is_empty());
+
+        let mut archive = Cursor::new(Vec::new());
+        let manifest = export_library(&source, &mut archive, Some(&source_cache)).unwrap();
+        assert_eq!(manifest.audio.len(), 1);
+        assert_eq!(
+            manifest.audio[0].path,
+            format!("audio/{}/a1.mp3", exported_id)
+        );
+
+        let mut target = library();
+        db::save_document(&target, &content, None).unwrap();
+        let target_dir = tempfile::tempdir().unwrap();
+        let target_cache = Arc::new(AudioCache::open(target_dir.path(), 1 << 20).unwrap());
+        archive.set_position(0);
+        let imported = import_library(&mut target, archive, None, Some(&target_cache)).unwrap();
+        let id = imported[0].document_id;
+        assert_ne!(id, exported_id);
+
+        assert!(target_cache.get(exported_id, "a1").is_none());
+        let cached = target_cache.get(id, "a1").unwrap();
+        assert_eq!(fs::read(cached.path).unwrap(), b"ID3 chapter one");
+    }
+}
@@ -0,0 +1,547 @@
+//! Synthesized chapters kept on disk, so replaying a chapter does not synthesize it again
+//!
+//! Files are named after a hash of everything that decides the audio: the chapter's text,
+//! the voice, the speaker, the prosody and the format. They are kept in a directory per
+//! document, so deleting a document deletes its audio and a document's audio can move to
+//! another id. A chapter is written
+//! to a temporary file while it is synthesized and only enters the cache once it is
+//! complete. The cache is kept under a size limit by deleting the least recently played
+//! chapters; the order is kept in memory and rebuilt from the files' modification times
+//! when the server starts.
+
+use crate::services::audio_format::AudioFormat;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Where audio is cached unless `EPUB_AUDIO_CACHE_DIR` says otherwise
+pub const AUDIO_CACHE_DIR: &str = "./audio_cache";
+
+/// Bytes of audio kept unless `EPUB_AUDIO_CACHE_BYTES` says otherwise
+pub const AUDIO_CACHE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Changes whenever the same inputs would give different files
+const CACHE_VERSION: &str = "3";
+
+const PARTIAL_EXTENSION: &str = "part";
+
+/// A complete chapter in the cache
+#[derive(Debug, Clone)]
+pub struct CachedAudio {
+    pub path: PathBuf,
+    pub len: u64,
+    /// When the chapter was synthesized
+    pub modified: SystemTime,
+    /// The cache key, which names the audio as exactly as the file does
+    pub key: String,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Of the file, which tells its format
+    extension: String,
+    size: u64,
+    /// Larger is more recent
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    /// By document and cache key
+    entries: HashMap<(i64, String), Entry>,
+    /// Keys of the entries of each document
+    documents: HashMap<i64, HashSet<String>>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+impl Index {
+    fn touch(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Add an entry in place of any with the same key
+    fn insert(&mut self, document_id: i64, key: &str, entry: Entry) {
+        self.remove(document_id, key);
+        self.total_bytes += entry.size;
+        self.documents
+            .entry(document_id)
+            .or_default()
+            .insert(key.to_string());
+        self.entries.insert((document_id, key.to_string()), entry);
+    }
+
+    fn remove(&mut self, document_id: i64, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(&(document_id, key.to_string()))?;
+        self.total_bytes -= entry.size;
+        if let Some(keys) = self.documents.get_mut(&document_id) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.documents.remove(&document_id);
+            }
+        }
+        Some(entry)
+    }
+}
+
+#[derive(Debug)]
+pub struct AudioCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Index>,
+}
+
+/// The cache key for chapter text read with the given voice settings
+///
+/// `settings` describes the voice, speaker, prosody and format; it must change whenever
+/// they do.
+pub fn cache_key(text: &str, settings: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [CACHE_VERSION, settings, text] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Whether a file is named like cached audio, `<key>.<format>` or `<key>.part`
+fn is_cache_file(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(|stem| stem.to_str());
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let (Some(stem), Some(extension)) = (stem, extension) else {
+        return false;
+    };
+
+    !stem.is_empty()
+        && stem.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && (extension == PARTIAL_EXTENSION
+            || AudioFormat::ALL
+                .iter()
+                .any(|format| format.name() == extension))
+}
+
+impl AudioCache {
+    /// The cache in `dir`, created if needed; chapters left half written by a previous run,
+    /// and cached audio of earlier versions of the cache outside a document's directory, are
+    /// deleted. Other files in `dir` are left alone.
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let document_id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<i64>().ok());
+            let Some(document_id) = document_id.filter(|_| path.is_dir()) else {
+                if path.is_file() && is_cache_file(&path) {
+                    let _ = fs::remove_file(&path);
+                }
+                continue;
+            };
+
+            for entry in fs::read_dir(&path)? {
+                let path = entry?.path();
+                let Some(extension) = path.extension().and_then(|extension| extension.to_str())
+                else {
+                    continue;
+                };
+                if extension == PARTIAL_EXTENSION {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                let metadata = fs::metadata(&path)?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((
+                    modified,
+                    key.to_string(),
+                    document_id,
+                    extension.to_string(),
+                    metadata.len(),
+                ));
+            }
+        }
+
+        // Without a record of plays, the newest chapters count as the most recently used
+        files.sort();
+        let mut index = Index::default();
+        for (_, key, document_id, extension, size) in files {
+            let last_used = index.touch();
+            let entry = Entry {
+                extension,
+                size,
+                last_used,
+            };
+            index.insert(document_id, &key, entry);
+        }
+
+        let cache = AudioCache {
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+        };
+        cache.evict(&mut cache.index());
+        Ok(cache)
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        // The index is only changed while locked, a panic cannot leave it half changed
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn document_dir(&self, document_id: i64) -> PathBuf {
+        self.dir.join(document_id.to_string())
+    }
+
+    fn path(&self, document_id: i64, key: &str, extension: &str) -> PathBuf {
+        self.document_dir(document_id)
+            .join(format!("{}.{}", key, extension))
+    }
+
+    /// A cached chapter of a document, marking it as the most recently used
+    pub fn get(&self, document_id: i64, key: &str) -> Option<CachedAudio> {
+        let mut index = self.index();
+        let clock = index.touch();
+        let entry = index.entries.get_mut(&(document_id, key.to_string()))?;
+        entry.last_used = clock;
+
+        let path = self.path(document_id, key, &entry.extension);
+        match fs::metadata(&path) {
+            Ok(metadata) => Some(CachedAudio {
+                len: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                path,
+                key: key.to_string(),
+            }),
+            Err(e) => {
+                // Deleted behind our back
+                warn!("Cached audio {} is gone: {}", path.display(), e);
+                index.remove(document_id, key);
+                None
+            }
+        }
+    }
+
+    /// Start caching a chapter of a document, in a file with `extension`
+    pub fn writer(
+        self: &Arc<Self>,
+        document_id: i64,
+        key: &str,
+        extension: &str,
+    ) -> io::Result<CacheWriter> {
+        let dir = self.document_dir(document_id);
+        fs::create_dir_all(&dir)?;
+        let partial = dir.join(format!(
+            "{}.{}.{}",
+            key,
+            uuid::Uuid::new_v4().simple(),
+            PARTIAL_EXTENSION
+        ));
+        let file = File::create(&partial)?;
+
+        Ok(CacheWriter {
+            cache: self.clone(),
+            document_id,
+            key: key.to_string(),
+            extension: extension.to_string(),
+            file: Some(file),
+            partial,
+            len: 0,
+        })
+    }
+
+    /// Add a complete chapter, making room for it
+    fn insert(&self, document_id: i64, key: &str, extension: &str, size: u64) {
+        if size > self.max_bytes {
+            // Keeping it would take the place of everything else
+            let _ = fs::remove_file(self.path(document_id, key, extension));
+            return;
+        }
+        let mut index = self.index();
+        let last_used = index.touch();
+        let entry = Entry {
+            extension: extension.to_string(),
+            size,
+            last_used,
+        };
+        index.insert(document_id, key, entry);
+        self.evict(&mut index);
+    }
+
+    /// Delete the audio of a document, also keeping chapters still being synthesized for it
+    /// out of the cache
+    pub fn remove_document(&self, document_id: i64) -> io::Result<()> {
+        let mut index = self.index();
+        let keys = index
+            .documents
+            .get(&document_id)
+            .cloned()
+            .unwrap_or_default();
+        for key in keys {
+            index.remove(document_id, &key);
+        }
+        // Listeners still reading a file keep it until they are done
+        match fs::remove_dir_all(self.document_dir(document_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The cached chapters of a document, without marking them as used
+    pub fn document_audio(&self, document_id: i64) -> Vec<CachedAudio> {
+        let index = self.index();
+        let mut audio = Vec::new();
+        for key in index.documents.get(&document_id).into_iter().flatten() {
+            let entry = &index.entries[&(document_id, key.clone())];
+            let path = self.path(document_id, key, &entry.extension);
+            // Files deleted behind our back are left for `get` to notice
+            if let Ok(metadata) = fs::metadata(&path) {
+                audio.push(CachedAudio {
+                    len: metadata.len(),
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    path,
+                    key: key.clone(),
+                });
+            }
+        }
+        audio.sort_by(|a, b| a.key.cmp(&b.key));
+        audio
+    }
+
+    /// Add a chapter synthesized elsewhere, such as one from a library export
+    pub fn import(
+        self: &Arc<Self>,
+        document_id: i64,
+        key: &str,
+        extension: &str,
+        mut data: impl Read,
+    ) -> io::Result<()> {
+        // Both name the file, they must not reach outside the document's directory
+        let is_name =
+            |name: &str| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric());
+        if !is_name(key) || !is_name(extension) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a cached chapter: {}.{}", key, extension),
+            ));
+        }
+        let mut writer = self.writer(document_id, key, extension)?;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match data.read(&mut buffer)? {
+                0 => return writer.finish(None),
+                n => writer.write(&buffer[..n])?,
+            }
+        }
+    }
+
+    /// Delete the least recently used chapters until the cache fits its limit
+    fn evict(&self, index: &mut Index) {
+        while index.total_bytes > self.max_bytes {
+            let Some((document_id, oldest)) = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = index
+                .remove(document_id, &oldest)
+                .expect("oldest entry exists");
+            // Listeners still reading the file keep it until they are done
+            if let Err(e) = fs::remove_file(self.path(document_id, &oldest, &entry.extension)) {
+                warn!("Failed to evict cached audio {}: {}", oldest, e);
+            }
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+/// A chapter being written to the cache; dropped without [`CacheWriter::finish`], the
+/// partial file is deleted
+pub struct CacheWriter {
+    cache: Arc<AudioCache>,
+    document_id: i64,
+    key: String,
+    extension: String,
+    file: Option<File>,
+    partial: PathBuf,
+    len: u64,
+}
+
+impl CacheWriter {
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| io::Error::other("finished"))?;
+        file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Add the file to the cache, first writing `header` over its start, for headers that
+    /// tell the length of the file
+    pub fn finish(mut self, header: Option<&[u8]>) -> io::Result<()> {
+        let mut file = self
+            .file
+            .take()
+            .ok_or_else(|| io::Error::other("finished"))?;
+        if let Some(header) = header {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(header)?;
+        }
+        file.sync_all()?;
+        drop(file);
+
+        // Fails if the document was deleted meanwhile, taking its directory along
+        fs::rename(
+            &self.partial,
+            self.cache
+                .path(self.document_id, &self.key, &self.extension),
+        )?;
+        self.cache
+            .insert(self.document_id, &self.key, &self.extension, self.len);
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache_chapter(cache: &Arc<AudioCache>, key: &str, len: usize) {
+        let mut writer = cache.writer(1, key, "wav").unwrap();
+        writer.write(&vec![1; len]).unwrap();
+        writer.finish(None).unwrap();
+    }
+
+    #[test]
+    fn test_cache_keys() {
+        let key = cache_key("Call me Ishmael.", "en_US-ryan-high");
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, cache_key("Call me Ishmael.", "en_US-ryan-high"));
+        assert_ne!(key, cache_key("Call me Ishmael.", "en_US-ryan-medium"));
+        assert_ne!(key, cache_key("Call me Ishmael", "en_US-ryan-high"));
+    }
+
+    #[test]
+    fn test_chapters_are_cached_once_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(AudioCache::open(dir.path(), 1000).unwrap());
+
+        // Abandoned chapters leave nothing behind
+        let mut writer = cache.writer(1, "abandoned", "wav").unwrap();
+        writer.write(&[1, 2]).unwrap();
+        drop(writer);
+        assert!(cache.get(1, "abandoned").is_none());
+        assert_eq!(fs::read_dir(dir.path().join("1")).unwrap().count(), 0);
+
+        let mut writer = cache.writer(1, "a", "flac").unwrap();
+        writer.write(b"fLaC?").unwrap();
+        writer.write(&[1, 2, 3]).unwrap();
+        writer.finish(Some(b"fLaC!")).unwrap();
+        let cached = cache.get(1, "a").unwrap();
+        assert_eq!(cached.len, 8);
+        assert_eq!(cached.path, dir.path().join("1").join("a.flac"));
+        assert_eq!(fs::read(&cached.path).unwrap(), b"fLaC!\x01\x02\x03");
+        assert!(cache.get(1, "b").is_none());
+    }
+
+    #[test]
+    fn test_least_recently_used_chapters_are_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(AudioCache::open(dir.path(), 800).unwrap());
+
+        cache_chapter(&cache, "a", 244);
+        cache_chapter(&cache, "b", 244);
+        cache_chapter(&cache, "c", 244);
+        // Playing "a" makes "b" the least recently used
+        assert!(cache.get(1, "a").is_some());
+        cache_chapter(&cache, "d", 244);
+        assert!(cache.get(1, "b").is_none());
+        assert!(cache.get(1, "a").is_some());
+        assert!(cache.get(1, "c").is_some());
+        assert!(cache.get(1, "d").is_some());
+        assert!(!dir.path().join("1").join("b.wav").exists());
+
+        // Chapters larger than the cache are not kept
+        cache_chapter(&cache, "huge", 2044);
+        assert!(cache.get(1, "huge").is_none());
+
+        // Reopening finds the chapters again, within a smaller limit
+        let cache = AudioCache::open(dir.path(), 500).unwrap();
+        let kept = ["a", "c", "d"]
+            .iter()
+            .filter(|key| cache.get(1, key).is_some())
+            .count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn test_deleting_a_document_deletes_its_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        // Left by an earlier version of the cache, next to files it did not write
+        let old = cache_key("Call me Ishmael.", "old");
+        for name in [format!("{old}.wav"), format!("{old}.part")] {
+            fs::write(dir.path().join(name), b"RIFF").unwrap();
+        }
+        for name in ["notes.wav", "README.txt", "cafe"] {
+            fs::write(dir.path().join(name), b"keep").unwrap();
+        }
+        let cache = Arc::new(AudioCache::open(dir.path(), 1000).unwrap());
+        assert!(!dir.path().join(format!("{old}.wav")).exists());
+        assert!(!dir.path().join(format!("{old}.part")).exists());
+        for name in ["notes.wav", "README.txt", "cafe"] {
+            assert!(dir.path().join(name).exists());
+        }
+
+        for (document_id, key) in [(1, "a"), (1, "b"), (2, "c")] {
+            let mut writer = cache.writer(document_id, key, "wav").unwrap();
+            writer.write(&[1; 100]).unwrap();
+            writer.finish(None).unwrap();
+        }
+        // Still being synthesized when the document goes
+        let mut writer = cache.writer(1, "d", "wav").unwrap();
+        writer.write(&[1; 100]).unwrap();
+
+        cache.remove_document(1).unwrap();
+        assert!(cache.get(1, "a").is_none());
+        assert!(cache.get(1, "b").is_none());
+        assert!(cache.get(2, "c").is_some());
+        assert!(!dir.path().join("1").exists());
+        assert!(writer.finish(None).is_err());
+        assert!(cache.get(1, "d").is_none());
+        assert_eq!(cache.index().total_bytes, 100);
+
+        // Documents without audio have nothing to delete
+        cache.remove_document(3).unwrap();
+
+        // Imported chapters cannot name files outside their document's directory
+        assert!(cache.import(3, "../1/a", "wav", &[1][..]).is_err());
+        assert!(cache.import(3, "a", "", &[1][..]).is_err());
+    }
+}
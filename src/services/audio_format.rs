@@ -0,0 +1,667 @@
+//! Formats chapter audio is sent in, and encoders turning synthesized samples into them
+//!
+//! Piper speaks 16-bit mono samples. WAV sends them as they are; FLAC compresses them
+//! losslessly, MP3 and Ogg Opus with loss at bitrates meant for speech. Every encoder works
+//! sentence by sentence, so compressed audio streams as soon as WAV does.
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, Stream, StreamInfo};
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Fill, FrameBuf};
+use md5::{Digest, Md5};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::fmt;
+
+/// Size of the header of a WAV file
+pub const WAV_HEADER_LEN: usize = 44;
+
+/// Synthesized audio is 16-bit mono
+pub const BYTES_PER_SAMPLE: u32 = 2;
+
+/// Samples per FLAC frame
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+/// MP3 is sent at a constant bitrate, which players can seek in without an index
+const MP3_BITRATE: mp3lame_encoder::Bitrate = mp3lame_encoder::Bitrate::Kbps64;
+
+/// Bits per second of Opus audio, plenty for a single voice
+const OPUS_BITRATE: i32 = 32_000;
+
+/// The sample rates Opus encodes
+const OPUS_SAMPLE_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+/// Ogg granule positions count samples at 48 kHz whatever the rate encoded
+const OPUS_GRANULE_RATE: u32 = 48_000;
+
+/// The only logical stream in each Ogg file
+const OGG_SERIAL: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Opus,
+    Mp3,
+    Flac,
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl AudioFormat {
+    pub const ALL: [AudioFormat; 4] = [
+        AudioFormat::Wav,
+        AudioFormat::Opus,
+        AudioFormat::Mp3,
+        AudioFormat::Flac,
+    ];
+
+    /// The name used in `?format=`, also the file extension
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Opus => "opus",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Flac => "flac",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Opus => "audio/ogg",
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::Flac => "audio/flac",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    /// The format for a media type from an `Accept` header
+    pub fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.trim().to_ascii_lowercase().as_str() {
+            "audio/wav" | "audio/wave" | "audio/x-wav" | "audio/vnd.wave" => Some(AudioFormat::Wav),
+            "audio/ogg" | "audio/opus" | "application/ogg" => Some(AudioFormat::Opus),
+            "audio/mpeg" | "audio/mp3" => Some(AudioFormat::Mp3),
+            "audio/flac" | "audio/x-flac" => Some(AudioFormat::Flac),
+            _ => None,
+        }
+    }
+
+    /// An encoder for samples at `sample_rate`
+    pub fn encoder(&self, sample_rate: u32) -> Result<Box<dyn Encoder>, String> {
+        Ok(match self {
+            AudioFormat::Wav => Box::new(WavEncoder {
+                sample_rate,
+                data_len: 0,
+            }),
+            AudioFormat::Opus => Box::new(OpusEncoder::new(sample_rate)?),
+            AudioFormat::Mp3 => Box::new(Mp3Encoder::new(sample_rate)?),
+            AudioFormat::Flac => Box::new(FlacEncoder::new(sample_rate)?),
+        })
+    }
+}
+
+/// Turns 16-bit little-endian mono samples into a file, a piece at a time
+pub trait Encoder: Send {
+    /// What the file starts with, sent before the first sentence is spoken
+    fn header(&mut self) -> Result<Vec<u8>, String>;
+
+    /// Encode more samples, returning what is ready to send
+    fn encode(&mut self, pcm: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// The end of the file, once every sample was encoded
+    fn finish(&mut self) -> Result<Vec<u8>, String>;
+
+    /// The header again, now with the length of the finished file, for files kept whole;
+    /// it has the length of the header sent first
+    fn final_header(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// 16-bit little-endian samples as numbers
+pub fn samples(pcm: &[u8]) -> Vec<i16> {
+    pcm.chunks_exact(BYTES_PER_SAMPLE as usize)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+        .collect()
+}
+
+/// A WAV header for 16-bit mono samples, for a stream of unknown length without `data_len`
+pub fn wav_header(sample_rate: u32, data_len: Option<u32>) -> Vec<u8> {
+    // Players read a stream with the largest sizes until it ends
+    let (riff_len, data_len) = match data_len {
+        Some(data_len) => (data_len.saturating_add(36), data_len),
+        None => (u32::MAX, u32::MAX),
+    };
+
+    let mut header = Vec::with_capacity(WAV_HEADER_LEN);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&riff_len.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * BYTES_PER_SAMPLE).to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE as u16).to_le_bytes());
+    header.extend_from_slice(&(BYTES_PER_SAMPLE as u16 * 8).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+struct WavEncoder {
+    sample_rate: u32,
+    data_len: u64,
+}
+
+impl Encoder for WavEncoder {
+    fn header(&mut self) -> Result<Vec<u8>, String> {
+        Ok(wav_header(self.sample_rate, None))
+    }
+
+    fn encode(&mut self, pcm: &[u8]) -> Result<Vec<u8>, String> {
+        self.data_len += pcm.len() as u64;
+        Ok(pcm.to_vec())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        Ok(Vec::new())
+    }
+
+    fn final_header(&self) -> Option<Vec<u8>> {
+        let data_len = u32::try_from(self.data_len).ok()?;
+        Some(wav_header(self.sample_rate, Some(data_len)))
+    }
+}
+
+struct FlacEncoder {
+    config: Verified<flacenc::config::Encoder>,
+    stream_info: StreamInfo,
+    /// Samples waiting for a full frame
+    pending: Vec<u8>,
+    frame_number: usize,
+    samples: u64,
+    md5: Md5,
+}
+
+impl FlacEncoder {
+    fn new(sample_rate: u32) -> Result<Self, String> {
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| e.to_string())?;
+        let mut stream_info = StreamInfo::new(sample_rate as usize, 1, 16)
+            .and_then(|mut info| {
+                info.set_block_sizes(FLAC_BLOCK_SIZE, FLAC_BLOCK_SIZE)?;
+                // Frame sizes are unknown until the end, and never needed
+                info.set_frame_sizes(0, 0)?;
+                Ok(info)
+            })
+            .map_err(|e| e.to_string())?;
+        stream_info.set_total_samples(0);
+
+        Ok(Self {
+            config,
+            stream_info,
+            pending: Vec::new(),
+            frame_number: 0,
+            samples: 0,
+            md5: Md5::new(),
+        })
+    }
+
+    fn stream_header(stream_info: &StreamInfo) -> Result<Vec<u8>, String> {
+        let mut sink = ByteSink::new();
+        Stream::with_stream_info(stream_info.clone())
+            .write(&mut sink)
+            .map_err(|e| e.to_string())?;
+        Ok(sink.into_inner())
+    }
+
+    fn frame(&mut self, pcm: &[u8]) -> Result<Vec<u8>, String> {
+        let mut framebuf = FrameBuf::with_size(1, FLAC_BLOCK_SIZE).map_err(|e| e.to_string())?;
+        // Only the last frame is short
+        framebuf.resize(pcm.len() / BYTES_PER_SAMPLE as usize);
+        framebuf
+            .fill_le_bytes(pcm, BYTES_PER_SAMPLE as usize)
+            .map_err(|e| e.to_string())?;
+
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &framebuf,
+            self.frame_number,
+            &self.stream_info,
+        )
+        .map_err(|e| format!("{:?}", e))?;
+        self.frame_number += 1;
+
+        let mut sink = ByteSink::new();
+        frame.write(&mut sink).map_err(|e| e.to_string())?;
+        Ok(sink.into_inner())
+    }
+}
+
+impl Encoder for FlacEncoder {
+    fn header(&mut self) -> Result<Vec<u8>, String> {
+        Self::stream_header(&self.stream_info)
+    }
+
+    fn encode(&mut self, pcm: &[u8]) -> Result<Vec<u8>, String> {
+        self.md5.update(pcm);
+        self.samples += (pcm.len() / BYTES_PER_SAMPLE as usize) as u64;
+        self.pending.extend_from_slice(pcm);
+
+        let frame_len = FLAC_BLOCK_SIZE * BYTES_PER_SAMPLE as usize;
+        let mut encoded = Vec::new();
+        let mut start = 0;
+        while self.pending.len() - start >= frame_len {
+            let frame = self.pending[start..start + frame_len].to_vec();
+            encoded.extend(self.frame(&frame)?);
+            start += frame_len;
+        }
+        self.pending.drain(..start);
+        Ok(encoded)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        if self.pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.frame(&pending)
+    }
+
+    fn final_header(&self) -> Option<Vec<u8>> {
+        let mut stream_info = self.stream_info.clone();
+        stream_info.set_total_samples(self.samples as usize);
+        stream_info.set_md5_digest(&self.md5.clone().finalize().into());
+        Self::stream_header(&stream_info).ok()
+    }
+}
+
+struct Mp3Encoder {
+    lame: mp3lame_encoder::Encoder,
+}
+
+impl Mp3Encoder {
+    fn new(sample_rate: u32) -> Result<Self, String> {
+        use mp3lame_encoder::{Builder, Mode, Quality};
+
+        let lame = Builder::new()
+            .ok_or("Failed to set up the MP3 encoder")?
+            .with_num_channels(1)
+            .and_then(|builder| builder.with_sample_rate(sample_rate))
+            .and_then(|builder| builder.with_mode(Mode::Mono))
+            .and_then(|builder| builder.with_brate(MP3_BITRATE))
+            .and_then(|builder| builder.with_quality(Quality::Good))
+            // The tag goes at the start of the file, which is sent before its length is known
+            .and_then(|builder| builder.with_to_write_vbr_tag(false))
+            .and_then(|builder| builder.build())
+            .map_err(|e| e.to_string())?;
+        Ok(Self { lame })
+    }
+}
+
+impl Encoder for Mp3Encoder {
+    fn header(&mut self) -> Result<Vec<u8>, String> {
+        Ok(Vec::new())
+    }
+
+    fn encode(&mut self, pcm: &[u8]) -> Result<Vec<u8>, String> {
+        let samples = samples(pcm);
+        let mut encoded =
+            Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(samples.len()));
+        self.lame
+            .encode_to_vec(mp3lame_encoder::MonoPcm(samples.as_slice()), &mut encoded)
+            .map_err(|e| e.to_string())?;
+        Ok(encoded)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        // LAME needs at most this much for what it still holds
+        let mut encoded = Vec::with_capacity(7200);
+        self.lame
+            .flush_to_vec::<mp3lame_encoder::FlushNoGap>(&mut encoded)
+            .map_err(|e| e.to_string())?;
+        Ok(encoded)
+    }
+}
+
+/// Linear interpolation between sample rates, enough for a voice moved to a rate Opus takes
+struct Resampler {
+    /// Input samples per output sample
+    step: f64,
+    /// Where the next output sample falls, counted from the first input sample not yet seen;
+    /// between -1 and 0 it falls after the last sample seen
+    position: f64,
+    last: f64,
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        Self {
+            step: f64::from(from) / f64::from(to),
+            position: 0.0,
+            last: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        let Some(&last) = input.last() else {
+            return;
+        };
+        let sample = |index: isize| match index {
+            -1 => self.last,
+            index => f64::from(input[index as usize]),
+        };
+
+        while self.position < (input.len() - 1) as f64 {
+            let index = self.position.floor();
+            let fraction = self.position - index;
+            let (before, after) = (sample(index as isize), sample(index as isize + 1));
+            output.push((before + (after - before) * fraction).round() as i16);
+            self.position += self.step;
+        }
+        self.position -= input.len() as f64;
+        self.last = f64::from(last);
+    }
+}
+
+struct OpusEncoder {
+    state: *mut unsafe_libopus::OpusEncoder,
+    /// The rate encoded and the resampler to it, if Piper speaks at another rate
+    rate: u32,
+    resampler: Option<Resampler>,
+    input_rate: u32,
+    /// Samples waiting for a full frame
+    pending: Vec<i16>,
+    /// Samples, at the rate encoded, given to the encoder and in frames already encoded
+    samples: u64,
+    encoded: u64,
+    /// Samples the encoder delays its output by
+    lookahead: u64,
+    ogg: PacketWriter<'static, Vec<u8>>,
+}
+
+// The encoder state is only used through `&mut self`
+unsafe impl Send for OpusEncoder {}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { unsafe_libopus::opus_encoder_destroy(self.state) }
+    }
+}
+
+impl OpusEncoder {
+    fn new(input_rate: u32) -> Result<Self, String> {
+        let rate = OPUS_SAMPLE_RATES
+            .into_iter()
+            .find(|&rate| rate >= input_rate)
+            .unwrap_or(OPUS_GRANULE_RATE);
+
+        let mut error = 0;
+        let state = unsafe {
+            unsafe_libopus::opus_encoder_create(
+                rate as i32,
+                1,
+                unsafe_libopus::OPUS_APPLICATION_AUDIO,
+                &mut error,
+            )
+        };
+        if state.is_null() || error != unsafe_libopus::OPUS_OK {
+            return Err(format!(
+                "Failed to set up the Opus encoder: error {}",
+                error
+            ));
+        }
+        let mut encoder = Self {
+            state,
+            rate,
+            resampler: (rate != input_rate).then(|| Resampler::new(input_rate, rate)),
+            input_rate,
+            pending: Vec::new(),
+            samples: 0,
+            encoded: 0,
+            lookahead: 0,
+            ogg: PacketWriter::new(Vec::new()),
+        };
+
+        let mut lookahead = 0;
+        let result = unsafe {
+            unsafe_libopus::opus_encoder_ctl!(
+                state,
+                unsafe_libopus::OPUS_SET_BITRATE_REQUEST,
+                OPUS_BITRATE
+            )
+            .min(unsafe_libopus::opus_encoder_ctl!(
+                state,
+                unsafe_libopus::OPUS_SET_SIGNAL_REQUEST,
+                unsafe_libopus::OPUS_SIGNAL_VOICE
+            ))
+            .min(unsafe_libopus::opus_encoder_ctl!(
+                state,
+                unsafe_libopus::OPUS_GET_LOOKAHEAD_REQUEST,
+                &mut lookahead
+            ))
+        };
+        if result != unsafe_libopus::OPUS_OK {
+            return Err(format!(
+                "Failed to set up the Opus encoder: error {}",
+                result
+            ));
+        }
+        encoder.lookahead = lookahead as u64;
+        Ok(encoder)
+    }
+
+    /// 20 ms frames
+    fn frame_size(&self) -> usize {
+        self.rate as usize / 50
+    }
+
+    /// A granule position for samples at the rate encoded
+    fn granule(&self, samples: u64) -> u64 {
+        samples * u64::from(OPUS_GRANULE_RATE / self.rate)
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> Result<Vec<u8>, String> {
+        // The largest packet Opus makes
+        let mut packet = vec![0u8; 1275];
+        let len = unsafe {
+            unsafe_libopus::opus_encode(
+                self.state,
+                frame.as_ptr(),
+                frame.len() as i32,
+                packet.as_mut_ptr(),
+                packet.len() as i32,
+            )
+        };
+        if len < 0 {
+            return Err(format!("Failed to encode Opus audio: error {}", len));
+        }
+        packet.truncate(len as usize);
+        self.encoded += frame.len() as u64;
+        Ok(packet)
+    }
+
+    fn write_packet(
+        &mut self,
+        packet: Vec<u8>,
+        end: PacketWriteEndInfo,
+        granule: u64,
+    ) -> Result<(), String> {
+        self.ogg
+            .write_packet(packet, OGG_SERIAL, end, granule)
+            .map_err(|e| e.to_string())
+    }
+
+    fn take_pages(&mut self) -> Vec<u8> {
+        std::mem::take(self.ogg.inner_mut())
+    }
+}
+
+impl Encoder for OpusEncoder {
+    fn header(&mut self) -> Result<Vec<u8>, String> {
+        // RFC 7845: the identification header, then the comment header, each on its own page
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(1);
+        head.extend_from_slice(&(self.granule(self.lookahead) as u16).to_le_bytes());
+        head.extend_from_slice(&self.input_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        self.write_packet(head, PacketWriteEndInfo::EndPage, 0)?;
+
+        let vendor = env!("CARGO_PKG_NAME").as_bytes();
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor);
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        self.write_packet(tags, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(self.take_pages())
+    }
+
+    fn encode(&mut self, pcm: &[u8]) -> Result<Vec<u8>, String> {
+        let samples = samples(pcm);
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(&samples, &mut self.pending),
+            None => self.pending.extend_from_slice(&samples),
+        }
+
+        let frame_size = self.frame_size();
+        let frames = self.pending.len() / frame_size;
+        let mut remaining = std::mem::take(&mut self.pending);
+        let ready: Vec<i16> = remaining.drain(..frames * frame_size).collect();
+        self.samples += ready.len() as u64;
+        self.pending = remaining;
+
+        for (number, frame) in ready.chunks(frame_size).enumerate() {
+            let packet = self.encode_frame(frame)?;
+            // Each sentence ends a page, so it is sent at once
+            let end = if number + 1 == frames {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            let granule = self.granule(self.encoded);
+            self.write_packet(packet, end, granule)?;
+        }
+        Ok(self.take_pages())
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        self.samples += self.pending.len() as u64;
+        // The encoder lags, pad with silence until the last sample is out
+        let end = self.samples + self.lookahead;
+        let frame_size = self.frame_size();
+        let mut pending = std::mem::take(&mut self.pending);
+
+        loop {
+            pending.resize(frame_size, 0);
+            let packet = self.encode_frame(&pending)?;
+            pending.clear();
+            if self.encoded >= end {
+                // The last page's position trims the padding
+                let granule = self.granule(end);
+                self.write_packet(packet, PacketWriteEndInfo::EndStream, granule)?;
+                break;
+            }
+            let granule = self.granule(self.encoded);
+            self.write_packet(packet, PacketWriteEndInfo::NormalPacket, granule)?;
+        }
+        Ok(self.take_pages())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A second of a 440 Hz tone
+    fn tone(sample_rate: u32) -> Vec<u8> {
+        (0..sample_rate)
+            .map(|n| {
+                let t = f64::from(n) / f64::from(sample_rate);
+                ((t * 440.0 * std::f64::consts::TAU).sin() * 8000.0) as i16
+            })
+            .flat_map(i16::to_le_bytes)
+            .collect()
+    }
+
+    fn encode(format: AudioFormat, sample_rate: u32) -> (Vec<u8>, Option<Vec<u8>>) {
+        let mut encoder = format.encoder(sample_rate).unwrap();
+        let mut file = encoder.header().unwrap();
+        for sentence in tone(sample_rate).chunks(9000) {
+            file.extend(encoder.encode(sentence).unwrap());
+        }
+        file.extend(encoder.finish().unwrap());
+        (file, encoder.final_header())
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(AudioFormat::parse("MP3"), Some(AudioFormat::Mp3));
+        assert_eq!(AudioFormat::parse("aac"), None);
+        assert_eq!(
+            AudioFormat::from_media_type("audio/x-flac"),
+            Some(AudioFormat::Flac)
+        );
+        assert_eq!(AudioFormat::from_media_type("audio/webm"), None);
+
+        let header = wav_header(22050, Some(100));
+        assert_eq!(&header[4..8], &136u32.to_le_bytes());
+        assert_eq!(&header[24..28], &22050u32.to_le_bytes());
+        assert_eq!(&header[40..], &100u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_encoders_compress_a_second_of_speech() {
+        let wav_len = WAV_HEADER_LEN + 2 * 22050;
+
+        let (wav, final_header) = encode(AudioFormat::Wav, 22050);
+        assert_eq!(wav.len(), wav_len);
+        assert_eq!(final_header.unwrap(), wav_header(22050, Some(44100)));
+
+        let (flac, final_header) = encode(AudioFormat::Flac, 22050);
+        assert_eq!(&flac[..4], b"fLaC");
+        assert!(flac.len() < wav_len);
+        // The total samples and checksum fill in a header of the same size
+        let final_header = final_header.unwrap();
+        assert_eq!(final_header.len(), 42);
+        assert_ne!(final_header, flac[..42]);
+
+        let (mp3, _) = encode(AudioFormat::Mp3, 22050);
+        // A frame sync at the start, and about 64 kbit for the second
+        assert_eq!(mp3[0], 0xff);
+        assert!(mp3.len() > 6000 && mp3.len() < 10000, "{} bytes", mp3.len());
+
+        let (opus, _) = encode(AudioFormat::Opus, 22050);
+        assert_eq!(&opus[..4], b"OggS");
+        assert_eq!(&opus[28..36], b"OpusHead");
+        assert!(
+            opus.len() > 2000 && opus.len() < 8000,
+            "{} bytes",
+            opus.len()
+        );
+    }
+
+    #[test]
+    fn test_resampler_keeps_the_duration() {
+        let mut resampler = Resampler::new(22050, 24000);
+        let mut output = Vec::new();
+        for chunk in vec![1000i16; 22050].chunks(1000) {
+            resampler.process(chunk, &mut output);
+        }
+        assert!((23990..=24000).contains(&output.len()), "{}", output.len());
+        assert!(output[1..].iter().all(|&sample| sample == 1000));
+    }
+}
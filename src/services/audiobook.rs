@@ -0,0 +1,688 @@
+//! Whole books as a single audio file, built in the background
+//!
+//! An export reads every body chapter of a document with one voice and assembles the
+//! audio into an M4B or MP3 file, with a chapter mark for each entry of the table of
+//! contents and the book's title, author and cover. Reading a book aloud takes hours, so
+//! exports run one at a time on their own thread and report their progress; finished
+//! files are kept for a day. Exports are not kept across restarts.
+
+use crate::models::metadata::TocEntry;
+use crate::services::audio_format::{self, AudioFormat, Encoder, WAV_HEADER_LEN};
+use crate::services::id3;
+use crate::services::m4b::M4bWriter;
+use crate::services::quota::QuotaError;
+use crate::services::store::{DocumentStore, StoreError, StoredChapter};
+use crate::services::tts::{TtsError, TtsService};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Where audiobooks are built unless `EPUB_AUDIOBOOK_DIR` says otherwise
+pub const AUDIOBOOK_DIR: &str = "./audiobooks";
+
+/// How long finished audiobooks can be downloaded
+pub const AUDIOBOOK_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+const PARTIAL_EXTENSION: &str = "part";
+
+#[derive(Error, Debug)]
+pub enum AudiobookError {
+    #[error("{0}")]
+    Store(#[from] StoreError),
+
+    #[error("{0}")]
+    Tts(#[from] TtsError),
+
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Quota(#[from] QuotaError),
+
+    /// The owner already has an export queued or running
+    #[error("Audiobook {0} is still being exported, wait for it to finish")]
+    InProgress(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudiobookFormat {
+    /// AAC in an MP4 file, with chapters as Apple's and most other players read them
+    M4b,
+    /// With chapters in its ID3 tag
+    Mp3,
+}
+
+impl AudiobookFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudiobookFormat::M4b => "m4b",
+            AudiobookFormat::Mp3 => "mp3",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AudiobookFormat::M4b => "audio/mp4",
+            AudiobookFormat::Mp3 => "audio/mpeg",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "m4b" => Some(AudiobookFormat::M4b),
+            "mp3" => Some(AudiobookFormat::Mp3),
+            _ => None,
+        }
+    }
+}
+
+/// Where a chapter of the audiobook starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChapterMark {
+    pub title: String,
+    /// In samples from the start of the book
+    pub start: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cover {
+    pub data: Vec<u8>,
+    pub media_type: String,
+}
+
+/// What an audiobook is labelled with
+#[derive(Debug, Clone)]
+pub struct BookInfo {
+    pub title: String,
+    pub author: String,
+    pub cover: Option<Cover>,
+}
+
+/// A chapter of the audiobook and the chapters of the document read in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookChapter {
+    pub title: String,
+    pub chapters: Vec<usize>,
+}
+
+/// The body chapters of a document, grouped under the titles of the table of contents
+///
+/// Chapters without text, such as cover pages, and the front matter before the first
+/// chapter in the table of contents are left out. A chapter the table of contents does not
+/// point into continues the one before it, as books are often split into several files per
+/// chapter. Without a table of contents, every chapter is a chapter of the audiobook.
+pub fn book_chapters(chapters: &[StoredChapter], toc: &[TocEntry]) -> Vec<BookChapter> {
+    let mut titles = HashMap::new();
+    for entry in toc {
+        let title = entry.title.trim();
+        if let Some(index) = entry.chapter_index.filter(|_| !title.is_empty()) {
+            titles.entry(index).or_insert(title);
+        }
+    }
+    let first = titles.keys().min().copied().unwrap_or(0);
+
+    let mut book: Vec<BookChapter> = Vec::new();
+    for (index, chapter) in chapters.iter().enumerate().skip(first) {
+        if chapter.text.trim().is_empty() {
+            continue;
+        }
+        match (titles.get(&index), book.last_mut()) {
+            (None, Some(previous)) if !titles.is_empty() => previous.chapters.push(index),
+            (title, _) => book.push(BookChapter {
+                title: title.map_or_else(|| chapter.title.clone(), |title| title.to_string()),
+                chapters: vec![index],
+            }),
+        }
+    }
+    book
+}
+
+/// Everything an export needs besides the chapters' text, gathered when it is requested
+pub struct Export {
+    pub document_id: i64,
+    pub format: AudiobookFormat,
+    pub info: BookInfo,
+    pub chapters: Vec<BookChapter>,
+}
+
+impl Export {
+    /// Chapters of the document to read, which progress is counted in
+    pub fn chapters_total(&self) -> usize {
+        self.chapters
+            .iter()
+            .map(|chapter| chapter.chapters.len())
+            .sum()
+    }
+}
+
+/// The audio of a book being assembled
+enum BookWriter {
+    M4b(M4bWriter),
+    Mp3 {
+        encoder: Box<dyn Encoder>,
+        /// The tag goes in front of the audio, but the chapters are only known at the end
+        audio: BufWriter<File>,
+    },
+}
+
+impl BookWriter {
+    fn new(format: AudiobookFormat, sample_rate: u32) -> io::Result<Self> {
+        Ok(match format {
+            AudiobookFormat::M4b => BookWriter::M4b(M4bWriter::new(sample_rate)?),
+            AudiobookFormat::Mp3 => BookWriter::Mp3 {
+                encoder: AudioFormat::Mp3
+                    .encoder(sample_rate)
+                    .map_err(io::Error::other)?,
+                audio: BufWriter::new(tempfile::tempfile()?),
+            },
+        })
+    }
+
+    fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        match self {
+            BookWriter::M4b(writer) => writer.write(pcm),
+            BookWriter::Mp3 { encoder, audio } => {
+                audio.write_all(&encoder.encode(pcm).map_err(io::Error::other)?)
+            }
+        }
+    }
+
+    fn finish(
+        self,
+        info: &BookInfo,
+        marks: &[ChapterMark],
+        sample_rate: u32,
+        samples: u64,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        match self {
+            BookWriter::M4b(writer) => writer.finish(info, marks, out),
+            BookWriter::Mp3 {
+                mut encoder,
+                mut audio,
+            } => {
+                audio.write_all(&encoder.finish().map_err(io::Error::other)?)?;
+                let mut audio = audio.into_inner().map_err(|e| e.into_error())?;
+                audio.seek(SeekFrom::Start(0))?;
+                out.write_all(&id3::tag(info, marks, sample_rate, samples))?;
+                io::copy(&mut audio, out)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Read the book of `export` with `tts` and write it to `out`
+///
+/// `on_chapter` is called as each chapter of the document is done and `on_synthesized`
+/// with the seconds synthesized for it, stopping the export if it fails; chapters found in
+/// the audio cache as WAV are read from there instead.
+pub fn build(
+    export: &Export,
+    store: &dyn DocumentStore,
+    tts: &TtsService,
+    out: &mut impl Write,
+    mut on_chapter: impl FnMut(),
+    mut on_synthesized: impl FnMut(f64) -> Result<(), AudiobookError>,
+) -> Result<(), AudiobookError> {
+    let sample_rate = tts.sample_rate();
+    let mut writer = BookWriter::new(export.format, sample_rate)?;
+    let mut samples = 0u64;
+    let mut marks = Vec::new();
+
+    for chapter in &export.chapters {
+        marks.push(ChapterMark {
+            title: chapter.title.clone(),
+            start: samples,
+        });
+
+        for &index in &chapter.chapters {
+            let html = store.get_chapter_html(export.document_id, index)?;
+            let text = tts.extract_text_from_html(&html)?;
+
+            let cached = tts
+                .cached_audio(&text, AudioFormat::Wav)
+                .and_then(|cached| fs::read(cached.path).ok())
+                .filter(|wav| wav.len() >= WAV_HEADER_LEN);
+            if let Some(wav) = cached {
+                let pcm = &wav[WAV_HEADER_LEN..];
+                samples += (pcm.len() / audio_format::BYTES_PER_SAMPLE as usize) as u64;
+                writer.write(pcm)?;
+            } else if !text.is_empty() {
+                let start = samples;
+                tts.text_to_samples(&text, |pcm| {
+                    samples += (pcm.len() / audio_format::BYTES_PER_SAMPLE as usize) as u64;
+                    Ok(writer.write(pcm)?)
+                })?;
+                on_synthesized((samples - start) as f64 / f64::from(sample_rate))?;
+            }
+            on_chapter();
+        }
+    }
+
+    writer.finish(&export.info, &marks, sample_rate, samples, out)?;
+    Ok(())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+/// What clients see of an export
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub document_id: i64,
+    pub format: AudiobookFormat,
+    pub state: JobState,
+    pub chapters_done: usize,
+    pub chapters_total: usize,
+    pub error: Option<String>,
+    /// Bytes of the finished file
+    pub size: Option<u64>,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+/// An export, queued, running or finished
+pub struct Job {
+    status: Mutex<JobStatus>,
+    /// The user who asked for it, `None` while authentication is disabled
+    owner: Option<i64>,
+    /// Names the book, voice and format, so asking again finds the same export
+    key: String,
+    path: PathBuf,
+    /// Offered to clients downloading the file
+    pub filename: String,
+}
+
+impl Job {
+    fn status_mut(&self) -> MutexGuard<'_, JobStatus> {
+        // The status is only changed while locked, a panic cannot leave it half changed
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.status_mut().clone()
+    }
+
+    pub fn owner(&self) -> Option<i64> {
+        self.owner
+    }
+
+    /// The finished file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn partial_path(&self) -> PathBuf {
+        self.path.with_extension(PARTIAL_EXTENSION)
+    }
+
+    /// Count another chapter of the document as done
+    pub fn chapter_done(&self) {
+        self.status_mut().chapters_done += 1;
+    }
+
+    fn expired(&self, now: i64) -> bool {
+        self.status_mut()
+            .finished_at
+            .is_some_and(|finished| now - finished >= AUDIOBOOK_RETENTION.as_secs() as i64)
+    }
+}
+
+/// Whether a file is named like an export, `<job id>.<format>` or `<job id>.part`
+fn is_export_file(path: &Path) -> bool {
+    let stem = path.file_stem().and_then(|stem| stem.to_str());
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let (Some(stem), Some(extension)) = (stem, extension) else {
+        return false;
+    };
+
+    !stem.is_empty()
+        && stem.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && (extension == PARTIAL_EXTENSION
+            || [AudiobookFormat::M4b, AudiobookFormat::Mp3]
+                .iter()
+                .any(|format| format.name() == extension))
+}
+
+/// What an export does on the worker thread, writing the book to the file it is given
+pub type Work = Box<dyn FnOnce(&Job, &Export, &mut File) -> Result<(), AudiobookError> + Send>;
+
+/// The exports of the server, run one after the other
+pub struct AudiobookJobs {
+    dir: PathBuf,
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    queue: mpsc::Sender<(Arc<Job>, Export, Work)>,
+}
+
+impl AudiobookJobs {
+    /// Exports built in `dir`, created if needed; books left by a previous run are deleted,
+    /// other files in `dir` are left alone
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_file() && is_export_file(&path) {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Failed to delete audiobook {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        let (queue, jobs) = mpsc::channel::<(Arc<Job>, Export, Work)>();
+        std::thread::Builder::new()
+            .name("audiobook-export".to_string())
+            .spawn(move || {
+                for (job, export, work) in jobs {
+                    run(&job, &export, work);
+                }
+            })?;
+
+        Ok(Self {
+            dir,
+            jobs: Mutex::new(HashMap::new()),
+            queue,
+        })
+    }
+
+    fn jobs(&self) -> MutexGuard<'_, HashMap<String, Arc<Job>>> {
+        // Jobs are only added and removed while locked, a panic cannot leave them half changed
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue `work` exporting `export` for `owner`, or find the export they asked for before
+    ///
+    /// `key` names the book, voice and format; an export with the same key that has not
+    /// failed is returned instead of starting another. With `one_at_a_time`, another export
+    /// is refused while the owner has one queued or running.
+    pub fn submit(
+        &self,
+        owner: Option<i64>,
+        key: String,
+        export: Export,
+        work: Work,
+        one_at_a_time: bool,
+    ) -> Result<Arc<Job>, AudiobookError> {
+        self.expire();
+        let mut jobs = self.jobs();
+        if let Some(job) = jobs.values().find(|job| {
+            job.owner == owner && job.key == key && job.status().state != JobState::Failed
+        }) {
+            return Ok(job.clone());
+        }
+        if one_at_a_time {
+            let pending = jobs.values().find(|job| {
+                job.owner == owner
+                    && matches!(job.status().state, JobState::Queued | JobState::Running)
+            });
+            if let Some(job) = pending {
+                return Err(AudiobookError::InProgress(job.status().id));
+            }
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let job = Arc::new(Job {
+            status: Mutex::new(JobStatus {
+                id: id.clone(),
+                document_id: export.document_id,
+                format: export.format,
+                state: JobState::Queued,
+                chapters_done: 0,
+                chapters_total: export.chapters_total(),
+                error: None,
+                size: None,
+                created_at: now(),
+                finished_at: None,
+            }),
+            owner,
+            key,
+            path: self.dir.join(format!("{}.{}", id, export.format.name())),
+            filename: filename(&export.info.title, export.format),
+        });
+        self.queue
+            .send((job.clone(), export, work))
+            .map_err(|_| io::Error::other("The audiobook worker has stopped"))?;
+        jobs.insert(id, job.clone());
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Job>> {
+        self.expire();
+        self.jobs().get(id).cloned()
+    }
+
+    /// Forget exports finished longer than [`AUDIOBOOK_RETENTION`] ago and delete their files
+    fn expire(&self) {
+        let now = now();
+        self.jobs().retain(|_, job| {
+            if !job.expired(now) {
+                return true;
+            }
+            if let Err(e) = fs::remove_file(&job.path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to delete audiobook {}: {}", job.path.display(), e);
+                }
+            }
+            false
+        });
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+/// Run an export on the worker thread, keeping the file only once it is complete
+fn run(job: &Job, export: &Export, work: Work) {
+    job.status_mut().state = JobState::Running;
+    let partial = job.partial_path();
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut file = File::create(&partial)?;
+        work(job, export, &mut file)?;
+        file.sync_all()?;
+        fs::rename(&partial, &job.path)?;
+        Ok::<_, AudiobookError>(fs::metadata(&job.path)?.len())
+    }))
+    .unwrap_or_else(|_| Err(io::Error::other("The export panicked").into()));
+
+    let mut status = job.status_mut();
+    status.finished_at = Some(now());
+    match result {
+        Ok(size) => {
+            info!("Audiobook {} is done, {} bytes", status.id, size);
+            status.state = JobState::Done;
+            status.size = Some(size);
+        }
+        Err(e) => {
+            warn!("Audiobook {} failed: {}", status.id, e);
+            let _ = fs::remove_file(&partial);
+            status.state = JobState::Failed;
+            status.error = Some(e.to_string());
+        }
+    }
+}
+
+/// A file name for the book, in ASCII for clients that cannot take more
+fn filename(title: &str, format: AudiobookFormat) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { ' ' })
+        .collect();
+    let name = name.split_whitespace().collect::<Vec<_>>().join("-");
+    let name = if name.is_empty() { "audiobook" } else { &name };
+    format!("{}.{}", name, format.name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(text: &str) -> StoredChapter {
+        StoredChapter {
+            title: "Chapter".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn entry(title: &str, chapter_index: Option<usize>) -> TocEntry {
+        TocEntry {
+            title: title.to_string(),
+            href: String::new(),
+            level: 0,
+            chapter_index,
+        }
+    }
+
+    #[test]
+    fn test_book_chapters_follow_the_toc() {
+        let chapters = [
+            chapter("Moby-Dick, or The Whale"),
+            chapter(""),
+            chapter("Call me Ishmael."),
+            chapter("...and so on."),
+            chapter("I stuffed a shirt or two into my old carpet-bag."),
+        ];
+        let toc = [
+            entry("Loomings", Some(2)),
+            entry("Part one", Some(2)),
+            entry("The Carpet-Bag", Some(4)),
+            entry("Missing", None),
+        ];
+
+        let book = book_chapters(&chapters, &toc);
+        assert_eq!(
+            book,
+            [
+                BookChapter {
+                    title: "Loomings".to_string(),
+                    chapters: vec![2, 3],
+                },
+                BookChapter {
+                    title: "The Carpet-Bag".to_string(),
+                    chapters: vec![4],
+                },
+            ]
+        );
+
+        // Without a table of contents, every chapter with text is read
+        let book = book_chapters(&chapters, &[]);
+        let read: Vec<_> = book
+            .iter()
+            .flat_map(|chapter| chapter.chapters.clone())
+            .collect();
+        assert_eq!(read, [0, 2, 3, 4]);
+        assert_eq!(book[0].title, "Chapter");
+    }
+
+    #[test]
+    fn test_jobs_keep_finished_books() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = uuid::Uuid::new_v4().simple().to_string();
+        for name in [format!("{old}.m4b"), format!("{old}.part")] {
+            fs::write(dir.path().join(name), b"left over").unwrap();
+        }
+        fs::write(dir.path().join("notes.mp3"), b"keep").unwrap();
+        let jobs = AudiobookJobs::open(dir.path()).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(dir.path().join("notes.mp3").exists());
+
+        let export = || Export {
+            document_id: 1,
+            format: AudiobookFormat::Mp3,
+            info: BookInfo {
+                title: "Moby-Dick; or, The Whale".to_string(),
+                author: "Herman Melville".to_string(),
+                cover: None,
+            },
+            chapters: vec![BookChapter {
+                title: "Loomings".to_string(),
+                chapters: vec![0, 1],
+            }],
+        };
+        let work: Work = Box::new(|job, _, file| {
+            job.chapter_done();
+            job.chapter_done();
+            file.write_all(b"ID3")?;
+            Ok(())
+        });
+        let job = jobs
+            .submit(Some(7), "book".to_string(), export(), work, false)
+            .unwrap();
+        assert_eq!(job.filename, "Moby-Dick-or-The-Whale.mp3");
+        assert_eq!(job.status().chapters_total, 2);
+
+        let failing: Work = Box::new(|_, _, _| Err(io::Error::other("no voice").into()));
+        let failed = jobs
+            .submit(Some(7), "other".to_string(), export(), failing, false)
+            .unwrap();
+
+        // Jobs run one after the other, so the second is done once the first is
+        let mut status = failed.status();
+        for _ in 0..100 {
+            status = failed.status();
+            if status.finished_at.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(status.state, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some("IO error: no voice"));
+
+        let status = job.status();
+        assert_eq!(status.state, JobState::Done);
+        assert_eq!(status.chapters_done, 2);
+        assert_eq!(status.size, Some(3));
+        assert_eq!(fs::read(job.path()).unwrap(), b"ID3");
+        // The finished book, next to the file the server did not write
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Asking again finds the finished book, unless it failed or is someone else's
+        let again: Work = Box::new(|_, _, _| Ok(()));
+        let found = jobs
+            .submit(Some(7), "book".to_string(), export(), again, true)
+            .unwrap();
+        assert_eq!(found.status().id, status.id);
+        let again: Work = Box::new(|_, _, _| Ok(()));
+        let other = jobs
+            .submit(Some(8), "book".to_string(), export(), again, true)
+            .unwrap();
+        assert_ne!(other.status().id, status.id);
+        assert!(jobs.get(&status.id).is_some());
+        assert!(jobs.get("unknown").is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::services::store::DocumentAccess;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Start of every API token, so leaked tokens are easy to search for
+pub const TOKEN_PREFIX: &str = "epub_";
+
+/// Shortest accepted password, in characters
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Longest accepted user name, in characters
+pub const MAX_USERNAME_LENGTH: usize = 64;
+
+/// Name of the caller when authentication is disabled
+pub const LOCAL_USER: &str = "default";
+
+/// What an API token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read documents and keep one's own annotations and reading progress
+    Read,
+    /// Add, change and delete documents, tags and collections
+    Upload,
+    /// Everything, including the admin endpoints and every user's documents
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Upload => "upload",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "upload" => Some(Scope::Upload),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Scopes as stored, e.g. `read,upload`
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Stored scopes back into a sorted list; unknown scopes are dropped
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    let mut scopes: Vec<Scope> = scopes.split(',').filter_map(Scope::parse).collect();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub is_admin: bool,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// An API token, without the token itself which is only shown when it is created
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+/// Who is making a request, as established by the authentication middleware
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// `None` when authentication is disabled
+    pub user_id: Option<i64>,
+    pub username: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl Caller {
+    /// The caller when authentication is disabled, who may do everything
+    pub fn local() -> Self {
+        Caller {
+            user_id: None,
+            username: LOCAL_USER.to_string(),
+            scopes: vec![Scope::Read, Scope::Upload, Scope::Admin],
+        }
+    }
+
+    /// The caller using `token`; its admin scope only counts while the user is an admin
+    pub fn for_token(user: &User, token: &ApiToken) -> Self {
+        Caller {
+            user_id: Some(user.id),
+            username: user.username.clone(),
+            scopes: token
+                .scopes
+                .iter()
+                .copied()
+                .filter(|&scope| scope != Scope::Admin || user.is_admin)
+                .collect(),
+        }
+    }
+
+    /// Whether the caller may use `scope`; the admin scope includes the others
+    pub fn has(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.has(Scope::Admin)
+    }
+
+    /// The user whose documents the caller sees, `None` for everything
+    pub fn visible_to(&self) -> Option<i64> {
+        if self.is_admin() {
+            None
+        } else {
+            self.user_id
+        }
+    }
+
+    /// Documents are visible to their owner, to everyone once shared, and to everyone if
+    /// they have no owner
+    pub fn can_read(&self, access: &DocumentAccess) -> bool {
+        self.is_admin()
+            || access.shared
+            || access.owner_id.is_none()
+            || access.owner_id == self.user_id
+    }
+
+    /// Only owners change their documents; documents without an owner are left to admins
+    pub fn can_write(&self, access: &DocumentAccess) -> bool {
+        self.owns(access.owner_id)
+    }
+
+    /// Whether the caller may change what `owner_id` owns; admins may change everything
+    pub fn owns(&self, owner_id: Option<i64>) -> bool {
+        self.is_admin() || (owner_id.is_some() && owner_id == self.user_id)
+    }
+}
+
+/// User names are shown in URLs and progress, so they are kept short and without spaces
+pub fn check_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(format!(
+            "username must be 1 to {} characters",
+            MAX_USERNAME_LENGTH
+        ));
+    }
+    if username
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err("username must not contain spaces".to_string());
+    }
+    if username.eq_ignore_ascii_case(LOCAL_USER) {
+        return Err(format!("username {} is reserved", LOCAL_USER));
+    }
+    Ok(())
+}
+
+pub fn check_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Failed to hash password: {}", e))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// A new random API token and the hash it is stored under
+pub fn generate_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+    let hash = hash_token(&token);
+    (token, hash)
+}
+
+/// Tokens are random, so a plain SHA-256 is enough to keep them out of the database
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passwords_and_tokens() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("correct horse!", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+
+        let (token, hash) = generate_token();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(token.len(), TOKEN_PREFIX.len() + 64);
+        assert_eq!(hash_token(&token), hash);
+        assert_ne!(generate_token().0, token);
+
+        assert_eq!(
+            parse_scopes("upload,read,bogus,read"),
+            vec![Scope::Read, Scope::Upload]
+        );
+        assert_eq!(format_scopes(&[Scope::Read, Scope::Admin]), "read,admin");
+
+        assert!(check_username("ann").is_ok());
+        assert!(check_username("ann smith").is_err());
+        assert!(check_username("Default").is_err());
+        assert!(check_password("short").is_err());
+    }
+
+    #[test]
+    fn test_document_access() {
+        let ann = Caller {
+            user_id: Some(1),
+            username: "ann".to_string(),
+            scopes: vec![Scope::Read, Scope::Upload],
+        };
+        let access = |owner_id, shared| DocumentAccess { owner_id, shared };
+
+        assert!(ann.can_read(&access(Some(1), false)));
+        assert!(ann.can_write(&access(Some(1), false)));
+        assert!(!ann.can_read(&access(Some(2), false)));
+        assert!(ann.can_read(&access(Some(2), true)));
+        assert!(!ann.can_write(&access(Some(2), true)));
+        assert!(ann.can_read(&access(None, false)));
+        assert!(!ann.can_write(&access(None, false)));
+        assert_eq!(ann.visible_to(), Some(1));
+
+        let local = Caller::local();
+        assert!(local.can_write(&access(Some(2), false)));
+        assert_eq!(local.visible_to(), None);
+    }
+}
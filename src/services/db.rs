@@ -1,90 +1,2764 @@
-use crate::models::metadata::EpubMetadata;
-use rusqlite::{params, Connection, Result};
-use serde_json::Value;
+use crate::models::metadata::{EpubMetadata, TocEntry};
+use crate::services::annotations::{
+    self, Annotation, AnnotationKind, AnnotationUpdate, NewAnnotation, TextAnchor,
+};
+use crate::services::archive::{self, ArchiveError, ImportedDocument, Manifest};
+use crate::services::audio_cache::AudioCache;
+use crate::services::auth::{self, ApiToken, Scope, User};
+use crate::services::epub_parser::{EpubContent, PARSER_VERSION};
+use crate::services::koreader::{self, SyncProgress, SyncUser};
+use crate::services::migrations;
+use crate::services::search::{Analyzer, Query};
+use crate::services::store::{
+    ChapterHit, Collection, CollectionUpdate, ContentCounts, Document, DocumentAccess,
+    DocumentFilter, DocumentSort, DocumentStore, DocumentSummary, DocumentUpdate, ListCursor,
+    OriginalFile, Progress, SortKey, SortOrder, StorageUsage, StoreError, StoreResult,
+    StoredChapter, TagCount,
+};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef};
+use rusqlite::{
+    named_params, params, params_from_iter, Connection, ErrorCode, OptionalExtension, Result, Row,
+};
+use std::collections::HashSet;
+use std::fs::File;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 
-pub struct Document {
-    pub id: i64,
-    pub metadata: String,      // JSON string
-    pub chapters_html: String, // JSON string containing HTML chapters
+pub const DB_PATH: &str = "epub_documents.db";
+
+/// Upper bound on open connections; WAL lets readers proceed while one of them writes
+const POOL_SIZE: u32 = 8;
+
+/// How long a connection waits on a locked database before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+type PooledConnection = r2d2::PooledConnection<SqliteConnectionManager>;
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Connection pool error: {0}")]
+    Pool(#[from] r2d2::Error),
+}
+
+impl ToSql for SortKey {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        match self {
+            SortKey::Integer(value) => value.to_sql(),
+            SortKey::Text(value) => value.to_sql(),
+        }
+    }
 }
 
-pub fn init_db() -> Result<Connection> {
-    let db_path = "epub_documents.db";
-    let is_new = !Path::new(db_path).exists();
+fn sort_column(sort: DocumentSort) -> &'static str {
+    match sort {
+        DocumentSort::Title => "d.title COLLATE NOCASE",
+        DocumentSort::Author => "d.author COLLATE NOCASE",
+        DocumentSort::Uploaded => "d.created_at",
+    }
+}
 
-    let conn = Connection::open(db_path)?;
+/// Open the connection pool for the database at `path` and apply any pending migrations
+pub fn init_pool<P: AsRef<Path>>(path: P) -> Result<DbPool, DbError> {
+    let manager = SqliteConnectionManager::file(path).with_init(configure_connection);
+    let pool = r2d2::Pool::builder().max_size(POOL_SIZE).build(manager)?;
 
-    if is_new {
-        conn.execute(
-            "CREATE TABLE documents (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                metadata TEXT NOT NULL,
-                chapters_html TEXT NOT NULL
-            )",
-            [],
+    migrations::run(&mut *pool.get()?)?;
+
+    Ok(pool)
+}
+
+/// Per-connection settings: WAL journaling, busy timeout and foreign keys
+pub fn configure_connection(conn: &mut Connection) -> Result<()> {
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+}
+
+impl ToSql for AnnotationKind {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for AnnotationKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let kind = value.as_str()?;
+        AnnotationKind::parse(kind).ok_or_else(|| FromSqlError::Other(kind.into()))
+    }
+}
+
+/// The SQLite document store; every call takes a connection from the pool
+pub struct SqliteStore {
+    pool: DbPool,
+}
+
+impl SqliteStore {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// Open the database at `path`, applying any pending migrations
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DbError> {
+        init_pool(path).map(Self::new)
+    }
+
+    fn conn(&self) -> StoreResult<PooledConnection> {
+        Ok(self.pool.get()?)
+    }
+}
+
+impl DocumentStore for SqliteStore {
+    fn save_document(
+        &self,
+        content: &EpubContent,
+        original: Option<&OriginalFile>,
+    ) -> StoreResult<i64> {
+        let conn = self.conn()?;
+        Ok(save_document(&conn, content, original)?)
+    }
+
+    fn get_document(&self, id: i64) -> StoreResult<Document> {
+        let conn = self.conn()?;
+        Ok(get_document(&conn, id)?)
+    }
+
+    fn get_chapters(&self, id: i64) -> StoreResult<Vec<StoredChapter>> {
+        let conn = self.conn()?;
+        // An unknown document is not found rather than a document without chapters
+        get_document(&conn, id)?;
+        Ok(get_chapters(&conn, id)?)
+    }
+
+    fn get_chapter_html(&self, id: i64, index: usize) -> StoreResult<String> {
+        let conn = self.conn()?;
+        Ok(get_chapter_html_by_index(&conn, id, index)?)
+    }
+
+    fn get_chapter_text(&self, id: i64, index: usize) -> StoreResult<String> {
+        let conn = self.conn()?;
+        Ok(get_chapter_text_by_index(&conn, id, index)?)
+    }
+
+    fn get_toc(&self, id: i64) -> StoreResult<Vec<TocEntry>> {
+        let conn = self.conn()?;
+        Ok(get_toc(&conn, id)?)
+    }
+
+    fn get_original(&self, id: i64) -> StoreResult<OriginalFile> {
+        let conn = self.conn()?;
+        Ok(get_original(&conn, id)?)
+    }
+
+    fn list_documents(
+        &self,
+        filter: &DocumentFilter,
+        sort: DocumentSort,
+        order: SortOrder,
+        after: Option<&ListCursor>,
+        limit: usize,
+    ) -> StoreResult<(Vec<DocumentSummary>, Option<ListCursor>)> {
+        let conn = self.conn()?;
+        Ok(list_documents(&conn, filter, sort, order, after, limit)?)
+    }
+
+    fn update_document(&self, id: i64, update: &DocumentUpdate) -> StoreResult<Document> {
+        let conn = self.conn()?;
+        Ok(update_document(&conn, id, update)?)
+    }
+
+    fn delete_document(&self, id: i64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(delete_document(&conn, id)?)
+    }
+
+    fn search_chapters(
+        &self,
+        query: &Query,
+        language: Option<&str>,
+        visible_to: Option<i64>,
+        limit: usize,
+        offset: usize,
+    ) -> StoreResult<(usize, Vec<ChapterHit>)> {
+        let conn = self.conn()?;
+        Ok(search_chapters(
+            &conn, query, language, visible_to, limit, offset,
+        )?)
+    }
+
+    fn replace_content(&self, id: i64, content: &EpubContent) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(replace_content(&conn, id, content)?)
+    }
+
+    fn content_counts(&self, id: i64) -> StoreResult<ContentCounts> {
+        let conn = self.conn()?;
+        Ok(content_counts(&conn, id)?)
+    }
+
+    fn parser_version(&self, id: i64) -> StoreResult<i64> {
+        let conn = self.conn()?;
+        Ok(parser_version(&conn, id)?)
+    }
+
+    fn document_ids(&self, parsed_before: Option<i64>) -> StoreResult<Vec<i64>> {
+        let conn = self.conn()?;
+        Ok(document_ids(&conn, parsed_before)?)
+    }
+
+    fn set_tags(&self, id: i64, tags: &[String]) -> StoreResult<Vec<String>> {
+        let conn = self.conn()?;
+        Ok(set_tags(&conn, id, tags)?)
+    }
+
+    fn list_tags(&self, visible_to: Option<i64>) -> StoreResult<Vec<TagCount>> {
+        let conn = self.conn()?;
+        Ok(list_tags(&conn, visible_to)?)
+    }
+
+    fn create_collection(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        owner_id: Option<i64>,
+    ) -> StoreResult<Collection> {
+        let conn = self.conn()?;
+        create_collection(&conn, name, description, owner_id).map_err(|e| name_conflict(e, name))
+    }
+
+    fn list_collections(&self, visible_to: Option<i64>) -> StoreResult<Vec<Collection>> {
+        let conn = self.conn()?;
+        Ok(list_collections(&conn, visible_to)?)
+    }
+
+    fn get_collection(&self, id: i64, visible_to: Option<i64>) -> StoreResult<Collection> {
+        let conn = self.conn()?;
+        Ok(get_collection(&conn, id, visible_to)?)
+    }
+
+    fn update_collection(&self, id: i64, update: &CollectionUpdate) -> StoreResult<Collection> {
+        let conn = self.conn()?;
+        update_collection(&conn, id, update).map_err(|e| match &update.name {
+            Some(name) => name_conflict(e, name),
+            None => e.into(),
+        })
+    }
+
+    fn delete_collection(&self, id: i64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(delete_collection(&conn, id)?)
+    }
+
+    fn collection_documents(&self, id: i64) -> StoreResult<Vec<DocumentSummary>> {
+        let conn = self.conn()?;
+        Ok(collection_documents(&conn, id)?)
+    }
+
+    fn set_collection_documents(&self, id: i64, document_ids: &[i64]) -> StoreResult<()> {
+        let conn = self.conn()?;
+        set_collection_documents(&conn, id, document_ids)
+    }
+
+    fn add_to_collection(
+        &self,
+        id: i64,
+        document_id: i64,
+        position: Option<usize>,
+    ) -> StoreResult<()> {
+        let conn = self.conn()?;
+        add_to_collection(&conn, id, document_id, position)
+    }
+
+    fn remove_from_collection(&self, id: i64, document_id: i64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(remove_from_collection(&conn, id, document_id)?)
+    }
+
+    fn create_annotation(
+        &self,
+        document_id: i64,
+        annotation: &NewAnnotation,
+    ) -> StoreResult<Annotation> {
+        let conn = self.conn()?;
+        Ok(create_annotation(&conn, document_id, annotation)?)
+    }
+
+    fn list_annotations(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+    ) -> StoreResult<Vec<Annotation>> {
+        let conn = self.conn()?;
+        // An unknown document is not found rather than a document without annotations
+        get_document(&conn, document_id)?;
+        Ok(user_annotations(&conn, document_id, user_id)?)
+    }
+
+    fn update_annotation(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+        id: i64,
+        update: &AnnotationUpdate,
+    ) -> StoreResult<Annotation> {
+        let conn = self.conn()?;
+        Ok(update_annotation(&conn, document_id, user_id, id, update)?)
+    }
+
+    fn delete_annotation(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+        id: i64,
+    ) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(delete_annotation(&conn, document_id, user_id, id)?)
+    }
+
+    fn get_progress(&self, user_id: Option<i64>, document_id: i64) -> StoreResult<Progress> {
+        let conn = self.conn()?;
+        Ok(get_progress(&conn, user_id, document_id)?)
+    }
+
+    fn list_progress(&self, user_id: Option<i64>) -> StoreResult<Vec<Progress>> {
+        let conn = self.conn()?;
+        Ok(list_progress(&conn, user_id)?)
+    }
+
+    fn save_progress(&self, progress: &Progress) -> StoreResult<bool> {
+        let conn = self.conn()?;
+        Ok(save_progress(&conn, progress)?)
+    }
+
+    fn find_by_digest(&self, digest: &str, visible_to: Option<i64>) -> StoreResult<i64> {
+        let conn = self.conn()?;
+        Ok(find_by_digest(&conn, digest, visible_to)?)
+    }
+
+    fn create_sync_user(&self, user: &SyncUser) -> StoreResult<()> {
+        let conn = self.conn()?;
+        if !create_sync_user(&conn, user)? {
+            return Err(StoreError::Conflict(format!(
+                "Username {} is already registered",
+                user.username
+            )));
+        }
+        Ok(())
+    }
+
+    fn get_sync_user(&self, username: &str) -> StoreResult<SyncUser> {
+        let conn = self.conn()?;
+        Ok(get_sync_user(&conn, username)?)
+    }
+
+    fn link_sync_user(&self, username: &str, user_id: i64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(link_sync_user(&conn, username, user_id)?)
+    }
+
+    fn save_sync_progress(&self, progress: &SyncProgress) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(save_sync_progress(&conn, progress)?)
+    }
+
+    fn get_sync_progress(&self, username: &str, document: &str) -> StoreResult<SyncProgress> {
+        let conn = self.conn()?;
+        Ok(get_sync_progress(&conn, username, document)?)
+    }
+
+    fn document_access(&self, id: i64) -> StoreResult<DocumentAccess> {
+        let conn = self.conn()?;
+        Ok(document_access(&conn, id)?)
+    }
+
+    fn set_document_access(&self, id: i64, access: &DocumentAccess) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(set_document_access(&conn, id, access)?)
+    }
+
+    fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        is_admin: bool,
+    ) -> StoreResult<User> {
+        let conn = self.conn()?;
+        create_user(&conn, username, password_hash, is_admin).map_err(|e| match e {
+            rusqlite::Error::SqliteFailure(err, _)
+                if err.code == ErrorCode::ConstraintViolation =>
+            {
+                StoreError::Conflict(format!("Username {} is already taken", username))
+            }
+            e => e.into(),
+        })
+    }
+
+    fn list_users(&self) -> StoreResult<Vec<User>> {
+        let conn = self.conn()?;
+        Ok(list_users(&conn)?)
+    }
+
+    fn get_user_by_name(&self, username: &str) -> StoreResult<(User, String)> {
+        let conn = self.conn()?;
+        Ok(get_user_by_name(&conn, username)?)
+    }
+
+    fn set_password(&self, user_id: i64, password_hash: &str) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(set_password(&conn, user_id, password_hash)?)
+    }
+
+    fn delete_user(&self, id: i64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        let owned: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM documents WHERE owner_id = ?1",
+            params![id],
+            |row| row.get(0),
         )?;
+        if owned > 0 {
+            return Err(StoreError::Conflict(format!(
+                "User {} still owns {} documents",
+                id, owned
+            )));
+        }
+        Ok(delete_user(&conn, id)?)
+    }
+
+    fn claim_unowned(&self, user: &User) -> StoreResult<usize> {
+        let conn = self.conn()?;
+        Ok(claim_unowned(&conn, user)?)
+    }
+
+    fn create_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &[Scope],
+        token_hash: &str,
+    ) -> StoreResult<ApiToken> {
+        let conn = self.conn()?;
+        Ok(create_token(&conn, user_id, name, scopes, token_hash)?)
     }
 
-    Ok(conn)
+    fn list_tokens(&self, user_id: i64) -> StoreResult<Vec<ApiToken>> {
+        let conn = self.conn()?;
+        Ok(list_tokens(&conn, user_id)?)
+    }
+
+    fn delete_token(&self, user_id: i64, id: i64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(delete_token(&conn, user_id, id)?)
+    }
+
+    fn authenticate_token(&self, token_hash: &str) -> StoreResult<(User, ApiToken)> {
+        let conn = self.conn()?;
+        Ok(authenticate_token(&conn, token_hash)?)
+    }
+
+    fn storage_usage(&self, user_id: i64) -> StoreResult<StorageUsage> {
+        let conn = self.conn()?;
+        Ok(storage_usage(&conn, user_id)?)
+    }
+
+    fn audio_usage(&self, user_id: i64, period: &str) -> StoreResult<f64> {
+        let conn = self.conn()?;
+        Ok(audio_usage(&conn, user_id, period)?)
+    }
+
+    fn add_audio_usage(&self, user_id: i64, period: &str, seconds: f64) -> StoreResult<()> {
+        let conn = self.conn()?;
+        Ok(add_audio_usage(&conn, user_id, period, seconds)?)
+    }
+
+    fn export_library(
+        &self,
+        out: &mut File,
+        audio: Option<&AudioCache>,
+    ) -> Result<Manifest, ArchiveError> {
+        let conn = self.conn()?;
+        archive::export_library(&conn, out, audio)
+    }
+
+    fn import_library(
+        &self,
+        file: File,
+        importer: Option<i64>,
+        audio: Option<&Arc<AudioCache>>,
+    ) -> Result<Vec<ImportedDocument>, ArchiveError> {
+        let mut conn = self.conn()?;
+        archive::import_library(&mut conn, file, importer, audio)
+    }
 }
 
-pub fn save_document(metadata: &EpubMetadata, chapters_html: &Value) -> Result<i64> {
-    let conn = init_db()?;
+/// Store a parsed EPUB, with the file it was parsed from, and return the id of the new document
+pub fn save_document(
+    conn: &Connection,
+    content: &EpubContent,
+    original: Option<&OriginalFile>,
+) -> Result<i64> {
+    let tx = conn.unchecked_transaction()?;
 
-    let metadata_json = serde_json::to_value(metadata)
-        .map_err(|e| rusqlite::Error::InvalidParameterName(e.to_string()))?;
+    let original_sha256 = match original {
+        Some(original) => {
+            let sha256 = original.sha256();
+            // The same file uploaded twice is only stored once
+            tx.execute(
+                "INSERT OR IGNORE INTO originals (sha256, media_type, size, data, partial_md5)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    sha256,
+                    original.media_type,
+                    original.data.len() as i64,
+                    original.data,
+                    original.partial_md5()
+                ],
+            )?;
+            Some(sha256)
+        }
+        None => None,
+    };
 
-    conn.execute(
-        "INSERT INTO documents (metadata, chapters_html) VALUES (?1, ?2)",
-        params![metadata_json.to_string(), chapters_html.to_string(),],
+    let metadata = &content.metadata;
+    tx.execute(
+        "INSERT INTO documents
+            (title, author, publication_date, language, description, series, series_index,
+             original_sha256, original_filename, parser_version)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            metadata.title,
+            metadata.author,
+            metadata.publication_date,
+            metadata.language,
+            metadata.description,
+            metadata.series,
+            metadata.series_index,
+            original_sha256,
+            original.map(|original| &original.filename),
+            PARSER_VERSION,
+        ],
     )?;
+    let document_id = tx.last_insert_rowid();
 
-    Ok(conn.last_insert_rowid())
+    insert_content(&tx, document_id, content, metadata.language.as_deref())?;
+
+    tx.commit()?;
+    Ok(document_id)
 }
 
-pub fn get_document(id: i64) -> Result<Document> {
-    let conn = init_db()?;
+/// Store the chapters, resources, TOC and tags of a parsed EPUB for a document,
+/// indexing chapter text for search in `language`
+fn insert_content(
+    conn: &Connection,
+    document_id: i64,
+    content: &EpubContent,
+    language: Option<&str>,
+) -> Result<()> {
+    let analyzer = Analyzer::for_language(language);
+    let mut stmt = conn.prepare(
+        "INSERT INTO chapters (document_id, chapter_index, title, path, text, html)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    let mut search_stmt = conn.prepare(
+        "INSERT INTO chapter_search (rowid, title, body, stemming) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (index, chapter) in content.chapters.iter().enumerate() {
+        stmt.execute(params![
+            document_id,
+            index as i64,
+            chapter.title,
+            chapter.path,
+            chapter.content,
+            chapter.html,
+        ])?;
+        search_stmt.execute(params![
+            conn.last_insert_rowid(),
+            analyzer.index_text(&chapter.title),
+            analyzer.index_text(&chapter.content),
+            analyzer.stemming_name(),
+        ])?;
+    }
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO resources (document_id, path, media_type, data) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for resource in &content.resources {
+        stmt.execute(params![
+            document_id,
+            resource.path,
+            resource.media_type,
+            resource.data,
+        ])?;
+    }
 
     let mut stmt =
-        conn.prepare("SELECT id, metadata, chapters_html FROM documents WHERE id = ?1")?;
-    let document = stmt.query_row(params![id], |row| {
-        Ok(Document {
-            id: row.get(0)?,
-            metadata: row.get(1)?,
-            chapters_html: row.get(2)?,
-        })
-    })?;
+        conn.prepare("INSERT OR IGNORE INTO document_tags (document_id, tag) VALUES (?1, ?2)")?;
+    for tag in &content.metadata.tags {
+        stmt.execute(params![document_id, tag])?;
+    }
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO toc_entries (document_id, position, level, title, href, chapter_index)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for (position, entry) in content.toc.iter().enumerate() {
+        stmt.execute(params![
+            document_id,
+            position as i64,
+            entry.level as i64,
+            entry.title,
+            entry.href,
+            entry.chapter_index.map(|index| index as i64),
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Replace the stored content of a document with a new parse of the same book
+///
+/// The document keeps its id, and with it everything that refers to it. Metadata already
+/// stored is kept, since it may have been corrected by hand; the new parse only fills in
+/// fields that are empty and adds tags.
+pub fn replace_content(conn: &Connection, id: i64, content: &EpubContent) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    // Fails with not found for unknown documents
+    get_document(&tx, id)?;
+    let previous = get_chapters(&tx, id)?;
+
+    for table in ["chapters", "resources", "toc_entries"] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE document_id = ?1", table),
+            params![id],
+        )?;
+    }
+
+    let metadata = &content.metadata;
+    tx.execute(
+        "UPDATE documents SET
+            publication_date = COALESCE(publication_date, ?2),
+            language = COALESCE(language, ?3),
+            description = COALESCE(description, ?4),
+            series = COALESCE(series, ?5),
+            series_index = COALESCE(series_index, ?6),
+            parser_version = ?7
+         WHERE id = ?1",
+        params![
+            id,
+            metadata.publication_date,
+            metadata.language,
+            metadata.description,
+            metadata.series,
+            metadata.series_index,
+            PARSER_VERSION,
+        ],
+    )?;
+
+    let language = get_document(&tx, id)?.metadata.language;
+    insert_content(&tx, id, content, language.as_deref())?;
+    reanchor_annotations(&tx, id, content)?;
+    relocate_progress(&tx, id, &previous, content)?;
+
+    tx.commit()
+}
+
+/// Move the annotations of a document to where their text is in new content, marking
+/// those whose text is gone as detached
+fn reanchor_annotations(conn: &Connection, document_id: i64, content: &EpubContent) -> Result<()> {
+    let texts: Vec<&str> = content
+        .chapters
+        .iter()
+        .map(|chapter| chapter.content.as_str())
+        .collect();
+
+    for annotation in list_annotations(conn, document_id)? {
+        match annotations::locate(&texts, &annotation.anchor) {
+            Some(anchor) => conn.execute(
+                "UPDATE annotations SET chapter_index = ?2, start_offset = ?3, end_offset = ?4,
+                        prefix = ?5, suffix = ?6, detached = 0
+                 WHERE id = ?1",
+                params![
+                    annotation.id,
+                    anchor.chapter_index as i64,
+                    anchor.start_offset as i64,
+                    anchor.end_offset as i64,
+                    anchor.prefix,
+                    anchor.suffix,
+                ],
+            )?,
+            None => conn.execute(
+                "UPDATE annotations SET detached = 1 WHERE id = ?1",
+                params![annotation.id],
+            )?,
+        };
+    }
+
+    Ok(())
+}
+
+/// Move every reader's position in a document to where it is in new content
+fn relocate_progress(
+    conn: &Connection,
+    document_id: i64,
+    previous: &[StoredChapter],
+    content: &EpubContent,
+) -> Result<()> {
+    let previous: Vec<&str> = previous
+        .iter()
+        .map(|chapter| chapter.text.as_str())
+        .collect();
+    let texts: Vec<&str> = content
+        .chapters
+        .iter()
+        .map(|chapter| chapter.content.as_str())
+        .collect();
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {PROGRESS_COLUMNS} FROM reading_progress WHERE document_id = ?1"
+    ))?;
+    let rows = stmt
+        .query_map(params![document_id], progress)?
+        .collect::<Result<Vec<_>>>()?;
+
+    for mut progress in rows {
+        progress.relocate(&previous, &texts);
+        conn.execute(
+            "UPDATE reading_progress SET chapter_index = ?3, char_offset = ?4, audio_position = ?5
+             WHERE user_id IS ?1 AND document_id = ?2",
+            params![
+                progress.user_id,
+                document_id,
+                progress.chapter_index as i64,
+                progress.char_offset as i64,
+                progress.audio_position,
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Number of chapters, resources and TOC entries stored for a document
+pub fn content_counts(conn: &Connection, id: i64) -> Result<ContentCounts> {
+    conn.query_row(
+        "SELECT
+            (SELECT COUNT(*) FROM chapters WHERE document_id = ?1),
+            (SELECT COUNT(*) FROM resources WHERE document_id = ?1),
+            (SELECT COUNT(*) FROM toc_entries WHERE document_id = ?1)",
+        params![id],
+        |row| {
+            Ok(ContentCounts {
+                chapters: row.get::<_, i64>(0)? as usize,
+                resources: row.get::<_, i64>(1)? as usize,
+                toc_entries: row.get::<_, i64>(2)? as usize,
+            })
+        },
+    )
+}
+
+/// Version of the parser a document was last parsed with; 0 for documents stored before
+/// versions were recorded
+pub fn parser_version(conn: &Connection, id: i64) -> Result<i64> {
+    conn.query_row(
+        "SELECT parser_version FROM documents WHERE id = ?1",
+        params![id],
+        |row| row.get(0),
+    )
+}
+
+/// Ids of all documents, or only of those parsed with an older parser than `version`
+pub fn document_ids(conn: &Connection, parsed_before: Option<i64>) -> Result<Vec<i64>> {
+    let mut stmt =
+        conn.prepare("SELECT id FROM documents WHERE parser_version < ?1 ORDER BY id")?;
+    let ids = stmt
+        .query_map(params![parsed_before.unwrap_or(i64::MAX)], |row| row.get(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+/// Apply metadata changes to a document and return the updated document
+///
+/// Changing the language re-stems the document's full-text index entries.
+pub fn update_document(conn: &Connection, id: i64, update: &DocumentUpdate) -> Result<Document> {
+    let tx = conn.unchecked_transaction()?;
+    let current = get_document(&tx, id)?.metadata;
+
+    let language = match &update.language {
+        Some(language) => language.clone(),
+        None => current.language.clone(),
+    };
+    let pick = |field: &Option<Option<String>>, current: Option<String>| match field {
+        Some(value) => value.clone(),
+        None => current,
+    };
+
+    tx.execute(
+        "UPDATE documents SET title = ?2, author = ?3, publication_date = ?4, language = ?5,
+                description = ?6, series = ?7, series_index = ?8
+         WHERE id = ?1",
+        params![
+            id,
+            update.title.as_ref().unwrap_or(&current.title),
+            update.author.as_ref().unwrap_or(&current.author),
+            pick(&update.publication_date, current.publication_date),
+            language,
+            pick(&update.description, current.description),
+            pick(&update.series, current.series),
+            update.series_index.unwrap_or(current.series_index),
+        ],
+    )?;
+
+    if language != current.language {
+        index_chapters(&tx, id, language.as_deref())?;
+    }
+
+    let document = get_document(&tx, id)?;
+    tx.commit()?;
+    Ok(document)
+}
+
+/// Rebuild the full-text index entries of a document's chapters
+pub fn index_chapters(conn: &Connection, document_id: i64, language: Option<&str>) -> Result<()> {
+    let analyzer = Analyzer::for_language(language);
+    let chapters = {
+        let mut stmt =
+            conn.prepare("SELECT id, title, text FROM chapters WHERE document_id = ?1")?;
+        let chapters = stmt
+            .query_map(params![document_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        chapters
+    };
+
+    let mut delete_stmt = conn.prepare("DELETE FROM chapter_search WHERE rowid = ?1")?;
+    let mut insert_stmt = conn.prepare(
+        "INSERT INTO chapter_search (rowid, title, body, stemming) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (id, title, text) in chapters {
+        delete_stmt.execute(params![id])?;
+        insert_stmt.execute(params![
+            id,
+            analyzer.index_text(&title),
+            analyzer.index_text(&text),
+            analyzer.stemming_name()
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Remove a document with everything stored for it
+///
+/// Chapters, resources, TOC entries and tags go with it through `ON DELETE CASCADE`, and
+/// the full-text index entries through the chapter delete trigger. Fails with
+/// `QueryReturnedNoRows` if there is no such document.
+pub fn delete_document(conn: &Connection, id: i64) -> Result<()> {
+    match conn.execute("DELETE FROM documents WHERE id = ?1", params![id])? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+/// The file a document was uploaded as; documents stored before originals were kept have none
+pub fn get_original(conn: &Connection, id: i64) -> Result<OriginalFile> {
+    conn.query_row(
+        "SELECT d.original_filename, o.media_type, o.data
+         FROM documents d JOIN originals o ON o.sha256 = d.original_sha256
+         WHERE d.id = ?1",
+        params![id],
+        |row| {
+            Ok(OriginalFile {
+                filename: row.get(0)?,
+                media_type: row.get(1)?,
+                data: row.get(2)?,
+            })
+        },
+    )
+}
+
+pub fn get_document(conn: &Connection, id: i64) -> Result<Document> {
+    let mut document = conn.query_row(
+        "SELECT id, title, author, publication_date, language, description, series, series_index
+         FROM documents WHERE id = ?1",
+        params![id],
+        |row| {
+            let mut metadata = EpubMetadata::new(
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            );
+            metadata.series = row.get(6)?;
+            metadata.series_index = row.get(7)?;
+
+            Ok(Document {
+                id: row.get(0)?,
+                metadata,
+            })
+        },
+    )?;
+    document.metadata.tags = get_tags(conn, id)?;
 
     Ok(document)
 }
 
-pub fn get_chapter_html_by_index(id: i64, index: usize) -> Result<String> {
-    let document = get_document(id)?;
+/// Tags of a document, alphabetically
+pub fn get_tags(conn: &Connection, document_id: i64) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT tag FROM document_tags WHERE document_id = ?1 ORDER BY tag COLLATE NOCASE",
+    )?;
+    let tags = stmt
+        .query_map(params![document_id], |row| row.get(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(tags)
+}
+
+/// `text` with LIKE's wildcards escaped, to match it literally with `ESCAPE '\'`
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
 
-    // Parse chapters_html as an array
-    let binding =
-        serde_json::from_str::<serde_json::Value>(&document.chapters_html).map_err(|e| {
-            rusqlite::Error::InvalidParameterName(format!("Invalid chapters_html JSON: {}", e))
-        })?;
-    let chaptersObject = binding.as_object().ok_or_else(|| {
-        rusqlite::Error::InvalidParameterName("chapters_html is not an object".into())
-    })?;
+/// One page of the library, with the cursor of the next page if there is one
+pub fn list_documents(
+    conn: &Connection,
+    filter: &DocumentFilter,
+    sort: DocumentSort,
+    order: SortOrder,
+    after: Option<&ListCursor>,
+    limit: usize,
+) -> Result<(Vec<DocumentSummary>, Option<ListCursor>)> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
 
-    let chapters = chaptersObject
-        .get("chapters")
-        .and_then(|v| v.as_array())
-        .ok_or_else(|| rusqlite::Error::InvalidParameterName("chapters is not an array".into()))?;
+    if let Some(language) = &filter.language {
+        conditions.push("lower(d.language) LIKE lower(?) || '%' ESCAPE '\\'".to_string());
+        values.push(Box::new(escape_like(language)));
+    }
+    if let Some(author) = &filter.author {
+        conditions.push("d.author = ? COLLATE NOCASE".to_string());
+        values.push(Box::new(author.clone()));
+    }
+    if let Some(tag) = &filter.tag {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM document_tags t WHERE t.document_id = d.id AND t.tag = ?)"
+                .to_string(),
+        );
+        values.push(Box::new(tag.clone()));
+    }
+    if let Some(format) = &filter.format {
+        conditions.push("d.format = ? COLLATE NOCASE".to_string());
+        values.push(Box::new(format.clone()));
+    }
+    if let Some(collection) = filter.collection {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM collection_documents cd
+                     WHERE cd.document_id = d.id AND cd.collection_id = ?)"
+                .to_string(),
+        );
+        values.push(Box::new(collection));
+    }
+    if let Some(user_id) = filter.visible_to {
+        conditions.push("(d.owner_id IS NULL OR d.owner_id = ? OR d.shared = 1)".to_string());
+        values.push(Box::new(user_id));
+    }
 
-    // Check if index is valid
-    if index >= chapters.len() {
-        return Err(rusqlite::Error::QueryReturnedNoRows);
+    let column = sort_column(sort);
+    let (direction, comparison) = match order {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    // Ties on the sort column are broken by id, so every document has a unique position
+    if let Some(cursor) = after {
+        conditions.push(format!("({}, d.id) {} (?, ?)", column, comparison));
+        values.push(Box::new(cursor.key.clone()));
+        values.push(Box::new(cursor.id));
     }
 
-    // Get the HTML text of the chapter at the given index
-    let chapter_html = chapters[index]["content"]
-        .as_str()
-        .ok_or_else(|| rusqlite::Error::InvalidParameterName("Invalid chapter content".into()))?;
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    // One extra row tells whether there is a next page
+    values.push(Box::new(limit as i64 + 1));
+
+    let sql = format!(
+        "SELECT {SUMMARY_COLUMNS}, {column}
+         FROM documents d
+         {where_clause}
+         ORDER BY {column} {direction}, d.id {direction}
+         LIMIT ?"
+    );
 
-    Ok(chapter_html.to_string())
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt
+        .query_map(params_from_iter(values.iter()), |row| {
+            Ok((document_summary(row)?, sort_key(row.get(10)?)))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    let next = if rows.len() > limit {
+        rows.truncate(limit);
+        rows.last().map(|(document, key)| ListCursor {
+            key: key.clone(),
+            id: document.id,
+        })
+    } else {
+        None
+    };
+
+    let mut documents = Vec::with_capacity(rows.len());
+    for (mut document, _) in rows {
+        document.tags = get_tags(conn, document.id)?;
+        documents.push(document);
+    }
+
+    Ok((documents, next))
+}
+
+/// Columns read by [`document_summary`], for a query over `documents d`
+const SUMMARY_COLUMNS: &str = "d.id, d.title, d.author, d.publication_date, d.language, d.format,
+    d.created_at, (SELECT COUNT(*) FROM chapters c WHERE c.document_id = d.id),
+    d.series, d.series_index";
+
+fn document_summary(row: &Row) -> Result<DocumentSummary> {
+    Ok(DocumentSummary {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        author: row.get(2)?,
+        publication_date: row.get(3)?,
+        language: row.get(4)?,
+        format: row.get(5)?,
+        uploaded_at: row.get(6)?,
+        chapter_count: row.get::<_, i64>(7)? as usize,
+        series: row.get(8)?,
+        series_index: row.get(9)?,
+        tags: Vec::new(),
+    })
+}
+
+fn sort_key(value: Value) -> SortKey {
+    match value {
+        Value::Integer(value) => SortKey::Integer(value),
+        Value::Text(value) => SortKey::Text(value),
+        other => SortKey::Text(format!("{:?}", other)),
+    }
+}
+
+/// Replace the tags of a document and return them, alphabetically
+pub fn set_tags(conn: &Connection, id: i64, tags: &[String]) -> Result<Vec<String>> {
+    let tx = conn.unchecked_transaction()?;
+    // Fails with not found for unknown documents
+    get_document(&tx, id)?;
+
+    tx.execute(
+        "DELETE FROM document_tags WHERE document_id = ?1",
+        params![id],
+    )?;
+    {
+        let mut stmt =
+            tx.prepare("INSERT OR IGNORE INTO document_tags (document_id, tag) VALUES (?1, ?2)")?;
+        for tag in tags {
+            stmt.execute(params![id, tag])?;
+        }
+    }
+
+    let tags = get_tags(&tx, id)?;
+    tx.commit()?;
+    Ok(tags)
+}
+
+/// Every tag of the documents `visible_to` may see, with the number of those documents that
+/// have it, alphabetically
+pub fn list_tags(conn: &Connection, visible_to: Option<i64>) -> Result<Vec<TagCount>> {
+    let mut stmt = conn.prepare(
+        "SELECT MIN(t.tag), COUNT(*)
+         FROM document_tags t JOIN documents d ON d.id = t.document_id
+         WHERE ?1 IS NULL OR d.owner_id IS NULL OR d.owner_id = ?1 OR d.shared = 1
+         GROUP BY t.tag ORDER BY t.tag",
+    )?;
+    let tags = stmt
+        .query_map(params![visible_to], |row| {
+            Ok(TagCount {
+                tag: row.get(0)?,
+                document_count: row.get::<_, i64>(1)? as usize,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(tags)
+}
+
+/// Turn the unique constraint on collection names into a conflict
+fn name_conflict(err: rusqlite::Error, name: &str) -> StoreError {
+    match err {
+        rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation => {
+            StoreError::Conflict(format!("A collection named {} already exists", name))
+        }
+        err => err.into(),
+    }
+}
+
+/// Counts only the documents `:visible_to` may see
+const COLLECTION_COLUMNS: &str = "c.id, c.name, c.description, c.created_at, c.owner_id,
+    (SELECT COUNT(*) FROM collection_documents cd JOIN documents d ON d.id = cd.document_id
+     WHERE cd.collection_id = c.id
+       AND (:visible_to IS NULL OR d.owner_id IS NULL OR d.owner_id = :visible_to
+            OR d.shared = 1))";
+
+fn collection(row: &Row) -> Result<Collection> {
+    Ok(Collection {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        created_at: row.get(3)?,
+        owner_id: row.get(4)?,
+        document_count: row.get::<_, i64>(5)? as usize,
+    })
+}
+
+pub fn create_collection(
+    conn: &Connection,
+    name: &str,
+    description: Option<&str>,
+    owner_id: Option<i64>,
+) -> Result<Collection> {
+    conn.execute(
+        "INSERT INTO collections (name, description, owner_id) VALUES (?1, ?2, ?3)",
+        params![name, description, owner_id],
+    )?;
+    get_collection(conn, conn.last_insert_rowid(), None)
+}
+
+/// All collections, alphabetically, counting the documents `visible_to` may see
+pub fn list_collections(conn: &Connection, visible_to: Option<i64>) -> Result<Vec<Collection>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {COLLECTION_COLUMNS} FROM collections c ORDER BY c.name, c.id"
+    ))?;
+    let collections = stmt
+        .query_map(named_params! { ":visible_to": visible_to }, collection)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(collections)
+}
+
+pub fn get_collection(conn: &Connection, id: i64, visible_to: Option<i64>) -> Result<Collection> {
+    conn.query_row(
+        &format!("SELECT {COLLECTION_COLUMNS} FROM collections c WHERE c.id = :id"),
+        named_params! { ":id": id, ":visible_to": visible_to },
+        collection,
+    )
+}
+
+pub fn update_collection(
+    conn: &Connection,
+    id: i64,
+    update: &CollectionUpdate,
+) -> Result<Collection> {
+    let current = get_collection(conn, id, None)?;
+    conn.execute(
+        "UPDATE collections SET name = ?2, description = ?3 WHERE id = ?1",
+        params![
+            id,
+            update.name.as_ref().unwrap_or(&current.name),
+            match &update.description {
+                Some(description) => description.as_ref(),
+                None => current.description.as_ref(),
+            },
+        ],
+    )?;
+    get_collection(conn, id, None)
+}
+
+/// Remove a collection; its documents stay in the library
+pub fn delete_collection(conn: &Connection, id: i64) -> Result<()> {
+    match conn.execute("DELETE FROM collections WHERE id = ?1", params![id])? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+/// Documents of a collection in their order within it
+pub fn collection_documents(conn: &Connection, id: i64) -> Result<Vec<DocumentSummary>> {
+    // Fails with not found for unknown collections
+    get_collection(conn, id, None)?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {SUMMARY_COLUMNS}
+         FROM collection_documents cd JOIN documents d ON d.id = cd.document_id
+         WHERE cd.collection_id = ?1
+         ORDER BY cd.position, cd.document_id"
+    ))?;
+    let mut documents = stmt
+        .query_map(params![id], document_summary)?
+        .collect::<Result<Vec<_>>>()?;
+    for document in &mut documents {
+        document.tags = get_tags(conn, document.id)?;
+    }
+
+    Ok(documents)
+}
+
+/// Ids of the documents of a collection, in order
+fn collection_document_ids(conn: &Connection, id: i64) -> Result<Vec<i64>> {
+    let mut stmt = conn.prepare(
+        "SELECT document_id FROM collection_documents
+         WHERE collection_id = ?1 ORDER BY position, document_id",
+    )?;
+    let ids = stmt
+        .query_map(params![id], |row| row.get(0))?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(ids)
+}
+
+/// Store the documents of a collection, numbering their positions from 0
+fn write_collection_documents(conn: &Connection, id: i64, document_ids: &[i64]) -> Result<()> {
+    conn.execute(
+        "DELETE FROM collection_documents WHERE collection_id = ?1",
+        params![id],
+    )?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO collection_documents (collection_id, document_id, position)
+         VALUES (?1, ?2, ?3)",
+    )?;
+    for (position, document_id) in document_ids.iter().enumerate() {
+        stmt.execute(params![id, document_id, position as i64])?;
+    }
+
+    Ok(())
+}
+
+/// Fail with [`StoreError::Invalid`] if there is no such document
+fn check_document_exists(conn: &Connection, document_id: i64) -> StoreResult<()> {
+    conn.query_row(
+        "SELECT 1 FROM documents WHERE id = ?1",
+        params![document_id],
+        |_| Ok(()),
+    )
+    .optional()?
+    .ok_or_else(|| StoreError::Invalid(format!("Document not found: {}", document_id)))
+}
+
+/// Replace the documents of a collection with `document_ids`, in that order
+pub fn set_collection_documents(
+    conn: &Connection,
+    id: i64,
+    document_ids: &[i64],
+) -> StoreResult<()> {
+    let tx = conn.unchecked_transaction()?;
+    get_collection(&tx, id, None)?;
+
+    let mut seen = HashSet::new();
+    for &document_id in document_ids {
+        if !seen.insert(document_id) {
+            return Err(StoreError::Invalid(format!(
+                "Document {} is listed more than once",
+                document_id
+            )));
+        }
+        check_document_exists(&tx, document_id)?;
+    }
+    write_collection_documents(&tx, id, document_ids)?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Put a document at `position` in a collection, or at the end, moving it if it is already there
+pub fn add_to_collection(
+    conn: &Connection,
+    id: i64,
+    document_id: i64,
+    position: Option<usize>,
+) -> StoreResult<()> {
+    let tx = conn.unchecked_transaction()?;
+    get_collection(&tx, id, None)?;
+    check_document_exists(&tx, document_id)?;
+
+    let mut document_ids = collection_document_ids(&tx, id)?;
+    document_ids.retain(|&other| other != document_id);
+    let position = position
+        .unwrap_or(document_ids.len())
+        .min(document_ids.len());
+    document_ids.insert(position, document_id);
+    write_collection_documents(&tx, id, &document_ids)?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Take a document out of a collection; fails with `QueryReturnedNoRows` if it is not in it
+pub fn remove_from_collection(conn: &Connection, id: i64, document_id: i64) -> Result<()> {
+    match conn.execute(
+        "DELETE FROM collection_documents WHERE collection_id = ?1 AND document_id = ?2",
+        params![id, document_id],
+    )? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+const ANNOTATION_COLUMNS: &str = "id, document_id, kind, chapter_index, start_offset,
+    end_offset, quote, prefix, suffix, note, color, detached, created_at, updated_at, user_id";
+
+fn annotation(row: &Row) -> Result<Annotation> {
+    Ok(Annotation {
+        id: row.get(0)?,
+        document_id: row.get(1)?,
+        kind: row.get(2)?,
+        anchor: TextAnchor {
+            chapter_index: row.get::<_, i64>(3)? as usize,
+            start_offset: row.get::<_, i64>(4)? as usize,
+            end_offset: row.get::<_, i64>(5)? as usize,
+            quote: row.get(6)?,
+            prefix: row.get(7)?,
+            suffix: row.get(8)?,
+        },
+        note: row.get(9)?,
+        color: row.get(10)?,
+        detached: row.get(11)?,
+        created_at: row.get(12)?,
+        updated_at: row.get(13)?,
+        user_id: row.get(14)?,
+    })
+}
+
+pub fn create_annotation(
+    conn: &Connection,
+    document_id: i64,
+    annotation: &NewAnnotation,
+) -> Result<Annotation> {
+    // Fails with not found for unknown documents
+    get_document(conn, document_id)?;
+
+    let anchor = &annotation.anchor;
+    conn.execute(
+        "INSERT INTO annotations
+            (document_id, kind, chapter_index, start_offset, end_offset, quote, prefix, suffix,
+             note, color, user_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            document_id,
+            annotation.kind,
+            anchor.chapter_index as i64,
+            anchor.start_offset as i64,
+            anchor.end_offset as i64,
+            anchor.quote,
+            anchor.prefix,
+            anchor.suffix,
+            annotation.note,
+            annotation.color,
+            annotation.user_id,
+        ],
+    )?;
+    get_annotation(
+        conn,
+        document_id,
+        annotation.user_id,
+        conn.last_insert_rowid(),
+    )
+}
+
+/// Annotations of a document by every user in reading order
+pub fn list_annotations(conn: &Connection, document_id: i64) -> Result<Vec<Annotation>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {ANNOTATION_COLUMNS} FROM annotations
+         WHERE document_id = ?1
+         ORDER BY chapter_index, start_offset, end_offset, id"
+    ))?;
+    let annotations = stmt
+        .query_map(params![document_id], annotation)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(annotations)
+}
+
+/// One user's annotations of a document in reading order; `None` for those made without
+/// authentication
+pub fn user_annotations(
+    conn: &Connection,
+    document_id: i64,
+    user_id: Option<i64>,
+) -> Result<Vec<Annotation>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {ANNOTATION_COLUMNS} FROM annotations
+         WHERE document_id = ?1 AND user_id IS ?2
+         ORDER BY chapter_index, start_offset, end_offset, id"
+    ))?;
+    let annotations = stmt
+        .query_map(params![document_id, user_id], annotation)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(annotations)
+}
+
+pub fn get_annotation(
+    conn: &Connection,
+    document_id: i64,
+    user_id: Option<i64>,
+    id: i64,
+) -> Result<Annotation> {
+    conn.query_row(
+        &format!(
+            "SELECT {ANNOTATION_COLUMNS} FROM annotations
+             WHERE id = ?1 AND document_id = ?2 AND user_id IS ?3"
+        ),
+        params![id, document_id, user_id],
+        annotation,
+    )
+}
+
+pub fn update_annotation(
+    conn: &Connection,
+    document_id: i64,
+    user_id: Option<i64>,
+    id: i64,
+    update: &AnnotationUpdate,
+) -> Result<Annotation> {
+    let current = get_annotation(conn, document_id, user_id, id)?;
+    conn.execute(
+        "UPDATE annotations SET note = ?2, color = ?3, updated_at = strftime('%s', 'now')
+         WHERE id = ?1",
+        params![
+            id,
+            update.note.clone().unwrap_or(current.note),
+            update.color.clone().unwrap_or(current.color),
+        ],
+    )?;
+    get_annotation(conn, document_id, user_id, id)
+}
+
+pub fn delete_annotation(
+    conn: &Connection,
+    document_id: i64,
+    user_id: Option<i64>,
+    id: i64,
+) -> Result<()> {
+    match conn.execute(
+        "DELETE FROM annotations WHERE id = ?1 AND document_id = ?2 AND user_id IS ?3",
+        params![id, document_id, user_id],
+    )? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+const PROGRESS_COLUMNS: &str = "user_id, document_id, chapter_index, char_offset, audio_position,
+    percentage, device, updated_at";
+
+fn progress(row: &Row) -> Result<Progress> {
+    Ok(Progress {
+        user_id: row.get(0)?,
+        document_id: row.get(1)?,
+        chapter_index: row.get::<_, i64>(2)? as usize,
+        char_offset: row.get::<_, i64>(3)? as usize,
+        audio_position: row.get(4)?,
+        percentage: row.get(5)?,
+        device: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+/// `user_id` is `None` for the local reader
+pub fn get_progress(conn: &Connection, user_id: Option<i64>, document_id: i64) -> Result<Progress> {
+    conn.query_row(
+        &format!(
+            "SELECT {PROGRESS_COLUMNS} FROM reading_progress
+             WHERE user_id IS ?1 AND document_id = ?2"
+        ),
+        params![user_id, document_id],
+        progress,
+    )
+}
+
+/// A reader's progress in every document, most recently updated first
+pub fn list_progress(conn: &Connection, user_id: Option<i64>) -> Result<Vec<Progress>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {PROGRESS_COLUMNS} FROM reading_progress
+         WHERE user_id IS ?1
+         ORDER BY updated_at DESC, document_id"
+    ))?;
+    let progress = stmt
+        .query_map(params![user_id], progress)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(progress)
+}
+
+/// Save progress unless the stored progress is newer; returns whether it was saved
+pub fn save_progress(conn: &Connection, progress: &Progress) -> Result<bool> {
+    // Fails with not found for unknown documents
+    get_document(conn, progress.document_id)?;
+
+    let saved = conn.execute(
+        "INSERT INTO reading_progress
+            (user_id, document_id, chapter_index, char_offset, audio_position, percentage,
+             device, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+         ON CONFLICT (IFNULL(user_id, 0), document_id) DO UPDATE SET
+            chapter_index = excluded.chapter_index,
+            char_offset = excluded.char_offset,
+            audio_position = excluded.audio_position,
+            percentage = excluded.percentage,
+            device = excluded.device,
+            updated_at = excluded.updated_at
+         WHERE excluded.updated_at >= reading_progress.updated_at",
+        params![
+            progress.user_id,
+            progress.document_id,
+            progress.chapter_index as i64,
+            progress.char_offset as i64,
+            progress.audio_position,
+            progress.percentage,
+            progress.device,
+            progress.updated_at,
+        ],
+    )?;
+
+    Ok(saved > 0)
+}
+
+/// The oldest document `visible_to` may see whose original has KOReader's partial MD5 or
+/// file name MD5 `digest`
+pub fn find_by_digest(conn: &Connection, digest: &str, visible_to: Option<i64>) -> Result<i64> {
+    let by_content = conn
+        .query_row(
+            "SELECT d.id FROM documents d JOIN originals o ON o.sha256 = d.original_sha256
+             WHERE o.partial_md5 = ?1
+               AND (?2 IS NULL OR d.owner_id IS NULL OR d.owner_id = ?2 OR d.shared = 1)
+             ORDER BY d.id LIMIT 1",
+            params![digest, visible_to],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(id) = by_content {
+        return Ok(id);
+    }
+
+    // File name digests cannot be indexed without storing them, and libraries are small
+    let mut stmt = conn.prepare(
+        "SELECT d.id, d.original_filename FROM documents d
+         WHERE d.original_filename IS NOT NULL
+           AND (?1 IS NULL OR d.owner_id IS NULL OR d.owner_id = ?1 OR d.shared = 1)
+         ORDER BY d.id",
+    )?;
+    let mut rows = stmt.query(params![visible_to])?;
+    while let Some(row) = rows.next()? {
+        let filename: String = row.get(1)?;
+        if koreader::filename_md5(&filename) == digest {
+            return row.get(0);
+        }
+    }
+
+    Err(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// Add a KOReader account; returns false if the name is taken
+pub fn create_sync_user(conn: &Connection, user: &SyncUser) -> Result<bool> {
+    let created = conn.execute(
+        "INSERT OR IGNORE INTO koreader_users (username, salt, key_hash, user_id)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![user.username, user.salt, user.key_hash, user.user_id],
+    )?;
+    Ok(created > 0)
+}
+
+pub fn get_sync_user(conn: &Connection, username: &str) -> Result<SyncUser> {
+    conn.query_row(
+        "SELECT username, salt, key_hash, user_id FROM koreader_users WHERE username = ?1",
+        params![username],
+        |row| {
+            Ok(SyncUser {
+                username: row.get(0)?,
+                salt: row.get(1)?,
+                key_hash: row.get(2)?,
+                user_id: row.get(3)?,
+            })
+        },
+    )
+}
+
+pub fn link_sync_user(conn: &Connection, username: &str, user_id: i64) -> Result<()> {
+    match conn.execute(
+        "UPDATE koreader_users SET user_id = ?2 WHERE username = ?1",
+        params![username, user_id],
+    )? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+pub fn save_sync_progress(conn: &Connection, progress: &SyncProgress) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO koreader_progress
+            (username, document, progress, percentage, device, device_id, timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            progress.username,
+            progress.document,
+            progress.progress,
+            progress.percentage,
+            progress.device,
+            progress.device_id,
+            progress.timestamp,
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_sync_progress(
+    conn: &Connection,
+    username: &str,
+    document: &str,
+) -> Result<SyncProgress> {
+    conn.query_row(
+        "SELECT username, document, progress, percentage, device, device_id, timestamp
+         FROM koreader_progress WHERE username = ?1 AND document = ?2",
+        params![username, document],
+        |row| {
+            Ok(SyncProgress {
+                username: row.get(0)?,
+                document: row.get(1)?,
+                progress: row.get(2)?,
+                percentage: row.get(3)?,
+                device: row.get(4)?,
+                device_id: row.get(5)?,
+                timestamp: row.get(6)?,
+            })
+        },
+    )
+}
+
+pub fn document_access(conn: &Connection, id: i64) -> Result<DocumentAccess> {
+    conn.query_row(
+        "SELECT owner_id, shared FROM documents WHERE id = ?1",
+        params![id],
+        |row| {
+            Ok(DocumentAccess {
+                owner_id: row.get(0)?,
+                shared: row.get(1)?,
+            })
+        },
+    )
+}
+
+pub fn set_document_access(conn: &Connection, id: i64, access: &DocumentAccess) -> Result<()> {
+    match conn.execute(
+        "UPDATE documents SET owner_id = ?2, shared = ?3 WHERE id = ?1",
+        params![id, access.owner_id, access.shared],
+    )? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+const USER_COLUMNS: &str = "id, username, is_admin, created_at";
+
+fn user(row: &Row) -> Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        is_admin: row.get(2)?,
+        created_at: row.get(3)?,
+    })
+}
+
+pub fn create_user(
+    conn: &Connection,
+    username: &str,
+    password_hash: &str,
+    is_admin: bool,
+) -> Result<User> {
+    conn.execute(
+        "INSERT INTO users (username, password_hash, is_admin) VALUES (?1, ?2, ?3)",
+        params![username, password_hash, is_admin],
+    )?;
+    conn.query_row(
+        &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"),
+        params![conn.last_insert_rowid()],
+        user,
+    )
+}
+
+pub fn list_users(conn: &Connection) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {USER_COLUMNS} FROM users ORDER BY username COLLATE NOCASE"
+    ))?;
+    let users = stmt.query_map([], user)?.collect::<Result<Vec<_>>>()?;
+    Ok(users)
+}
+
+/// A user and their password hash; user names are matched ignoring case
+pub fn get_user_by_name(conn: &Connection, username: &str) -> Result<(User, String)> {
+    conn.query_row(
+        &format!("SELECT {USER_COLUMNS}, password_hash FROM users WHERE username = ?1"),
+        params![username],
+        |row| Ok((user(row)?, row.get(4)?)),
+    )
+}
+
+pub fn set_password(conn: &Connection, user_id: i64, password_hash: &str) -> Result<()> {
+    match conn.execute(
+        "UPDATE users SET password_hash = ?2 WHERE id = ?1",
+        params![user_id, password_hash],
+    )? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+/// Delete a user; their tokens, annotations, progress and KOReader accounts go with them
+pub fn delete_user(conn: &Connection, id: i64) -> Result<()> {
+    match conn.execute("DELETE FROM users WHERE id = ?1", params![id])? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+/// Give documents, collections and annotations without an owner, and the local reader's
+/// progress, to `user`; returns the number of documents given
+pub fn claim_unowned(conn: &Connection, user: &User) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let documents = tx.execute(
+        "UPDATE documents SET owner_id = ?1 WHERE owner_id IS NULL",
+        params![user.id],
+    )?;
+    tx.execute(
+        "UPDATE annotations SET user_id = ?1 WHERE user_id IS NULL",
+        params![user.id],
+    )?;
+    tx.execute(
+        "UPDATE collections SET owner_id = ?1 WHERE owner_id IS NULL",
+        params![user.id],
+    )?;
+    // Progress the user already has is newer than what was saved before accounts
+    tx.execute(
+        "UPDATE OR IGNORE reading_progress SET user_id = ?1 WHERE user_id IS NULL",
+        params![user.id],
+    )?;
+    tx.execute_batch("DELETE FROM reading_progress WHERE user_id IS NULL")?;
+    tx.commit()?;
+    Ok(documents)
+}
+
+const TOKEN_COLUMNS: &str = "t.id, t.user_id, t.name, t.scopes, t.created_at, t.last_used_at";
+
+fn api_token(row: &Row) -> Result<ApiToken> {
+    Ok(ApiToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        scopes: auth::parse_scopes(&row.get::<_, String>(3)?),
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+    })
+}
+
+pub fn create_token(
+    conn: &Connection,
+    user_id: i64,
+    name: &str,
+    scopes: &[Scope],
+    token_hash: &str,
+) -> Result<ApiToken> {
+    conn.execute(
+        "INSERT INTO api_tokens (user_id, name, token_hash, scopes) VALUES (?1, ?2, ?3, ?4)",
+        params![user_id, name, token_hash, auth::format_scopes(scopes)],
+    )?;
+    conn.query_row(
+        &format!("SELECT {TOKEN_COLUMNS} FROM api_tokens t WHERE t.id = ?1"),
+        params![conn.last_insert_rowid()],
+        api_token,
+    )
+}
+
+/// A user's tokens, newest first
+pub fn list_tokens(conn: &Connection, user_id: i64) -> Result<Vec<ApiToken>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {TOKEN_COLUMNS} FROM api_tokens t WHERE t.user_id = ?1 ORDER BY t.id DESC"
+    ))?;
+    let tokens = stmt
+        .query_map(params![user_id], api_token)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(tokens)
+}
+
+pub fn delete_token(conn: &Connection, user_id: i64, id: i64) -> Result<()> {
+    match conn.execute(
+        "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+        params![id, user_id],
+    )? {
+        0 => Err(rusqlite::Error::QueryReturnedNoRows),
+        _ => Ok(()),
+    }
+}
+
+pub fn authenticate_token(conn: &Connection, token_hash: &str) -> Result<(User, ApiToken)> {
+    let (user, token) = conn.query_row(
+        &format!(
+            "SELECT u.id, u.username, u.is_admin, u.created_at, {TOKEN_COLUMNS}
+             FROM api_tokens t JOIN users u ON u.id = t.user_id
+             WHERE t.token_hash = ?1"
+        ),
+        params![token_hash],
+        |row| {
+            let token = ApiToken {
+                id: row.get(4)?,
+                user_id: row.get(5)?,
+                name: row.get(6)?,
+                scopes: auth::parse_scopes(&row.get::<_, String>(7)?),
+                created_at: row.get(8)?,
+                last_used_at: row.get(9)?,
+            };
+            Ok((user(row)?, token))
+        },
+    )?;
+    conn.execute(
+        "UPDATE api_tokens SET last_used_at = strftime('%s', 'now') WHERE id = ?1",
+        params![token.id],
+    )?;
+    Ok((user, token))
+}
+
+/// Documents a user owns and the size of their originals; a file uploaded twice counts twice
+pub fn storage_usage(conn: &Connection, user_id: i64) -> Result<StorageUsage> {
+    conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(o.size), 0)
+         FROM documents d LEFT JOIN originals o ON o.sha256 = d.original_sha256
+         WHERE d.owner_id = ?1",
+        params![user_id],
+        |row| {
+            Ok(StorageUsage {
+                documents: row.get::<_, i64>(0)? as u64,
+                bytes: row.get::<_, i64>(1)? as u64,
+            })
+        },
+    )
+}
+
+pub fn audio_usage(conn: &Connection, user_id: i64, period: &str) -> Result<f64> {
+    let seconds = conn
+        .query_row(
+            "SELECT seconds FROM audio_usage WHERE user_id = ?1 AND period = ?2",
+            params![user_id, period],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(seconds.unwrap_or(0.0))
+}
+
+pub fn add_audio_usage(conn: &Connection, user_id: i64, period: &str, seconds: f64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO audio_usage (user_id, period, seconds) VALUES (?1, ?2, ?3)
+         ON CONFLICT (user_id, period) DO UPDATE SET seconds = seconds + excluded.seconds",
+        params![user_id, period, seconds],
+    )?;
+    Ok(())
+}
+
+/// All chapters of a document in reading order
+pub fn get_chapters(conn: &Connection, document_id: i64) -> Result<Vec<StoredChapter>> {
+    let mut stmt = conn.prepare(
+        "SELECT title, text FROM chapters
+         WHERE document_id = ?1 ORDER BY chapter_index",
+    )?;
+    let chapters = stmt
+        .query_map(params![document_id], |row| {
+            Ok(StoredChapter {
+                title: row.get(0)?,
+                text: row.get(1)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(chapters)
+}
+
+pub fn get_chapter_html_by_index(conn: &Connection, id: i64, index: usize) -> Result<String> {
+    conn.query_row(
+        "SELECT html FROM chapters WHERE document_id = ?1 AND chapter_index = ?2",
+        params![id, index as i64],
+        |row| row.get(0),
+    )
+}
+
+pub fn get_chapter_text_by_index(conn: &Connection, id: i64, index: usize) -> Result<String> {
+    conn.query_row(
+        "SELECT text FROM chapters WHERE document_id = ?1 AND chapter_index = ?2",
+        params![id, index as i64],
+        |row| row.get(0),
+    )
+}
+
+pub fn get_toc(conn: &Connection, id: i64) -> Result<Vec<TocEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT title, href, level, chapter_index FROM toc_entries
+         WHERE document_id = ?1 ORDER BY position",
+    )?;
+    let toc = stmt
+        .query_map(params![id], |row| {
+            Ok(TocEntry {
+                title: row.get(0)?,
+                href: row.get(1)?,
+                level: row.get::<_, i64>(2)? as usize,
+                chapter_index: row.get::<_, Option<i64>>(3)?.map(|index| index as usize),
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(toc)
+}
+
+/// Full-text search over all chapters, best matches first
+///
+/// Chapters of books whose language starts with `language` are ranked ahead of the rest.
+/// Returns the total number of matching chapters and the requested page of hits.
+pub fn search_chapters(
+    conn: &Connection,
+    query: &Query,
+    language: Option<&str>,
+    visible_to: Option<i64>,
+    limit: usize,
+    offset: usize,
+) -> Result<(usize, Vec<ChapterHit>)> {
+    let fts_query = query.to_fts();
+
+    let total: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM chapter_search
+         JOIN chapters c ON c.id = chapter_search.rowid
+         JOIN documents d ON d.id = c.document_id
+         WHERE chapter_search MATCH ?1
+            AND (?2 IS NULL OR d.owner_id IS NULL OR d.owner_id = ?2 OR d.shared = 1)",
+        params![fts_query, visible_to],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT c.document_id, d.title, d.author, d.language, c.chapter_index, c.title, c.text,
+                bm25(chapter_search, 2.0, 1.0, 0.0) AS score
+         FROM chapter_search
+         JOIN chapters c ON c.id = chapter_search.rowid
+         JOIN documents d ON d.id = c.document_id
+         WHERE chapter_search MATCH ?1
+            AND (?5 IS NULL OR d.owner_id IS NULL OR d.owner_id = ?5 OR d.shared = 1)
+         ORDER BY CASE WHEN lower(d.language) LIKE lower(?2) || '%' ESCAPE '\\' THEN 0 ELSE 1 END,
+            score
+         LIMIT ?3 OFFSET ?4",
+    )?;
+    let hits = stmt
+        .query_map(
+            params![
+                fts_query,
+                language.map(escape_like),
+                limit as i64,
+                offset as i64,
+                visible_to
+            ],
+            |row| {
+                Ok(ChapterHit {
+                    document_id: row.get(0)?,
+                    document_title: row.get(1)?,
+                    author: row.get(2)?,
+                    language: row.get(3)?,
+                    chapter_index: row.get::<_, i64>(4)? as usize,
+                    chapter_title: row.get(5)?,
+                    text: row.get(6)?,
+                    score: row.get(7)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((total as usize, hits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::metadata::{Chapter, Resource};
+    use crate::services::store;
+    use std::sync::Arc;
+
+    fn sample_content() -> EpubContent {
+        EpubContent {
+            metadata: EpubMetadata::new(
+                "Moby-Dick".to_string(),
+                "Herman Melville".to_string(),
+                None,
+                Some("en-US".to_string()),
+                None,
+            ),
+            chapters: vec![
+                Chapter {
+                    title: "Chapter 1".to_string(),
+                    path: "c1".to_string(),
+                    content: "Call me Ishmael.".to_string(),
+                    html: "<p>Call me Ishmael.</p>".to_string(),
+                },
+                Chapter {
+                    title: "Chapter 2".to_string(),
+                    path: "c2".to_string(),
+                    content: "I stuffed a shirt or two.".to_string(),
+                    html: "<p>I stuffed a shirt or two.</p>".to_string(),
+                },
+            ],
+            resources: vec![Resource {
+                path: "OEBPS/image/cover.png".to_string(),
+                media_type: "image/png".to_string(),
+                data: vec![1, 2, 3],
+            }],
+            toc: vec![TocEntry {
+                title: "Loomings".to_string(),
+                href: "OEBPS/c1.xhtml#loomings".to_string(),
+                level: 0,
+                chapter_index: Some(0),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_document() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        configure_connection(&mut conn).unwrap();
+        migrations::run(&mut conn).unwrap();
+
+        let id = save_document(&conn, &sample_content(), None).unwrap();
+
+        let document = get_document(&conn, id).unwrap();
+        assert_eq!(document.metadata.title, "Moby-Dick");
+        assert_eq!(document.metadata.language.as_deref(), Some("en-US"));
+
+        let chapters = get_chapters(&conn, id).unwrap();
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].text, "I stuffed a shirt or two.");
+
+        assert_eq!(
+            get_chapter_html_by_index(&conn, id, 0).unwrap(),
+            "<p>Call me Ishmael.</p>"
+        );
+        assert_eq!(
+            get_chapter_text_by_index(&conn, id, 0).unwrap(),
+            "Call me Ishmael."
+        );
+        assert!(matches!(
+            get_chapter_html_by_index(&conn, id, 2),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+
+        let toc = get_toc(&conn, id).unwrap();
+        assert_eq!(toc[0].chapter_index, Some(0));
+    }
+
+    #[test]
+    fn test_search_chapters_matches_stemmed_terms() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        configure_connection(&mut conn).unwrap();
+        migrations::run(&mut conn).unwrap();
+        let id = save_document(&conn, &sample_content(), None).unwrap();
+
+        let (total, hits) =
+            search_chapters(&conn, &Query::parse("shirts"), Some("en"), None, 10, 0).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(hits[0].document_id, id);
+        assert_eq!(hits[0].chapter_index, 1);
+
+        let (total, _) =
+            search_chapters(&conn, &Query::parse("queequeg"), None, None, 10, 0).unwrap();
+        assert_eq!(total, 0);
+
+        // "whales" stems to "whale" in English only, so a Russian book mentioning a
+        // "whale" does not match, just as no snippet could be found in it
+        let mut russian = sample_content();
+        russian.metadata.language = Some("ru".to_string());
+        russian.chapters[0].content = "Кит, он же whale.".to_string();
+        save_document(&conn, &russian, None).unwrap();
+        let (total, hits) =
+            search_chapters(&conn, &Query::parse("whales"), None, None, 10, 0).unwrap();
+        assert_eq!(total, 0);
+        assert!(hits.is_empty());
+        let (total, hits) =
+            search_chapters(&conn, &Query::parse("whale"), None, None, 10, 0).unwrap();
+        assert_eq!(total, 1);
+        assert_ne!(hits[0].document_id, id);
+    }
+
+    #[test]
+    fn test_list_documents_pages_with_cursor() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        configure_connection(&mut conn).unwrap();
+        migrations::run(&mut conn).unwrap();
+
+        for (title, tag) in [("b", "Sea"), ("A", "Sea"), ("c", "Russia")] {
+            let mut content = sample_content();
+            content.metadata.title = title.to_string();
+            content.metadata.tags = vec![tag.to_string()];
+            save_document(&conn, &content, None).unwrap();
+        }
+
+        let filter = DocumentFilter::default();
+        let (first, next) =
+            list_documents(&conn, &filter, DocumentSort::Title, SortOrder::Asc, None, 2).unwrap();
+        let titles: Vec<&str> = first.iter().map(|d| d.title.as_str()).collect();
+        assert_eq!(titles, vec!["A", "b"]);
+        assert_eq!(first[0].chapter_count, 2);
+        assert_eq!(first[0].format, "epub");
+
+        let (second, next) = list_documents(
+            &conn,
+            &filter,
+            DocumentSort::Title,
+            SortOrder::Asc,
+            next.as_ref(),
+            2,
+        )
+        .unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].title, "c");
+        assert!(next.is_none());
+
+        let filter = DocumentFilter {
+            tag: Some("sea".to_string()),
+            language: Some("en".to_string()),
+            ..Default::default()
+        };
+        let (sea, _) = list_documents(
+            &conn,
+            &filter,
+            DocumentSort::Uploaded,
+            SortOrder::Desc,
+            None,
+            10,
+        )
+        .unwrap();
+        let titles: Vec<&str> = sea.iter().map(|d| d.title.as_str()).collect();
+        assert_eq!(titles, vec!["A", "b"]);
+        assert_eq!(sea[0].tags, vec!["Sea"]);
+
+        // Wildcards in the language are taken literally
+        for language in ["%", "e_", "\\"] {
+            let filter = DocumentFilter {
+                language: Some(language.to_string()),
+                ..Default::default()
+            };
+            let (matched, _) = list_documents(
+                &conn,
+                &filter,
+                DocumentSort::Uploaded,
+                SortOrder::Desc,
+                None,
+                10,
+            )
+            .unwrap();
+            assert!(matched.is_empty(), "{}", language);
+        }
+    }
+
+    #[test]
+    fn test_update_and_delete_document() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        configure_connection(&mut conn).unwrap();
+        migrations::run(&mut conn).unwrap();
+        let id = save_document(&conn, &sample_content(), None).unwrap();
+
+        let update = DocumentUpdate {
+            title: Some("Moby Dick".to_string()),
+            language: Some(None),
+            series: Some(Some("Sea Stories".to_string())),
+            ..Default::default()
+        };
+        let document = update_document(&conn, id, &update).unwrap();
+        assert_eq!(document.metadata.title, "Moby Dick");
+        assert_eq!(document.metadata.author, "Herman Melville");
+        assert_eq!(document.metadata.language, None);
+        assert_eq!(document.metadata.series.as_deref(), Some("Sea Stories"));
+        // Without a language the index keeps words as they are instead of English stems
+        let body: String = conn
+            .query_row(
+                "SELECT body FROM chapter_search WHERE body LIKE '%shirt%'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(body, "i stuffed a shirt or two");
+
+        delete_document(&conn, id).unwrap();
+        assert!(matches!(
+            get_document(&conn, id),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+        let (total, _) = search_chapters(&conn, &Query::parse("shirt"), None, None, 10, 0).unwrap();
+        assert_eq!(total, 0);
+        let resources: i64 = conn
+            .query_row("SELECT COUNT(*) FROM resources", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(resources, 0);
+        assert!(matches!(
+            delete_document(&conn, id),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+    }
+
+    #[test]
+    fn test_originals_are_shared_until_last_document_is_deleted() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        configure_connection(&mut conn).unwrap();
+        migrations::run(&mut conn).unwrap();
+
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: b"PK not really a zip".to_vec(),
+        };
+        let first = save_document(&conn, &sample_content(), Some(&original)).unwrap();
+        let second = save_document(&conn, &sample_content(), Some(&original)).unwrap();
+        let without = save_document(&conn, &sample_content(), None).unwrap();
+
+        let count_originals = |conn: &Connection| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM originals", [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(count_originals(&conn), 1);
+        assert_eq!(get_original(&conn, second).unwrap().data, original.data);
+        assert!(matches!(
+            get_original(&conn, without),
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        ));
+
+        delete_document(&conn, first).unwrap();
+        assert_eq!(
+            get_original(&conn, second).unwrap().filename,
+            "moby-dick.epub"
+        );
+        delete_document(&conn, second).unwrap();
+        assert_eq!(count_originals(&conn), 0);
+    }
+
+    #[test]
+    fn test_collections_keep_order_and_filter_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let ids: Vec<i64> = (0..3)
+            .map(|_| store.save_document(&sample_content(), None).unwrap())
+            .collect();
+
+        let course = store
+            .create_collection("Course 101", Some("Week one"), None)
+            .unwrap();
+        assert!(matches!(
+            store.create_collection("course 101", None, None),
+            Err(StoreError::Conflict(_))
+        ));
+
+        store
+            .set_collection_documents(course.id, &[ids[2], ids[0]])
+            .unwrap();
+        store.add_to_collection(course.id, ids[1], Some(0)).unwrap();
+        // Adding a member again moves it
+        store.add_to_collection(course.id, ids[2], None).unwrap();
+        let order = |store: &SqliteStore| -> Vec<i64> {
+            store
+                .collection_documents(course.id)
+                .unwrap()
+                .iter()
+                .map(|document| document.id)
+                .collect()
+        };
+        assert_eq!(order(&store), vec![ids[1], ids[0], ids[2]]);
+        assert!(matches!(
+            store.set_collection_documents(course.id, &[ids[0], ids[0]]),
+            Err(StoreError::Invalid(_))
+        ));
+        assert!(matches!(
+            store.add_to_collection(course.id, 999, None),
+            Err(StoreError::Invalid(_))
+        ));
+
+        let filter = DocumentFilter {
+            collection: Some(course.id),
+            ..Default::default()
+        };
+        store.remove_from_collection(course.id, ids[0]).unwrap();
+        store.delete_document(ids[1]).unwrap();
+        let (listed, _) = store
+            .list_documents(&filter, DocumentSort::Uploaded, SortOrder::Asc, None, 10)
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, ids[2]);
+        assert_eq!(
+            store
+                .get_collection(course.id, None)
+                .unwrap()
+                .document_count,
+            1
+        );
+
+        // Other users' private documents are not counted
+        let alice = store.create_user("alice", "hash", false).unwrap();
+        let bob = store.create_user("bob", "hash", false).unwrap();
+        let access = DocumentAccess {
+            owner_id: Some(alice.id),
+            shared: false,
+        };
+        store.set_document_access(ids[2], &access).unwrap();
+        let count = |user: &User| store.list_collections(Some(user.id)).unwrap()[0].document_count;
+        assert_eq!(count(&alice), 1);
+        assert_eq!(count(&bob), 0);
+
+        store.delete_collection(course.id).unwrap();
+        assert!(store.get_document(ids[2]).is_ok());
+        assert!(store
+            .get_collection(course.id, None)
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    #[test]
+    fn test_set_tags_replaces_parsed_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let mut content = sample_content();
+        content.metadata.tags = vec!["Whaling".to_string()];
+        let first = store.save_document(&content, None).unwrap();
+        let second = store.save_document(&content, None).unwrap();
+
+        let tags = store
+            .set_tags(
+                first,
+                &["sea".to_string(), "Course".to_string(), "Sea".to_string()],
+            )
+            .unwrap();
+        assert_eq!(tags, vec!["Course", "sea"]);
+
+        let counts = |visible_to| -> Vec<(String, usize)> {
+            store
+                .list_tags(visible_to)
+                .unwrap()
+                .into_iter()
+                .map(|tag| (tag.tag, tag.document_count))
+                .collect()
+        };
+        assert_eq!(
+            counts(None),
+            vec![
+                ("Course".to_string(), 1),
+                ("sea".to_string(), 1),
+                ("Whaling".to_string(), 1)
+            ]
+        );
+
+        // Tags of other users' private documents are left out
+        let alice = store.create_user("alice", "hash", false).unwrap();
+        let bob = store.create_user("bob", "hash", false).unwrap();
+        let access = DocumentAccess {
+            owner_id: Some(alice.id),
+            shared: false,
+        };
+        store.set_document_access(first, &access).unwrap();
+        assert_eq!(counts(Some(alice.id)).len(), 3);
+        assert_eq!(counts(Some(bob.id)), vec![("Whaling".to_string(), 1)]);
+        assert!(store.set_tags(999, &[]).unwrap_err().is_not_found());
+        assert_eq!(
+            store.get_document(second).unwrap().metadata.tags,
+            vec!["Whaling"]
+        );
+    }
+
+    #[test]
+    fn test_annotations_follow_reparsed_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let id = store.save_document(&sample_content(), None).unwrap();
+        let chapters = store.get_chapters(id).unwrap();
+        let new_annotation = |chapter_index: usize, start, end| NewAnnotation {
+            user_id: None,
+            kind: AnnotationKind::Highlight,
+            anchor: annotations::anchor(&chapters[chapter_index].text, chapter_index, start, end)
+                .unwrap(),
+            note: None,
+            color: Some("yellow".to_string()),
+        };
+
+        let shirt = store
+            .create_annotation(id, &new_annotation(1, 12, 17))
+            .unwrap();
+        assert_eq!(shirt.anchor.quote, "shirt");
+        let ishmael = store
+            .create_annotation(id, &new_annotation(0, 8, 15))
+            .unwrap();
+        assert!(store
+            .create_annotation(999, &new_annotation(0, 8, 15))
+            .unwrap_err()
+            .is_not_found());
+
+        let mut content = sample_content();
+        content.chapters[0].content = "Call me Queequeg.".to_string();
+        content.chapters[1].content = "Next morning. I stuffed a shirt or two.".to_string();
+        store.replace_content(id, &content).unwrap();
+
+        let listed = store.list_annotations(id, None).unwrap();
+        let ids: Vec<i64> = listed.iter().map(|annotation| annotation.id).collect();
+        assert_eq!(ids, vec![ishmael.id, shirt.id]);
+        assert!(listed[0].detached);
+        assert!(!listed[1].detached);
+        assert_eq!(listed[1].anchor.start_offset, 26);
+        assert_eq!(listed[1].anchor.prefix, "Next morning. I stuffed a ");
+
+        let update = AnnotationUpdate {
+            note: Some(Some("Packing".to_string())),
+            color: Some(None),
+        };
+        let updated = store
+            .update_annotation(id, None, shirt.id, &update)
+            .unwrap();
+        assert_eq!(updated.note.as_deref(), Some("Packing"));
+        assert_eq!(updated.color, None);
+        store.delete_annotation(id, None, ishmael.id).unwrap();
+        assert!(store
+            .delete_annotation(id, None, ishmael.id)
+            .unwrap_err()
+            .is_not_found());
+
+        store.delete_document(id).unwrap();
+        let remaining: i64 = store
+            .conn()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM annotations", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn test_progress_follows_reparsed_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let id = store.save_document(&sample_content(), None).unwrap();
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let progress = |user_id| Progress {
+            user_id,
+            document_id: id,
+            chapter_index: 1,
+            char_offset: 12,
+            audio_position: Some(30.0),
+            percentage: 0.8,
+            device: None,
+            updated_at: 1000,
+        };
+        store.save_progress(&progress(None)).unwrap();
+        store.save_progress(&progress(Some(ann.id))).unwrap();
+
+        // Both chapters merged into one: the position follows its text
+        let mut content = sample_content();
+        content.chapters.truncate(1);
+        content.chapters[0].content = "Call me Ishmael. I stuffed a shirt or two.".to_string();
+        store.replace_content(id, &content).unwrap();
+        for user_id in [None, Some(ann.id)] {
+            let moved = store.get_progress(user_id, id).unwrap();
+            assert_eq!((moved.chapter_index, moved.char_offset), (0, 29));
+            assert_eq!(moved.audio_position, None);
+            assert_eq!(moved.percentage, 0.8);
+        }
+
+        // The text is gone: the position is kept inside the last chapter
+        content.chapters[0].content = "Etymology.".to_string();
+        store.replace_content(id, &content).unwrap();
+        let clamped = store.get_progress(Some(ann.id), id).unwrap();
+        assert_eq!((clamped.chapter_index, clamped.char_offset), (0, 10));
+    }
+
+    #[test]
+    fn test_progress_last_writer_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let first = store.save_document(&sample_content(), None).unwrap();
+        let second = store.save_document(&sample_content(), None).unwrap();
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let progress = |document_id, chapter_index, device: &str, updated_at| Progress {
+            user_id: Some(ann.id),
+            document_id,
+            chapter_index,
+            char_offset: 10,
+            audio_position: None,
+            percentage: 0.5,
+            device: Some(device.to_string()),
+            updated_at,
+        };
+
+        assert!(store
+            .save_progress(&progress(first, 1, "phone", 2000))
+            .unwrap());
+        // An update made earlier but arriving later loses
+        assert!(!store
+            .save_progress(&progress(first, 0, "laptop", 1000))
+            .unwrap());
+        assert_eq!(
+            store.get_progress(Some(ann.id), first).unwrap(),
+            progress(first, 1, "phone", 2000)
+        );
+        assert!(store
+            .save_progress(&progress(first, 0, "laptop", 3000))
+            .unwrap());
+        assert!(store
+            .save_progress(&progress(second, 1, "phone", 2500))
+            .unwrap());
+
+        let documents: Vec<i64> = store
+            .list_progress(Some(ann.id))
+            .unwrap()
+            .into_iter()
+            .map(|progress| progress.document_id)
+            .collect();
+        assert_eq!(documents, vec![first, second]);
+        // The local reader keeps progress of their own
+        assert!(store.get_progress(None, first).unwrap_err().is_not_found());
+        let local = Progress {
+            user_id: None,
+            ..progress(first, 2, "laptop", 1000)
+        };
+        assert!(store.save_progress(&local).unwrap());
+        assert!(!store
+            .save_progress(&Progress {
+                updated_at: 500,
+                ..local.clone()
+            })
+            .unwrap());
+        assert_eq!(store.list_progress(None).unwrap(), vec![local]);
+        assert!(store
+            .save_progress(&progress(999, 0, "phone", 1000))
+            .unwrap_err()
+            .is_not_found());
+
+        store.delete_document(first).unwrap();
+        assert_eq!(store.list_progress(Some(ann.id)).unwrap().len(), 1);
+
+        // Progress goes with the account, not to the next one of the same name
+        store.delete_user(ann.id).unwrap();
+        let ann = store.create_user("ANN", "hash", false).unwrap();
+        assert!(store.list_progress(Some(ann.id)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_koreader_digests_find_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: (0..5000).map(|i| (i % 7) as u8).collect(),
+        };
+        store.save_document(&sample_content(), None).unwrap();
+        let id = store
+            .save_document(&sample_content(), Some(&original))
+            .unwrap();
+        let by_name = koreader::filename_md5("moby-dick.epub");
+
+        assert_eq!(
+            store.find_by_digest(&original.partial_md5(), None).unwrap(),
+            id
+        );
+        assert_eq!(store.find_by_digest(&by_name, None).unwrap(), id);
+        assert!(store
+            .find_by_digest("0123", None)
+            .unwrap_err()
+            .is_not_found());
+
+        // Private documents are only found for their owner
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let bob = store.create_user("bob", "hash", false).unwrap();
+        let private = DocumentAccess {
+            owner_id: Some(ann.id),
+            shared: false,
+        };
+        store.set_document_access(id, &private).unwrap();
+        assert_eq!(store.find_by_digest(&by_name, Some(ann.id)).unwrap(), id);
+        assert!(store
+            .find_by_digest(&original.partial_md5(), Some(bob.id))
+            .unwrap_err()
+            .is_not_found());
+        assert!(store
+            .find_by_digest(&by_name, Some(bob.id))
+            .unwrap_err()
+            .is_not_found());
+
+        let user = SyncUser::new("ann".to_string(), "5ebe2294ecd0e0f08eab7690d2a6ee69", None);
+        store.create_sync_user(&user).unwrap();
+        assert!(matches!(
+            store.create_sync_user(&user),
+            Err(StoreError::Conflict(_))
+        ));
+        assert!(store
+            .get_sync_user("ann")
+            .unwrap()
+            .verify("5ebe2294ecd0e0f08eab7690d2a6ee69"));
+
+        // Linked accounts go with their user
+        store.link_sync_user("ann", bob.id).unwrap();
+        assert_eq!(store.get_sync_user("ann").unwrap().user_id, Some(bob.id));
+        assert!(store
+            .link_sync_user("carol", bob.id)
+            .unwrap_err()
+            .is_not_found());
+        store.delete_user(bob.id).unwrap();
+        assert!(store.get_sync_user("ann").unwrap_err().is_not_found());
+    }
+
+    #[test]
+    fn test_users_tokens_and_document_owners() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let unowned = store.save_document(&sample_content(), None).unwrap();
+        let private = store.save_document(&sample_content(), None).unwrap();
+        store
+            .save_progress(&Progress {
+                user_id: None,
+                document_id: unowned,
+                chapter_index: 0,
+                char_offset: 0,
+                audio_position: None,
+                percentage: 0.1,
+                device: None,
+                updated_at: 1000,
+            })
+            .unwrap();
+
+        let ann = store.create_user("ann", "hash", true).unwrap();
+        let bob = store.create_user("bob", "hash", false).unwrap();
+        assert!(matches!(
+            store.create_user("ANN", "hash", false),
+            Err(StoreError::Conflict(_))
+        ));
+        assert_eq!(store.get_user_by_name("Bob").unwrap().0.id, bob.id);
+
+        assert_eq!(store.claim_unowned(&ann).unwrap(), 2);
+        assert_eq!(
+            store
+                .get_progress(Some(ann.id), unowned)
+                .unwrap()
+                .percentage,
+            0.1
+        );
+        assert!(store.list_progress(None).unwrap().is_empty());
+        assert_eq!(
+            store.document_access(private).unwrap().owner_id,
+            Some(ann.id)
+        );
+
+        let shared = DocumentAccess {
+            owner_id: Some(ann.id),
+            shared: true,
+        };
+        store.set_document_access(unowned, &shared).unwrap();
+        let filter = DocumentFilter {
+            visible_to: Some(bob.id),
+            ..Default::default()
+        };
+        let (visible, _) = store
+            .list_documents(&filter, DocumentSort::Uploaded, SortOrder::Asc, None, 10)
+            .unwrap();
+        assert_eq!(visible.len(), 1);
+        assert_eq!(visible[0].id, unowned);
+        let (total, _) = store
+            .search_chapters(&Query::parse("ishmael"), None, Some(bob.id), 10, 0)
+            .unwrap();
+        assert_eq!(total, 1);
+
+        let token = store
+            .create_token(bob.id, "phone", &[Scope::Read], "token-hash")
+            .unwrap();
+        let (user, used) = store.authenticate_token("token-hash").unwrap();
+        assert_eq!(user.id, bob.id);
+        assert_eq!(used.scopes, vec![Scope::Read]);
+        assert!(store.list_tokens(bob.id).unwrap()[0].last_used_at.is_some());
+        assert!(store
+            .delete_token(ann.id, token.id)
+            .unwrap_err()
+            .is_not_found());
+
+        // Owners keep their account until their documents are gone
+        assert!(matches!(
+            store.delete_user(ann.id),
+            Err(StoreError::Conflict(_))
+        ));
+        store.delete_user(bob.id).unwrap();
+        assert!(store
+            .authenticate_token("token-hash")
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    #[test]
+    fn test_storage_and_audio_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(dir.path().join("test.db")).unwrap();
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let original = OriginalFile {
+            filename: "book.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: vec![0; 100],
+        };
+        let owned = store
+            .save_document(&sample_content(), Some(&original))
+            .unwrap();
+        store.save_document(&sample_content(), None).unwrap();
+        let access = DocumentAccess {
+            owner_id: Some(ann.id),
+            shared: false,
+        };
+        store.set_document_access(owned, &access).unwrap();
+
+        assert_eq!(
+            store.storage_usage(ann.id).unwrap(),
+            StorageUsage {
+                documents: 1,
+                bytes: 100
+            }
+        );
+
+        assert_eq!(store.audio_usage(ann.id, "2026-10").unwrap(), 0.0);
+        store.add_audio_usage(ann.id, "2026-10", 90.5).unwrap();
+        store.add_audio_usage(ann.id, "2026-10", 30.0).unwrap();
+        store.add_audio_usage(ann.id, "2026-11", 5.0).unwrap();
+        assert_eq!(store.audio_usage(ann.id, "2026-10").unwrap(), 120.5);
+    }
+
+    #[tokio::test]
+    async fn test_pool_runs_queries_in_wal_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = init_pool(dir.path().join("test.db")).unwrap();
+
+        let journal_mode: String = pool
+            .get()
+            .unwrap()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        let store: Arc<dyn DocumentStore> = Arc::new(SqliteStore::new(pool));
+        let id = store::run(&store, |store| store.save_document(&sample_content(), None))
+            .await
+            .unwrap();
+        let missing = store::run(&store, move |store| store.get_chapter_html(id, 5)).await;
+        assert!(missing.unwrap_err().is_not_found());
+    }
 }
@@ -1,11 +1,20 @@
-use crate::models::metadata::{Chapter, EpubMetadata};
-use epub::doc::EpubDoc;
+use crate::models::metadata::{Chapter, EpubMetadata, Resource, TocEntry};
+use epub::doc::{EpubDoc, NavPoint};
 use scraper::{Html, Selector};
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek};
+
+/// Version of what the parser extracts from a book, recorded with every document
+///
+/// Bump it whenever a parser change alters the stored chapters, resources, TOC or metadata,
+/// so documents parsed by an older version can be found and re-parsed.
+pub const PARSER_VERSION: i64 = 1;
 
 pub struct EpubContent {
     pub metadata: EpubMetadata,
     pub chapters: Vec<Chapter>,
+    pub resources: Vec<Resource>,
+    pub toc: Vec<TocEntry>,
 }
 
 /// Parse an EPUB file from bytes
@@ -14,6 +23,7 @@ pub struct EpubContent {
 /// 1. Metadata (title, author, etc.)
 /// 2. Chapter information
 /// 3. HTML content for each chapter
+/// 4. Packaged resources and the table of contents
 ///
 /// Returns an EpubContent struct with all extracted data
 pub fn parse_epub(data: &[u8]) -> Result<EpubContent, String> {
@@ -25,41 +35,32 @@ pub fn parse_epub(data: &[u8]) -> Result<EpubContent, String> {
         EpubDoc::from_reader(cursor).map_err(|e| format!("Failed to parse EPUB: {}", e))?;
 
     // Extract metadata from the EPUB document
-    let title = doc
-        .metadata
-        .get("title")
-        .and_then(|titles| titles.first())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "Unknown Title".to_string());
-
-    let author = doc
-        .metadata
-        .get("creator")
-        .and_then(|authors| authors.first())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "Unknown Author".to_string());
-
-    let publication_date = doc
-        .metadata
-        .get("date")
-        .and_then(|dates| dates.first())
-        .map(|s| s.to_string());
-
-    let language = doc
-        .metadata
-        .get("language")
-        .and_then(|langs| langs.first())
-        .map(|s| s.to_string());
-
-    let description = doc
-        .metadata
-        .get("description")
-        .and_then(|descs| descs.first())
-        .map(|s| s.to_string());
+    let title = first_metadata_value(&doc, "title").unwrap_or_else(|| "Unknown Title".to_string());
+
+    let author =
+        first_metadata_value(&doc, "creator").unwrap_or_else(|| "Unknown Author".to_string());
+
+    let publication_date = first_metadata_value(&doc, "date");
+
+    let language = first_metadata_value(&doc, "language");
+
+    let description = first_metadata_value(&doc, "description");
+
+    // Calibre's metadata, or the EPUB 3 collection it is a shorthand for
+    let series = first_metadata_value(&doc, "calibre:series")
+        .or_else(|| first_metadata_value(&doc, "belongs-to-collection"));
+    let series_index = first_metadata_value(&doc, "calibre:series_index")
+        .or_else(|| first_metadata_value(&doc, "group-position"))
+        .and_then(|index| index.parse::<f64>().ok());
+
+    let tags = metadata_values(&doc, "subject");
 
     // Extract chapters and HTML content
     let mut chapters = Vec::new();
 
+    // Archive path of each chapter, used to resolve TOC targets to chapter indexes
+    let mut chapter_paths = HashMap::new();
+
     //print chapter length
     println!("Number of chapters: {}", doc.spine.len());
 
@@ -74,17 +75,45 @@ pub fn parse_epub(data: &[u8]) -> Result<EpubContent, String> {
         if doc.set_current_page(i) {
             // Get content from current page
             if let Some(content) = doc.get_current_str() {
-                // print content
+                if let Some((path, _)) = doc.resources.get(&spine_id) {
+                    chapter_paths.insert(path.to_string_lossy().to_string(), chapters.len());
+                }
+
                 // Add this chapter to our list
                 chapters.push(Chapter {
                     title: chapter_title,
                     path: spine_id,
                     content: extract_text_from_html(&content.0),
+                    html: content.0,
                 });
             }
         }
     }
 
+    // Keep everything that is not a chapter so the HTML can be rendered later
+    let mut resource_ids: Vec<String> = doc
+        .resources
+        .keys()
+        .filter(|id| !doc.spine.contains(id))
+        .cloned()
+        .collect();
+    resource_ids.sort();
+
+    let mut resources = Vec::new();
+    for id in resource_ids {
+        let path = doc.resources[&id].0.to_string_lossy().to_string();
+        if let Some((data, media_type)) = doc.get_resource(&id) {
+            resources.push(Resource {
+                path,
+                media_type,
+                data,
+            });
+        }
+    }
+
+    let mut toc = Vec::new();
+    flatten_toc(&doc.toc, 0, &chapter_paths, &mut toc);
+
     // Return the parsed content
     Ok(EpubContent {
         metadata: EpubMetadata {
@@ -93,11 +122,63 @@ pub fn parse_epub(data: &[u8]) -> Result<EpubContent, String> {
             publication_date,
             language,
             description,
+            series,
+            series_index,
+            tags,
         },
         chapters,
+        resources,
+        toc,
     })
 }
 
+/// The cover image of an EPUB file with its media type, if the book names one
+pub fn parse_cover(data: &[u8]) -> Option<(Vec<u8>, String)> {
+    EpubDoc::from_reader(Cursor::new(data)).ok()?.get_cover()
+}
+
+/// Return the first non-empty value of a metadata field
+fn first_metadata_value<R: Read + Seek>(doc: &EpubDoc<R>, name: &str) -> Option<String> {
+    doc.metadata
+        .get(name)
+        .and_then(|values| values.iter().find(|value| !value.trim().is_empty()))
+        .map(|s| s.trim().to_string())
+}
+
+/// Return every distinct non-empty value of a metadata field, in document order
+fn metadata_values<R: Read + Seek>(doc: &EpubDoc<R>, name: &str) -> Vec<String> {
+    let mut values: Vec<String> = Vec::new();
+    for value in doc.metadata.get(name).into_iter().flatten() {
+        let value = value.trim();
+        if !value.is_empty() && !values.iter().any(|v| v == value) {
+            values.push(value.to_string());
+        }
+    }
+    values
+}
+
+/// Flatten the nested navigation points into a list of TOC entries in reading order
+fn flatten_toc(
+    nav_points: &[NavPoint],
+    level: usize,
+    chapter_paths: &HashMap<String, usize>,
+    toc: &mut Vec<TocEntry>,
+) {
+    for nav_point in nav_points {
+        let href = nav_point.content.to_string_lossy().to_string();
+        let path = href.split('#').next().unwrap_or(&href);
+
+        toc.push(TocEntry {
+            title: nav_point.label.trim().to_string(),
+            chapter_index: chapter_paths.get(path).copied(),
+            href,
+            level,
+        });
+
+        flatten_toc(&nav_point.children, level + 1, chapter_paths, toc);
+    }
+}
+
 /// Helper function to extract plain text content from HTML
 ///
 /// This function removes HTML tags and returns just the text content.
@@ -153,6 +234,13 @@ mod tests {
             !epub_content.metadata.author.is_empty(),
             "Author should not be empty"
         );
+        assert!(
+            epub_content
+                .toc
+                .iter()
+                .any(|entry| entry.chapter_index.is_some()),
+            "TOC entries should point into chapters"
+        );
 
         // Print some info about what we found
         println!("EPUB Title: {}", epub_content.metadata.title);
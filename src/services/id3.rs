@@ -0,0 +1,165 @@
+//! ID3v2.3 tags for MP3 audiobooks
+//!
+//! Besides the title, author and cover, the tag lists the chapters as in the ID3v2 Chapter
+//! Frame Addendum: a `CHAP` frame with the start and end of each chapter, and a `CTOC`
+//! frame putting them in order. Version 2.3 is the one every player reads.
+
+use crate::services::audiobook::{BookInfo, ChapterMark};
+
+/// Text encoding of frames: UTF-16 with a byte order mark, as 2.3 has no UTF-8
+const UTF16: u8 = 1;
+const LATIN1: u8 = 0;
+
+/// Picture type of a front cover
+const FRONT_COVER: u8 = 3;
+
+/// The table of contents lists at most this many chapters
+pub const MAX_CHAPTERS: usize = 255;
+
+/// A frame with its header
+fn frame(id: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(content.len() + 10);
+    frame.extend_from_slice(id);
+    frame.extend_from_slice(&(content.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(content);
+    frame
+}
+
+fn text_frame(id: &[u8; 4], text: &str) -> Vec<u8> {
+    let mut content = vec![UTF16, 0xff, 0xfe];
+    content.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    frame(id, &content)
+}
+
+/// The element id tying a chapter to the table of contents
+fn chapter_id(index: usize) -> String {
+    format!("ch{}", index)
+}
+
+/// A size in 7 bits per byte, so it never looks like the start of an MP3 frame
+fn syncsafe(size: u32) -> [u8; 4] {
+    [
+        (size >> 21) as u8 & 0x7f,
+        (size >> 14) as u8 & 0x7f,
+        (size >> 7) as u8 & 0x7f,
+        size as u8 & 0x7f,
+    ]
+}
+
+/// The tag for a book of `samples` at `sample_rate` with `marks` as its chapters; only the
+/// first [`MAX_CHAPTERS`] are listed
+pub fn tag(info: &BookInfo, marks: &[ChapterMark], sample_rate: u32, samples: u64) -> Vec<u8> {
+    let milliseconds = |samples: u64| (samples * 1000 / u64::from(sample_rate)) as u32;
+
+    let mut frames = vec![
+        text_frame(b"TIT2", &info.title),
+        text_frame(b"TALB", &info.title),
+        text_frame(b"TPE1", &info.author),
+        text_frame(b"TCON", "Audiobook"),
+    ];
+
+    if let Some(cover) = &info.cover {
+        let mut content = vec![LATIN1];
+        content.extend_from_slice(cover.media_type.as_bytes());
+        content.extend_from_slice(&[0, FRONT_COVER, 0]);
+        content.extend_from_slice(&cover.data);
+        frames.push(frame(b"APIC", &content));
+    }
+
+    let marks = &marks[..marks.len().min(MAX_CHAPTERS)];
+    if !marks.is_empty() {
+        let mut toc = b"toc\0".to_vec();
+        // Top level and ordered
+        toc.push(0x03);
+        toc.push(marks.len() as u8);
+        for index in 0..marks.len() {
+            toc.extend_from_slice(chapter_id(index).as_bytes());
+            toc.push(0);
+        }
+        frames.push(frame(b"CTOC", &toc));
+    }
+
+    let ends = marks.iter().skip(1).map(|mark| mark.start).chain([samples]);
+    for (index, (mark, end)) in marks.iter().zip(ends).enumerate() {
+        let mut content = chapter_id(index).into_bytes();
+        content.push(0);
+        content.extend_from_slice(&milliseconds(mark.start).to_be_bytes());
+        content.extend_from_slice(&milliseconds(end).to_be_bytes());
+        // Byte offsets are not used, times are
+        content.extend_from_slice(&[0xff; 8]);
+        content.extend(text_frame(b"TIT2", &mark.title));
+        frames.push(frame(b"CHAP", &content));
+    }
+
+    let frames = frames.concat();
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    tag.extend_from_slice(&syncsafe(frames.len() as u32));
+    tag.extend(frames);
+    tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The frames of a tag, or of a frame's subframes, by id
+    fn frames(mut data: &[u8]) -> Vec<(String, &[u8])> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+            let id = String::from_utf8(data[..4].to_vec()).unwrap();
+            frames.push((id, &data[10..10 + size]));
+            data = &data[10 + size..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_chapters_are_tagged() {
+        let info = BookInfo {
+            title: "Моби Дик".to_string(),
+            author: "Herman Melville".to_string(),
+            cover: None,
+        };
+        let marks = [
+            ChapterMark {
+                title: "Loomings".to_string(),
+                start: 0,
+            },
+            ChapterMark {
+                title: "The Carpet-Bag".to_string(),
+                start: 44100,
+            },
+        ];
+        let tag = tag(&info, &marks, 22050, 22050 * 5);
+
+        assert_eq!(&tag[..6], b"ID3\x03\x00\x00");
+        let size = tag[6..10]
+            .iter()
+            .fold(0, |size, &byte| (size << 7) | usize::from(byte));
+        assert_eq!(size, tag.len() - 10);
+
+        let frames = frames(&tag[10..]);
+        let ids: Vec<_> = frames.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(
+            ids,
+            ["TIT2", "TALB", "TPE1", "TCON", "CTOC", "CHAP", "CHAP"]
+        );
+
+        let title: Vec<u16> = frames[0].1[3..]
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&title).unwrap(), "Моби Дик");
+        assert_eq!(frames[4].1, b"toc\0\x03\x02ch0\0ch1\0");
+
+        // The second chapter runs from 2 s to the end at 5 s
+        let chapter = frames[6].1;
+        assert_eq!(&chapter[..4], b"ch1\0");
+        assert_eq!(&chapter[4..8], &2000u32.to_be_bytes());
+        assert_eq!(&chapter[8..12], &5000u32.to_be_bytes());
+        let subframes = super::tests::frames(&chapter[20..]);
+        assert_eq!(subframes[0].0, "TIT2");
+    }
+}
@@ -0,0 +1,152 @@
+use md5::{Digest, Md5};
+use serde::Serialize;
+use sha2::Sha256;
+
+/// Bytes KOReader hashes at each sampled offset of a file
+const SAMPLE_SIZE: usize = 1024;
+
+/// A KOReader sync account
+///
+/// KOReader never sends the password, only its MD5 as the user's key. The key is stored
+/// salted and hashed again so a leaked database does not give away working keys.
+#[derive(Debug, Clone)]
+pub struct SyncUser {
+    pub username: String,
+    pub salt: String,
+    pub key_hash: String,
+    /// The library user whose reading progress the account shares; unlinked accounts only
+    /// sync between KOReader devices
+    pub user_id: Option<i64>,
+}
+
+impl SyncUser {
+    /// A new account for the key KOReader sent on registration
+    pub fn new(username: String, key: &str, user_id: Option<i64>) -> Self {
+        let salt = uuid::Uuid::new_v4().simple().to_string();
+        let key_hash = hash_key(&salt, key);
+        SyncUser {
+            username,
+            salt,
+            key_hash,
+            user_id,
+        }
+    }
+
+    pub fn verify(&self, key: &str) -> bool {
+        let hash = hash_key(&self.salt, key);
+        // Compare every byte so the time taken does not tell how much of the key matched
+        hash.len() == self.key_hash.len()
+            && hash
+                .bytes()
+                .zip(self.key_hash.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+fn hash_key(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(key.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Reading position as KOReader reports it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncProgress {
+    #[serde(skip)]
+    pub username: String,
+    /// KOReader's digest of the book, see [`partial_md5`]
+    pub document: String,
+    /// An XPointer into the book for reflowable formats, a page number otherwise
+    pub progress: String,
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: i64,
+}
+
+/// KOReader's digest of a book file
+///
+/// The MD5 of 1 KiB samples taken at offsets 0, 1 KiB, 4 KiB, 16 KiB and so on, each four
+/// times the last, up to 1 GiB or the end of the file. It is what KOReader sends as the
+/// `document` of its progress by default.
+pub fn partial_md5(data: &[u8]) -> String {
+    let mut hasher = Md5::new();
+    let offsets = std::iter::once(0).chain((0..=10).map(|i| SAMPLE_SIZE << (2 * i)));
+    for offset in offsets {
+        if offset >= data.len() {
+            break;
+        }
+        hasher.update(&data[offset..(offset + SAMPLE_SIZE).min(data.len())]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// The key KOReader sends for a password, its MD5
+pub fn password_key(password: &str) -> String {
+    format!("{:x}", Md5::digest(password.as_bytes()))
+}
+
+/// KOReader's other document digest, the MD5 of the file name
+pub fn filename_md5(filename: &str) -> String {
+    format!("{:x}", Md5::digest(filename.as_bytes()))
+}
+
+/// The chapter an EPUB XPointer such as `/body/DocFragment[12]/body/p[3]/text().15` points
+/// into
+///
+/// `DocFragment` counts the spine items from 1, and chapters are the spine items in order.
+pub fn chapter_from_xpointer(xpointer: &str) -> Option<usize> {
+    let rest = xpointer.strip_prefix("/body/DocFragment[")?;
+    let (fragment, _) = rest.split_once(']')?;
+    fragment.parse::<usize>().ok()?.checked_sub(1)
+}
+
+/// An XPointer to the start of a chapter, for progress saved by other readers
+pub fn chapter_xpointer(chapter_index: usize) -> String {
+    format!("/body/DocFragment[{}]", chapter_index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_md5_samples_growing_offsets() {
+        // Small files are hashed whole
+        assert_eq!(partial_md5(b"abc"), format!("{:x}", Md5::digest(b"abc")));
+
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let mut samples = Vec::new();
+        samples.extend_from_slice(&data[0..1024]);
+        samples.extend_from_slice(&data[1024..2048]);
+        samples.extend_from_slice(&data[4096..5120]);
+        samples.extend_from_slice(&data[16384..17408]);
+        assert_eq!(partial_md5(&data), format!("{:x}", Md5::digest(&samples)));
+    }
+
+    #[test]
+    fn test_keys_are_salted() {
+        let key = password_key("secret");
+        assert_eq!(key, "5ebe2294ecd0e0f08eab7690d2a6ee69");
+        let ann = SyncUser::new("ann".to_string(), &key, None);
+        let bob = SyncUser::new("bob".to_string(), &key, Some(1));
+
+        assert!(ann.verify(&key));
+        assert!(!ann.verify("secret"));
+        assert_ne!(ann.key_hash, bob.key_hash);
+    }
+
+    #[test]
+    fn test_chapter_from_xpointer() {
+        assert_eq!(
+            chapter_from_xpointer("/body/DocFragment[12]/body/div/p[3]/text().15"),
+            Some(11)
+        );
+        assert_eq!(chapter_from_xpointer(&chapter_xpointer(4)), Some(4));
+        assert_eq!(chapter_from_xpointer("/body/DocFragment[0]"), None);
+        assert_eq!(chapter_from_xpointer("42"), None);
+    }
+}
@@ -0,0 +1,728 @@
+//! M4B audiobooks: AAC audio in an MP4 file, with chapters and iTunes metadata
+//!
+//! AAC frames are written to a temporary file as the book is synthesized. Once it is done,
+//! the file is assembled with its index in front of the frames, so players can start
+//! before the download is complete. Chapters are written twice, as a QuickTime chapter
+//! track for Apple's players and as a Nero `chpl` list for most others.
+
+use crate::services::audio_format;
+use crate::services::audiobook::{BookInfo, ChapterMark};
+use fdk_aac::enc::{AudioObjectType, BitRate, ChannelMode, EncoderParams, Transport};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+/// Plenty for a single voice
+const AAC_BITRATE: u32 = 64_000;
+
+/// Frames stored together; the index lists where each group starts
+const FRAMES_PER_CHUNK: usize = 64;
+
+/// Times of the movie and the chapters are in milliseconds
+const MOVIE_TIMESCALE: u32 = 1000;
+
+/// ISO 639-2 `und`, packed into 15 bits
+const UNDETERMINED_LANGUAGE: u16 = 0x55c4;
+
+/// Nero chapter titles have a one byte length
+const MAX_TITLE_BYTES: usize = 255;
+
+/// What follows each chapter title in the chapter track: its encoding, UTF-8
+const TEXT_ENCODING: [u8; 12] = [0, 0, 0, 12, b'e', b'n', b'c', b'd', 0, 0, 1, 0];
+
+const IDENTITY_MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000];
+
+/// Track ids
+const AUDIO_TRACK: u32 = 1;
+const CHAPTER_TRACK: u32 = 2;
+
+/// The content of a box, built up field by field
+#[derive(Default)]
+struct Content(Vec<u8>);
+
+impl Content {
+    fn u8(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn bytes(mut self, value: &[u8]) -> Self {
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn zeros(mut self, count: usize) -> Self {
+        self.0.resize(self.0.len() + count, 0);
+        self
+    }
+
+    fn matrix(self) -> Self {
+        IDENTITY_MATRIX
+            .iter()
+            .fold(self, |content, &value| content.u32(value))
+    }
+}
+
+/// A box of `kind` around `content`
+fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    Content::default()
+        .u32(content.len() as u32 + 8)
+        .bytes(kind)
+        .bytes(content)
+        .0
+}
+
+/// A box that starts with a version and flags
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, content: &[u8]) -> Vec<u8> {
+    let content = Content::default()
+        .u32((u32::from(version) << 24) | flags)
+        .bytes(content);
+    mp4_box(kind, &content.0)
+}
+
+/// Boxes one after the other
+fn boxes(boxes: &[Vec<u8>]) -> Vec<u8> {
+    boxes.concat()
+}
+
+/// An MPEG-4 descriptor; everything here is short enough for a one byte length
+fn descriptor(tag: u8, content: &[u8]) -> Vec<u8> {
+    Content::default()
+        .u8(tag)
+        .u8(content.len() as u8)
+        .bytes(content)
+        .0
+}
+
+/// An iTunes metadata item of the given data type
+fn metadata_item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> Vec<u8> {
+    let data = Content::default().u32(data_type).u32(0).bytes(value);
+    mp4_box(kind, &mp4_box(b"data", &data.0))
+}
+
+/// `text` cut to at most `max` bytes on a character boundary
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Where the samples of a track are in the file: per sample sizes, grouped into chunks
+struct SampleTable {
+    sizes: Vec<u32>,
+    per_chunk: usize,
+    chunk_offsets: Vec<u64>,
+}
+
+impl SampleTable {
+    fn new(sizes: Vec<u32>, per_chunk: usize, start: u64) -> Self {
+        let mut offset = start;
+        let chunk_offsets = sizes
+            .chunks(per_chunk)
+            .map(|chunk| {
+                let chunk_offset = offset;
+                offset += chunk.iter().map(|&size| u64::from(size)).sum::<u64>();
+                chunk_offset
+            })
+            .collect();
+        Self {
+            sizes,
+            per_chunk,
+            chunk_offsets,
+        }
+    }
+
+    fn boxes(&self, large: bool) -> Vec<u8> {
+        // Every chunk but the last is full
+        let chunks = self.chunk_offsets.len() as u32;
+        let full = self.sizes.len() / self.per_chunk;
+        let last = self.sizes.len() % self.per_chunk;
+        let mut runs = Vec::new();
+        if full > 0 {
+            runs.push((1, self.per_chunk));
+        }
+        if last > 0 {
+            runs.push((full as u32 + 1, last));
+        }
+        let stsc = runs.iter().fold(
+            Content::default().u32(runs.len() as u32),
+            |content, &(first, count)| content.u32(first).u32(count as u32).u32(1),
+        );
+
+        let stsz = self.sizes.iter().fold(
+            Content::default().u32(0).u32(self.sizes.len() as u32),
+            |content, &size| content.u32(size),
+        );
+
+        let offsets = Content::default().u32(chunks);
+        let offsets = if large {
+            let offsets = self
+                .chunk_offsets
+                .iter()
+                .fold(offsets, |content, &offset| content.u64(offset));
+            full_box(b"co64", 0, 0, &offsets.0)
+        } else {
+            let offsets = self
+                .chunk_offsets
+                .iter()
+                .fold(offsets, |content, &offset| content.u32(offset as u32));
+            full_box(b"stco", 0, 0, &offsets.0)
+        };
+
+        boxes(&[
+            full_box(b"stsc", 0, 0, &stsc.0),
+            full_box(b"stsz", 0, 0, &stsz.0),
+            offsets,
+        ])
+    }
+}
+
+/// A book being encoded to AAC
+pub struct M4bWriter {
+    encoder: fdk_aac::enc::Encoder,
+    sample_rate: u32,
+    /// Samples per frame
+    frame_length: usize,
+    /// Silence the encoder puts in front of the audio
+    delay: u64,
+    /// AudioSpecificConfig, which tells players how the frames are encoded
+    config: Vec<u8>,
+    /// Samples waiting for a whole frame
+    pending: Vec<i16>,
+    samples: u64,
+    /// Encoded frames, kept on disk until the index in front of them is written
+    frames: BufWriter<File>,
+    frame_sizes: Vec<u32>,
+    buffer: Vec<u8>,
+}
+
+impl M4bWriter {
+    pub fn new(sample_rate: u32) -> io::Result<Self> {
+        let encoder = fdk_aac::enc::Encoder::new(EncoderParams {
+            bit_rate: BitRate::Cbr(AAC_BITRATE),
+            sample_rate,
+            transport: Transport::Raw,
+            channels: ChannelMode::Mono,
+            audio_object_type: AudioObjectType::Mpeg4LowComplexity,
+        })
+        .map_err(|e| io::Error::other(format!("Failed to set up the AAC encoder: {}", e)))?;
+        let info = encoder
+            .info()
+            .map_err(|e| io::Error::other(format!("Failed to set up the AAC encoder: {}", e)))?;
+
+        Ok(Self {
+            encoder,
+            sample_rate,
+            frame_length: info.frameLength as usize,
+            delay: u64::from(info.nDelay),
+            config: info.confBuf[..info.confSize as usize].to_vec(),
+            pending: Vec::new(),
+            samples: 0,
+            frames: BufWriter::new(tempfile::tempfile()?),
+            frame_sizes: Vec::new(),
+            buffer: vec![0; info.maxOutBufBytes as usize],
+        })
+    }
+
+    /// Encode 16-bit mono samples
+    pub fn write(&mut self, pcm: &[u8]) -> io::Result<()> {
+        let samples = audio_format::samples(pcm);
+        self.samples += samples.len() as u64;
+        self.pending.extend_from_slice(&samples);
+
+        let frames = self.pending.len() / self.frame_length;
+        let pending = std::mem::take(&mut self.pending);
+        for frame in pending.chunks_exact(self.frame_length).take(frames) {
+            self.encode_frame(frame)?;
+        }
+        self.pending = pending[frames * self.frame_length..].to_vec();
+        Ok(())
+    }
+
+    fn encode_frame(&mut self, frame: &[i16]) -> io::Result<()> {
+        let encoded = self
+            .encoder
+            .encode(frame, &mut self.buffer)
+            .map_err(|e| io::Error::other(format!("Failed to encode AAC audio: {}", e)))?;
+        if encoded.input_consumed != frame.len() || encoded.output_size == 0 {
+            return Err(io::Error::other(
+                "The AAC encoder did not take a whole frame",
+            ));
+        }
+        self.frames.write_all(&self.buffer[..encoded.output_size])?;
+        self.frame_sizes.push(encoded.output_size as u32);
+        Ok(())
+    }
+
+    fn milliseconds(&self, samples: u64) -> u64 {
+        samples * u64::from(MOVIE_TIMESCALE) / u64::from(self.sample_rate)
+    }
+
+    /// Finish encoding and write the whole file to `out`, with `marks` as its chapters
+    pub fn finish(
+        mut self,
+        info: &BookInfo,
+        marks: &[ChapterMark],
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        // Silence pushes the last samples through the encoder's delay
+        let end = self.delay + self.samples;
+        let mut frame = std::mem::take(&mut self.pending);
+        while ((self.frame_sizes.len() * self.frame_length) as u64) < end {
+            frame.resize(self.frame_length, 0);
+            self.encode_frame(&frame)?;
+            frame.clear();
+        }
+
+        let duration = self.milliseconds(self.samples);
+        let starts: Vec<u64> = marks
+            .iter()
+            .map(|mark| self.milliseconds(mark.start))
+            .collect();
+        let titles: Vec<Vec<u8>> = marks
+            .iter()
+            .map(|mark| {
+                let title = truncate(&mark.title, u16::MAX as usize);
+                Content::default()
+                    .u16(title.len() as u16)
+                    .bytes(title.as_bytes())
+                    .bytes(&TEXT_ENCODING)
+                    .0
+            })
+            .collect();
+
+        let ftyp = Content::default()
+            .bytes(b"M4B ")
+            .u32(0x200)
+            .bytes(b"M4B M4A mp42isom");
+        let ftyp = mp4_box(b"ftyp", &ftyp.0);
+
+        let audio_len: u64 = self.frame_sizes.iter().map(|&size| u64::from(size)).sum();
+        let data_len = audio_len + titles.iter().map(|title| title.len() as u64).sum::<u64>();
+        // Files over 4 GiB need 64-bit sizes and offsets
+        let large = data_len + 16 > u64::from(u32::MAX);
+        let mdat_header = if large { 16 } else { 8 };
+
+        // The offsets in the index depend on its own size, which they do not change
+        let moov_len = self
+            .moov(info, marks, &starts, duration, &titles, 0, large)
+            .len() as u64;
+        let data_start = ftyp.len() as u64 + moov_len + mdat_header;
+        let moov = self.moov(info, marks, &starts, duration, &titles, data_start, large);
+
+        out.write_all(&ftyp)?;
+        out.write_all(&moov)?;
+        if large {
+            out.write_all(
+                &Content::default()
+                    .u32(1)
+                    .bytes(b"mdat")
+                    .u64(data_len + 16)
+                    .0,
+            )?;
+        } else {
+            out.write_all(&Content::default().u32(data_len as u32 + 8).bytes(b"mdat").0)?;
+        }
+        let mut frames = self.frames.into_inner().map_err(|e| e.into_error())?;
+        frames.seek(SeekFrom::Start(0))?;
+        io::copy(&mut frames, out)?;
+        for title in &titles {
+            out.write_all(title)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn moov(
+        &self,
+        info: &BookInfo,
+        marks: &[ChapterMark],
+        starts: &[u64],
+        duration: u64,
+        titles: &[Vec<u8>],
+        data_start: u64,
+        large: bool,
+    ) -> Vec<u8> {
+        let duration32 = duration.min(u64::from(u32::MAX)) as u32;
+        let audio_len: u64 = self.frame_sizes.iter().map(|&size| u64::from(size)).sum();
+
+        let mvhd = Content::default()
+            .u32(0)
+            .u32(0)
+            .u32(MOVIE_TIMESCALE)
+            .u32(duration32)
+            .u32(0x10000)
+            .u16(0x100)
+            .zeros(10)
+            .matrix()
+            .zeros(24)
+            .u32(CHAPTER_TRACK + 1);
+
+        let mut tracks = vec![self.audio_track(duration32, data_start, large)];
+        if !marks.is_empty() {
+            tracks.push(chapter_track(
+                starts,
+                duration,
+                titles,
+                data_start + audio_len,
+                large,
+            ));
+        }
+
+        let mut moov = vec![full_box(b"mvhd", 0, 0, &mvhd.0)];
+        moov.extend(tracks);
+        moov.push(mp4_box(
+            b"udta",
+            &boxes(&[nero_chapters(marks, starts), itunes_metadata(info)]),
+        ));
+        mp4_box(b"moov", &boxes(&moov))
+    }
+
+    fn audio_track(&self, duration: u32, data_start: u64, large: bool) -> Vec<u8> {
+        let frames = self.frame_sizes.len() as u32;
+
+        let tkhd = track_header(AUDIO_TRACK, duration, 0x100);
+        // Players skip the encoder's delay at the start
+        let elst = Content::default()
+            .u32(1)
+            .u32(duration)
+            .u32(self.delay as u32)
+            .u16(1)
+            .u16(0);
+        let edts = mp4_box(b"edts", &full_box(b"elst", 0, 0, &elst.0));
+        let tref = mp4_box(b"tref", &mp4_box(b"chap", &CHAPTER_TRACK.to_be_bytes()));
+
+        let mdhd = media_header(self.sample_rate, frames as u64 * self.frame_length as u64);
+        let hdlr = handler(b"soun", "SoundHandler");
+
+        let decoder_config = Content::default()
+            .u8(0x40)
+            // Audio stream
+            .u8(0x15)
+            .u8(0)
+            .u16(self.buffer.len() as u16)
+            .u32(AAC_BITRATE)
+            .u32(AAC_BITRATE)
+            .bytes(&descriptor(5, &self.config));
+        let es = Content::default()
+            .u16(AUDIO_TRACK as u16)
+            .u8(0)
+            .bytes(&descriptor(4, &decoder_config.0))
+            .bytes(&descriptor(6, &[2]));
+        let esds = full_box(b"esds", 0, 0, &descriptor(3, &es.0));
+        let mp4a = Content::default()
+            .zeros(6)
+            .u16(1)
+            .zeros(8)
+            .u16(1)
+            .u16(16)
+            .zeros(4)
+            .u32(self.sample_rate << 16)
+            .bytes(&esds);
+        let stsd = Content::default().u32(1).bytes(&mp4_box(b"mp4a", &mp4a.0));
+        let stts = Content::default()
+            .u32(1)
+            .u32(frames)
+            .u32(self.frame_length as u32);
+        let samples = SampleTable::new(self.frame_sizes.clone(), FRAMES_PER_CHUNK, data_start);
+        let stbl = boxes(&[
+            full_box(b"stsd", 0, 0, &stsd.0),
+            full_box(b"stts", 0, 0, &stts.0),
+            samples.boxes(large),
+        ]);
+
+        let smhd = full_box(b"smhd", 0, 0, &[0; 4]);
+        let minf = boxes(&[smhd, data_information(), mp4_box(b"stbl", &stbl)]);
+        let mdia = boxes(&[mdhd, hdlr, mp4_box(b"minf", &minf)]);
+        mp4_box(
+            b"trak",
+            &boxes(&[tkhd, tref, edts, mp4_box(b"mdia", &mdia)]),
+        )
+    }
+}
+
+fn track_header(id: u32, duration: u32, volume: u16) -> Vec<u8> {
+    let tkhd = Content::default()
+        .u32(0)
+        .u32(0)
+        .u32(id)
+        .u32(0)
+        .u32(duration)
+        .zeros(8)
+        .u16(0)
+        .u16(0)
+        .u16(volume)
+        .u16(0)
+        .matrix()
+        .u32(0)
+        .u32(0);
+    // Enabled and part of the movie; the chapter track is only referred to
+    let flags = if id == AUDIO_TRACK { 3 } else { 2 };
+    full_box(b"tkhd", 0, flags, &tkhd.0)
+}
+
+fn media_header(timescale: u32, duration: u64) -> Vec<u8> {
+    let mdhd = Content::default()
+        .u32(0)
+        .u32(0)
+        .u32(timescale)
+        .u32(duration.min(u64::from(u32::MAX)) as u32)
+        .u16(UNDETERMINED_LANGUAGE)
+        .u16(0);
+    full_box(b"mdhd", 0, 0, &mdhd.0)
+}
+
+fn handler(kind: &[u8; 4], name: &str) -> Vec<u8> {
+    let hdlr = Content::default()
+        .u32(0)
+        .bytes(kind)
+        .zeros(12)
+        .bytes(name.as_bytes())
+        .u8(0);
+    full_box(b"hdlr", 0, 0, &hdlr.0)
+}
+
+/// The samples are in this file
+fn data_information() -> Vec<u8> {
+    let url = full_box(b"url ", 0, 1, &[]);
+    let dref = full_box(b"dref", 0, 0, &Content::default().u32(1).bytes(&url).0);
+    mp4_box(b"dinf", &dref)
+}
+
+/// A QuickTime text track with the title of each chapter as a sample lasting the chapter
+fn chapter_track(
+    starts: &[u64],
+    duration: u64,
+    titles: &[Vec<u8>],
+    data_start: u64,
+    large: bool,
+) -> Vec<u8> {
+    let duration32 = duration.min(u64::from(u32::MAX)) as u32;
+    let tkhd = track_header(CHAPTER_TRACK, duration32, 0);
+    let mdhd = media_header(MOVIE_TIMESCALE, duration);
+    let hdlr = handler(b"text", "ChapterHandler");
+
+    let gmin = Content::default()
+        .u16(0x40)
+        .u16(0x8000)
+        .u16(0x8000)
+        .u16(0x8000)
+        .u16(0)
+        .u16(0);
+    let text = Content::default()
+        .u16(1)
+        .zeros(12)
+        .u32(1)
+        .zeros(12)
+        .u32(0x4000)
+        .u16(0);
+    let gmhd = mp4_box(
+        b"gmhd",
+        &boxes(&[full_box(b"gmin", 0, 0, &gmin.0), mp4_box(b"text", &text.0)]),
+    );
+
+    // Display settings are left empty, the titles are not shown as subtitles
+    let text_entry = Content::default().zeros(6).u16(1).zeros(35);
+    let stsd = Content::default()
+        .u32(1)
+        .bytes(&mp4_box(b"text", &text_entry.0));
+    let ends = starts.iter().skip(1).copied().chain([duration]);
+    let stts = starts.iter().zip(ends).fold(
+        Content::default().u32(starts.len() as u32),
+        |content, (&start, end)| content.u32(1).u32(end.saturating_sub(start) as u32),
+    );
+    let sizes = titles.iter().map(|title| title.len() as u32).collect();
+    let samples = SampleTable::new(sizes, titles.len(), data_start);
+    let stbl = boxes(&[
+        full_box(b"stsd", 0, 0, &stsd.0),
+        full_box(b"stts", 0, 0, &stts.0),
+        samples.boxes(large),
+    ]);
+
+    let minf = boxes(&[gmhd, data_information(), mp4_box(b"stbl", &stbl)]);
+    let mdia = boxes(&[mdhd, hdlr, mp4_box(b"minf", &minf)]);
+    mp4_box(b"trak", &boxes(&[tkhd, mp4_box(b"mdia", &mdia)]))
+}
+
+/// Nero's chapter list, with start times in 100 ns units
+fn nero_chapters(marks: &[ChapterMark], starts: &[u64]) -> Vec<u8> {
+    let chpl = marks.iter().zip(starts).fold(
+        Content::default().u32(0).u8(marks.len() as u8),
+        |content, (mark, &start)| {
+            let title = truncate(&mark.title, MAX_TITLE_BYTES);
+            content
+                .u64(start * 10_000)
+                .u8(title.len() as u8)
+                .bytes(title.as_bytes())
+        },
+    );
+    full_box(b"chpl", 1, 0, &chpl.0)
+}
+
+/// The title, author and cover in iTunes' metadata, which marks the file as an audiobook
+fn itunes_metadata(info: &BookInfo) -> Vec<u8> {
+    const UTF8: u32 = 1;
+    const JPEG: u32 = 13;
+    const PNG: u32 = 14;
+    const INTEGER: u32 = 21;
+    const AUDIOBOOK: u8 = 2;
+
+    let mut items = vec![
+        metadata_item(b"\xa9nam", UTF8, info.title.as_bytes()),
+        metadata_item(b"\xa9alb", UTF8, info.title.as_bytes()),
+        metadata_item(b"\xa9ART", UTF8, info.author.as_bytes()),
+        metadata_item(b"aART", UTF8, info.author.as_bytes()),
+        metadata_item(b"\xa9gen", UTF8, b"Audiobook"),
+        metadata_item(b"stik", INTEGER, &[AUDIOBOOK]),
+    ];
+    if let Some(cover) = &info.cover {
+        let data_type = match cover.media_type.as_str() {
+            "image/jpeg" => Some(JPEG),
+            "image/png" => Some(PNG),
+            _ => None,
+        };
+        if let Some(data_type) = data_type {
+            items.push(metadata_item(b"covr", data_type, &cover.data));
+        }
+    }
+
+    let hdlr = Content::default()
+        .u32(0)
+        .bytes(b"mdir")
+        .bytes(b"appl")
+        .zeros(8)
+        .u8(0);
+    let meta = boxes(&[
+        full_box(b"hdlr", 0, 0, &hdlr.0),
+        mp4_box(b"ilst", &boxes(&items)),
+    ]);
+    full_box(b"meta", 0, 0, &meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::audiobook::Cover;
+
+    /// The boxes in `data` by kind, with their content
+    fn children(data: &[u8]) -> Vec<(String, &[u8])> {
+        let mut children = Vec::new();
+        let mut rest = data;
+        while rest.len() >= 8 {
+            let size = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let kind = String::from_utf8_lossy(&rest[4..8]).to_string();
+            children.push((kind, &rest[8..size]));
+            rest = &rest[size..];
+        }
+        assert!(rest.is_empty());
+        children
+    }
+
+    fn child<'a>(data: &'a [u8], kind: &str) -> &'a [u8] {
+        children(data)
+            .into_iter()
+            .find(|(found, _)| found == kind)
+            .unwrap_or_else(|| panic!("no {} box", kind))
+            .1
+    }
+
+    #[test]
+    fn test_chaptered_audiobook() {
+        let mut writer = M4bWriter::new(22050).unwrap();
+        // Three seconds, written in pieces that do not line up with frames
+        for _ in 0..30 {
+            writer.write(&vec![1; 2 * 2205]).unwrap();
+        }
+        let info = BookInfo {
+            title: "Moby-Dick".to_string(),
+            author: "Herman Melville".to_string(),
+            cover: Some(Cover {
+                data: vec![0xff, 0xd8, 0xff],
+                media_type: "image/jpeg".to_string(),
+            }),
+        };
+        let marks = [
+            ChapterMark {
+                title: "Loomings".to_string(),
+                start: 0,
+            },
+            ChapterMark {
+                title: "The Carpet-Bag".to_string(),
+                start: 22050,
+            },
+        ];
+        let mut file = Vec::new();
+        writer.finish(&info, &marks, &mut file).unwrap();
+
+        let top = children(&file);
+        let kinds: Vec<_> = top.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["ftyp", "moov", "mdat"]);
+        assert_eq!(&top[0].1[..4], b"M4B ");
+
+        let moov = top[1].1;
+        let tracks: Vec<_> = children(moov)
+            .into_iter()
+            .filter(|(kind, _)| kind == "trak")
+            .collect();
+        assert_eq!(tracks.len(), 2);
+
+        // Every sample of the audio is in the index, the first where the data starts
+        let stbl = child(child(child(tracks[0].1, "mdia"), "minf"), "stbl");
+        let stsz = child(stbl, "stsz");
+        let frames = u32::from_be_bytes(stsz[8..12].try_into().unwrap()) as usize;
+        // The delay and the end of the last frame are padded with silence
+        assert_eq!(frames, (2048 + 3 * 22050_usize).div_ceil(1024));
+        let stco = child(stbl, "stco");
+        let first_chunk = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+        let mdat_start = file.len() - top[2].1.len();
+        assert_eq!(first_chunk, mdat_start);
+        let edts = child(child(tracks[0].1, "edts"), "elst");
+        assert_eq!(&edts[8..16], &[0, 0, 0x0b, 0xb8, 0, 0, 0x08, 0]);
+
+        // The chapter track's samples are the titles, lasting a second and two seconds
+        let stbl = child(child(child(tracks[1].1, "mdia"), "minf"), "stbl");
+        let stts = child(stbl, "stts");
+        assert_eq!(&stts[4..8], &2u32.to_be_bytes());
+        assert_eq!(&stts[12..16], &1000u32.to_be_bytes());
+        assert_eq!(&stts[20..24], &2000u32.to_be_bytes());
+        let stco = child(stbl, "stco");
+        let titles = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+        assert_eq!(&file[titles..titles + 10], b"\x00\x08Loomings");
+        assert!(file.ends_with(&TEXT_ENCODING));
+
+        let udta = child(moov, "udta");
+        let chpl = child(udta, "chpl");
+        assert_eq!(chpl[8], 2);
+        assert_eq!(&chpl[9..17], &0u64.to_be_bytes());
+        assert_eq!(&chpl[17..26], b"\x08Loomings");
+        assert_eq!(&chpl[26..34], &10_000_000u64.to_be_bytes());
+
+        let ilst = child(&child(udta, "meta")[4..], "ilst");
+        let title = child(child(ilst, "\u{fffd}nam"), "data");
+        assert_eq!(&title[8..], b"Moby-Dick");
+        let cover = child(child(ilst, "covr"), "data");
+        assert_eq!(&cover[..4], &13u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_titles_are_cut_on_character_boundaries() {
+        assert_eq!(truncate("Глава", 3), "Г");
+        assert_eq!(truncate("Loomings", 255), "Loomings");
+    }
+}
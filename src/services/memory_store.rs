@@ -0,0 +1,1593 @@
+use crate::models::metadata::{Chapter, EpubMetadata, TocEntry};
+use crate::services::annotations::{self, Annotation, AnnotationUpdate, NewAnnotation};
+use crate::services::auth::{ApiToken, Scope, User};
+use crate::services::epub_parser::{EpubContent, PARSER_VERSION};
+use crate::services::koreader::{self, SyncProgress, SyncUser};
+use crate::services::search::{Analyzer, Query};
+use crate::services::store::{
+    ChapterHit, Collection, CollectionUpdate, ContentCounts, Document, DocumentAccess,
+    DocumentFilter, DocumentSort, DocumentStore, DocumentSummary, DocumentUpdate, ListCursor,
+    OriginalFile, Progress, SortKey, SortOrder, StorageUsage, StoreError, StoreResult,
+    StoredChapter, TagCount,
+};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A document store that keeps everything in memory and loses it on restart
+///
+/// Meant for tests and throwaway servers. Listing, sorting and search follow the SQLite
+/// store, except that search scores are only a rough stand-in for bm25. Library export
+/// and import are not supported.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    last_id: i64,
+    documents: BTreeMap<i64, StoredDocument>,
+    /// Original files by SHA-256, shared by documents uploaded from the same file
+    originals: HashMap<String, StoredOriginal>,
+    last_collection_id: i64,
+    collections: BTreeMap<i64, StoredCollection>,
+    last_annotation_id: i64,
+    annotations: BTreeMap<i64, Annotation>,
+    /// Reading progress by user id, `None` for the local reader, and document
+    progress: BTreeMap<(Option<i64>, i64), Progress>,
+    sync_users: HashMap<String, SyncUser>,
+    /// KOReader progress by user and KOReader's document digest
+    sync_progress: HashMap<(String, String), SyncProgress>,
+    last_user_id: i64,
+    /// Users with their password hashes
+    users: BTreeMap<i64, (User, String)>,
+    last_token_id: i64,
+    /// API tokens with the hashes they are found by
+    tokens: BTreeMap<i64, (ApiToken, String)>,
+    /// Seconds of synthesized audio by user and period
+    audio_usage: HashMap<(i64, String), f64>,
+}
+
+struct StoredDocument {
+    metadata: EpubMetadata,
+    format: String,
+    uploaded_at: i64,
+    parser_version: i64,
+    /// SHA-256 and filename of the original file
+    original: Option<(String, String)>,
+    chapters: Vec<Chapter>,
+    resource_count: usize,
+    toc: Vec<TocEntry>,
+    access: DocumentAccess,
+}
+
+struct StoredOriginal {
+    media_type: String,
+    data: Vec<u8>,
+    partial_md5: String,
+}
+
+struct StoredCollection {
+    name: String,
+    owner_id: Option<i64>,
+    description: Option<String>,
+    created_at: i64,
+    /// Document ids in collection order
+    documents: Vec<i64>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic while holding the lock leaves no partial writes behind, so keep going
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn document(&self, id: i64) -> StoreResult<&StoredDocument> {
+        self.documents.get(&id).ok_or(StoreError::NotFound)
+    }
+
+    fn document_mut(&mut self, id: i64) -> StoreResult<&mut StoredDocument> {
+        self.documents.get_mut(&id).ok_or(StoreError::NotFound)
+    }
+
+    /// A collection, counting the documents `visible_to` may see
+    fn collection(&self, id: i64, visible_to: Option<i64>) -> StoreResult<Collection> {
+        let collection = self.collections.get(&id).ok_or(StoreError::NotFound)?;
+        let document_count = collection
+            .documents
+            .iter()
+            .filter_map(|id| self.documents.get(id))
+            .filter(|document| visible_to.is_none_or(|user_id| document.visible_to(user_id)))
+            .count();
+
+        Ok(Collection {
+            id,
+            name: collection.name.clone(),
+            description: collection.description.clone(),
+            document_count,
+            created_at: collection.created_at,
+            owner_id: collection.owner_id,
+        })
+    }
+
+    fn collection_mut(&mut self, id: i64) -> StoreResult<&mut StoredCollection> {
+        self.collections.get_mut(&id).ok_or(StoreError::NotFound)
+    }
+
+    /// Fail with [`StoreError::Conflict`] if another collection has the name, ignoring case
+    fn check_collection_name(&self, name: &str, except: Option<i64>) -> StoreResult<()> {
+        let taken = self
+            .collections
+            .iter()
+            .any(|(id, other)| Some(*id) != except && other.name.eq_ignore_ascii_case(name));
+        if taken {
+            return Err(StoreError::Conflict(format!(
+                "A collection named {} already exists",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fail with [`StoreError::Invalid`] if there is no such document
+    fn check_document_exists(&self, document_id: i64) -> StoreResult<()> {
+        if !self.documents.contains_key(&document_id) {
+            return Err(StoreError::Invalid(format!(
+                "Document not found: {}",
+                document_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl StoredDocument {
+    fn to_document(&self, id: i64) -> Document {
+        Document {
+            id,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    fn sort_key(&self, sort: DocumentSort) -> SortKey {
+        match sort {
+            DocumentSort::Title => SortKey::Text(self.metadata.title.clone()),
+            DocumentSort::Author => SortKey::Text(self.metadata.author.clone()),
+            DocumentSort::Uploaded => SortKey::Integer(self.uploaded_at),
+        }
+    }
+
+    fn summary(&self, id: i64) -> DocumentSummary {
+        DocumentSummary {
+            id,
+            title: self.metadata.title.clone(),
+            author: self.metadata.author.clone(),
+            publication_date: self.metadata.publication_date.clone(),
+            language: self.metadata.language.clone(),
+            series: self.metadata.series.clone(),
+            series_index: self.metadata.series_index,
+            format: self.format.clone(),
+            tags: self.metadata.tags.clone(),
+            chapter_count: self.chapters.len(),
+            uploaded_at: self.uploaded_at,
+        }
+    }
+
+    fn matches(&self, filter: &DocumentFilter) -> bool {
+        let language = match (&filter.language, &self.metadata.language) {
+            (Some(prefix), Some(language)) => starts_with_ignore_case(language, prefix),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let author = filter
+            .author
+            .as_ref()
+            .is_none_or(|author| author.eq_ignore_ascii_case(&self.metadata.author));
+        let tag = filter.tag.as_ref().is_none_or(|tag| {
+            self.metadata
+                .tags
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(tag))
+        });
+        let format = filter
+            .format
+            .as_ref()
+            .is_none_or(|format| format.eq_ignore_ascii_case(&self.format));
+
+        let visible = filter
+            .visible_to
+            .is_none_or(|user_id| self.visible_to(user_id));
+
+        language && author && tag && format && visible
+    }
+
+    fn visible_to(&self, user_id: i64) -> bool {
+        self.access.shared
+            || self
+                .access
+                .owner_id
+                .is_none_or(|owner_id| owner_id == user_id)
+    }
+
+    /// Store the parsed content, merging tags into the ones already set
+    fn set_content(&mut self, content: &EpubContent) {
+        self.chapters = content.chapters.clone();
+        self.resource_count = content.resources.len();
+        self.toc = content.toc.clone();
+        for tag in &content.metadata.tags {
+            add_tag(&mut self.metadata.tags, tag);
+        }
+    }
+}
+
+/// Add a tag unless the document already has it in some case, keeping tags in order
+fn add_tag(tags: &mut Vec<String>, tag: &str) {
+    if !tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+        tags.push(tag.to_string());
+        tags.sort_by_key(|t| t.to_ascii_lowercase());
+    }
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.to_lowercase().starts_with(&prefix.to_lowercase())
+}
+
+/// Order of sort keys as SQLite compares them with `COLLATE NOCASE`
+fn compare_keys(a: &SortKey, b: &SortKey) -> Ordering {
+    match (a, b) {
+        (SortKey::Integer(a), SortKey::Integer(b)) => a.cmp(b),
+        (SortKey::Text(a), SortKey::Text(b)) => a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase()),
+        // Numbers sort before text
+        (SortKey::Integer(_), SortKey::Text(_)) => Ordering::Less,
+        (SortKey::Text(_), SortKey::Integer(_)) => Ordering::Greater,
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl DocumentStore for MemoryStore {
+    fn save_document(
+        &self,
+        content: &EpubContent,
+        original: Option<&OriginalFile>,
+    ) -> StoreResult<i64> {
+        let mut state = self.state();
+
+        let original = original.map(|original| {
+            let sha256 = original.sha256();
+            state
+                .originals
+                .entry(sha256.clone())
+                .or_insert_with(|| StoredOriginal {
+                    media_type: original.media_type.clone(),
+                    data: original.data.clone(),
+                    partial_md5: original.partial_md5(),
+                });
+            (sha256, original.filename.clone())
+        });
+
+        let mut metadata = content.metadata.clone();
+        metadata.tags = Vec::new();
+        let mut document = StoredDocument {
+            metadata,
+            format: "epub".to_string(),
+            uploaded_at: now(),
+            parser_version: PARSER_VERSION,
+            original,
+            chapters: Vec::new(),
+            resource_count: 0,
+            toc: Vec::new(),
+            access: DocumentAccess::default(),
+        };
+        document.set_content(content);
+
+        state.last_id += 1;
+        let id = state.last_id;
+        state.documents.insert(id, document);
+
+        Ok(id)
+    }
+
+    fn get_document(&self, id: i64) -> StoreResult<Document> {
+        Ok(self.state().document(id)?.to_document(id))
+    }
+
+    fn get_chapters(&self, id: i64) -> StoreResult<Vec<StoredChapter>> {
+        let state = self.state();
+        let chapters = state
+            .document(id)?
+            .chapters
+            .iter()
+            .map(|chapter| StoredChapter {
+                title: chapter.title.clone(),
+                text: chapter.content.clone(),
+            })
+            .collect();
+
+        Ok(chapters)
+    }
+
+    fn get_chapter_html(&self, id: i64, index: usize) -> StoreResult<String> {
+        self.state()
+            .document(id)?
+            .chapters
+            .get(index)
+            .map(|chapter| chapter.html.clone())
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn get_chapter_text(&self, id: i64, index: usize) -> StoreResult<String> {
+        self.state()
+            .document(id)?
+            .chapters
+            .get(index)
+            .map(|chapter| chapter.content.clone())
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn get_toc(&self, id: i64) -> StoreResult<Vec<TocEntry>> {
+        Ok(self.state().document(id)?.toc.clone())
+    }
+
+    fn get_original(&self, id: i64) -> StoreResult<OriginalFile> {
+        let state = self.state();
+        let (sha256, filename) = state
+            .document(id)?
+            .original
+            .as_ref()
+            .ok_or(StoreError::NotFound)?;
+        let original = state.originals.get(sha256).ok_or(StoreError::NotFound)?;
+
+        Ok(OriginalFile {
+            filename: filename.clone(),
+            media_type: original.media_type.clone(),
+            data: original.data.clone(),
+        })
+    }
+
+    fn list_documents(
+        &self,
+        filter: &DocumentFilter,
+        sort: DocumentSort,
+        order: SortOrder,
+        after: Option<&ListCursor>,
+        limit: usize,
+    ) -> StoreResult<(Vec<DocumentSummary>, Option<ListCursor>)> {
+        let state = self.state();
+
+        // Ties on the sort key are broken by id, so every document has a unique position
+        let position = |key: &SortKey, id: i64, other: &ListCursor| {
+            let ordering = compare_keys(key, &other.key).then(id.cmp(&other.id));
+            match order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        };
+
+        let mut rows: Vec<(i64, SortKey, &StoredDocument)> = state
+            .documents
+            .iter()
+            .filter(|(_, document)| document.matches(filter))
+            .filter(|(id, _)| {
+                filter.collection.is_none_or(|collection| {
+                    state
+                        .collections
+                        .get(&collection)
+                        .is_some_and(|collection| collection.documents.contains(id))
+                })
+            })
+            .map(|(id, document)| (*id, document.sort_key(sort), document))
+            .filter(|(id, key, _)| {
+                after.is_none_or(|cursor| position(key, *id, cursor) == Ordering::Greater)
+            })
+            .collect();
+        rows.sort_by(|(a_id, a_key, _), (b_id, b_key, _)| {
+            position(
+                a_key,
+                *a_id,
+                &ListCursor {
+                    key: b_key.clone(),
+                    id: *b_id,
+                },
+            )
+        });
+
+        let next = if rows.len() > limit {
+            rows.truncate(limit);
+            rows.last().map(|(id, key, _)| ListCursor {
+                key: key.clone(),
+                id: *id,
+            })
+        } else {
+            None
+        };
+
+        let documents = rows
+            .into_iter()
+            .map(|(id, _, document)| document.summary(id))
+            .collect();
+
+        Ok((documents, next))
+    }
+
+    fn update_document(&self, id: i64, update: &DocumentUpdate) -> StoreResult<Document> {
+        let mut state = self.state();
+        let document = state.document_mut(id)?;
+        let metadata = &mut document.metadata;
+
+        if let Some(title) = &update.title {
+            metadata.title = title.clone();
+        }
+        if let Some(author) = &update.author {
+            metadata.author = author.clone();
+        }
+        if let Some(publication_date) = &update.publication_date {
+            metadata.publication_date = publication_date.clone();
+        }
+        if let Some(language) = &update.language {
+            metadata.language = language.clone();
+        }
+        if let Some(description) = &update.description {
+            metadata.description = description.clone();
+        }
+        if let Some(series) = &update.series {
+            metadata.series = series.clone();
+        }
+        if let Some(series_index) = update.series_index {
+            metadata.series_index = series_index;
+        }
+
+        Ok(document.to_document(id))
+    }
+
+    fn delete_document(&self, id: i64) -> StoreResult<()> {
+        let mut state = self.state();
+        let document = state.documents.remove(&id).ok_or(StoreError::NotFound)?;
+
+        // Originals go once no document refers to them any more
+        if let Some((sha256, _)) = document.original {
+            let shared = state
+                .documents
+                .values()
+                .any(|other| matches!(&other.original, Some((other, _)) if *other == sha256));
+            if !shared {
+                state.originals.remove(&sha256);
+            }
+        }
+        for collection in state.collections.values_mut() {
+            collection.documents.retain(|&other| other != id);
+        }
+        state
+            .annotations
+            .retain(|_, annotation| annotation.document_id != id);
+        state
+            .progress
+            .retain(|(_, document_id), _| *document_id != id);
+
+        Ok(())
+    }
+
+    fn search_chapters(
+        &self,
+        query: &Query,
+        language: Option<&str>,
+        visible_to: Option<i64>,
+        limit: usize,
+        offset: usize,
+    ) -> StoreResult<(usize, Vec<ChapterHit>)> {
+        let state = self.state();
+
+        let mut hits = Vec::new();
+        let documents = state
+            .documents
+            .iter()
+            .filter(|(_, document)| visible_to.is_none_or(|user_id| document.visible_to(user_id)));
+        for (id, document) in documents {
+            let analyzer = Analyzer::for_language(document.metadata.language.as_deref());
+            for (index, chapter) in document.chapters.iter().enumerate() {
+                let in_title = query.count_parts(&chapter.title, &analyzer);
+                let in_text = query.count_parts(&chapter.content, &analyzer);
+                if in_title.iter().zip(&in_text).any(|(t, b)| t + b == 0) {
+                    continue;
+                }
+
+                // Lower is better, like bm25; titles weigh twice as much as text
+                let occurrences: usize =
+                    in_title.iter().map(|t| t * 2).sum::<usize>() + in_text.iter().sum::<usize>();
+                hits.push(ChapterHit {
+                    document_id: *id,
+                    document_title: document.metadata.title.clone(),
+                    author: document.metadata.author.clone(),
+                    language: document.metadata.language.clone(),
+                    chapter_index: index,
+                    chapter_title: chapter.title.clone(),
+                    text: chapter.content.clone(),
+                    score: -(occurrences as f64),
+                });
+            }
+        }
+
+        let preferred = |hit: &ChapterHit| match (language, &hit.language) {
+            (Some(prefix), Some(language)) => starts_with_ignore_case(language, prefix),
+            _ => false,
+        };
+        hits.sort_by(|a, b| {
+            preferred(b)
+                .cmp(&preferred(a))
+                .then(a.score.total_cmp(&b.score))
+        });
+
+        let total = hits.len();
+        let page = hits.into_iter().skip(offset).take(limit).collect();
+
+        Ok((total, page))
+    }
+
+    fn replace_content(&self, id: i64, content: &EpubContent) -> StoreResult<()> {
+        let mut state = self.state();
+        let document = state.document_mut(id)?;
+
+        // Metadata already stored may have been corrected by hand, only fill in the gaps
+        let metadata = &mut document.metadata;
+        let parsed = &content.metadata;
+        metadata.publication_date = metadata
+            .publication_date
+            .take()
+            .or_else(|| parsed.publication_date.clone());
+        metadata.language = metadata.language.take().or_else(|| parsed.language.clone());
+        metadata.description = metadata
+            .description
+            .take()
+            .or_else(|| parsed.description.clone());
+        metadata.series = metadata.series.take().or_else(|| parsed.series.clone());
+        metadata.series_index = metadata.series_index.or(parsed.series_index);
+
+        let previous = std::mem::take(&mut document.chapters);
+        document.set_content(content);
+        document.parser_version = PARSER_VERSION;
+
+        let texts: Vec<&str> = content
+            .chapters
+            .iter()
+            .map(|chapter| chapter.content.as_str())
+            .collect();
+        for annotation in state.annotations.values_mut() {
+            if annotation.document_id != id {
+                continue;
+            }
+            match annotations::locate(&texts, &annotation.anchor) {
+                Some(anchor) => {
+                    annotation.anchor = anchor;
+                    annotation.detached = false;
+                }
+                None => annotation.detached = true,
+            }
+        }
+
+        let previous: Vec<&str> = previous
+            .iter()
+            .map(|chapter| chapter.content.as_str())
+            .collect();
+        for progress in state.progress.values_mut() {
+            if progress.document_id == id {
+                progress.relocate(&previous, &texts);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn content_counts(&self, id: i64) -> StoreResult<ContentCounts> {
+        let state = self.state();
+        let document = state.document(id)?;
+
+        Ok(ContentCounts {
+            chapters: document.chapters.len(),
+            resources: document.resource_count,
+            toc_entries: document.toc.len(),
+        })
+    }
+
+    fn parser_version(&self, id: i64) -> StoreResult<i64> {
+        Ok(self.state().document(id)?.parser_version)
+    }
+
+    fn document_ids(&self, parsed_before: Option<i64>) -> StoreResult<Vec<i64>> {
+        let parsed_before = parsed_before.unwrap_or(i64::MAX);
+        let ids = self
+            .state()
+            .documents
+            .iter()
+            .filter(|(_, document)| document.parser_version < parsed_before)
+            .map(|(id, _)| *id)
+            .collect();
+
+        Ok(ids)
+    }
+
+    fn set_tags(&self, id: i64, tags: &[String]) -> StoreResult<Vec<String>> {
+        let mut state = self.state();
+        let document = state.document_mut(id)?;
+
+        document.metadata.tags.clear();
+        for tag in tags {
+            add_tag(&mut document.metadata.tags, tag);
+        }
+
+        Ok(document.metadata.tags.clone())
+    }
+
+    fn list_tags(&self, visible_to: Option<i64>) -> StoreResult<Vec<TagCount>> {
+        let state = self.state();
+
+        let mut tags: Vec<TagCount> = Vec::new();
+        let visible = state
+            .documents
+            .values()
+            .filter(|document| visible_to.is_none_or(|user_id| document.visible_to(user_id)));
+        for document in visible {
+            for tag in &document.metadata.tags {
+                match tags.iter_mut().find(|t| t.tag.eq_ignore_ascii_case(tag)) {
+                    Some(count) => count.document_count += 1,
+                    None => tags.push(TagCount {
+                        tag: tag.clone(),
+                        document_count: 1,
+                    }),
+                }
+            }
+        }
+        tags.sort_by_key(|t| t.tag.to_ascii_lowercase());
+
+        Ok(tags)
+    }
+
+    fn create_collection(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        owner_id: Option<i64>,
+    ) -> StoreResult<Collection> {
+        let mut state = self.state();
+        state.check_collection_name(name, None)?;
+
+        state.last_collection_id += 1;
+        let id = state.last_collection_id;
+        state.collections.insert(
+            id,
+            StoredCollection {
+                name: name.to_string(),
+                owner_id,
+                description: description.map(str::to_string),
+                created_at: now(),
+                documents: Vec::new(),
+            },
+        );
+
+        state.collection(id, None)
+    }
+
+    fn list_collections(&self, visible_to: Option<i64>) -> StoreResult<Vec<Collection>> {
+        let state = self.state();
+        let mut collections = state
+            .collections
+            .keys()
+            .map(|id| state.collection(*id, visible_to))
+            .collect::<StoreResult<Vec<_>>>()?;
+        collections.sort_by_key(|c| c.name.to_ascii_lowercase());
+
+        Ok(collections)
+    }
+
+    fn get_collection(&self, id: i64, visible_to: Option<i64>) -> StoreResult<Collection> {
+        self.state().collection(id, visible_to)
+    }
+
+    fn update_collection(&self, id: i64, update: &CollectionUpdate) -> StoreResult<Collection> {
+        let mut state = self.state();
+        state.collection_mut(id)?;
+        if let Some(name) = &update.name {
+            state.check_collection_name(name, Some(id))?;
+        }
+
+        let collection = state.collection_mut(id)?;
+        if let Some(name) = &update.name {
+            collection.name = name.clone();
+        }
+        if let Some(description) = &update.description {
+            collection.description = description.clone();
+        }
+
+        state.collection(id, None)
+    }
+
+    fn delete_collection(&self, id: i64) -> StoreResult<()> {
+        self.state()
+            .collections
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn collection_documents(&self, id: i64) -> StoreResult<Vec<DocumentSummary>> {
+        let state = self.state();
+        let collection = state.collections.get(&id).ok_or(StoreError::NotFound)?;
+
+        Ok(collection
+            .documents
+            .iter()
+            .filter_map(|id| Some(state.documents.get(id)?.summary(*id)))
+            .collect())
+    }
+
+    fn set_collection_documents(&self, id: i64, document_ids: &[i64]) -> StoreResult<()> {
+        let mut state = self.state();
+        state.collection_mut(id)?;
+
+        let mut seen = HashSet::new();
+        for &document_id in document_ids {
+            if !seen.insert(document_id) {
+                return Err(StoreError::Invalid(format!(
+                    "Document {} is listed more than once",
+                    document_id
+                )));
+            }
+            state.check_document_exists(document_id)?;
+        }
+        state.collection_mut(id)?.documents = document_ids.to_vec();
+
+        Ok(())
+    }
+
+    fn add_to_collection(
+        &self,
+        id: i64,
+        document_id: i64,
+        position: Option<usize>,
+    ) -> StoreResult<()> {
+        let mut state = self.state();
+        state.collection_mut(id)?;
+        state.check_document_exists(document_id)?;
+
+        let documents = &mut state.collection_mut(id)?.documents;
+        documents.retain(|&other| other != document_id);
+        let position = position.unwrap_or(documents.len()).min(documents.len());
+        documents.insert(position, document_id);
+
+        Ok(())
+    }
+
+    fn create_annotation(
+        &self,
+        document_id: i64,
+        annotation: &NewAnnotation,
+    ) -> StoreResult<Annotation> {
+        let mut state = self.state();
+        state.document(document_id)?;
+
+        state.last_annotation_id += 1;
+        let id = state.last_annotation_id;
+        let created_at = now();
+        let annotation = Annotation {
+            id,
+            document_id,
+            user_id: annotation.user_id,
+            kind: annotation.kind,
+            anchor: annotation.anchor.clone(),
+            note: annotation.note.clone(),
+            color: annotation.color.clone(),
+            detached: false,
+            created_at,
+            updated_at: created_at,
+        };
+        state.annotations.insert(id, annotation.clone());
+
+        Ok(annotation)
+    }
+
+    fn list_annotations(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+    ) -> StoreResult<Vec<Annotation>> {
+        let state = self.state();
+        state.document(document_id)?;
+
+        let mut annotations: Vec<Annotation> = state
+            .annotations
+            .values()
+            .filter(|annotation| {
+                annotation.document_id == document_id && annotation.user_id == user_id
+            })
+            .cloned()
+            .collect();
+        annotations.sort_by_key(|annotation| {
+            (
+                annotation.anchor.chapter_index,
+                annotation.anchor.start_offset,
+                annotation.anchor.end_offset,
+                annotation.id,
+            )
+        });
+
+        Ok(annotations)
+    }
+
+    fn update_annotation(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+        id: i64,
+        update: &AnnotationUpdate,
+    ) -> StoreResult<Annotation> {
+        let mut state = self.state();
+        let annotation = state
+            .annotations
+            .get_mut(&id)
+            .filter(|annotation| {
+                annotation.document_id == document_id && annotation.user_id == user_id
+            })
+            .ok_or(StoreError::NotFound)?;
+
+        if let Some(note) = &update.note {
+            annotation.note = note.clone();
+        }
+        if let Some(color) = &update.color {
+            annotation.color = color.clone();
+        }
+        annotation.updated_at = now();
+
+        Ok(annotation.clone())
+    }
+
+    fn delete_annotation(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+        id: i64,
+    ) -> StoreResult<()> {
+        let mut state = self.state();
+        match state.annotations.get(&id) {
+            Some(annotation)
+                if annotation.document_id == document_id && annotation.user_id == user_id =>
+            {
+                state.annotations.remove(&id);
+                Ok(())
+            }
+            _ => Err(StoreError::NotFound),
+        }
+    }
+
+    fn remove_from_collection(&self, id: i64, document_id: i64) -> StoreResult<()> {
+        let mut state = self.state();
+        let documents = &mut state.collection_mut(id)?.documents;
+
+        let before = documents.len();
+        documents.retain(|&other| other != document_id);
+        if documents.len() == before {
+            return Err(StoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn get_progress(&self, user_id: Option<i64>, document_id: i64) -> StoreResult<Progress> {
+        self.state()
+            .progress
+            .get(&(user_id, document_id))
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn list_progress(&self, user_id: Option<i64>) -> StoreResult<Vec<Progress>> {
+        let mut progress: Vec<Progress> = self
+            .state()
+            .progress
+            .values()
+            .filter(|progress| progress.user_id == user_id)
+            .cloned()
+            .collect();
+        progress.sort_by(|a, b| {
+            b.updated_at
+                .cmp(&a.updated_at)
+                .then(a.document_id.cmp(&b.document_id))
+        });
+
+        Ok(progress)
+    }
+
+    fn save_progress(&self, progress: &Progress) -> StoreResult<bool> {
+        let mut state = self.state();
+        state.document(progress.document_id)?;
+
+        let key = (progress.user_id, progress.document_id);
+        if state
+            .progress
+            .get(&key)
+            .is_some_and(|stored| stored.updated_at > progress.updated_at)
+        {
+            return Ok(false);
+        }
+        state.progress.insert(key, progress.clone());
+
+        Ok(true)
+    }
+
+    fn find_by_digest(&self, digest: &str, visible_to: Option<i64>) -> StoreResult<i64> {
+        let state = self.state();
+        let originals = state.documents.iter().filter_map(|(id, document)| {
+            if visible_to.is_some_and(|user_id| !document.visible_to(user_id)) {
+                return None;
+            }
+            let (sha256, filename) = document.original.as_ref()?;
+            Some((*id, state.originals.get(sha256)?, filename))
+        });
+
+        // Like SQLite, the file content wins over the file name
+        let by_content = originals
+            .clone()
+            .find(|(_, original, _)| original.partial_md5 == digest);
+        by_content
+            .or_else(|| {
+                originals
+                    .clone()
+                    .find(|(_, _, filename)| koreader::filename_md5(filename) == digest)
+            })
+            .map(|(id, _, _)| id)
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn create_sync_user(&self, user: &SyncUser) -> StoreResult<()> {
+        let mut state = self.state();
+        if state.sync_users.contains_key(&user.username) {
+            return Err(StoreError::Conflict(format!(
+                "Username {} is already registered",
+                user.username
+            )));
+        }
+        state.sync_users.insert(user.username.clone(), user.clone());
+
+        Ok(())
+    }
+
+    fn get_sync_user(&self, username: &str) -> StoreResult<SyncUser> {
+        self.state()
+            .sync_users
+            .get(username)
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn link_sync_user(&self, username: &str, user_id: i64) -> StoreResult<()> {
+        let mut state = self.state();
+        let user = state
+            .sync_users
+            .get_mut(username)
+            .ok_or(StoreError::NotFound)?;
+        user.user_id = Some(user_id);
+        Ok(())
+    }
+
+    fn save_sync_progress(&self, progress: &SyncProgress) -> StoreResult<()> {
+        self.state().sync_progress.insert(
+            (progress.username.clone(), progress.document.clone()),
+            progress.clone(),
+        );
+        Ok(())
+    }
+
+    fn get_sync_progress(&self, username: &str, document: &str) -> StoreResult<SyncProgress> {
+        self.state()
+            .sync_progress
+            .get(&(username.to_string(), document.to_string()))
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn document_access(&self, id: i64) -> StoreResult<DocumentAccess> {
+        Ok(self.state().document(id)?.access)
+    }
+
+    fn set_document_access(&self, id: i64, access: &DocumentAccess) -> StoreResult<()> {
+        self.state().document_mut(id)?.access = *access;
+        Ok(())
+    }
+
+    fn create_user(
+        &self,
+        username: &str,
+        password_hash: &str,
+        is_admin: bool,
+    ) -> StoreResult<User> {
+        let mut state = self.state();
+        if state
+            .users
+            .values()
+            .any(|(user, _)| user.username.eq_ignore_ascii_case(username))
+        {
+            return Err(StoreError::Conflict(format!(
+                "Username {} is already taken",
+                username
+            )));
+        }
+
+        state.last_user_id += 1;
+        let user = User {
+            id: state.last_user_id,
+            username: username.to_string(),
+            is_admin,
+            created_at: now(),
+        };
+        state
+            .users
+            .insert(user.id, (user.clone(), password_hash.to_string()));
+
+        Ok(user)
+    }
+
+    fn list_users(&self) -> StoreResult<Vec<User>> {
+        let mut users: Vec<User> = self
+            .state()
+            .users
+            .values()
+            .map(|(user, _)| user.clone())
+            .collect();
+        users.sort_by_key(|user| user.username.to_ascii_lowercase());
+        Ok(users)
+    }
+
+    fn get_user_by_name(&self, username: &str) -> StoreResult<(User, String)> {
+        self.state()
+            .users
+            .values()
+            .find(|(user, _)| user.username.eq_ignore_ascii_case(username))
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+
+    fn set_password(&self, user_id: i64, password_hash: &str) -> StoreResult<()> {
+        let mut state = self.state();
+        let (_, hash) = state.users.get_mut(&user_id).ok_or(StoreError::NotFound)?;
+        *hash = password_hash.to_string();
+        Ok(())
+    }
+
+    fn delete_user(&self, id: i64) -> StoreResult<()> {
+        let mut state = self.state();
+        if !state.users.contains_key(&id) {
+            return Err(StoreError::NotFound);
+        }
+        let owned = state
+            .documents
+            .values()
+            .filter(|document| document.access.owner_id == Some(id))
+            .count();
+        if owned > 0 {
+            return Err(StoreError::Conflict(format!(
+                "User {} still owns {} documents",
+                id, owned
+            )));
+        }
+
+        state.users.remove(&id);
+        state.tokens.retain(|_, (token, _)| token.user_id != id);
+        state
+            .annotations
+            .retain(|_, annotation| annotation.user_id != Some(id));
+        state.audio_usage.retain(|(user_id, _), _| *user_id != id);
+        state
+            .progress
+            .retain(|(user_id, _), _| *user_id != Some(id));
+        let accounts: Vec<String> = state
+            .sync_users
+            .values()
+            .filter(|user| user.user_id == Some(id))
+            .map(|user| user.username.clone())
+            .collect();
+        for username in accounts {
+            state.sync_users.remove(&username);
+            state
+                .sync_progress
+                .retain(|(other, _), _| *other != username);
+        }
+        for collection in state.collections.values_mut() {
+            if collection.owner_id == Some(id) {
+                collection.owner_id = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn claim_unowned(&self, user: &User) -> StoreResult<usize> {
+        let mut state = self.state();
+
+        let mut claimed = 0;
+        for document in state.documents.values_mut() {
+            if document.access.owner_id.is_none() {
+                document.access.owner_id = Some(user.id);
+                claimed += 1;
+            }
+        }
+        for annotation in state.annotations.values_mut() {
+            annotation.user_id.get_or_insert(user.id);
+        }
+        for collection in state.collections.values_mut() {
+            collection.owner_id.get_or_insert(user.id);
+        }
+        // Progress the user already has is newer than what was saved before accounts
+        let local: Vec<(Option<i64>, i64)> = state
+            .progress
+            .keys()
+            .filter(|(user_id, _)| user_id.is_none())
+            .copied()
+            .collect();
+        for key in local {
+            let Some(mut progress) = state.progress.remove(&key) else {
+                continue;
+            };
+            progress.user_id = Some(user.id);
+            state
+                .progress
+                .entry((Some(user.id), key.1))
+                .or_insert(progress);
+        }
+
+        Ok(claimed)
+    }
+
+    fn create_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &[Scope],
+        token_hash: &str,
+    ) -> StoreResult<ApiToken> {
+        let mut state = self.state();
+        if !state.users.contains_key(&user_id) {
+            return Err(StoreError::NotFound);
+        }
+
+        state.last_token_id += 1;
+        let token = ApiToken {
+            id: state.last_token_id,
+            user_id,
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            created_at: now(),
+            last_used_at: None,
+        };
+        state
+            .tokens
+            .insert(token.id, (token.clone(), token_hash.to_string()));
+
+        Ok(token)
+    }
+
+    fn list_tokens(&self, user_id: i64) -> StoreResult<Vec<ApiToken>> {
+        Ok(self
+            .state()
+            .tokens
+            .values()
+            .rev()
+            .filter(|(token, _)| token.user_id == user_id)
+            .map(|(token, _)| token.clone())
+            .collect())
+    }
+
+    fn delete_token(&self, user_id: i64, id: i64) -> StoreResult<()> {
+        let mut state = self.state();
+        match state.tokens.get(&id) {
+            Some((token, _)) if token.user_id == user_id => {
+                state.tokens.remove(&id);
+                Ok(())
+            }
+            _ => Err(StoreError::NotFound),
+        }
+    }
+
+    fn authenticate_token(&self, token_hash: &str) -> StoreResult<(User, ApiToken)> {
+        let mut state = self.state();
+        let (token, _) = state
+            .tokens
+            .values_mut()
+            .find(|(_, hash)| hash == token_hash)
+            .ok_or(StoreError::NotFound)?;
+        token.last_used_at = Some(now());
+        let token = token.clone();
+        let (user, _) = state
+            .users
+            .get(&token.user_id)
+            .ok_or(StoreError::NotFound)?;
+
+        Ok((user.clone(), token))
+    }
+
+    fn storage_usage(&self, user_id: i64) -> StoreResult<StorageUsage> {
+        let state = self.state();
+        let mut usage = StorageUsage::default();
+        for document in state.documents.values() {
+            if document.access.owner_id != Some(user_id) {
+                continue;
+            }
+            usage.documents += 1;
+            if let Some((sha256, _)) = &document.original {
+                usage.bytes += state
+                    .originals
+                    .get(sha256)
+                    .map_or(0, |original| original.data.len() as u64);
+            }
+        }
+        Ok(usage)
+    }
+
+    fn audio_usage(&self, user_id: i64, period: &str) -> StoreResult<f64> {
+        Ok(self
+            .state()
+            .audio_usage
+            .get(&(user_id, period.to_string()))
+            .copied()
+            .unwrap_or(0.0))
+    }
+
+    fn add_audio_usage(&self, user_id: i64, period: &str, seconds: f64) -> StoreResult<()> {
+        *self
+            .state()
+            .audio_usage
+            .entry((user_id, period.to_string()))
+            .or_insert(0.0) += seconds;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::metadata::Resource;
+    use crate::services::annotations::AnnotationKind;
+
+    fn sample_content(title: &str, tags: &[&str]) -> EpubContent {
+        let mut metadata = EpubMetadata::new(
+            title.to_string(),
+            "Herman Melville".to_string(),
+            None,
+            Some("en-US".to_string()),
+            None,
+        );
+        metadata.tags = tags.iter().map(|tag| tag.to_string()).collect();
+
+        EpubContent {
+            metadata,
+            chapters: vec![
+                Chapter {
+                    title: "Loomings".to_string(),
+                    path: "c1".to_string(),
+                    content: "Call me Ishmael.".to_string(),
+                    html: "<p>Call me Ishmael.</p>".to_string(),
+                },
+                Chapter {
+                    title: "The Carpet-Bag".to_string(),
+                    path: "c2".to_string(),
+                    content: "I stuffed a shirt or two into my old carpet-bag.".to_string(),
+                    html: "<p>I stuffed a shirt or two into my old carpet-bag.</p>".to_string(),
+                },
+            ],
+            resources: vec![Resource {
+                path: "OEBPS/image/cover.png".to_string(),
+                media_type: "image/png".to_string(),
+                data: vec![1, 2, 3],
+            }],
+            toc: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_list_documents_pages_like_sqlite() {
+        let store = MemoryStore::new();
+        for (title, tag) in [("b", "Sea"), ("A", "Sea"), ("c", "Russia")] {
+            store
+                .save_document(&sample_content(title, &[tag]), None)
+                .unwrap();
+        }
+
+        let filter = DocumentFilter::default();
+        let (first, next) = store
+            .list_documents(&filter, DocumentSort::Title, SortOrder::Asc, None, 2)
+            .unwrap();
+        let titles: Vec<&str> = first.iter().map(|d| d.title.as_str()).collect();
+        assert_eq!(titles, vec!["A", "b"]);
+        assert_eq!(first[0].chapter_count, 2);
+
+        let (second, next) = store
+            .list_documents(
+                &filter,
+                DocumentSort::Title,
+                SortOrder::Asc,
+                next.as_ref(),
+                2,
+            )
+            .unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].title, "c");
+        assert!(next.is_none());
+
+        let filter = DocumentFilter {
+            tag: Some("sea".to_string()),
+            language: Some("EN".to_string()),
+            ..Default::default()
+        };
+        let (sea, _) = store
+            .list_documents(&filter, DocumentSort::Uploaded, SortOrder::Desc, None, 10)
+            .unwrap();
+        let titles: Vec<&str> = sea.iter().map(|d| d.title.as_str()).collect();
+        assert_eq!(titles, vec!["A", "b"]);
+    }
+
+    #[test]
+    fn test_search_requires_every_part() {
+        let store = MemoryStore::new();
+        let id = store
+            .save_document(&sample_content("Moby-Dick", &[]), None)
+            .unwrap();
+
+        let (total, hits) = store
+            .search_chapters(&Query::parse("shirts carpet"), Some("en"), None, 10, 0)
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(hits[0].document_id, id);
+        assert_eq!(hits[0].chapter_index, 1);
+
+        let (total, _) = store
+            .search_chapters(&Query::parse("shirts ishmael"), None, None, 10, 0)
+            .unwrap();
+        assert_eq!(total, 0);
+    }
+
+    #[test]
+    fn test_collections_follow_deleted_documents() {
+        let store = MemoryStore::new();
+        let first = store
+            .save_document(&sample_content("A", &[]), None)
+            .unwrap();
+        let second = store
+            .save_document(&sample_content("B", &[]), None)
+            .unwrap();
+
+        let course = store.create_collection("Course", None, None).unwrap();
+        assert!(matches!(
+            store.create_collection("COURSE", None, None),
+            Err(StoreError::Conflict(_))
+        ));
+        store.add_to_collection(course.id, first, None).unwrap();
+        store.add_to_collection(course.id, second, Some(0)).unwrap();
+        let titles = |store: &MemoryStore| -> Vec<String> {
+            store
+                .collection_documents(course.id)
+                .unwrap()
+                .into_iter()
+                .map(|document| document.title)
+                .collect()
+        };
+        assert_eq!(titles(&store), vec!["B", "A"]);
+
+        store.delete_document(second).unwrap();
+        assert_eq!(titles(&store), vec!["A"]);
+        let filter = DocumentFilter {
+            collection: Some(course.id),
+            ..Default::default()
+        };
+        let (listed, _) = store
+            .list_documents(&filter, DocumentSort::Title, SortOrder::Asc, None, 10)
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(store
+            .remove_from_collection(course.id, second)
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    #[test]
+    fn test_update_replace_and_delete() {
+        let store = MemoryStore::new();
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data: b"PK not really a zip".to_vec(),
+        };
+        let first = store
+            .save_document(&sample_content("Moby-Dick", &["Sea"]), Some(&original))
+            .unwrap();
+        let second = store
+            .save_document(&sample_content("Moby-Dick", &[]), Some(&original))
+            .unwrap();
+
+        let update = DocumentUpdate {
+            title: Some("Moby Dick".to_string()),
+            language: Some(None),
+            ..Default::default()
+        };
+        store.update_document(first, &update).unwrap();
+        store
+            .replace_content(first, &sample_content("Moby-Dick", &["sea", "Whales"]))
+            .unwrap();
+        let document = store.get_document(first).unwrap();
+        assert_eq!(document.metadata.title, "Moby Dick");
+        assert_eq!(document.metadata.language.as_deref(), Some("en-US"));
+        assert_eq!(document.metadata.tags, vec!["Sea", "Whales"]);
+
+        store.delete_document(first).unwrap();
+        assert!(matches!(
+            store.get_document(first),
+            Err(StoreError::NotFound)
+        ));
+        assert!(store.delete_document(first).unwrap_err().is_not_found());
+        assert_eq!(store.get_original(second).unwrap().data, original.data);
+        store.delete_document(second).unwrap();
+        assert!(store.state().originals.is_empty());
+    }
+
+    #[test]
+    fn test_annotations_are_reanchored_and_deleted() {
+        let store = MemoryStore::new();
+        let id = store
+            .save_document(&sample_content("Moby-Dick", &[]), None)
+            .unwrap();
+        let chapters = store.get_chapters(id).unwrap();
+        let bookmark = NewAnnotation {
+            user_id: None,
+            kind: AnnotationKind::Bookmark,
+            anchor: annotations::anchor(&chapters[1].text, 1, 26, 26).unwrap(),
+            note: None,
+            color: None,
+        };
+        let bookmark = store.create_annotation(id, &bookmark).unwrap();
+
+        let mut content = sample_content("Moby-Dick", &[]);
+        content.chapters.insert(0, content.chapters[0].clone());
+        content.chapters[0].content = "Etymology.".to_string();
+        store.replace_content(id, &content).unwrap();
+
+        let listed = store.list_annotations(id, None).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].anchor.chapter_index, 2);
+        assert_eq!(listed[0].anchor.start_offset, 26);
+        assert!(!listed[0].detached);
+
+        store.delete_document(id).unwrap();
+        assert!(store.state().annotations.is_empty());
+        assert!(store
+            .delete_annotation(id, None, bookmark.id)
+            .unwrap_err()
+            .is_not_found());
+    }
+
+    #[test]
+    fn test_progress_is_kept_inside_reparsed_chapters() {
+        let store = MemoryStore::new();
+        let id = store
+            .save_document(&sample_content("Moby-Dick", &[]), None)
+            .unwrap();
+        let progress = Progress {
+            user_id: None,
+            document_id: id,
+            chapter_index: 1,
+            char_offset: 40,
+            audio_position: Some(30.0),
+            percentage: 0.8,
+            device: None,
+            updated_at: 1000,
+        };
+        store.save_progress(&progress).unwrap();
+
+        let mut content = sample_content("Moby-Dick", &[]);
+        content.chapters.truncate(1);
+        content.chapters[0].content =
+            "Call me Ishmael. I stuffed a shirt or two into my old carpet-bag.".to_string();
+        store.replace_content(id, &content).unwrap();
+        let moved = store.get_progress(None, id).unwrap();
+        assert_eq!((moved.chapter_index, moved.char_offset), (0, 57));
+        assert_eq!(moved.audio_position, None);
+
+        content.chapters[0].content = "Etymology.".to_string();
+        store.replace_content(id, &content).unwrap();
+        let clamped = store.get_progress(None, id).unwrap();
+        assert_eq!((clamped.chapter_index, clamped.char_offset), (0, 10));
+    }
+
+    #[test]
+    fn test_progress_keeps_newest_update() {
+        let store = MemoryStore::new();
+        let id = store
+            .save_document(&sample_content("Moby-Dick", &[]), None)
+            .unwrap();
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let progress = |percentage, updated_at| Progress {
+            user_id: Some(ann.id),
+            document_id: id,
+            chapter_index: 1,
+            char_offset: 0,
+            audio_position: Some(30.0),
+            percentage,
+            device: None,
+            updated_at,
+        };
+
+        assert!(store.save_progress(&progress(0.6, 2000)).unwrap());
+        assert!(!store.save_progress(&progress(0.2, 1000)).unwrap());
+        assert!(store.save_progress(&progress(0.7, 2000)).unwrap());
+        assert_eq!(
+            store.get_progress(Some(ann.id), id).unwrap().percentage,
+            0.7
+        );
+        assert!(store.get_progress(None, id).unwrap_err().is_not_found());
+
+        store.delete_user(ann.id).unwrap();
+        assert!(store.list_progress(Some(ann.id)).unwrap().is_empty());
+
+        let local = Progress {
+            user_id: None,
+            ..progress(0.1, 1000)
+        };
+        assert!(store.save_progress(&local).unwrap());
+        store.delete_document(id).unwrap();
+        assert!(store.list_progress(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_documents_are_private_until_shared() {
+        let store = MemoryStore::new();
+        let ann = store.create_user("ann", "hash", false).unwrap();
+        let bob = store.create_user("bob", "hash", false).unwrap();
+        let id = store
+            .save_document(&sample_content("Moby-Dick", &[]), None)
+            .unwrap();
+        store
+            .set_document_access(
+                id,
+                &DocumentAccess {
+                    owner_id: Some(ann.id),
+                    shared: false,
+                },
+            )
+            .unwrap();
+
+        let visible = |user_id| {
+            let filter = DocumentFilter {
+                visible_to: Some(user_id),
+                ..Default::default()
+            };
+            store
+                .list_documents(&filter, DocumentSort::Title, SortOrder::Asc, None, 10)
+                .unwrap()
+                .0
+                .len()
+        };
+        assert_eq!(visible(ann.id), 1);
+        assert_eq!(visible(bob.id), 0);
+
+        store
+            .set_document_access(
+                id,
+                &DocumentAccess {
+                    owner_id: Some(ann.id),
+                    shared: true,
+                },
+            )
+            .unwrap();
+        assert_eq!(visible(bob.id), 1);
+        assert!(matches!(
+            store.delete_user(ann.id),
+            Err(StoreError::Conflict(_))
+        ));
+    }
+}
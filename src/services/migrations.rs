@@ -0,0 +1,703 @@
+use crate::models::metadata::EpubMetadata;
+use crate::services::auth::LOCAL_USER;
+use crate::services::koreader;
+use crate::services::search::Analyzer;
+use rusqlite::{params, Connection, Result, Transaction};
+use serde::Deserialize;
+use tracing::info;
+
+/// A single schema or data change, applied once in its own transaction
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> Result<()>,
+}
+
+/// All migrations in the order they must be applied. Versions must be strictly increasing;
+/// never edit a migration that has shipped, add a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "normalized documents, chapters, resources and toc tables",
+        apply: normalized_schema,
+    },
+    Migration {
+        version: 2,
+        description: "convert JSON blob documents into the normalized tables",
+        apply: convert_legacy_documents,
+    },
+    Migration {
+        version: 3,
+        description: "full-text search index over chapter text",
+        apply: chapter_search_index,
+    },
+    Migration {
+        version: 4,
+        description: "document format and tags",
+        apply: document_tags,
+    },
+    Migration {
+        version: 5,
+        description: "document series",
+        apply: document_series,
+    },
+    Migration {
+        version: 6,
+        description: "original uploaded files",
+        apply: original_files,
+    },
+    Migration {
+        version: 7,
+        description: "parser version per document",
+        apply: parser_version,
+    },
+    Migration {
+        version: 8,
+        description: "collections of documents",
+        apply: collections,
+    },
+    Migration {
+        version: 9,
+        description: "annotations",
+        apply: annotations,
+    },
+    Migration {
+        version: 10,
+        description: "reading progress",
+        apply: reading_progress,
+    },
+    Migration {
+        version: 11,
+        description: "KOReader sync accounts and progress",
+        apply: koreader_sync,
+    },
+    Migration {
+        version: 12,
+        description: "user accounts, API tokens and document owners",
+        apply: users,
+    },
+    Migration {
+        version: 13,
+        description: "synthesized audio per user and month",
+        apply: audio_usage,
+    },
+    Migration {
+        version: 14,
+        description: "stemming of each chapter in the search index",
+        apply: search_stemming,
+    },
+    Migration {
+        version: 15,
+        description: "collection owners",
+        apply: collection_owners,
+    },
+    Migration {
+        version: 16,
+        description: "reading progress by user id and KOReader accounts linked to users",
+        apply: progress_user_ids,
+    },
+];
+
+/// Bring the database up to the latest schema version
+///
+/// The current version is kept in `PRAGMA user_version`. Every pending migration runs in
+/// its own transaction together with the version bump, so a failure leaves the database at
+/// the last successfully applied version.
+pub fn run(conn: &mut Connection) -> Result<()> {
+    let current: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            "Applying database migration {}: {}",
+            migration.version, migration.description
+        );
+
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn table_exists(tx: &Transaction, name: &str) -> Result<bool> {
+    tx.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![name],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+fn column_exists(tx: &Transaction, table: &str, column: &str) -> Result<bool> {
+    tx.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+fn normalized_schema(tx: &Transaction) -> Result<()> {
+    // Databases created before versioning kept everything in a single JSON blob table;
+    // move it aside so the data migration can pick it up
+    if column_exists(tx, "documents", "chapters_html")? {
+        tx.execute_batch("ALTER TABLE documents RENAME TO legacy_documents;")?;
+    }
+
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS documents (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            title TEXT NOT NULL,
+            author TEXT NOT NULL,
+            publication_date TEXT,
+            language TEXT,
+            description TEXT,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE INDEX IF NOT EXISTS idx_documents_title ON documents (title);
+        CREATE INDEX IF NOT EXISTS idx_documents_author ON documents (author);
+        CREATE INDEX IF NOT EXISTS idx_documents_language ON documents (language);
+
+        CREATE TABLE IF NOT EXISTS chapters (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+            chapter_index INTEGER NOT NULL,
+            title TEXT NOT NULL,
+            path TEXT NOT NULL,
+            text TEXT NOT NULL,
+            html TEXT NOT NULL,
+            UNIQUE (document_id, chapter_index)
+        );
+
+        CREATE TABLE IF NOT EXISTS resources (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+            path TEXT NOT NULL,
+            media_type TEXT NOT NULL,
+            data BLOB NOT NULL,
+            UNIQUE (document_id, path)
+        );
+
+        CREATE TABLE IF NOT EXISTS toc_entries (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+            position INTEGER NOT NULL,
+            level INTEGER NOT NULL,
+            title TEXT NOT NULL,
+            href TEXT NOT NULL,
+            chapter_index INTEGER,
+            UNIQUE (document_id, position)
+        );",
+    )
+}
+
+#[derive(Deserialize)]
+struct LegacyChapters {
+    chapters: Vec<LegacyChapter>,
+}
+
+#[derive(Deserialize)]
+struct LegacyChapter {
+    title: String,
+    content: String,
+}
+
+fn convert_legacy_documents(tx: &Transaction) -> Result<()> {
+    if !table_exists(tx, "legacy_documents")? {
+        return Ok(());
+    }
+
+    let rows = {
+        let mut stmt =
+            tx.prepare("SELECT id, metadata, chapters_html FROM legacy_documents ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        rows
+    };
+
+    for (id, metadata_json, chapters_json) in rows {
+        let metadata: EpubMetadata = serde_json::from_str(&metadata_json).map_err(|e| {
+            rusqlite::Error::InvalidParameterName(format!(
+                "Invalid metadata JSON for document {}: {}",
+                id, e
+            ))
+        })?;
+        let legacy: LegacyChapters = serde_json::from_str(&chapters_json).map_err(|e| {
+            rusqlite::Error::InvalidParameterName(format!(
+                "Invalid chapters_html JSON for document {}: {}",
+                id, e
+            ))
+        })?;
+
+        // Keep the original id so existing links keep working
+        tx.execute(
+            "INSERT INTO documents (id, title, author, publication_date, language, description)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                id,
+                metadata.title,
+                metadata.author,
+                metadata.publication_date,
+                metadata.language,
+                metadata.description,
+            ],
+        )?;
+
+        // The blob only kept extracted text, which is also what the chapter endpoint served
+        for (index, chapter) in legacy.chapters.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO chapters (document_id, chapter_index, title, path, text, html)
+                 VALUES (?1, ?2, ?3, '', ?4, ?4)",
+                params![id, index as i64, chapter.title, chapter.content],
+            )?;
+        }
+    }
+
+    tx.execute_batch("DROP TABLE legacy_documents;")
+}
+
+fn chapter_search_index(tx: &Transaction) -> Result<()> {
+    // Terms are stemmed in Rust for the language of each book (see services::search),
+    // so the FTS tokenizer only has to split on the spaces between them
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE chapter_search USING fts5(
+            title,
+            body,
+            tokenize = 'unicode61 remove_diacritics 0'
+        );
+
+        CREATE TRIGGER chapters_search_delete AFTER DELETE ON chapters BEGIN
+            DELETE FROM chapter_search WHERE rowid = old.id;
+        END;",
+    )?;
+
+    let chapters = {
+        let mut stmt = tx.prepare(
+            "SELECT c.id, c.title, c.text, d.language
+             FROM chapters c JOIN documents d ON d.id = c.document_id",
+        )?;
+        let chapters = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        chapters
+    };
+
+    let mut stmt =
+        tx.prepare("INSERT INTO chapter_search (rowid, title, body) VALUES (?1, ?2, ?3)")?;
+    for (id, title, text, language) in chapters {
+        let analyzer = Analyzer::for_language(language.as_deref());
+        stmt.execute(params![
+            id,
+            analyzer.index_text(&title),
+            analyzer.index_text(&text)
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn document_tags(tx: &Transaction) -> Result<()> {
+    // Earlier versions only ever stored EPUBs; their subjects were not kept
+    tx.execute_batch(
+        "ALTER TABLE documents ADD COLUMN format TEXT NOT NULL DEFAULT 'epub';
+        CREATE INDEX idx_documents_created_at ON documents (created_at);
+
+        CREATE TABLE document_tags (
+            document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+            tag TEXT NOT NULL COLLATE NOCASE,
+            PRIMARY KEY (document_id, tag)
+        );
+        CREATE INDEX idx_document_tags_tag ON document_tags (tag);",
+    )
+}
+
+fn document_series(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE documents ADD COLUMN series TEXT;
+        ALTER TABLE documents ADD COLUMN series_index REAL;",
+    )
+}
+
+fn original_files(tx: &Transaction) -> Result<()> {
+    // Files are stored once per content hash; the last document using one takes it along
+    tx.execute_batch(
+        "CREATE TABLE originals (
+            sha256 TEXT PRIMARY KEY,
+            media_type TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            data BLOB NOT NULL
+        );
+
+        ALTER TABLE documents ADD COLUMN original_sha256 TEXT REFERENCES originals (sha256);
+        ALTER TABLE documents ADD COLUMN original_filename TEXT;
+        CREATE INDEX idx_documents_original ON documents (original_sha256);
+
+        CREATE TRIGGER documents_original_delete AFTER DELETE ON documents
+        WHEN old.original_sha256 IS NOT NULL BEGIN
+            DELETE FROM originals
+            WHERE sha256 = old.original_sha256
+              AND NOT EXISTS (SELECT 1 FROM documents WHERE original_sha256 = old.original_sha256);
+        END;",
+    )
+}
+
+fn parser_version(tx: &Transaction) -> Result<()> {
+    // 0 marks documents parsed before versions were recorded
+    tx.execute_batch("ALTER TABLE documents ADD COLUMN parser_version INTEGER NOT NULL DEFAULT 0;")
+}
+
+fn collections(tx: &Transaction) -> Result<()> {
+    // Positions only order the documents of a collection; deleting a document leaves a gap
+    tx.execute_batch(
+        "CREATE TABLE collections (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            description TEXT,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+
+        CREATE TABLE collection_documents (
+            collection_id INTEGER NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+            document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+            position INTEGER NOT NULL,
+            PRIMARY KEY (collection_id, document_id)
+        );
+        CREATE INDEX idx_collection_documents_document ON collection_documents (document_id);",
+    )
+}
+
+fn annotations(tx: &Transaction) -> Result<()> {
+    // Offsets are in characters of the chapter's plain text
+    tx.execute_batch(
+        "CREATE TABLE annotations (
+            id INTEGER PRIMARY KEY,
+            document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+            kind TEXT NOT NULL,
+            chapter_index INTEGER NOT NULL,
+            start_offset INTEGER NOT NULL,
+            end_offset INTEGER NOT NULL,
+            quote TEXT NOT NULL,
+            prefix TEXT NOT NULL,
+            suffix TEXT NOT NULL,
+            note TEXT,
+            color TEXT,
+            detached INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            updated_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE INDEX idx_annotations_document
+            ON annotations (document_id, chapter_index, start_offset);",
+    )
+}
+
+fn reading_progress(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE reading_progress (
+            user TEXT NOT NULL,
+            document_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+            chapter_index INTEGER NOT NULL,
+            char_offset INTEGER NOT NULL,
+            audio_position REAL,
+            percentage REAL NOT NULL,
+            device TEXT,
+            updated_at INTEGER NOT NULL,
+            PRIMARY KEY (user, document_id)
+        );
+        CREATE INDEX idx_reading_progress_document ON reading_progress (document_id);",
+    )
+}
+
+fn koreader_sync(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE originals ADD COLUMN partial_md5 TEXT;
+        CREATE INDEX idx_originals_partial_md5 ON originals (partial_md5);
+
+        CREATE TABLE koreader_users (
+            username TEXT PRIMARY KEY,
+            salt TEXT NOT NULL,
+            key_hash TEXT NOT NULL,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+
+        -- Keyed by KOReader's digest, which may not match any stored document
+        CREATE TABLE koreader_progress (
+            username TEXT NOT NULL REFERENCES koreader_users (username) ON DELETE CASCADE,
+            document TEXT NOT NULL,
+            progress TEXT NOT NULL,
+            percentage REAL NOT NULL,
+            device TEXT NOT NULL,
+            device_id TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY (username, document)
+        );",
+    )?;
+
+    let originals = {
+        let mut stmt = tx.prepare("SELECT sha256, data FROM originals")?;
+        let originals = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        originals
+    };
+    for (sha256, data) in originals {
+        tx.execute(
+            "UPDATE originals SET partial_md5 = ?2 WHERE sha256 = ?1",
+            params![sha256, koreader::partial_md5(&data)],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn users(tx: &Transaction) -> Result<()> {
+    // Existing documents and annotations keep no owner until an admin claims them
+    tx.execute_batch(
+        "CREATE TABLE users (
+            id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            password_hash TEXT NOT NULL,
+            is_admin INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+        );
+
+        -- Only a hash of each token is kept; scopes are a comma separated list
+        CREATE TABLE api_tokens (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+            last_used_at INTEGER
+        );
+        CREATE INDEX idx_api_tokens_user ON api_tokens (user_id);
+
+        ALTER TABLE documents ADD COLUMN owner_id INTEGER REFERENCES users (id);
+        ALTER TABLE documents ADD COLUMN shared INTEGER NOT NULL DEFAULT 0;
+        CREATE INDEX idx_documents_owner ON documents (owner_id);
+
+        ALTER TABLE annotations ADD COLUMN user_id INTEGER
+            REFERENCES users (id) ON DELETE CASCADE;",
+    )
+}
+
+fn audio_usage(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE audio_usage (
+            user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+            period TEXT NOT NULL,
+            seconds REAL NOT NULL,
+            PRIMARY KEY (user_id, period)
+        );",
+    )
+}
+
+fn search_stemming(tx: &Transaction) -> Result<()> {
+    // FTS5 tables cannot gain columns, so the index is built again
+    tx.execute_batch(
+        "DROP TABLE chapter_search;
+        CREATE VIRTUAL TABLE chapter_search USING fts5(
+            title,
+            body,
+            stemming,
+            tokenize = 'unicode61 remove_diacritics 0'
+        );",
+    )?;
+
+    let chapters = {
+        let mut stmt = tx.prepare(
+            "SELECT c.id, c.title, c.text, d.language
+             FROM chapters c JOIN documents d ON d.id = c.document_id",
+        )?;
+        let chapters = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+        chapters
+    };
+
+    let mut stmt = tx.prepare(
+        "INSERT INTO chapter_search (rowid, title, body, stemming) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (id, title, text, language) in chapters {
+        let analyzer = Analyzer::for_language(language.as_deref());
+        stmt.execute(params![
+            id,
+            analyzer.index_text(&title),
+            analyzer.index_text(&text),
+            analyzer.stemming_name()
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn collection_owners(tx: &Transaction) -> Result<()> {
+    // Existing collections keep no owner, like documents did when accounts were added
+    tx.execute_batch(
+        "ALTER TABLE collections ADD COLUMN owner_id INTEGER
+            REFERENCES users (id) ON DELETE SET NULL;",
+    )
+}
+
+fn progress_user_ids(tx: &Transaction) -> Result<()> {
+    // Progress was kept by user name, so it outlived deleted accounts and passed to the next
+    // account of the same name. The local reader's progress keeps no user, progress of names
+    // without an account is dropped, and of names differing only in case the newest is kept.
+    tx.execute_batch(
+        "CREATE TABLE progress_by_user (
+            user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+            document_id INTEGER NOT NULL REFERENCES documents(id) ON DELETE CASCADE,
+            chapter_index INTEGER NOT NULL,
+            char_offset INTEGER NOT NULL,
+            audio_position REAL,
+            percentage REAL NOT NULL,
+            device TEXT,
+            updated_at INTEGER NOT NULL
+        );
+        -- The local reader has no user id, and NULLs are never equal in unique indexes
+        CREATE UNIQUE INDEX idx_reading_progress_user
+            ON progress_by_user (IFNULL(user_id, 0), document_id);",
+    )?;
+    tx.execute(
+        "INSERT OR IGNORE INTO progress_by_user
+            (user_id, document_id, chapter_index, char_offset, audio_position, percentage,
+             device, updated_at)
+         SELECT u.id, p.document_id, p.chapter_index, p.char_offset, p.audio_position,
+            p.percentage, p.device, p.updated_at
+         FROM reading_progress p LEFT JOIN users u ON u.username = p.user
+         WHERE p.user = ?1 OR u.id IS NOT NULL
+         ORDER BY p.updated_at DESC",
+        params![LOCAL_USER],
+    )?;
+
+    // KOReader accounts could be registered by anyone under any name, so existing ones are
+    // left unlinked until their owner links them with a token
+    tx.execute_batch(
+        "DROP TABLE reading_progress;
+        ALTER TABLE progress_by_user RENAME TO reading_progress;
+        CREATE INDEX idx_reading_progress_document ON reading_progress (document_id);
+
+        ALTER TABLE koreader_users ADD COLUMN user_id INTEGER
+            REFERENCES users (id) ON DELETE CASCADE;",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::db;
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        run(&mut conn).unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+        assert_eq!(user_version(&conn), latest);
+
+        // Running again is a no-op
+        run(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), latest);
+    }
+
+    #[test]
+    fn test_legacy_documents_are_converted() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE documents (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                metadata TEXT NOT NULL,
+                chapters_html TEXT NOT NULL
+            );",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO documents (id, metadata, chapters_html) VALUES (7, ?1, ?2)",
+            params![
+                r#"{"title":"Moby-Dick","author":"Herman Melville","publication_date":null,"language":"en-US","description":null}"#,
+                r#"{"chapters":[{"title":"Chapter 1","content":"Call me Ishmael."}]}"#,
+            ],
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+
+        let document = db::get_document(&conn, 7).unwrap();
+        assert_eq!(document.metadata.author, "Herman Melville");
+        assert_eq!(
+            db::get_chapter_html_by_index(&conn, 7, 0).unwrap(),
+            "Call me Ishmael."
+        );
+        assert!(!table_exists(&conn.unchecked_transaction().unwrap(), "legacy_documents").unwrap());
+    }
+
+    #[test]
+    fn test_progress_is_keyed_by_user_id() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in MIGRATIONS.iter().filter(|m| m.version < 16) {
+            let tx = conn.transaction().unwrap();
+            (migration.apply)(&tx).unwrap();
+            tx.pragma_update(None, "user_version", migration.version)
+                .unwrap();
+            tx.commit().unwrap();
+        }
+        conn.execute_batch(
+            "INSERT INTO documents (id, title, author) VALUES (7, 'Moby-Dick', 'Herman Melville');
+            INSERT INTO users (id, username, password_hash) VALUES (3, 'alice', 'hash');
+            INSERT INTO koreader_users (username, salt, key_hash) VALUES ('alice', 'salt', 'hash');
+            INSERT INTO reading_progress
+                (user, document_id, chapter_index, char_offset, percentage, updated_at)
+            VALUES ('default', 7, 0, 0, 0.1, 1000), ('alice', 7, 1, 0, 0.5, 3000),
+                ('ALICE', 7, 0, 0, 0.2, 2000), ('ghost', 7, 2, 0, 0.9, 4000);",
+        )
+        .unwrap();
+
+        run(&mut conn).unwrap();
+
+        // The local reader keeps no user, names differing in case keep the newest and names
+        // without an account are gone
+        assert_eq!(db::get_progress(&conn, None, 7).unwrap().percentage, 0.1);
+        assert_eq!(
+            db::list_progress(&conn, Some(3)).unwrap()[0].updated_at,
+            3000
+        );
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM reading_progress", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(rows, 2);
+        // KOReader accounts of the same name are not taken to be the user's
+        assert_eq!(db::get_sync_user(&conn, "alice").unwrap().user_id, None);
+    }
+}
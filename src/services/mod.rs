@@ -1,3 +1,19 @@
 pub mod epub_parser;
+pub mod annotations;
+pub mod audio_cache;
+pub mod audio_format;
+pub mod audiobook;
+pub mod archive;
+pub mod auth;
 pub mod db;
+pub mod id3;
+pub mod koreader;
+pub mod m4b;
+pub mod memory_store;
+pub mod migrations;
+pub mod quota;
+pub mod reparse;
+pub mod search;
+pub mod store;
 pub mod tts;
+pub mod voices;
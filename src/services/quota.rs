@@ -0,0 +1,187 @@
+use crate::services::store::StorageUsage;
+use serde::Serialize;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Limits on what each user may store and synthesize; `None` is unlimited
+///
+/// Admins are not limited. Audio is counted per calendar month (UTC), storage as the size of
+/// the original files of the documents a user owns.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Quotas {
+    pub max_documents: Option<u64>,
+    pub max_stored_bytes: Option<u64>,
+    pub max_audio_seconds: Option<f64>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum QuotaError {
+    #[error("Document quota reached: {limit} documents")]
+    Documents { limit: u64 },
+
+    #[error("Storage quota exceeded: {used} of {limit} bytes used, the file has {size} bytes")]
+    StoredBytes { used: u64, limit: u64, size: u64 },
+
+    #[error("Audio quota reached: {used:.0} of {limit:.0} seconds synthesized this month")]
+    AudioSeconds { used: f64, limit: f64 },
+}
+
+/// A user's usage with their limits, as shown by the usage endpoints
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub documents: Meter<u64>,
+    pub stored_bytes: Meter<u64>,
+    pub audio_seconds: Meter<f64>,
+    /// The month audio seconds are counted in, e.g. `2026-10`
+    pub period: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Meter<T> {
+    pub used: T,
+    pub limit: Option<T>,
+}
+
+fn env_limit<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("{} must be a number, got {}", name, value)),
+        _ => Ok(None),
+    }
+}
+
+impl Quotas {
+    /// Limits from `EPUB_QUOTA_DOCUMENTS`, `EPUB_QUOTA_BYTES` and `EPUB_QUOTA_AUDIO_SECONDS`
+    pub fn from_env() -> Result<Self, String> {
+        Ok(Quotas {
+            max_documents: env_limit("EPUB_QUOTA_DOCUMENTS")?,
+            max_stored_bytes: env_limit("EPUB_QUOTA_BYTES")?,
+            max_audio_seconds: env_limit("EPUB_QUOTA_AUDIO_SECONDS")?,
+        })
+    }
+
+    /// Whether a file of `size` bytes may be added to what a user stores
+    pub fn check_upload(&self, usage: &StorageUsage, size: u64) -> Result<(), QuotaError> {
+        if let Some(limit) = self.max_documents {
+            if usage.documents >= limit {
+                return Err(QuotaError::Documents { limit });
+            }
+        }
+        if let Some(limit) = self.max_stored_bytes {
+            if usage.bytes + size > limit {
+                return Err(QuotaError::StoredBytes {
+                    used: usage.bytes,
+                    limit,
+                    size,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a user who synthesized `used` seconds this month may synthesize more
+    ///
+    /// Audio is counted as it is synthesized, so the quota can be overshot by the sentence,
+    /// or for audiobooks the chapter, that used it up.
+    pub fn check_audio(&self, used: f64) -> Result<(), QuotaError> {
+        match self.max_audio_seconds {
+            Some(limit) if used >= limit => Err(QuotaError::AudioSeconds { used, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn usage(&self, storage: &StorageUsage, audio_seconds: f64, period: String) -> Usage {
+        Usage {
+            documents: Meter {
+                used: storage.documents,
+                limit: self.max_documents,
+            },
+            stored_bytes: Meter {
+                used: storage.bytes,
+                limit: self.max_stored_bytes,
+            },
+            audio_seconds: Meter {
+                used: audio_seconds,
+                limit: self.max_audio_seconds,
+            },
+            period,
+        }
+    }
+}
+
+/// Year and month of a day counted from 1970-01-01, in the proleptic Gregorian calendar
+fn civil_month(days: i64) -> (i64, u32) {
+    // Howard Hinnant's days_from_civil inverse, with years starting in March
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32)
+}
+
+/// The month audio usage at `now`, in seconds since the Unix epoch, is counted in
+pub fn audio_period(now: i64) -> String {
+    let (year, month) = civil_month(now.div_euclid(86_400));
+    format!("{:04}-{:02}", year, month)
+}
+
+/// Seconds from `now` until the next month starts and audio usage is counted afresh
+pub fn seconds_until_next_period(now: i64) -> i64 {
+    let mut day = now.div_euclid(86_400);
+    let (_, month) = civil_month(day);
+    while civil_month(day).1 == month {
+        day += 1;
+    }
+    day * 86_400 - now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_periods_are_calendar_months() {
+        assert_eq!(audio_period(0), "1970-01");
+        // 2024-02-29 12:00 UTC
+        assert_eq!(audio_period(1_709_208_000), "2024-02");
+        assert_eq!(seconds_until_next_period(1_709_208_000), 12 * 3600);
+        // 2026-12-31 23:59:59 UTC
+        assert_eq!(audio_period(1_798_761_599), "2026-12");
+        assert_eq!(audio_period(1_798_761_600), "2027-01");
+    }
+
+    #[test]
+    fn test_quotas() {
+        let quotas = Quotas {
+            max_documents: Some(2),
+            max_stored_bytes: Some(1000),
+            max_audio_seconds: Some(60.0),
+        };
+        let usage = |documents, bytes| StorageUsage { documents, bytes };
+
+        assert!(quotas.check_upload(&usage(1, 400), 600).is_ok());
+        assert_eq!(
+            quotas.check_upload(&usage(2, 0), 1),
+            Err(QuotaError::Documents { limit: 2 })
+        );
+        assert!(matches!(
+            quotas.check_upload(&usage(1, 400), 601),
+            Err(QuotaError::StoredBytes { .. })
+        ));
+        assert!(quotas.check_audio(59.9).is_ok());
+        assert!(quotas.check_audio(60.0).is_err());
+        assert!(Quotas::default().check_audio(1e9).is_ok());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::services::epub_parser::{self, PARSER_VERSION};
+use crate::services::store::{ContentCounts, DocumentStore, StoreError};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum ReparseError {
+    #[error("No original file is stored for document {0}")]
+    NoOriginal(i64),
+
+    #[error("Failed to parse document {0}: {1}")]
+    Parse(i64, String),
+
+    #[error("{0}")]
+    Store(#[from] StoreError),
+}
+
+pub type ReparseResult = Result<ReparseReport, ReparseError>;
+
+/// Outcome of re-parsing one document, with what changed in the stored content
+#[derive(Debug, Serialize)]
+pub struct ReparseReport {
+    pub document_id: i64,
+    pub previous_parser_version: i64,
+    pub parser_version: i64,
+    pub before: ContentCounts,
+    pub after: ContentCounts,
+    /// `after.chapters - before.chapters`
+    pub chapter_diff: i64,
+}
+
+/// Parse a document's original file again with the current parser and replace its content
+///
+/// The document keeps its id, so everything stored against it is preserved.
+pub fn reparse_document(store: &dyn DocumentStore, id: i64) -> ReparseResult {
+    let previous_parser_version = store.parser_version(id)?;
+    let original = match store.get_original(id) {
+        Ok(original) => original,
+        Err(StoreError::NotFound) => return Err(ReparseError::NoOriginal(id)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let content =
+        epub_parser::parse_epub(&original.data).map_err(|e| ReparseError::Parse(id, e))?;
+
+    let before = store.content_counts(id)?;
+    store.replace_content(id, &content)?;
+    let after = store.content_counts(id)?;
+
+    info!(
+        "Re-parsed document {} (parser {} -> {}): {} -> {} chapters",
+        id, previous_parser_version, PARSER_VERSION, before.chapters, after.chapters
+    );
+
+    Ok(ReparseReport {
+        document_id: id,
+        previous_parser_version,
+        parser_version: PARSER_VERSION,
+        before,
+        after,
+        chapter_diff: after.chapters as i64 - before.chapters as i64,
+    })
+}
+
+/// Re-parse every document, or only those parsed by an older parser version
+///
+/// Documents are handled one at a time, each in its own transaction; a failure is
+/// reported for that document and does not stop the others.
+pub fn reparse_all(
+    store: &dyn DocumentStore,
+    outdated_only: bool,
+) -> Result<Vec<(i64, ReparseResult)>, ReparseError> {
+    let ids = store.document_ids(outdated_only.then_some(PARSER_VERSION))?;
+
+    Ok(ids
+        .into_iter()
+        .map(|id| {
+            let result = reparse_document(store, id);
+            if let Err(e) = &result {
+                warn!("Could not re-parse document {}: {}", id, e);
+            }
+            (id, result)
+        })
+        .collect())
+}
+
+impl ReparseError {
+    /// Whether the document to re-parse does not exist
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, ReparseError::Store(e) if e.is_not_found())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::db::{self, SqliteStore};
+    use crate::services::store::OriginalFile;
+    use std::fs;
+
+    #[test]
+    fn test_reparse_keeps_id_and_edited_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = db::init_pool(dir.path().join("test.db")).unwrap();
+        let store = SqliteStore::new(pool.clone());
+
+        let data = fs::read("moby-dick.epub").unwrap();
+        let content = epub_parser::parse_epub(&data).unwrap();
+        let chapters = content.chapters.len();
+        assert!(chapters > 1);
+        let original = OriginalFile {
+            filename: "moby-dick.epub".to_string(),
+            media_type: "application/epub+zip".to_string(),
+            data,
+        };
+        let id = store.save_document(&content, Some(&original)).unwrap();
+
+        // Pretend an older parser stored only part of the book
+        pool.get()
+            .unwrap()
+            .execute_batch(&format!(
+                "DELETE FROM chapters WHERE document_id = {id} AND chapter_index > 0;
+                 UPDATE documents SET parser_version = 0, title = 'Moby Dick' WHERE id = {id};"
+            ))
+            .unwrap();
+        assert_eq!(store.document_ids(Some(PARSER_VERSION)).unwrap(), vec![id]);
+
+        let report = reparse_document(&store, id).unwrap();
+        assert_eq!(report.previous_parser_version, 0);
+        assert_eq!(report.before.chapters, 1);
+        assert_eq!(report.after.chapters, chapters);
+        assert_eq!(report.chapter_diff, chapters as i64 - 1);
+
+        assert_eq!(store.get_document(id).unwrap().metadata.title, "Moby Dick");
+        assert!(store.document_ids(Some(PARSER_VERSION)).unwrap().is_empty());
+
+        let without_original = store.save_document(&content, None).unwrap();
+        assert!(matches!(
+            reparse_document(&store, without_original),
+            Err(ReparseError::NoOriginal(_))
+        ));
+        assert!(reparse_document(&store, 999).unwrap_err().is_not_found());
+    }
+}
@@ -0,0 +1,415 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::Serialize;
+
+/// Stemming algorithms by primary language subtag
+const STEMMERS: &[(&str, Algorithm)] = &[
+    ("ar", Algorithm::Arabic),
+    ("da", Algorithm::Danish),
+    ("de", Algorithm::German),
+    ("el", Algorithm::Greek),
+    ("en", Algorithm::English),
+    ("es", Algorithm::Spanish),
+    ("fi", Algorithm::Finnish),
+    ("fr", Algorithm::French),
+    ("hu", Algorithm::Hungarian),
+    ("it", Algorithm::Italian),
+    ("nl", Algorithm::Dutch),
+    ("no", Algorithm::Norwegian),
+    ("pt", Algorithm::Portuguese),
+    ("ro", Algorithm::Romanian),
+    ("ru", Algorithm::Russian),
+    ("sv", Algorithm::Swedish),
+    ("ta", Algorithm::Tamil),
+    ("tr", Algorithm::Turkish),
+];
+
+/// How the index names texts that are not stemmed
+const UNSTEMMED: &str = "none";
+
+/// Characters of context shown on each side of a match
+const SNIPPET_CONTEXT: usize = 60;
+
+/// A word of a text, with its position in characters
+#[derive(Debug)]
+pub struct Token {
+    pub start: usize,
+    pub end: usize,
+    pub word: String,
+}
+
+/// A matched range of a text, in characters
+#[derive(Debug, PartialEq)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A match with some surrounding text, the match itself wrapped in `<mark>`; the text is
+/// HTML-escaped, so the snippet can be shown as HTML
+#[derive(Debug, Serialize)]
+pub struct Snippet {
+    pub offset: usize,
+    pub length: usize,
+    pub text: String,
+}
+
+/// Turns words into the terms that are indexed and compared, stemming them for a language
+pub struct Analyzer {
+    /// Primary language subtag of the stemmer, as in [`STEMMERS`]
+    language: Option<&'static str>,
+    stemmer: Option<Stemmer>,
+}
+
+impl Analyzer {
+    /// An analyzer for a BCP 47 language tag such as `ru-RU`; unknown languages are not stemmed
+    pub fn for_language(language: Option<&str>) -> Self {
+        let primary = language
+            .and_then(|tag| tag.split(['-', '_']).next())
+            .map(|subtag| subtag.to_lowercase());
+
+        match primary.and_then(|subtag| STEMMERS.iter().find(|(code, _)| *code == subtag)) {
+            Some((code, algorithm)) => Self::stemming(code, *algorithm),
+            None => Self::unstemmed(),
+        }
+    }
+
+    fn stemming(code: &'static str, algorithm: Algorithm) -> Self {
+        Self {
+            language: Some(code),
+            stemmer: Some(Stemmer::create(algorithm)),
+        }
+    }
+
+    /// An analyzer that only folds case
+    pub fn unstemmed() -> Self {
+        Self {
+            language: None,
+            stemmer: None,
+        }
+    }
+
+    /// Names the stemming in the full-text index, so queries only match texts with the
+    /// terms they were stemmed into
+    pub fn stemming_name(&self) -> &'static str {
+        self.language.unwrap_or(UNSTEMMED)
+    }
+
+    /// Term for an already folded word
+    pub fn term(&self, word: &str) -> String {
+        match &self.stemmer {
+            Some(stemmer) => stemmer.stem(word).into_owned(),
+            None => word.to_string(),
+        }
+    }
+
+    /// Space separated terms of a text, as stored in the full-text index
+    pub fn index_text(&self, text: &str) -> String {
+        tokenize(text)
+            .iter()
+            .map(|token| self.term(&token.word))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Lowercase a word and fold `ё` into `е`, which Russian text uses interchangeably
+fn fold(word: &str) -> String {
+    word.to_lowercase().replace('ё', "е")
+}
+
+/// Split a text into folded words with their character offsets
+pub fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+
+    for (position, c) in text.chars().enumerate() {
+        if c.is_alphanumeric() {
+            if current.is_empty() {
+                start = position;
+            }
+            current.push(c);
+        } else if !current.is_empty() {
+            tokens.push(Token {
+                start,
+                end: position,
+                word: fold(&current),
+            });
+            current.clear();
+        }
+    }
+
+    if !current.is_empty() {
+        tokens.push(Token {
+            start,
+            end: start + current.chars().count(),
+            word: fold(&current),
+        });
+    }
+
+    tokens
+}
+
+/// A search query: words and `"quoted phrases"`, all of which must match
+#[derive(Debug)]
+pub struct Query {
+    /// Folded words of each part; a phrase has more than one
+    parts: Vec<Vec<String>>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Self {
+        let mut parts = Vec::new();
+
+        for (i, segment) in query.split('"').enumerate() {
+            let words: Vec<String> = tokenize(segment)
+                .into_iter()
+                .map(|token| token.word)
+                .collect();
+
+            // Odd segments are inside quotes
+            if i % 2 == 1 {
+                if !words.is_empty() {
+                    parts.push(words);
+                }
+            } else {
+                parts.extend(words.into_iter().map(|word| vec![word]));
+            }
+        }
+
+        Self { parts }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// FTS5 match expression for the index built by [`Analyzer::index_text`], with the
+    /// [`Analyzer::stemming_name`] of each text in its `stemming` column
+    ///
+    /// The language of each indexed text is only known per row, so the query is expanded
+    /// into one alternative per stemming language, plus an unstemmed one, each matching
+    /// only the texts stemmed that way. A text therefore matches exactly when
+    /// [`Query::find_matches`] finds every part in it with the analyzer of its language.
+    pub fn to_fts(&self) -> String {
+        let mut analyzers: Vec<Analyzer> = STEMMERS
+            .iter()
+            .map(|(code, algorithm)| Analyzer::stemming(code, *algorithm))
+            .collect();
+        analyzers.push(Analyzer::unstemmed());
+
+        // Stemmings that give the same terms share an alternative
+        let mut alternatives: Vec<(String, Vec<&str>)> = Vec::new();
+        for analyzer in &analyzers {
+            let alternative = self
+                .parts
+                .iter()
+                .map(|part| {
+                    let terms: Vec<String> = part.iter().map(|word| analyzer.term(word)).collect();
+                    format!("\"{}\"", terms.join(" "))
+                })
+                .collect::<Vec<_>>()
+                .join(" AND ");
+
+            match alternatives
+                .iter_mut()
+                .find(|(terms, _)| *terms == alternative)
+            {
+                Some((_, stemmings)) => stemmings.push(analyzer.stemming_name()),
+                None => alternatives.push((alternative, vec![analyzer.stemming_name()])),
+            }
+        }
+
+        alternatives
+            .iter()
+            .map(|(alternative, stemmings)| {
+                format!(
+                    "(stemming : ({}) AND {{title body}} : ({}))",
+                    stemmings.join(" OR "),
+                    alternative
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" OR ")
+    }
+
+    /// Every occurrence of any part of the query in a text, in order
+    pub fn find_matches(&self, text: &str, analyzer: &Analyzer) -> Vec<Match> {
+        let tokens = tokenize(text);
+        let terms: Vec<String> = tokens
+            .iter()
+            .map(|token| analyzer.term(&token.word))
+            .collect();
+        let parts: Vec<Vec<String>> = self
+            .parts
+            .iter()
+            .map(|part| part.iter().map(|word| analyzer.term(word)).collect())
+            .collect();
+
+        let mut matches = Vec::new();
+        let mut i = 0;
+        while i < terms.len() {
+            let matched = parts
+                .iter()
+                .filter(|part| terms[i..].starts_with(part))
+                .map(|part| part.len())
+                .max();
+
+            match matched {
+                Some(len) => {
+                    matches.push(Match {
+                        start: tokens[i].start,
+                        end: tokens[i + len - 1].end,
+                    });
+                    i += len;
+                }
+                None => i += 1,
+            }
+        }
+
+        matches
+    }
+
+    /// Number of occurrences of each part of the query in a text, in query order
+    pub fn count_parts(&self, text: &str, analyzer: &Analyzer) -> Vec<usize> {
+        let terms: Vec<String> = tokenize(text)
+            .iter()
+            .map(|token| analyzer.term(&token.word))
+            .collect();
+
+        self.parts
+            .iter()
+            .map(|part| {
+                let part: Vec<String> = part.iter().map(|word| analyzer.term(word)).collect();
+                (0..terms.len())
+                    .filter(|&i| terms[i..].starts_with(&part))
+                    .count()
+            })
+            .collect()
+    }
+}
+
+/// Build a snippet around a match, collapsing whitespace in the surrounding text
+pub fn snippet(text: &str, m: &Match) -> Snippet {
+    let chars: Vec<char> = text.chars().collect();
+    let from = m.start.saturating_sub(SNIPPET_CONTEXT);
+    let to = (m.end + SNIPPET_CONTEXT).min(chars.len());
+
+    // Chapter text is whatever the book contains, so it must not become markup
+    let before = escape_html(&chars[from..m.start]);
+    let matched = escape_html(&chars[m.start..m.end]);
+    let after = escape_html(&chars[m.end..to]);
+
+    let mut snippet_text = format!("{}<mark>{}</mark>{}", before, matched, after)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if from > 0 {
+        snippet_text.insert(0, '…');
+    }
+    if to < chars.len() {
+        snippet_text.push('…');
+    }
+
+    Snippet {
+        offset: m.start,
+        length: m.end - m.start,
+        text: snippet_text,
+    }
+}
+
+fn escape_html(text: &[char]) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for &c in text {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize_reports_character_offsets() {
+        let tokens = tokenize("Вишнёвый  сад, Queequeg!");
+        let words: Vec<&str> = tokens.iter().map(|t| t.word.as_str()).collect();
+        assert_eq!(words, vec!["вишневый", "сад", "queequeg"]);
+        assert_eq!((tokens[1].start, tokens[1].end), (10, 13));
+    }
+
+    #[test]
+    fn test_russian_stemming_matches_inflected_forms() {
+        let analyzer = Analyzer::for_language(Some("ru-RU"));
+        let query = Query::parse("вишнёвый сад");
+
+        let text = "Он продал вишнёвого сада половину.";
+        let matches = query.find_matches(text, &analyzer);
+        assert_eq!(matches.len(), 2);
+        let first: String = text
+            .chars()
+            .skip(matches[0].start)
+            .take(matches[0].end - matches[0].start)
+            .collect();
+        assert_eq!(first, "вишнёвого");
+    }
+
+    #[test]
+    fn test_phrase_matches_consecutive_words_only() {
+        let analyzer = Analyzer::for_language(Some("en"));
+        let query = Query::parse("\"white whale\"");
+
+        let matches = query.find_matches("The white whale, a white ship, a whale.", &analyzer);
+        assert_eq!(matches, vec![Match { start: 4, end: 15 }]);
+
+        let snippet = snippet("The white whale, a white ship.", &matches[0]);
+        assert_eq!(snippet.text, "The <mark>white whale</mark>, a white ship.");
+    }
+
+    #[test]
+    fn test_snippets_escape_the_chapter_text() {
+        let text = "Write <b>whale</b> & <img src=x onerror=alert(1)> ahoy";
+        let query = Query::parse("whale");
+        let matches = query.find_matches(text, &Analyzer::unstemmed());
+
+        let snippet = snippet(text, &matches[0]);
+        assert_eq!(
+            snippet.text,
+            "Write &lt;b&gt;<mark>whale</mark>&lt;/b&gt; &amp; &lt;img src=x onerror=alert(1)&gt; ahoy"
+        );
+        assert_eq!((snippet.offset, snippet.length), (9, 5));
+    }
+
+    #[test]
+    fn test_unstemmed_matches_whole_words_in_any_case() {
+        let query = Query::parse("Whale");
+
+        let matches =
+            query.find_matches("WHALE ahoy! The whale, the whales.", &Analyzer::unstemmed());
+        assert_eq!(
+            matches,
+            vec![Match { start: 0, end: 5 }, Match { start: 16, end: 21 }]
+        );
+    }
+
+    #[test]
+    fn test_fts_query_has_alternative_per_stemming() {
+        let fts = Query::parse("whales \"вишнёвый сад\"").to_fts();
+        // English stems the English word, Russian the Russian phrase, and each only
+        // matches texts stemmed the same way
+        assert!(
+            fts.contains("(stemming : (en) AND {title body} : (\"whale\" AND \"вишневый сад\"))")
+        );
+        assert!(
+            fts.contains("(stemming : (ru) AND {title body} : (\"whales\" AND \"вишнев сад\"))")
+        );
+        assert!(fts.contains(" OR none) AND "));
+    }
+}
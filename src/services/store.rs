@@ -0,0 +1,546 @@
+use crate::models::metadata::{EpubMetadata, TocEntry};
+use crate::services::annotations::{self, Annotation, AnnotationUpdate, NewAnnotation};
+use crate::services::archive::{ArchiveError, ImportedDocument, Manifest};
+use crate::services::audio_cache::AudioCache;
+use crate::services::auth::{ApiToken, Scope, User};
+use crate::services::db::DbError;
+use crate::services::epub_parser::EpubContent;
+use crate::services::koreader::{self, SyncProgress, SyncUser};
+use crate::services::search::Query;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error("Not found")]
+    NotFound,
+
+    #[error("{0}")]
+    Database(#[from] DbError),
+
+    /// The request conflicts with what is stored, e.g. a name already in use
+    #[error("{0}")]
+    Conflict(String),
+
+    /// The request refers to something that does not exist or makes no sense
+    #[error("{0}")]
+    Invalid(String),
+
+    #[error("Not supported by this store: {0}")]
+    Unsupported(&'static str),
+
+    #[error("Storage task failed: {0}")]
+    Task(String),
+}
+
+impl StoreError {
+    /// Whether the error means the requested document or chapter does not exist
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, StoreError::NotFound)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => StoreError::NotFound,
+            err => StoreError::Database(DbError::Sqlite(err)),
+        }
+    }
+}
+
+impl From<r2d2::Error> for StoreError {
+    fn from(err: r2d2::Error) -> Self {
+        StoreError::Database(DbError::Pool(err))
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+pub struct Document {
+    pub id: i64,
+    pub metadata: EpubMetadata,
+}
+
+pub struct StoredChapter {
+    pub title: String,
+    pub text: String,
+}
+
+/// A chapter matching a full-text search, with the document it belongs to
+pub struct ChapterHit {
+    pub document_id: i64,
+    pub document_title: String,
+    pub author: String,
+    pub language: Option<String>,
+    pub chapter_index: usize,
+    pub chapter_title: String,
+    pub text: String,
+    pub score: f64,
+}
+
+/// The file a document was uploaded as, kept so it can be downloaded again
+pub struct OriginalFile {
+    pub filename: String,
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+impl OriginalFile {
+    /// Hex SHA-256 of the content, which is what the file is stored under
+    pub fn sha256(&self) -> String {
+        format!("{:x}", Sha256::digest(&self.data))
+    }
+
+    /// KOReader's digest of the file, which its progress sync identifies books by
+    pub fn partial_md5(&self) -> String {
+        koreader::partial_md5(&self.data)
+    }
+}
+
+/// How much was stored for a document, to compare parses of the same book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ContentCounts {
+    pub chapters: usize,
+    pub resources: usize,
+    pub toc_entries: usize,
+}
+
+/// What a user stores: the documents they own and the size of their original files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StorageUsage {
+    pub documents: u64,
+    pub bytes: u64,
+}
+
+/// Library listing entry: document metadata without any chapter content
+#[derive(Debug, Serialize)]
+pub struct DocumentSummary {
+    pub id: i64,
+    pub title: String,
+    pub author: String,
+    pub publication_date: Option<String>,
+    pub language: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub format: String,
+    pub tags: Vec<String>,
+    pub chapter_count: usize,
+    /// Upload time, in seconds since the Unix epoch
+    pub uploaded_at: i64,
+}
+
+/// Restricts a library listing; every filter that is set must match
+#[derive(Debug, Default)]
+pub struct DocumentFilter {
+    /// Language tag prefix, e.g. `en` matches `en-US`
+    pub language: Option<String>,
+    /// Exact author, ignoring case
+    pub author: Option<String>,
+    /// Tag the document must have, ignoring case
+    pub tag: Option<String>,
+    pub format: Option<String>,
+    /// Id of a collection the document must belong to
+    pub collection: Option<i64>,
+    /// Only documents this user may see: their own, shared ones and those without an owner
+    pub visible_to: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentSort {
+    Title,
+    Author,
+    Uploaded,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Value of the sort column for the last document of a page
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    Integer(i64),
+    Text(String),
+}
+
+/// Position in a library listing: documents strictly after `(key, id)` come next.
+/// Only meaningful with the sort and order of the listing it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListCursor {
+    pub key: SortKey,
+    pub id: i64,
+}
+
+/// Metadata changes to a stored document; fields that are `None` are left as they are.
+/// For optional fields `Some(None)` clears the value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DocumentUpdate {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub publication_date: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub language: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub series: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub series_index: Option<Option<f64>>,
+}
+
+/// A tag in use, with the number of documents that have it
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub document_count: usize,
+}
+
+/// A named, ordered group of documents, such as a course reading list
+#[derive(Debug, Serialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    /// Of the documents the caller may see
+    pub document_count: usize,
+    /// Creation time, in seconds since the Unix epoch
+    pub created_at: i64,
+    /// Who may change it; `None` for collections made before accounts, which admins manage
+    #[serde(skip)]
+    pub owner_id: Option<i64>,
+}
+
+/// Changes to a collection; fields that are `None` are left as they are
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectionUpdate {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+}
+
+/// Where a reader is in a document, as last reported by one of their devices
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Progress {
+    /// `None` for the local reader, while authentication is disabled
+    #[serde(skip)]
+    pub user_id: Option<i64>,
+    pub document_id: i64,
+    pub chapter_index: usize,
+    /// Character offset in the chapter's text
+    pub char_offset: usize,
+    /// Position in the chapter's audio, in seconds
+    pub audio_position: Option<f64>,
+    /// How far through the document, from 0 to 1
+    pub percentage: f64,
+    pub device: Option<String>,
+    /// When the reader was at this position, in milliseconds since the Unix epoch
+    pub updated_at: i64,
+}
+
+impl Progress {
+    /// Move the position to where its text is in re-parsed chapters, the way annotations are
+    /// re-anchored, or keep it inside the new chapters when the text is gone
+    pub fn relocate(&mut self, previous: &[&str], chapters: &[&str]) {
+        let located = previous.get(self.chapter_index).and_then(|text| {
+            let offset = self.char_offset.min(text.chars().count());
+            let anchor = annotations::anchor(text, self.chapter_index, offset, offset).ok()?;
+            annotations::locate(chapters, &anchor)
+        });
+        let (chapter_index, char_offset) = match located {
+            Some(anchor) => (anchor.chapter_index, anchor.start_offset),
+            None => {
+                let chapter_index = self.chapter_index.min(chapters.len().saturating_sub(1));
+                let length = chapters
+                    .get(chapter_index)
+                    .map_or(0, |text| text.chars().count());
+                (chapter_index, self.char_offset.min(length))
+            }
+        };
+
+        // The audio position belongs to the old chapter
+        if (chapter_index, char_offset) != (self.chapter_index, self.char_offset) {
+            self.audio_position = None;
+        }
+        self.chapter_index = chapter_index;
+        self.char_offset = char_offset;
+    }
+}
+
+/// Who a document belongs to and whether others may read it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DocumentAccess {
+    /// The user who uploaded the document; `None` for documents stored before accounts
+    /// existed, imported ones and those uploaded with authentication disabled
+    pub owner_id: Option<i64>,
+    pub shared: bool,
+}
+
+/// Tell an explicit `null` (clear the field) apart from a missing field (keep it)
+pub(crate) fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Where documents are kept
+///
+/// Handlers reach the store through `ApiState`. Methods block, so async code calls them
+/// through [`run`]. Lookups of a document or chapter that does not exist fail with
+/// [`StoreError::NotFound`].
+pub trait DocumentStore: Send + Sync {
+    /// Store a parsed EPUB, with the file it was parsed from, and return the new document id
+    fn save_document(
+        &self,
+        content: &EpubContent,
+        original: Option<&OriginalFile>,
+    ) -> StoreResult<i64>;
+
+    fn get_document(&self, id: i64) -> StoreResult<Document>;
+
+    /// All chapters of a document in reading order
+    fn get_chapters(&self, id: i64) -> StoreResult<Vec<StoredChapter>>;
+
+    /// Original XHTML of one chapter
+    fn get_chapter_html(&self, id: i64, index: usize) -> StoreResult<String>;
+
+    /// Text of one chapter, as extracted when it was parsed
+    fn get_chapter_text(&self, id: i64, index: usize) -> StoreResult<String>;
+
+    fn get_toc(&self, id: i64) -> StoreResult<Vec<TocEntry>>;
+
+    /// The file a document was uploaded as; not found for documents stored without one
+    fn get_original(&self, id: i64) -> StoreResult<OriginalFile>;
+
+    /// One page of the library, with the cursor of the next page if there is one
+    fn list_documents(
+        &self,
+        filter: &DocumentFilter,
+        sort: DocumentSort,
+        order: SortOrder,
+        after: Option<&ListCursor>,
+        limit: usize,
+    ) -> StoreResult<(Vec<DocumentSummary>, Option<ListCursor>)>;
+
+    /// Apply metadata changes and return the updated document
+    fn update_document(&self, id: i64, update: &DocumentUpdate) -> StoreResult<Document>;
+
+    /// Remove a document with everything stored for it
+    fn delete_document(&self, id: i64) -> StoreResult<()>;
+
+    /// Chapters containing every part of the query, best matches first, chapters of books
+    /// in `language` ahead of the rest. Returns the total number of matching chapters and
+    /// the requested page.
+    fn search_chapters(
+        &self,
+        query: &Query,
+        language: Option<&str>,
+        visible_to: Option<i64>,
+        limit: usize,
+        offset: usize,
+    ) -> StoreResult<(usize, Vec<ChapterHit>)>;
+
+    /// Replace the parsed content of a document, keeping its id and the metadata already set
+    ///
+    /// Annotations are moved to where their quoted text is in the new content, see
+    /// [`annotations::locate`](crate::services::annotations::locate).
+    fn replace_content(&self, id: i64, content: &EpubContent) -> StoreResult<()>;
+
+    fn content_counts(&self, id: i64) -> StoreResult<ContentCounts>;
+
+    /// Version of the parser a document was last parsed with
+    fn parser_version(&self, id: i64) -> StoreResult<i64>;
+
+    /// Ids of all documents, or only of those parsed with a parser older than `parsed_before`
+    fn document_ids(&self, parsed_before: Option<i64>) -> StoreResult<Vec<i64>>;
+
+    /// Replace the tags of a document and return them, alphabetically
+    fn set_tags(&self, id: i64, tags: &[String]) -> StoreResult<Vec<String>>;
+
+    /// Every tag of the documents `visible_to` may see, as in [`DocumentFilter`],
+    /// alphabetically
+    fn list_tags(&self, visible_to: Option<i64>) -> StoreResult<Vec<TagCount>>;
+
+    /// Fails with [`StoreError::Conflict`] if the name is taken, ignoring case
+    fn create_collection(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        owner_id: Option<i64>,
+    ) -> StoreResult<Collection>;
+
+    /// All collections, alphabetically; only documents `visible_to` may see are counted
+    fn list_collections(&self, visible_to: Option<i64>) -> StoreResult<Vec<Collection>>;
+
+    fn get_collection(&self, id: i64, visible_to: Option<i64>) -> StoreResult<Collection>;
+
+    fn update_collection(&self, id: i64, update: &CollectionUpdate) -> StoreResult<Collection>;
+
+    /// Remove a collection; its documents stay in the library
+    fn delete_collection(&self, id: i64) -> StoreResult<()>;
+
+    /// Documents of a collection in their order within it
+    fn collection_documents(&self, id: i64) -> StoreResult<Vec<DocumentSummary>>;
+
+    /// Replace the documents of a collection with `document_ids`, in that order
+    ///
+    /// Fails with [`StoreError::Invalid`] if a document does not exist or is listed twice.
+    fn set_collection_documents(&self, id: i64, document_ids: &[i64]) -> StoreResult<()>;
+
+    /// Put a document at `position` in a collection, or at the end, moving it if it is
+    /// already there
+    fn add_to_collection(
+        &self,
+        id: i64,
+        document_id: i64,
+        position: Option<usize>,
+    ) -> StoreResult<()>;
+
+    /// Take a document out of a collection; not found if it is not in it
+    fn remove_from_collection(&self, id: i64, document_id: i64) -> StoreResult<()>;
+
+    fn create_annotation(
+        &self,
+        document_id: i64,
+        annotation: &NewAnnotation,
+    ) -> StoreResult<Annotation>;
+
+    /// A user's annotations of a document in reading order
+    fn list_annotations(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+    ) -> StoreResult<Vec<Annotation>>;
+
+    /// Change one of a user's annotations; not found for other users' annotations
+    fn update_annotation(
+        &self,
+        document_id: i64,
+        user_id: Option<i64>,
+        id: i64,
+        update: &AnnotationUpdate,
+    ) -> StoreResult<Annotation>;
+
+    fn delete_annotation(&self, document_id: i64, user_id: Option<i64>, id: i64)
+        -> StoreResult<()>;
+
+    /// A reader's progress in a document; not found if none was saved
+    fn get_progress(&self, user_id: Option<i64>, document_id: i64) -> StoreResult<Progress>;
+
+    /// A reader's progress in every document, most recently updated first
+    fn list_progress(&self, user_id: Option<i64>) -> StoreResult<Vec<Progress>>;
+
+    /// Save progress unless newer progress is already stored, so the last writer wins
+    /// regardless of the order updates arrive in. Returns whether it was saved.
+    fn save_progress(&self, progress: &Progress) -> StoreResult<bool>;
+
+    /// The document whose original file KOReader knows by `digest`, either its partial MD5
+    /// or the MD5 of its file name; the oldest one `visible_to` may see if several match
+    fn find_by_digest(&self, digest: &str, visible_to: Option<i64>) -> StoreResult<i64>;
+
+    /// Register a KOReader sync account; a conflict if the name is taken
+    fn create_sync_user(&self, user: &SyncUser) -> StoreResult<()>;
+
+    fn get_sync_user(&self, username: &str) -> StoreResult<SyncUser>;
+
+    /// Link a KOReader sync account to a user, so its progress is shared with theirs
+    fn link_sync_user(&self, username: &str, user_id: i64) -> StoreResult<()>;
+
+    /// Replace what a KOReader account last reported for a book
+    fn save_sync_progress(&self, progress: &SyncProgress) -> StoreResult<()>;
+
+    fn get_sync_progress(&self, username: &str, document: &str) -> StoreResult<SyncProgress>;
+
+    fn document_access(&self, id: i64) -> StoreResult<DocumentAccess>;
+
+    fn set_document_access(&self, id: i64, access: &DocumentAccess) -> StoreResult<()>;
+
+    /// Add a user with an already hashed password; a conflict if the name is taken,
+    /// ignoring case
+    fn create_user(&self, username: &str, password_hash: &str, is_admin: bool)
+        -> StoreResult<User>;
+
+    fn list_users(&self) -> StoreResult<Vec<User>>;
+
+    /// A user with their password hash, for logging in
+    fn get_user_by_name(&self, username: &str) -> StoreResult<(User, String)>;
+
+    fn set_password(&self, user_id: i64, password_hash: &str) -> StoreResult<()>;
+
+    /// Delete a user with their tokens, annotations, progress and KOReader accounts; a
+    /// conflict while they own documents
+    fn delete_user(&self, id: i64) -> StoreResult<()>;
+
+    /// Give documents, collections and annotations without an owner, and the progress of the
+    /// local reader, to a user. Returns the number of documents given.
+    fn claim_unowned(&self, user: &User) -> StoreResult<usize>;
+
+    fn create_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &[Scope],
+        token_hash: &str,
+    ) -> StoreResult<ApiToken>;
+
+    fn list_tokens(&self, user_id: i64) -> StoreResult<Vec<ApiToken>>;
+
+    fn delete_token(&self, user_id: i64, id: i64) -> StoreResult<()>;
+
+    /// The user and token for a token hash, recording that the token was used
+    fn authenticate_token(&self, token_hash: &str) -> StoreResult<(User, ApiToken)>;
+
+    fn storage_usage(&self, user_id: i64) -> StoreResult<StorageUsage>;
+
+    /// Seconds of audio synthesized for a user in a period, such as `2026-10`
+    fn audio_usage(&self, user_id: i64, period: &str) -> StoreResult<f64>;
+
+    fn add_audio_usage(&self, user_id: i64, period: &str, seconds: f64) -> StoreResult<()>;
+
+    /// Write the library as an archive, with the audio cached in `audio`, see
+    /// `services::archive`
+    fn export_library(
+        &self,
+        _out: &mut File,
+        _audio: Option<&AudioCache>,
+    ) -> Result<Manifest, ArchiveError> {
+        Err(StoreError::Unsupported("library export").into())
+    }
+
+    /// Add the documents of an exported archive under new ids; `importer` gets the documents
+    /// of owners without an account here and `audio` the audio in the archive
+    fn import_library(
+        &self,
+        _archive: File,
+        _importer: Option<i64>,
+        _audio: Option<&Arc<AudioCache>>,
+    ) -> Result<Vec<ImportedDocument>, ArchiveError> {
+        Err(StoreError::Unsupported("library import").into())
+    }
+}
+
+/// Run store calls on the blocking thread pool, so they never tie up the async workers
+pub async fn run<T, F>(store: &Arc<dyn DocumentStore>, f: F) -> StoreResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn DocumentStore) -> StoreResult<T> + Send + 'static,
+{
+    let store = store.clone();
+
+    tokio::task::spawn_blocking(move || f(store.as_ref()))
+        .await
+        .map_err(|e| StoreError::Task(e.to_string()))?
+}
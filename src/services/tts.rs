@@ -1,17 +1,29 @@
-use bytes::{Bytes, BytesMut};
+use crate::services::audio_cache::{self, AudioCache, CacheWriter, CachedAudio};
+use crate::services::audio_format::{AudioFormat, Encoder, BYTES_PER_SAMPLE};
+use crate::services::voices::{Prosody, Voice, VoiceRegistry, VoiceSettings};
+use bytes::Bytes;
 use futures::Stream;
 use piper_rs::synth::PiperSpeechSynthesizer;
+use piper_rs::PiperSynthesisConfig;
 use scraper::{Html, Selector};
-use std::io::{self, Cursor};
+use std::collections::VecDeque;
+use std::io;
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::task::{Context, Poll};
 use thiserror::Error;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tracing::{error, info};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Loaded models kept unless `EPUB_CACHED_VOICES` says otherwise
+pub const CACHED_MODELS: usize = 2;
+
+/// Sentences synthesized ahead of the listener
+const SENTENCES_AHEAD: usize = 4;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum TtsError {
     #[error("Failed to process HTML: {0}")]
     HtmlProcessingError(String),
@@ -24,6 +36,9 @@ pub enum TtsError {
 
     #[error("Model error: {0}")]
     ModelError(String),
+
+    #[error("Failed to encode audio: {0}")]
+    EncodingError(String),
 }
 
 impl From<io::Error> for TtsError {
@@ -38,6 +53,9 @@ pub struct TtsConfig {
     pub voice_path: String,
     pub sample_rate: u32,
     pub language: String,
+    /// Speaker id for multi-speaker voices
+    pub speaker: Option<i64>,
+    pub prosody: Prosody,
 }
 
 impl Default for TtsConfig {
@@ -49,135 +67,291 @@ impl Default for TtsConfig {
             voice_path: format!("./assets/voices/{}.onnx.config", voice_name),
             sample_rate: 22050,
             language: "en-US".to_string(),
+            speaker: None,
+            prosody: Prosody::default(),
         }
     }
 }
 
 impl TtsConfig {
-    pub fn new(voice_name: String, sample_rate: u32) -> Self {
+    /// Create a TtsConfig for one of the installed voices, read as it reads by default
+    pub fn from_voice(voice: &Voice) -> Self {
         Self {
-            voice_name: voice_name.clone(),
-            model_path: format!("./assets/voices/{}.onnx", voice_name),
-            voice_path: format!("./assets/voices/{}.onnx.config", voice_name),
-            sample_rate,
-            language: voice_name.split('-').next().unwrap_or("en_US").to_string(),
+            voice_name: voice.name.clone(),
+            model_path: voice.model_path.to_string_lossy().into_owned(),
+            voice_path: voice.config_path.to_string_lossy().into_owned(),
+            sample_rate: voice.sample_rate,
+            language: voice.language.clone(),
+            speaker: voice.speakers.first().map(|speaker| speaker.id),
+            prosody: voice.prosody,
         }
     }
 
-    /// Create a new TtsConfig based on the specified language
-    pub fn from_language(language: &str) -> Self {
-        let (voice_name, sample_rate) = match language {
-            "ru" | "ru-RU" => ("ru_RU-ruslan-medium".to_string(), 22050),
-            _ => ("en_US-ryan-high".to_string(), 22050), // Default to English
-        };
-
+    /// Create a TtsConfig for a voice with a chosen speaker and prosody
+    pub fn from_settings(settings: &VoiceSettings) -> Self {
         Self {
-            voice_name: voice_name.clone(),
-            model_path: format!("./assets/voices/{}.onnx", voice_name),
-            voice_path: format!("./assets/voices/{}.onnx.config", voice_name),
-            sample_rate,
-            language: language.to_string(),
+            speaker: settings.speaker,
+            prosody: settings.prosody,
+            ..Self::from_voice(&settings.voice)
         }
     }
 }
 
-/// A stream of audio bytes from Piper TTS
+/// Audio streamed as it is synthesized: the header of its format, then each sentence encoded
 pub struct AudioStream {
-    audio_data: Cursor<Vec<u8>>,
-    buffer: BytesMut,
-    position: usize,
-    pub total_len: usize,
+    header: Option<Bytes>,
+    sentences: mpsc::Receiver<Result<Bytes, TtsError>>,
 }
 
 impl Stream for AudioStream {
-    type Item = Result<Bytes, io::Error>;
+    type Item = Result<Bytes, TtsError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
 
-        // If we've reached the end of the data, we're done
-        if this.position >= this.total_len {
-            return Poll::Ready(None);
+        // The header goes out at once, so players start before the first sentence is ready
+        if let Some(header) = this.header.take() {
+            return Poll::Ready(Some(Ok(header)));
         }
 
-        // Ensure we have space
-        if this.buffer.len() == this.buffer.capacity() {
-            this.buffer.reserve(4096);
-        }
-
-        let mut buf = [0u8; 4096];
-
-        // Try to read some data
-        match io::Read::read(&mut this.audio_data, &mut buf) {
-            Ok(0) => {
-                // End of stream
-                this.position = this.total_len;
+        this.sentences.poll_recv(cx)
+    }
+}
 
-                // Return any remaining data
-                if !this.buffer.is_empty() {
-                    let bytes = this.buffer.split().freeze();
-                    return Poll::Ready(Some(Ok(bytes)));
+/// Speak sentences one after the other on their own thread, streaming each as it is done
+///
+/// `speak` turns one sentence into samples, which `encoder` turns into the output format.
+/// `on_spoken` is told the seconds of each sentence as it is spoken. Synthesis stops early
+/// when the listener goes away, a sentence fails or `on_spoken` returns false. The audio is
+/// also written to `cache`, which keeps it only once every sentence is spoken.
+fn stream_sentences<F>(
+    sentences: Vec<String>,
+    sample_rate: u32,
+    mut speak: F,
+    mut encoder: Box<dyn Encoder>,
+    mut cache: Option<CacheWriter>,
+    mut on_spoken: impl FnMut(f64) -> bool + Send + 'static,
+) -> Result<AudioStream, TtsError>
+where
+    F: FnMut(String) -> Result<Vec<u8>, TtsError> + Send + 'static,
+{
+    let header = encoder.header().map_err(TtsError::EncodingError)?;
+    cache_audio(&mut cache, &header);
+    let (sender, receiver) = mpsc::channel(SENTENCES_AHEAD);
+
+    std::thread::Builder::new()
+        .name("tts-synthesis".to_string())
+        .spawn(move || {
+            let mut complete = true;
+            for sentence in sentences {
+                let mut go_on = true;
+                let result = speak(sentence).and_then(|pcm| {
+                    let samples = pcm.len() / BYTES_PER_SAMPLE as usize;
+                    go_on = on_spoken(samples as f64 / f64::from(sample_rate));
+                    encoder.encode(&pcm).map_err(TtsError::EncodingError)
+                });
+                let failed = result.is_err();
+                if let Ok(audio) = &result {
+                    cache_audio(&mut cache, audio);
                 }
-
-                Poll::Ready(None)
-            }
-            Ok(n) => {
-                // Append data to our buffer
-                this.buffer.extend_from_slice(&buf[..n]);
-                this.position += n;
-
-                // Return a chunk if we have enough data
-                if this.buffer.len() >= 4096 {
-                    let bytes = this.buffer.split().freeze();
-                    Poll::Ready(Some(Ok(bytes)))
-                } else if this.position >= this.total_len {
-                    // Return whatever we have if we've read everything
-                    let bytes = this.buffer.split().freeze();
-                    Poll::Ready(Some(Ok(bytes)))
-                } else {
-                    cx.waker().wake_by_ref();
-                    Poll::Pending
+                // Encoders may hold samples back until they have a whole frame
+                let held_back = result.as_ref().is_ok_and(|audio| audio.is_empty());
+                let gone = !held_back && sender.blocking_send(result.map(Bytes::from)).is_err();
+                if gone || failed || !go_on {
+                    complete = false;
+                    break;
                 }
             }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                cx.waker().wake_by_ref();
-                Poll::Pending
+            if complete {
+                match encoder.finish() {
+                    Ok(audio) => {
+                        cache_audio(&mut cache, &audio);
+                        if !audio.is_empty() {
+                            let _ = sender.blocking_send(Ok(Bytes::from(audio)));
+                        }
+                        if let Some(writer) = cache {
+                            if let Err(e) = writer.finish(encoder.final_header().as_deref()) {
+                                warn!("Failed to cache audio: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = sender.blocking_send(Err(TtsError::EncodingError(e)));
+                    }
+                }
             }
-            Err(e) => Poll::Ready(Some(Err(e))),
+        })?;
+
+    Ok(AudioStream {
+        header: Some(Bytes::from(header)),
+        sentences: receiver,
+    })
+}
+
+/// Write encoded audio to the cache, giving up on caching the chapter if that fails
+fn cache_audio(cache: &mut Option<CacheWriter>, audio: &[u8]) {
+    if let Some(Err(e)) = cache.as_mut().map(|writer| writer.write(audio)) {
+        warn!("Failed to cache audio: {}", e);
+        *cache = None;
+    }
+}
+
+/// A loaded Piper model; the speaker and prosody are stored in the model, so it is locked
+/// while it speaks
+type Model = Mutex<PiperSpeechSynthesizer>;
+
+/// The most recently used values by key, at most `capacity` of them
+pub struct ModelCache<T> {
+    capacity: usize,
+    /// Least recently used first
+    entries: Mutex<VecDeque<(String, Arc<T>)>>,
+}
+
+impl<T> ModelCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, VecDeque<(String, Arc<T>)>> {
+        // The entries are only moved while locked, a panic cannot leave them half changed
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The value for `key`, marking it as the most recently used
+    pub fn get(&self, key: &str) -> Option<Arc<T>> {
+        let mut entries = self.entries();
+        let position = entries.iter().position(|(cached, _)| cached == key)?;
+        let entry = entries.remove(position)?;
+        let value = entry.1.clone();
+        entries.push_back(entry);
+        Some(value)
+    }
+
+    /// The value for `key`, loading it on a miss and evicting the least recently used
+    /// value when full
+    pub fn get_or_load<E>(
+        &self,
+        key: &str,
+        load: impl FnOnce() -> Result<T, E>,
+    ) -> Result<Arc<T>, E> {
+        if let Some(value) = self.get(key) {
+            return Ok(value);
+        }
+
+        // Loading a model takes seconds, so the cache stays usable meanwhile
+        let value = Arc::new(load()?);
+        if let Some(loaded) = self.get(key) {
+            // Another request loaded it first
+            return Ok(loaded);
         }
+        let mut entries = self.entries();
+        entries.push_back((key.to_string(), value.clone()));
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+        Ok(value)
     }
 }
 
 pub struct TtsService {
-    model_path: String,
-    config_path: String,
     config: TtsConfig,
+    voices: Arc<VoiceRegistry>,
+    models: Arc<ModelCache<Model>>,
+    audio: Option<Arc<AudioCache>>,
+    /// The document read, whose chapters are kept in the audio cache
+    document_id: Option<i64>,
 }
 
 impl TtsService {
-    pub fn new(config: TtsConfig) -> Result<Self, TtsError> {
-        info!("Setting up Piper TTS with model: {}", config.model_path);
+    /// A service reading with `config`, keeping up to `cached_models` models loaded
+    pub fn new(
+        config: TtsConfig,
+        voices: Arc<VoiceRegistry>,
+        cached_models: usize,
+    ) -> Result<Self, TtsError> {
+        info!(
+            "Setting up Piper TTS with model: {} ({} Hz)",
+            config.model_path, config.sample_rate
+        );
 
         Ok(Self {
-            model_path: config.model_path.clone(),
-            config_path: config.voice_path.clone(),
             config,
+            voices,
+            models: Arc::new(ModelCache::new(cached_models)),
+            audio: None,
+            document_id: None,
         })
     }
 
-    /// Change the TTS model based on the specified language
-    pub fn with_language(&self, language: &str) -> Result<Self, TtsError> {
-        let config = TtsConfig::from_language(language);
+    /// Keep synthesized chapters in `cache` and read them back from it
+    pub fn with_audio_cache(self, cache: Arc<AudioCache>) -> Self {
+        Self {
+            audio: Some(cache),
+            ..self
+        }
+    }
+
+    /// The audio cache, if chapters are cached
+    pub fn audio_cache(&self) -> Option<&Arc<AudioCache>> {
+        self.audio.as_ref()
+    }
+
+    /// Read chapters of a document, caching their audio; text that is not part of a
+    /// document is not cached
+    pub fn for_document(self, document_id: i64) -> Self {
+        Self {
+            document_id: Some(document_id),
+            ..self
+        }
+    }
+
+    /// The installed voices
+    pub fn voices(&self) -> &VoiceRegistry {
+        &self.voices
+    }
+
+    /// Use a voice, speaker and prosody chosen with [`VoiceRegistry::select`]
+    ///
+    /// The new service shares the loaded models of this one, so it is cheap to create.
+    pub fn with_settings(&self, settings: &VoiceSettings) -> Self {
+        let config = TtsConfig::from_settings(settings);
         info!(
-            "Switching TTS model to language: {} (using voice: {})",
-            language, config.voice_name
+            "Switching TTS model to voice: {} (speaker {:?}, {:?})",
+            config.voice_name, config.speaker, config.prosody
         );
 
-        Self::new(config)
+        Self {
+            config,
+            voices: self.voices.clone(),
+            models: self.models.clone(),
+            audio: self.audio.clone(),
+            document_id: self.document_id,
+        }
+    }
+
+    /// Load a voice's model ahead of its first request
+    pub fn preload(&self, voice: &Voice) -> Result<(), TtsError> {
+        self.model(&TtsConfig::from_voice(voice)).map(|_| ())
+    }
+
+    /// The model for a config's voice, loaded on first use
+    fn model(&self, config: &TtsConfig) -> Result<Arc<Model>, TtsError> {
+        self.models.get_or_load(&config.voice_name, || {
+            info!("Loading Piper model {}", config.model_path);
+            let model = piper_rs::from_config_path(Path::new(&config.voice_path))
+                .map_err(|e| TtsError::ModelError(e.to_string()))?;
+            let synth = PiperSpeechSynthesizer::new(model).map_err(|e| {
+                TtsError::PiperError(format!("Failed to create synthesizer: {}", e))
+            })?;
+            Ok(Mutex::new(synth))
+        })
     }
 
     /// Extract plain text from HTML content
-    fn extract_text_from_html(&self, html_content: &str) -> Result<String, TtsError> {
+    pub fn extract_text_from_html(&self, html_content: &str) -> Result<String, TtsError> {
         let document = Html::parse_document(html_content);
 
         // Select text nodes
@@ -221,92 +395,146 @@ impl TtsService {
         Ok(cleaned_text)
     }
 
-    /// Convert HTML content to an audio stream
-    pub fn html_to_audio(&self, html_content: &str) -> Result<AudioStream, TtsError> {
-        // Extract text from HTML
-        let text = self.extract_text_from_html(html_content)?;
-
-        info!(
-            "Synthesizing speech for text: {:?}",
-            text.chars().take(40).collect::<String>()
-        );
+    /// The voice, speaker, prosody and sample rate, which decide how text sounds
+    pub fn settings(&self) -> String {
+        let config = &self.config;
+        format!(
+            "{} {:?} {} {} {} {}",
+            config.voice_name,
+            config.speaker,
+            config.prosody.length_scale,
+            config.prosody.noise_scale,
+            config.prosody.noise_w,
+            config.sample_rate
+        )
+    }
 
-        // Load the model from config
-        let model = piper_rs::from_config_path(Path::new(&self.config_path))
-            .map_err(|e| TtsError::ModelError(e.to_string()))?;
-
-        // Create a synthesizer
-        let synth = PiperSpeechSynthesizer::new(model)
-            .map_err(|e| TtsError::PiperError(format!("Failed to create synthesizer: {}", e)))?;
-
-        // Create a temporary file for the audio
-        let temp_dir = std::env::temp_dir();
-        let output_path = temp_dir.join(format!("epub_audio_{}.wav", uuid::Uuid::new_v4()));
-
-        // Synthesize the text to a file
-        synth
-            .synthesize_to_file(&output_path, text, None)
-            .map_err(|e| TtsError::PiperError(format!("Failed to synthesize text: {}", e)))?;
-
-        // Read the file into memory
-        let raw_audio = std::fs::read(&output_path).map_err(|e| {
-            TtsError::IoError(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to read audio file: {}", e),
-            ))
-        })?;
+    /// Where `text` of this service's document read with its voice, speaker and prosody
+    /// is cached: the cache, the document and the key; `None` if it is not cached
+    fn cache_entry(
+        &self,
+        text: &str,
+        format: AudioFormat,
+    ) -> Option<(&Arc<AudioCache>, i64, String)> {
+        let cache = self.audio.as_ref()?;
+        let document_id = self.document_id?;
+        let settings = format!("{} {}", self.settings(), format);
+        let key = audio_cache::cache_key(text, &settings);
+        Some((cache, document_id, key))
+    }
 
-        // Delete the temporary file
-        let _ = std::fs::remove_file(&output_path);
+    /// `text` as read before with the same voice, speaker and prosody, in `format`
+    pub fn cached_audio(&self, text: &str, format: AudioFormat) -> Option<CachedAudio> {
+        let (cache, document_id, key) = self.cache_entry(text, format)?;
+        cache.get(document_id, &key)
+    }
 
-        let total_len = raw_audio.len();
+    /// The sample rate of the audio this service synthesizes
+    pub fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
 
+    /// The sentences of `text` and what speaks each of them as 16-bit samples
+    #[allow(clippy::type_complexity)]
+    fn speaker(
+        &self,
+        text: &str,
+    ) -> Result<
+        (
+            Vec<String>,
+            impl FnMut(String) -> Result<Vec<u8>, TtsError> + Send + 'static,
+        ),
+        TtsError,
+    > {
         info!(
-            "Speech synthesis completed, audio length: {} bytes",
-            total_len
+            "Synthesizing speech for text: {:?}",
+            text.chars().take(40).collect::<String>()
         );
 
-        // Create audio stream
-        let audio_cursor = Cursor::new(raw_audio);
+        // Phonemizing a chapter is quick, speaking it is what takes minutes
+        let model = self.model(&self.config)?;
+        let sentences = model
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone_model()
+            .phonemize_text(text)
+            .map_err(|e| TtsError::PiperError(format!("Failed to phonemize text: {}", e)))?
+            .to_vec();
+
+        let settings = PiperSynthesisConfig {
+            speaker: self.config.speaker,
+            length_scale: self.config.prosody.length_scale,
+            noise_scale: self.config.prosody.noise_scale,
+            noise_w: self.config.prosody.noise_w,
+        };
+        let speak = move |phonemes: String| {
+            // The settings are stored in the shared model, keep it to ourselves while speaking
+            let synth = model.lock().unwrap_or_else(|e| e.into_inner());
+            let model = synth.clone_model();
+            model
+                .set_fallback_synthesis_config(&settings)
+                .map_err(|e| TtsError::ModelError(e.to_string()))?;
+            let audio = model
+                .speak_one_sentence(phonemes)
+                .map_err(|e| TtsError::PiperError(format!("Failed to synthesize text: {}", e)))?;
+            Ok(audio.as_wave_bytes())
+        };
 
-        Ok(AudioStream {
-            audio_data: audio_cursor,
-            buffer: BytesMut::with_capacity(8192),
-            position: 0,
-            total_len,
-        })
+        Ok((sentences, speak))
     }
 
-    /// Convert a file containing HTML to an audio stream
-    pub async fn html_file_to_audio<P: AsRef<Path>>(
+    /// Convert text to an audio stream in `format` that starts as soon as the first sentence
+    /// is spoken
+    ///
+    /// `on_spoken` is called with the seconds of audio of each sentence as it is spoken, and
+    /// stops synthesis by returning false. Chapters read to the end are added to the audio
+    /// cache.
+    pub fn text_to_audio(
         &self,
-        file_path: P,
+        text: &str,
+        format: AudioFormat,
+        on_spoken: impl FnMut(f64) -> bool + Send + 'static,
     ) -> Result<AudioStream, TtsError> {
-        // Read the HTML file
-        let mut file = File::open(file_path).await?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).await?;
+        let (sentences, speak) = self.speaker(text)?;
+
+        let sample_rate = self.config.sample_rate;
+        let encoder = format
+            .encoder(sample_rate)
+            .map_err(TtsError::EncodingError)?;
+        let cache = match self.cache_entry(text, format) {
+            Some((cache, document_id, key)) => {
+                Some(cache.writer(document_id, &key, format.name())?)
+            }
+            None => None,
+        };
 
-        // Process the HTML
-        self.html_to_audio(&contents)
+        stream_sentences(sentences, sample_rate, speak, encoder, cache, on_spoken)
     }
 
-    /// Create a WAV file header
-    pub fn create_wav_header(&self, _audio_data_len: usize) -> Vec<u8> {
-        // Since the synthesizer now creates a complete WAV file,
-        // we don't need to create a header anymore. Return empty vector.
-        Vec::new()
+    /// Synthesize `text` on the calling thread, handing the samples of each sentence to
+    /// `on_samples` as it is spoken; for audio nobody listens to while it is made
+    pub fn text_to_samples(
+        &self,
+        text: &str,
+        mut on_samples: impl FnMut(&[u8]) -> Result<(), TtsError>,
+    ) -> Result<(), TtsError> {
+        let (sentences, mut speak) = self.speaker(text)?;
+        for sentence in sentences {
+            on_samples(&speak(sentence)?)?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::services::audio_format::WAV_HEADER_LEN;
 
     #[test]
     fn test_extract_text_from_html() {
         let config = TtsConfig::default();
-        let service = TtsService::new(config).unwrap();
+        let service = TtsService::new(config, Arc::default(), CACHED_MODELS).unwrap();
 
         let html = r#"<html><body>
             <h1>Chapter 1</h1>
@@ -321,4 +549,112 @@ mod tests {
             "Chapter 1 This is a test paragraph. Another paragraph."
         );
     }
+
+    #[tokio::test]
+    async fn test_audio_streams_sentence_by_sentence() {
+        use futures::StreamExt;
+
+        let (spoken, seconds) = std::sync::mpsc::channel();
+        let speak = |sentence: String| match sentence.as_str() {
+            "fail" => Err(TtsError::PiperError("no voice".to_string())),
+            _ => Ok(vec![1; sentence.len() * 2]),
+        };
+        let sentences = ["one", "three", "fail", "never"]
+            .map(str::to_string)
+            .to_vec();
+        let encoder = AudioFormat::Wav.encoder(8).unwrap();
+        let mut stream = stream_sentences(sentences, 8, speak, encoder, None, move |seconds| {
+            spoken.send(seconds).unwrap();
+            true
+        })
+        .unwrap();
+
+        let header = stream.next().await.unwrap().unwrap();
+        assert_eq!(header.len(), WAV_HEADER_LEN);
+        assert_eq!(&header[..4], b"RIFF");
+        assert_eq!(&header[40..], &u32::MAX.to_le_bytes());
+        assert_eq!(stream.next().await.unwrap().unwrap().len(), 6);
+        assert_eq!(stream.next().await.unwrap().unwrap().len(), 10);
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        assert_eq!(seconds.try_iter().collect::<Vec<_>>(), vec![0.375, 0.625]);
+    }
+
+    #[tokio::test]
+    async fn test_only_complete_chapters_are_cached() {
+        use futures::StreamExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(AudioCache::open(dir.path(), 1 << 20).unwrap());
+        let speak = |sentence: String| match sentence.as_str() {
+            "fail" => Err(TtsError::PiperError("no voice".to_string())),
+            _ => Ok(vec![1; sentence.len() * 2]),
+        };
+
+        let cases = [
+            ("complete", ["one", "two"], true),
+            ("failed", ["one", "fail"], true),
+            ("stopped", ["one", "two"], false),
+        ];
+        for (key, sentences, go_on) in cases {
+            let writer = cache.writer(1, key, "wav").unwrap();
+            let encoder = AudioFormat::Wav.encoder(8).unwrap();
+            let sentences = sentences.map(str::to_string).to_vec();
+            let stream =
+                stream_sentences(sentences, 8, speak, encoder, Some(writer), move |_| go_on)
+                    .unwrap();
+            let sent = stream.collect::<Vec<_>>().await;
+            if !go_on {
+                // The header and the sentence that was spoken
+                assert_eq!(sent.len(), 2);
+            }
+        }
+
+        // The synthesis thread finishes the file after the last sentence is sent
+        let mut cached = None;
+        for _ in 0..100 {
+            cached = cache.get(1, "complete");
+            if cached.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let cached = cached.unwrap();
+        assert_eq!(cached.len, WAV_HEADER_LEN as u64 + 12);
+        // The header tells the length once it is known
+        let data = std::fs::read(&cached.path).unwrap();
+        assert_eq!(&data[40..44], &12u32.to_le_bytes());
+        assert!(cache.get(1, "failed").is_none());
+        assert!(cache.get(1, "stopped").is_none());
+    }
+
+    #[test]
+    fn test_model_cache_keeps_recently_used() {
+        let cache = ModelCache::new(2);
+        let loads = std::cell::Cell::new(0);
+        let load = |name: &str| {
+            cache
+                .get_or_load(name, || {
+                    loads.set(loads.get() + 1);
+                    Ok::<_, TtsError>(name.to_uppercase())
+                })
+                .unwrap()
+        };
+
+        assert_eq!(*load("en"), "EN");
+        assert_eq!(*load("ru"), "RU");
+        assert_eq!(*load("en"), "EN");
+        assert_eq!(loads.get(), 2);
+
+        // "ru" was used least recently
+        load("de");
+        assert_eq!(cache.entries().len(), 2);
+        assert!(cache.get("ru").is_none());
+        assert!(cache.get("en").is_some());
+
+        // Failed loads are not cached
+        let failed = cache.get_or_load("fr", || Err(TtsError::ModelError("missing".into())));
+        assert!(failed.is_err());
+        assert!(cache.get("fr").is_none());
+    }
 }
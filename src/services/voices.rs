@@ -0,0 +1,510 @@
+//! The Piper voices found in the voices directory
+//!
+//! Every `<name>.onnx` model with a `<name>.onnx.config` next to it is a voice. The config
+//! is the JSON Piper ships with each model; the language, quality, sample rate and speakers
+//! are read from it, so adding a voice is just dropping both files in the directory.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Where voices are looked for unless `EPUB_VOICES_DIR` says otherwise
+pub const VOICES_DIR: &str = "./assets/voices";
+
+/// The voice used for languages without one of their own, if it is installed
+pub const DEFAULT_VOICE: &str = "en_US-ryan-high";
+
+const CONFIG_SUFFIX: &str = ".onnx.config";
+
+/// Piper's quality levels from worst to best
+const QUALITIES: [&str; 4] = ["x_low", "low", "medium", "high"];
+
+/// Accepted length scales; 2 reads at half speed
+const LENGTH_SCALE_RANGE: (f32, f32) = (0.25, 4.0);
+
+/// Accepted noise and noise width scales
+const NOISE_RANGE: (f32, f32) = (0.0, 2.0);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Voice {
+    pub name: String,
+    /// BCP 47 language tag, e.g. `en-US`
+    pub language: String,
+    /// `x_low`, `low`, `medium` or `high`
+    pub quality: Option<String>,
+    pub sample_rate: u32,
+    /// Speakers of multi-speaker models by id; empty for single speakers
+    pub speakers: Vec<Speaker>,
+    /// What the voice is read with unless a request says otherwise
+    pub prosody: Prosody,
+    #[serde(skip)]
+    pub model_path: PathBuf,
+    #[serde(skip)]
+    pub config_path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Speaker {
+    pub id: i64,
+    pub name: String,
+}
+
+/// How Piper reads: `length_scale` is the time each phoneme takes, so higher is slower;
+/// the noise scales vary the voice and the phoneme lengths
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Prosody {
+    pub length_scale: f32,
+    pub noise_scale: f32,
+    pub noise_w: f32,
+}
+
+impl Default for Prosody {
+    /// Piper's defaults
+    fn default() -> Self {
+        Prosody {
+            length_scale: 1.0,
+            noise_scale: 0.667,
+            noise_w: 0.8,
+        }
+    }
+}
+
+/// The voice, speaker and prosody a request asks for; missing values are left to the
+/// voice and the book's language
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct VoiceRequest {
+    pub voice: Option<String>,
+    /// A speaker's name or id
+    pub speaker: Option<String>,
+    #[serde(rename = "rate")]
+    pub length_scale: Option<f32>,
+    pub noise_scale: Option<f32>,
+    pub noise_w: Option<f32>,
+}
+
+/// A voice with everything needed to synthesize with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceSettings {
+    pub voice: Voice,
+    pub speaker: Option<i64>,
+    pub prosody: Prosody,
+}
+
+/// The parts of a Piper voice config that describe the voice
+#[derive(Debug, Deserialize)]
+struct PiperConfig {
+    audio: PiperAudio,
+    language: Option<PiperLanguage>,
+    #[serde(default)]
+    speaker_id_map: HashMap<String, i64>,
+    #[serde(default)]
+    inference: Option<Prosody>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PiperAudio {
+    sample_rate: u32,
+    quality: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PiperLanguage {
+    /// e.g. `en_US`
+    code: String,
+}
+
+impl Voice {
+    /// A voice from the text of its Piper config; voices named the Piper way, like
+    /// `en_US-ryan-high`, need no language in the config
+    pub fn from_config(name: &str, config: &str, dir: &Path) -> Result<Self, String> {
+        let config: PiperConfig =
+            serde_json::from_str(config).map_err(|e| format!("invalid voice config: {}", e))?;
+
+        let language = config
+            .language
+            .map(|language| language.code)
+            .or_else(|| name.split('-').next().map(str::to_string))
+            .filter(|code| !code.is_empty())
+            .ok_or_else(|| "the voice config has no language".to_string())?;
+
+        let mut speakers: Vec<Speaker> = config
+            .speaker_id_map
+            .into_iter()
+            .map(|(name, id)| Speaker { id, name })
+            .collect();
+        speakers.sort_by_key(|speaker| speaker.id);
+
+        Ok(Voice {
+            name: name.to_string(),
+            language: language_tag(&language),
+            quality: config.audio.quality,
+            sample_rate: config.audio.sample_rate,
+            speakers,
+            prosody: config.inference.unwrap_or_default(),
+            model_path: dir.join(format!("{}.onnx", name)),
+            config_path: dir.join(format!("{}{}", name, CONFIG_SUFFIX)),
+        })
+    }
+
+    fn quality_rank(&self) -> usize {
+        self.quality
+            .as_deref()
+            .and_then(|quality| QUALITIES.iter().position(|known| *known == quality))
+            .unwrap_or(0)
+    }
+
+    /// A speaker by name or id
+    fn speaker(&self, speaker: &str) -> Option<&Speaker> {
+        self.speakers
+            .iter()
+            .find(|known| known.name == speaker)
+            .or_else(|| {
+                let id: i64 = speaker.parse().ok()?;
+                self.speakers.iter().find(|known| known.id == id)
+            })
+    }
+}
+
+fn check_range(name: &str, value: Option<f32>, (min, max): (f32, f32)) -> Result<(), String> {
+    match value {
+        Some(value) if !(min..=max).contains(&value) => Err(format!(
+            "{} must be between {} and {}, got {}",
+            name, min, max, value
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// `en_US` and `en-us` as `en-US`
+fn language_tag(code: &str) -> String {
+    let mut parts = code.split(['_', '-']);
+    let mut tag = parts.next().unwrap_or_default().to_lowercase();
+    for part in parts {
+        tag.push('-');
+        if part.len() == 2 {
+            tag.push_str(&part.to_uppercase());
+        } else {
+            tag.push_str(part);
+        }
+    }
+    tag
+}
+
+fn primary_language(tag: &str) -> &str {
+    tag.split('-').next().unwrap_or(tag)
+}
+
+/// The installed voices, by name
+#[derive(Debug, Default)]
+pub struct VoiceRegistry {
+    voices: Vec<Voice>,
+    default_voice: Option<String>,
+}
+
+impl VoiceRegistry {
+    /// The voices in `dir`; files that are not valid voices are skipped with a warning
+    ///
+    /// `default_voice` is used for languages without a voice, falling back to
+    /// [`DEFAULT_VOICE`] and then any English voice.
+    pub fn scan(dir: &Path, default_voice: Option<&str>) -> io::Result<Self> {
+        let mut voices = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(CONFIG_SUFFIX))
+            else {
+                continue;
+            };
+
+            let voice = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|config| Voice::from_config(name, &config, dir));
+            match voice {
+                Ok(voice) if voice.model_path.is_file() => voices.push(voice),
+                Ok(voice) => warn!(
+                    "Skipping voice {}: model {} not found",
+                    name,
+                    voice.model_path.display()
+                ),
+                Err(e) => warn!("Skipping voice {}: {}", name, e),
+            }
+        }
+
+        Ok(Self::new(voices, default_voice))
+    }
+
+    pub fn new(mut voices: Vec<Voice>, default_voice: Option<&str>) -> Self {
+        voices.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut registry = VoiceRegistry {
+            voices,
+            default_voice: None,
+        };
+
+        registry.default_voice = default_voice
+            .into_iter()
+            .chain([DEFAULT_VOICE])
+            .find(|name| registry.get(name).is_some())
+            .map(str::to_string)
+            .or_else(|| registry.best_for("en").map(|voice| voice.name.clone()))
+            .or_else(|| registry.voices.first().map(|voice| voice.name.clone()));
+        registry
+    }
+
+    /// Every voice, sorted by name
+    pub fn voices(&self) -> &[Voice] {
+        &self.voices
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Voice> {
+        self.voices.iter().find(|voice| voice.name == name)
+    }
+
+    pub fn default_voice(&self) -> Option<&Voice> {
+        self.default_voice
+            .as_deref()
+            .and_then(|name| self.get(name))
+    }
+
+    /// The best voice for a language tag such as `ru-RU` or `ru`: one for exactly that
+    /// language, else one for the same primary language, else the default voice
+    pub fn for_language(&self, language: &str) -> Option<&Voice> {
+        let tag = language_tag(language.trim());
+        self.best(|voice| voice.language.eq_ignore_ascii_case(&tag))
+            .or_else(|| self.best_for(primary_language(&tag)))
+            .or_else(|| self.default_voice())
+    }
+
+    /// The voice, speaker and prosody for a request; without a voice in the request, the
+    /// best voice for `language` is used
+    pub fn select(
+        &self,
+        request: &VoiceRequest,
+        language: Option<&str>,
+    ) -> Result<VoiceSettings, String> {
+        let voice = match &request.voice {
+            Some(name) => self
+                .get(name)
+                .ok_or_else(|| format!("Unknown voice {}, see /voices", name))?,
+            None => match language {
+                Some(language) => self.for_language(language),
+                None => self.default_voice(),
+            }
+            .ok_or_else(|| "No voices are installed".to_string())?,
+        };
+
+        let speaker = match &request.speaker {
+            None => voice.speakers.first().map(|speaker| speaker.id),
+            Some(_) if voice.speakers.is_empty() => {
+                return Err(format!("Voice {} has a single speaker", voice.name));
+            }
+            Some(speaker) => Some(
+                voice
+                    .speaker(speaker)
+                    .ok_or_else(|| format!("Voice {} has no speaker {}", voice.name, speaker))?
+                    .id,
+            ),
+        };
+
+        check_range("rate", request.length_scale, LENGTH_SCALE_RANGE)?;
+        check_range("noise_scale", request.noise_scale, NOISE_RANGE)?;
+        check_range("noise_w", request.noise_w, NOISE_RANGE)?;
+        let prosody = Prosody {
+            length_scale: request.length_scale.unwrap_or(voice.prosody.length_scale),
+            noise_scale: request.noise_scale.unwrap_or(voice.prosody.noise_scale),
+            noise_w: request.noise_w.unwrap_or(voice.prosody.noise_w),
+        };
+
+        Ok(VoiceSettings {
+            voice: voice.clone(),
+            speaker,
+            prosody,
+        })
+    }
+
+    fn best_for(&self, primary: &str) -> Option<&Voice> {
+        self.best(|voice| primary_language(&voice.language).eq_ignore_ascii_case(primary))
+    }
+
+    /// The highest quality voice matching `filter`, the default voice among equals
+    fn best(&self, filter: impl Fn(&Voice) -> bool) -> Option<&Voice> {
+        self.voices
+            .iter()
+            .filter(|voice| filter(voice))
+            .max_by_key(|voice| {
+                (
+                    voice.quality_rank(),
+                    self.default_voice.as_deref() == Some(voice.name.as_str()),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(code: &str, quality: &str, speakers: &str) -> String {
+        format!(
+            r#"{{"audio": {{"sample_rate": 22050, "quality": "{}"}},
+                "language": {{"code": "{}", "family": "xx"}},
+                "num_speakers": 1, "speaker_id_map": {{{}}}}}"#,
+            quality, code, speakers
+        )
+    }
+
+    #[test]
+    fn test_voice_from_config() {
+        let dir = Path::new("voices");
+        let voice = Voice::from_config(
+            "en_US-libritts-high",
+            &config("en_US", "high", r#""p239": 1, "p3922": 0"#),
+            dir,
+        )
+        .unwrap();
+        assert_eq!(voice.language, "en-US");
+        assert_eq!(voice.quality.as_deref(), Some("high"));
+        assert_eq!(voice.sample_rate, 22050);
+        let speakers: Vec<(i64, &str)> = voice
+            .speakers
+            .iter()
+            .map(|speaker| (speaker.id, speaker.name.as_str()))
+            .collect();
+        assert_eq!(speakers, vec![(0, "p3922"), (1, "p239")]);
+        assert_eq!(voice.prosody, Prosody::default());
+        assert_eq!(voice.model_path, dir.join("en_US-libritts-high.onnx"));
+
+        // Older configs have no language, which the name tells
+        let voice = Voice::from_config(
+            "de_DE-thorsten-low",
+            r#"{"audio": {"sample_rate": 16000}}"#,
+            dir,
+        )
+        .unwrap();
+        assert_eq!(voice.language, "de-DE");
+        assert!(voice.speakers.is_empty());
+
+        assert!(Voice::from_config("broken", "version https://git-lfs", dir).is_err());
+    }
+
+    #[test]
+    fn test_scan_and_choose_voices() {
+        let dir = tempfile::tempdir().unwrap();
+        let add = |name: &str, code: &str, quality: &str| {
+            std::fs::write(
+                dir.path().join(format!("{}.onnx.config", name)),
+                config(code, quality, ""),
+            )
+            .unwrap();
+            std::fs::write(dir.path().join(format!("{}.onnx", name)), b"model").unwrap();
+        };
+        add("en_US-ryan-high", "en_US", "high");
+        add("en_GB-alan-low", "en_GB", "low");
+        add("ru_RU-ruslan-medium", "ru_RU", "medium");
+        add("ru_RU-irina-low", "ru_RU", "low");
+        // A config without its model is not a voice
+        std::fs::write(
+            dir.path().join("fr_FR-siwis-low.onnx.config"),
+            config("fr_FR", "low", ""),
+        )
+        .unwrap();
+
+        let registry = VoiceRegistry::scan(dir.path(), None).unwrap();
+        let names: Vec<&str> = registry.voices().iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "en_GB-alan-low",
+                "en_US-ryan-high",
+                "ru_RU-irina-low",
+                "ru_RU-ruslan-medium"
+            ]
+        );
+
+        let choose = |language| registry.for_language(language).unwrap().name.as_str();
+        assert_eq!(choose("ru"), "ru_RU-ruslan-medium");
+        assert_eq!(choose("ru-RU"), "ru_RU-ruslan-medium");
+        assert_eq!(choose("en-GB"), "en_GB-alan-low");
+        assert_eq!(choose("en-AU"), "en_US-ryan-high");
+        assert_eq!(choose("fr-FR"), "en_US-ryan-high");
+
+        let registry = VoiceRegistry::scan(dir.path(), Some("en_GB-alan-low")).unwrap();
+        assert_eq!(registry.default_voice().unwrap().name, "en_GB-alan-low");
+        assert_eq!(registry.for_language("fr").unwrap().name, "en_GB-alan-low");
+    }
+
+    #[test]
+    fn test_select_voice_speaker_and_prosody() {
+        let dir = Path::new("voices");
+        let voice = |name: &str, code: &str, speakers: &str| {
+            Voice::from_config(name, &config(code, "medium", speakers), dir).unwrap()
+        };
+        let registry = VoiceRegistry::new(
+            vec![
+                voice("en_US-libritts-medium", "en_US", r#""p3922": 0, "p239": 7"#),
+                voice("ru_RU-ruslan-medium", "ru_RU", ""),
+            ],
+            None,
+        );
+        let select = |request: VoiceRequest, language| registry.select(&request, language);
+
+        // The book's language picks the voice, the first speaker and the voice's prosody
+        let settings = select(VoiceRequest::default(), Some("ru")).unwrap();
+        assert_eq!(settings.voice.name, "ru_RU-ruslan-medium");
+        assert_eq!(settings.speaker, None);
+        assert_eq!(settings.prosody, Prosody::default());
+        let settings = select(VoiceRequest::default(), None).unwrap();
+        assert_eq!(settings.voice.name, "en_US-libritts-medium");
+        assert_eq!(settings.speaker, Some(0));
+
+        let request = VoiceRequest {
+            voice: Some("en_US-libritts-medium".to_string()),
+            speaker: Some("p239".to_string()),
+            length_scale: Some(1.5),
+            ..Default::default()
+        };
+        let settings = select(request.clone(), Some("ru")).unwrap();
+        assert_eq!(settings.voice.name, "en_US-libritts-medium");
+        assert_eq!(settings.speaker, Some(7));
+        assert_eq!(settings.prosody.length_scale, 1.5);
+        assert_eq!(settings.prosody.noise_w, 0.8);
+        let by_id = VoiceRequest {
+            speaker: Some("7".to_string()),
+            ..request.clone()
+        };
+        assert_eq!(select(by_id, None).unwrap().speaker, Some(7));
+
+        let invalid = [
+            VoiceRequest {
+                voice: Some("xx_XX-nobody-low".to_string()),
+                ..Default::default()
+            },
+            VoiceRequest {
+                speaker: Some("p1".to_string()),
+                ..request.clone()
+            },
+            VoiceRequest {
+                voice: Some("ru_RU-ruslan-medium".to_string()),
+                speaker: Some("0".to_string()),
+                ..Default::default()
+            },
+            VoiceRequest {
+                length_scale: Some(10.0),
+                ..Default::default()
+            },
+            VoiceRequest {
+                noise_w: Some(-1.0),
+                ..Default::default()
+            },
+        ];
+        for request in invalid {
+            assert!(select(request.clone(), None).is_err(), "{:?}", request);
+        }
+        assert!(VoiceRegistry::default()
+            .select(&VoiceRequest::default(), Some("en"))
+            .is_err());
+    }
+}